@@ -0,0 +1,119 @@
+//! The lock-free stream next to other broadcast channels
+//!
+//! One producer thread publishes a batch of `u64` items to four reader
+//! threads, which each read all of them. crossbeam has no broadcast
+//! channel, so it gets one channel per reader and the producer sends every
+//! item to each of them. Run with `cargo bench --bench broadcast`.
+
+use std::{
+    hint::black_box,
+    sync::{Arc, Barrier},
+    thread,
+};
+
+use component_model_multiple_consumers::lockfree;
+use criterion::{Criterion, Throughput};
+
+const BATCH: u64 = 1000;
+const READERS: usize = 4;
+
+/// Runs `read` on every reader in a thread of its own and `publish` on the
+/// current one, returns once all readers are done
+fn fan_out<R: Send + 'static>(readers: Vec<R>, read: fn(R), publish: impl FnOnce()) {
+    let start = Arc::new(Barrier::new(readers.len() + 1));
+    let threads: Vec<_> = readers
+        .into_iter()
+        .map(|reader| {
+            let start = start.clone();
+            thread::spawn(move || {
+                start.wait();
+                read(reader)
+            })
+        })
+        .collect();
+    start.wait();
+    publish();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+}
+
+fn lockfree(wakeups: bool) -> impl FnMut() {
+    move || {
+        let mut publisher = if wakeups {
+            lockfree::Publisher::with_wakeups(BATCH as usize, READERS)
+        } else {
+            lockfree::Publisher::new(BATCH as usize, READERS)
+        };
+        let readers = (0..READERS)
+            .map(|_| publisher.subscribe().unwrap())
+            .collect();
+        fan_out(
+            readers,
+            |mut reader| {
+                let mut left = BATCH as usize;
+                while left > 0 && reader.wait() {
+                    let batch = reader.read_batch(left);
+                    left -= batch.len();
+                    black_box(batch.iter().sum::<u64>());
+                }
+            },
+            move || {
+                for i in 0..BATCH {
+                    publisher.publish(i);
+                }
+            },
+        );
+    }
+}
+
+fn crossbeam() {
+    let (senders, readers): (Vec<_>, Vec<_>) = (0..READERS)
+        .map(|_| crossbeam_channel::bounded::<u64>(BATCH as usize))
+        .unzip();
+    fan_out(
+        readers,
+        |reader| {
+            for item in reader.iter() {
+                black_box(item);
+            }
+        },
+        move || {
+            for i in 0..BATCH {
+                for sender in senders.iter() {
+                    sender.send(i).unwrap();
+                }
+            }
+        },
+    );
+}
+
+fn tokio() {
+    let (sender, _) = tokio::sync::broadcast::channel::<u64>(BATCH as usize);
+    let readers = (0..READERS).map(|_| sender.subscribe()).collect();
+    fan_out(
+        readers,
+        |mut reader| {
+            while let Ok(item) = reader.blocking_recv() {
+                black_box(item);
+            }
+        },
+        move || {
+            for i in 0..BATCH {
+                sender.send(i).unwrap();
+            }
+        },
+    );
+}
+
+fn main() {
+    let mut c = Criterion::default().configure_from_args();
+    let mut group = c.benchmark_group("broadcast");
+    group.throughput(Throughput::Elements(BATCH));
+    group.bench_function("lockfree", |b| b.iter(lockfree(false)));
+    group.bench_function("lockfree with wakeups", |b| b.iter(lockfree(true)));
+    group.bench_function("crossbeam", |b| b.iter(crossbeam));
+    group.bench_function("tokio", |b| b.iter(tokio));
+    group.finish();
+    c.final_summary();
+}
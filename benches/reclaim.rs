@@ -0,0 +1,42 @@
+//! Cost of reading with many readers subscribed
+//!
+//! Every read may release items, so this shows how reclamation scales with
+//! the number of readers. Run with `cargo bench --bench reclaim`.
+
+use std::{hint::black_box, time::Instant};
+
+use component_model_multiple_consumers::{Publisher, StreamReader};
+
+const ITEMS: usize = 1000;
+
+fn run(readers: usize, interval: usize) {
+    let mut publisher = Publisher::new();
+    publisher.set_reclaim_interval(interval);
+    let mut all: Vec<StreamReader<u64>> = (0..readers).map(|_| StreamReader::new()).collect();
+    for reader in all.iter_mut() {
+        publisher.add_stream_reader(reader);
+    }
+    for i in 0..ITEMS as u64 {
+        publisher.publish(i);
+    }
+    let start = Instant::now();
+    for reader in all.iter_mut() {
+        while let Some(item) = reader.read() {
+            black_box(*item);
+        }
+    }
+    let elapsed = start.elapsed();
+    let reads = readers * ITEMS;
+    println!(
+        "{readers:>5} readers, reclaim every {interval:>2}: {reads:>8} reads in {elapsed:>10.2?}, {:>6.1} ns/read",
+        elapsed.as_nanos() as f64 / reads as f64
+    );
+}
+
+fn main() {
+    for interval in [1, 64] {
+        for readers in [1, 10, 100, 1000] {
+            run(readers, interval);
+        }
+    }
+}
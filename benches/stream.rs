@@ -0,0 +1,146 @@
+//! Criterion suite behind the performance targets in the README
+//!
+//! Run with `cargo bench --bench stream`, the memory report is printed
+//! before the timed benchmarks.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    hint::black_box,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use component_model_multiple_consumers::{Publisher, StreamReader};
+use criterion::{BenchmarkId, Criterion, Throughput};
+
+/// Counts the bytes currently allocated, to derive the memory per item
+struct Counting;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+const BATCH: u64 = 1000;
+
+fn readers(publisher: &mut Publisher<u64>, count: usize) -> Vec<StreamReader<u64>> {
+    let mut readers: Vec<_> = (0..count).map(|_| StreamReader::new()).collect();
+    for reader in readers.iter_mut() {
+        publisher.add_stream_reader(reader);
+    }
+    readers
+}
+
+/// Publish a batch and let every reader consume it
+///
+/// The capacity limit keeps `publish/0`, where nothing reads, from retaining
+/// every item of the run.
+fn publish(c: &mut Criterion) {
+    let mut group = c.benchmark_group("publish");
+    group.throughput(Throughput::Elements(BATCH));
+    for count in [0, 1, 4, 16, 64] {
+        group.bench_with_input(BenchmarkId::from_parameter(count), &count, |b, &count| {
+            let mut publisher = Publisher::new();
+            publisher.set_capacity_limit(BATCH as usize);
+            let mut readers = readers(&mut publisher, count);
+            b.iter(|| {
+                for i in 0..BATCH {
+                    publisher.publish(i);
+                }
+                for reader in readers.iter_mut() {
+                    while let Some(item) = reader.read() {
+                        black_box(*item);
+                    }
+                }
+            });
+        });
+    }
+    group.finish();
+}
+
+/// The same as `publish/4`, but handing over whole chunks
+fn chunks(c: &mut Criterion) {
+    let items: Vec<u64> = (0..BATCH).collect();
+    let mut group = c.benchmark_group("chunks");
+    group.throughput(Throughput::Elements(BATCH));
+    group.bench_function("4", |b| {
+        let mut publisher = Publisher::new();
+        let mut readers = readers(&mut publisher, 4);
+        b.iter(|| {
+            publisher.publish_slice(&items);
+            for reader in readers.iter_mut() {
+                while let Some(chunk) = reader.read_chunk(256) {
+                    black_box(chunk.iter().sum::<u64>());
+                }
+            }
+        });
+    });
+    group.finish();
+}
+
+/// Time from publishing an item until a reader holds it
+fn read_latency(c: &mut Criterion) {
+    let mut publisher = Publisher::new();
+    let mut readers = readers(&mut publisher, 1);
+    c.bench_function("read latency", |b| {
+        b.iter(|| {
+            publisher.publish(black_box(7));
+            black_box(*readers[0].read().unwrap());
+        });
+    });
+}
+
+/// Adding and removing a reader next to 16 existing ones with a backlog
+fn subscribe(c: &mut Criterion) {
+    let mut publisher = Publisher::new();
+    let _readers = readers(&mut publisher, 16);
+    for i in 0..BATCH {
+        publisher.publish(i);
+    }
+    c.bench_function("subscribe and unsubscribe", |b| {
+        b.iter(|| {
+            let mut reader = StreamReader::new();
+            publisher.add_stream_reader(&mut reader);
+            black_box(&reader);
+        });
+    });
+}
+
+/// Heap bytes per retained item, with one reader which doesn't read
+fn memory_report() {
+    const ITEMS: usize = 100_000;
+    for count in [1, 16] {
+        let before = ALLOCATED.load(Ordering::Relaxed);
+        let mut publisher = Publisher::new();
+        let readers = readers(&mut publisher, count);
+        for i in 0..ITEMS as u64 {
+            publisher.publish(i);
+        }
+        let used = ALLOCATED.load(Ordering::Relaxed) - before;
+        println!(
+            "memory: {:.2} bytes per retained u64 item with {count} readers",
+            used as f64 / ITEMS as f64
+        );
+        drop(readers);
+    }
+}
+
+fn main() {
+    memory_report();
+    let mut c = Criterion::default().configure_from_args();
+    publish(&mut c);
+    chunks(&mut c);
+    read_latency(&mut c);
+    subscribe(&mut c);
+    c.final_summary();
+}
@@ -0,0 +1,75 @@
+/* C interface of component-model-multiple-consumers, built with the `ffi`
+ * feature. See src/ffi.rs for the contract of each function. */
+#ifndef MULTIPLE_CONSUMERS_H
+#define MULTIPLE_CONSUMERS_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Version of the struct layouts, appending a field counts it up. Set
+ * `size` to the sizeof the struct before passing it in, the library fills in
+ * the fields it knows and stores how many bytes that were. */
+#define MC_ABI_VERSION 1
+
+#define MC_OK 0
+#define MC_EMPTY 1
+#define MC_CLOSED (-1)
+#define MC_LAGGED (-2)
+#define MC_RESET (-3)
+#define MC_TIMED_OUT (-4)
+#define MC_TOO_SMALL (-5)
+#define MC_INVALID (-6)
+
+typedef struct McPublisher McPublisher;
+typedef struct McReader McReader;
+
+typedef struct McReadResult {
+    uint32_t size;
+    int status;
+    size_t len;
+    uint64_t sequence;
+    uint64_t lagged;
+} McReadResult;
+
+typedef struct McReaderStats {
+    uint32_t size;
+    uint32_t version;
+    uint64_t delivered;
+    uint64_t read;
+    uint64_t missed;
+    uint64_t max_lag;
+    uint64_t current_lag;
+} McReaderStats;
+
+typedef struct McPublisherStats {
+    uint32_t size;
+    uint32_t version;
+    uint64_t retained;
+    uint64_t capacity;
+    uint64_t memory;
+} McPublisherStats;
+
+uint32_t mc_abi_version(void);
+const char *mc_status_message(int status);
+
+McPublisher *mc_publisher_new(void);
+void mc_publisher_free(McPublisher *publisher);
+uint64_t mc_publish(McPublisher *publisher, const uint8_t *data, size_t len);
+int mc_publisher_close(McPublisher *publisher);
+int mc_publisher_stats(const McPublisher *publisher, McPublisherStats *stats);
+
+McReader *mc_subscribe(McPublisher *publisher);
+void mc_unsubscribe(McReader *reader);
+int mc_read(McReader *reader, uint8_t *buf, size_t cap, size_t *len);
+int mc_read_result(McReader *reader, uint8_t *buf, size_t cap, McReadResult *result);
+int mc_reader_stats(const McReader *reader, McReaderStats *stats);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,159 @@
+//! Arrow record batches of retained items, for analytics consumers
+//!
+//! A plain-old-data record type implements [`Record`], usually through
+//! [`arrow_record!`](crate::arrow_record), which maps every field to a
+//! column. Readers then take whole chunks as a `RecordBatch` with
+//! [`StreamReader::read_batch`], and the retained history converts with
+//! [`Publisher::batch`]. Both copy the fields column by column into fresh
+//! Arrow buffers, one pass per column, ready to hand to DataFusion or
+//! Polars.
+//!
+//! Fields have to be primitive numbers, which are the types implementing
+//! [`Column`].
+
+pub use arrow_array;
+pub use arrow_schema;
+
+use arrow_array::{ArrowPrimitiveType, RecordBatch, types};
+use arrow_schema::SchemaRef;
+
+use crate::{Counter, Publisher, StreamReader};
+
+/// Primitive field types and their Arrow column type
+pub trait Column: Copy {
+    type Arrow: ArrowPrimitiveType<Native = Self>;
+}
+
+macro_rules! columns {
+    ($($native:ty => $arrow:ty),* $(,)?) => {
+        $(impl Column for $native {
+            type Arrow = $arrow;
+        })*
+    };
+}
+
+columns!(
+    i8 => types::Int8Type,
+    i16 => types::Int16Type,
+    i32 => types::Int32Type,
+    i64 => types::Int64Type,
+    u8 => types::UInt8Type,
+    u16 => types::UInt16Type,
+    u32 => types::UInt32Type,
+    u64 => types::UInt64Type,
+    f32 => types::Float32Type,
+    f64 => types::Float64Type,
+);
+
+/// Record type which converts to rows of a `RecordBatch`
+pub trait Record: Sized {
+    /// One column per field
+    fn schema() -> SchemaRef;
+    /// Convert the items to a batch of the schema
+    fn batch(items: &[&Self]) -> RecordBatch;
+}
+
+/// Implement [`Record`] for a struct with [`Column`] fields
+///
+/// Lists the struct and its fields with their types, e.g.
+/// `arrow_record!(Sample { time: i64, value: f64 });`
+#[macro_export]
+macro_rules! arrow_record {
+    ($record:ty { $($field:ident: $type:ty),* $(,)? }) => {
+        impl $crate::arrow::Record for $record {
+            fn schema() -> $crate::arrow::arrow_schema::SchemaRef {
+                use $crate::arrow::{Column, arrow_array::ArrowPrimitiveType};
+                ::std::sync::Arc::new($crate::arrow::arrow_schema::Schema::new(::std::vec![
+                    $($crate::arrow::arrow_schema::Field::new(
+                        stringify!($field),
+                        <<$type as Column>::Arrow as ArrowPrimitiveType>::DATA_TYPE,
+                        false,
+                    )),*
+                ]))
+            }
+            fn batch(items: &[&Self]) -> $crate::arrow::arrow_array::RecordBatch {
+                use $crate::arrow::{Column, arrow_array::{ArrayRef, PrimitiveArray}};
+                let columns: ::std::vec::Vec<ArrayRef> = ::std::vec![
+                    $(::std::sync::Arc::new(
+                        PrimitiveArray::<<$type as Column>::Arrow>::from_iter_values(
+                            items.iter().map(|item| item.$field),
+                        ),
+                    )),*
+                ];
+                $crate::arrow::arrow_array::RecordBatch::try_new(Self::schema(), columns)
+                    .expect("columns match the schema")
+            }
+        }
+    };
+}
+
+impl<T: Record> StreamReader<T> {
+    /// Read up to `max` items as one batch, `None` while nothing is queued
+    ///
+    /// Returns the sequence number of the first row with the batch.
+    pub fn read_batch(&mut self, max: usize) -> Option<(Counter, RecordBatch)> {
+        let chunk = self.read_chunk(max)?;
+        let items: Vec<&T> = chunk.iter().collect();
+        Some((chunk.sequence(), T::batch(&items)))
+    }
+}
+
+impl<T: Record> Publisher<T> {
+    /// The retained items within `range` as one batch, see [`Publisher::range`]
+    pub fn batch(&self, range: core::ops::Range<Counter>) -> RecordBatch {
+        let items: Vec<&T> = self.range(range).collect();
+        T::batch(&items)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Record;
+    use crate::{Publisher, StreamReader};
+    use arrow_array::{Array, Float64Array, Int64Array};
+
+    #[derive(Clone, Copy)]
+    struct Sample {
+        time: i64,
+        value: f64,
+    }
+    arrow_record!(Sample {
+        time: i64,
+        value: f64
+    });
+
+    #[test]
+    fn batches() {
+        let mut p = Publisher::new();
+        let mut r = StreamReader::new();
+        p.add_stream_reader(&mut r);
+        p.publish_iter((0..5).map(|n| Sample {
+            time: n,
+            value: n as f64 / 2.0,
+        }));
+        let (seq, batch) = r.read_batch(3).unwrap();
+        assert_eq!(seq, 0);
+        assert_eq!(batch.schema(), Sample::schema());
+        assert_eq!(batch.num_rows(), 3);
+        let time = batch
+            .column(0)
+            .as_any()
+            .downcast_ref::<Int64Array>()
+            .unwrap();
+        assert_eq!(time.values(), &[0, 1, 2]);
+        let (seq, batch) = r.read_batch(10).unwrap();
+        assert_eq!(seq, 3);
+        let value = batch.column_by_name("value").unwrap();
+        let value = value.as_any().downcast_ref::<Float64Array>().unwrap();
+        assert_eq!(value.values(), &[1.5, 2.0]);
+        assert!(r.read_batch(10).is_none());
+
+        let mut late = StreamReader::new();
+        p.add_stream_reader(&mut late);
+        p.publish(Sample {
+            time: 5,
+            value: 0.0,
+        });
+        assert_eq!(p.batch(p.retained()).num_rows(), 1);
+    }
+}
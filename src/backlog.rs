@@ -0,0 +1,226 @@
+//! Sequence numbers a reader still has to read
+//!
+//! Instead of one entry per item the backlog stores runs of consecutive
+//! sequence numbers, so a reader which receives every item only needs a
+//! single run no matter how far it is behind.
+
+use alloc::collections::{BTreeMap, VecDeque};
+
+use crate::{Counter, seq};
+
+#[derive(Default)]
+pub(crate) struct Backlog {
+    /// first sequence number and length of each run, oldest first
+    runs: VecDeque<(Counter, usize)>,
+    len: usize,
+}
+
+impl Backlog {
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    pub(crate) fn heap_size(&self) -> usize {
+        self.runs.capacity() * size_of::<(Counter, usize)>()
+    }
+    pub(crate) fn front(&self) -> Option<Counter> {
+        self.runs.front().map(|run| run.0)
+    }
+    /// First sequence number and length of the oldest run
+    pub(crate) fn front_run(&self) -> Option<(Counter, usize)> {
+        self.runs.front().copied()
+    }
+    /// The `n`th oldest entry
+    pub(crate) fn get(&self, mut n: usize) -> Option<Counter> {
+        for &(start, len) in self.runs.iter() {
+            if n < len {
+                return Some(seq::advance(start, n));
+            }
+            n -= len;
+        }
+        None
+    }
+    pub(crate) fn iter(&self) -> impl Iterator<Item = Counter> + '_ {
+        self.runs
+            .iter()
+            .flat_map(|&(start, len)| (0..len).map(move |n| seq::advance(start, n)))
+    }
+    /// Append a sequence number newer than all queued ones
+    pub(crate) fn push(&mut self, count: Counter) {
+        match self.runs.back_mut() {
+            Some((start, len)) if seq::advance(*start, *len) == count => *len += 1,
+            _ => self.runs.push_back((count, 1)),
+        }
+        self.len += 1;
+    }
+    /// Append `len` consecutive sequence numbers starting at `count`
+    pub(crate) fn push_run(&mut self, count: Counter, len: usize) {
+        if len == 0 {
+            return;
+        }
+        match self.runs.back_mut() {
+            Some((start, run)) if seq::advance(*start, *run) == count => *run += len,
+            _ => self.runs.push_back((count, len)),
+        }
+        self.len += len;
+    }
+    /// Remove a single sequence number, returns whether it was queued
+    pub(crate) fn remove(&mut self, count: Counter) -> bool {
+        let Some(pos) = self
+            .runs
+            .iter()
+            .position(|&(start, len)| seq::distance(start, count) < len)
+        else {
+            return false;
+        };
+        let (start, len) = self.runs[pos];
+        let offset = seq::distance(start, count);
+        if len == 1 {
+            self.runs.remove(pos);
+        } else if offset == 0 {
+            self.runs[pos] = (start.wrapping_add(1), len - 1);
+        } else if offset == len - 1 {
+            self.runs[pos].1 -= 1;
+        } else {
+            self.runs[pos].1 = offset;
+            self.runs
+                .insert(pos + 1, (count.wrapping_add(1), len - offset - 1));
+        }
+        self.len -= 1;
+        true
+    }
+    /// Remove the `n` oldest entries
+    pub(crate) fn skip(&mut self, mut n: usize) {
+        n = n.min(self.len);
+        self.len -= n;
+        while n > 0 {
+            let Some((start, len)) = self.runs.front_mut() else {
+                break;
+            };
+            if n < *len {
+                *start = seq::advance(*start, n);
+                *len -= n;
+                break;
+            }
+            n -= *len;
+            self.runs.pop_front();
+        }
+    }
+    /// Keep only the `n` oldest entries
+    pub(crate) fn truncate(&mut self, mut n: usize) {
+        if n >= self.len {
+            return;
+        }
+        self.len = n;
+        let mut keep = 0;
+        for run in self.runs.iter_mut() {
+            if n <= run.1 {
+                run.1 = n;
+                keep += usize::from(n > 0);
+                break;
+            }
+            n -= run.1;
+            keep += 1;
+        }
+        self.runs.truncate(keep);
+    }
+    pub(crate) fn clear(&mut self) {
+        self.runs.clear();
+        self.len = 0;
+    }
+}
+
+/// Oldest queued sequence number of every reader, as a sorted multiset
+///
+/// Lets the publisher find the item no reader needs any more without
+/// visiting every reader on each read.
+#[derive(Default)]
+pub(crate) struct Fronts {
+    counts: BTreeMap<Counter, usize>,
+}
+
+impl Fronts {
+    pub(crate) fn insert(&mut self, count: Counter) {
+        *self.counts.entry(count).or_default() += 1;
+    }
+    pub(crate) fn remove(&mut self, count: Counter) {
+        if let Some(n) = self.counts.get_mut(&count) {
+            *n -= 1;
+            if *n == 0 {
+                self.counts.remove(&count);
+            }
+        }
+    }
+    /// Bytes of the entries, the tree nodes around them are not counted
+    pub(crate) fn heap_size(&self) -> usize {
+        self.counts.len() * size_of::<(Counter, usize)>()
+    }
+    /// Oldest entry, counting from `first` so that wrapped numbers sort last
+    pub(crate) fn min(&self, first: Counter) -> Option<Counter> {
+        self.counts
+            .range(first..)
+            .next()
+            .or_else(|| self.counts.iter().next())
+            .map(|(count, _)| *count)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Backlog, Fronts};
+    use crate::Counter;
+
+    #[test]
+    fn runs() {
+        let mut backlog = Backlog::default();
+        for count in [1, 2, 3, 5, 6, 7, 8] {
+            backlog.push(count);
+        }
+        assert_eq!(backlog.runs.len(), 2);
+        assert!(backlog.remove(6));
+        assert!(!backlog.remove(6));
+        assert_eq!(backlog.iter().collect::<Vec<_>>(), vec![1, 2, 3, 5, 7, 8]);
+        assert_eq!(backlog.get(3), Some(5));
+        backlog.skip(2);
+        assert_eq!(backlog.front(), Some(3));
+        backlog.skip(1);
+        backlog.truncate(2);
+        assert_eq!(backlog.iter().collect::<Vec<_>>(), vec![5, 7]);
+        assert_eq!(backlog.len(), 2);
+        backlog.push_run(9, 3);
+        backlog.push_run(12, 2);
+        assert_eq!(backlog.front_run(), Some((5, 1)));
+        backlog.skip(2);
+        assert_eq!(backlog.front_run(), Some((9, 5)));
+        backlog.clear();
+        assert_eq!(backlog.front(), None);
+    }
+
+    #[test]
+    fn wrapping() {
+        let mut backlog = Backlog::default();
+        backlog.push(Counter::MAX);
+        backlog.push(0);
+        assert_eq!(backlog.runs.len(), 1);
+        assert!(backlog.remove(0));
+        assert_eq!(backlog.iter().collect::<Vec<_>>(), vec![Counter::MAX]);
+    }
+
+    #[test]
+    fn fronts() {
+        let mut fronts = Fronts::default();
+        fronts.insert(5);
+        fronts.insert(5);
+        fronts.insert(Counter::MAX);
+        fronts.insert(1);
+        assert_eq!(fronts.min(0), Some(1));
+        assert_eq!(fronts.min(Counter::MAX - 1), Some(Counter::MAX));
+        fronts.remove(1);
+        fronts.remove(5);
+        assert_eq!(fronts.min(2), Some(5));
+        fronts.remove(5);
+        assert_eq!(fronts.min(2), Some(Counter::MAX));
+    }
+}
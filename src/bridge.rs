@@ -0,0 +1,111 @@
+//! Socket drivers of the protocol sessions, shared by the `uds` and `tcp` bridges
+
+use std::{
+    io::{self, Read, Write},
+    time::Instant,
+};
+
+#[cfg(all(feature = "uds", unix))]
+use crate::protocol::Start;
+use crate::{
+    framing::FramePublisher,
+    protocol::{Client, HANDSHAKE, Session},
+};
+
+/// A connected client and its session
+pub(crate) struct Connection<S> {
+    pub stream: S,
+    session: Session,
+}
+
+impl<S: Read + Write> Connection<S> {
+    /// Subscribe a connection right away, it starts with the retained frames
+    #[cfg(all(feature = "uds", unix))]
+    pub fn subscribe(stream: S, frames: &mut FramePublisher) -> Self {
+        Self {
+            stream,
+            session: Session::subscribe(frames, Start::Oldest),
+        }
+    }
+    /// A connection which subscribes once the client sent its start
+    #[cfg(feature = "tcp")]
+    pub fn accept(stream: S, deadline: Option<Instant>) -> Self {
+        Self {
+            stream,
+            session: Session::accept(deadline),
+        }
+    }
+    #[cfg(feature = "tcp")]
+    pub fn is_streaming(&self) -> bool {
+        self.session.is_streaming()
+    }
+    /// Read the start and write as many frames as the socket takes, returns
+    /// false once the client is done with
+    pub fn poll(&mut self, frames: &mut FramePublisher, now: Instant) -> bool {
+        if self.session.handle_timeout(now).is_err() {
+            return false;
+        }
+        while !self.session.is_streaming() {
+            let mut received = [0; HANDSHAKE];
+            match self.stream.read(&mut received) {
+                Ok(0) => return false,
+                Ok(n) => {
+                    if self.session.handle_input(&received[..n], frames).is_err() {
+                        return false;
+                    }
+                }
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => return true,
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => return false,
+            }
+        }
+        loop {
+            // closed, lagged or desynced, the client can't continue
+            let Ok(pending) = self.session.poll_transmit() else {
+                return false;
+            };
+            if pending.is_empty() {
+                return true;
+            }
+            match self.stream.write(pending) {
+                Ok(0) => return false,
+                Ok(n) => self.session.advance(n),
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => return true,
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => return false,
+            }
+        }
+    }
+}
+
+/// Send the handshake of `client`, if it has one
+#[cfg(feature = "tcp")]
+pub(crate) fn connect(stream: &mut impl Write, client: &mut Client) -> io::Result<()> {
+    let handshake = client.poll_transmit();
+    let len = handshake.len();
+    stream.write_all(handshake)?;
+    client.advance(len);
+    Ok(())
+}
+
+/// Read the next frame, `None` at the end of the stream
+///
+/// Fails with [`InvalidData`](io::ErrorKind::InvalidData) on a frame above
+/// the maximum of `client`.
+pub(crate) fn recv(stream: &mut impl Read, client: &mut Client) -> io::Result<Option<Vec<u8>>> {
+    let mut received = [0; 4096];
+    loop {
+        match client.poll_frame() {
+            Ok(Some(frame)) => return Ok(Some(frame)),
+            Ok(None) => {}
+            Err(_) => return Err(io::ErrorKind::InvalidData.into()),
+        }
+        match stream.read(&mut received) {
+            Ok(0) if client.buffered() == 0 => return Ok(None),
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => client.handle_input(&received[..n]),
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+            Err(error) => return Err(error),
+        }
+    }
+}
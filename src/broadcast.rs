@@ -0,0 +1,307 @@
+//! Drop-in replacement for `tokio::sync::broadcast`
+//!
+//! [`channel`] returns a [`Sender`] and a [`Receiver`] with the methods and
+//! error types of tokio's broadcast channel, backed by the thread-safe
+//! [`Publisher`] with a capacity limit. As with tokio, a receiver only gets
+//! the values sent after it subscribed, and one which falls behind by more
+//! than the capacity misses the oldest values and sees `Lagged`.
+//!
+//! On top of that [`Receiver::recv_ref`] borrows a value instead of cloning
+//! it, so large values are read in place. Weak senders and waiting for all
+//! receivers to drop are not mirrored, and the capacity is not rounded up
+//! to a power of two.
+
+use core::{
+    future::{Future, poll_fn},
+    pin::pin,
+    task::{Context, Poll, Waker},
+};
+use std::{sync::Arc, task::Wake, thread};
+
+use self::error::{RecvError, SendError, TryRecvError};
+use crate::{
+    ReadError, ReaderHooks,
+    sync::{BorrowRead, Publisher, StreamReader},
+};
+
+/// Errors of sending and receiving
+pub mod error {
+    use core::fmt;
+
+    /// There was no receiver, the value is handed back
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct SendError<T>(pub T);
+
+    impl<T> fmt::Display for SendError<T> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("channel closed")
+        }
+    }
+
+    impl<T: fmt::Debug> core::error::Error for SendError<T> {}
+
+    /// Reasons why [`recv`](super::Receiver::recv) returned no value
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum RecvError {
+        /// Every sender was dropped and every value was received
+        Closed,
+        /// The receiver missed this many values, it continues with the oldest
+        /// one still held
+        Lagged(u64),
+    }
+
+    impl fmt::Display for RecvError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                RecvError::Closed => f.write_str("channel closed"),
+                RecvError::Lagged(n) => write!(f, "channel lagged by {n}"),
+            }
+        }
+    }
+
+    impl core::error::Error for RecvError {}
+
+    /// Reasons why [`try_recv`](super::Receiver::try_recv) returned no value
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum TryRecvError {
+        /// No value was sent since the last one received
+        Empty,
+        /// Every sender was dropped and every value was received
+        Closed,
+        /// The receiver missed this many values, it continues with the oldest
+        /// one still held
+        Lagged(u64),
+    }
+
+    impl fmt::Display for TryRecvError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                TryRecvError::Empty => f.write_str("channel empty"),
+                TryRecvError::Closed => f.write_str("channel closed"),
+                TryRecvError::Lagged(n) => write!(f, "channel lagged by {n}"),
+            }
+        }
+    }
+
+    impl core::error::Error for TryRecvError {}
+}
+
+/// The facade's error for a reader's one, the stream never times out or resets
+fn recv_error(error: ReadError) -> RecvError {
+    match error {
+        ReadError::Lagged(n) => RecvError::Lagged(n),
+        _ => RecvError::Closed,
+    }
+}
+
+/// Create a channel holding up to `capacity` values
+///
+/// # Panics
+///
+/// If `capacity` is zero.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "broadcast channel capacity cannot be zero");
+    let mut publisher = Publisher::new();
+    publisher.set_capacity_limit(capacity);
+    let reader = publisher.subscribe();
+    (Sender { publisher }, Receiver { reader })
+}
+
+/// Sending half of a [`channel`], clones feed the same receivers
+pub struct Sender<T> {
+    publisher: Publisher<T>,
+}
+
+impl<T> Sender<T> {
+    /// Send `value` to every receiver, returns how many there are
+    ///
+    /// Fails if there is none.
+    pub fn send(&self, value: T) -> Result<usize, SendError<T>> {
+        self.publisher.publish_if_read(value).map_err(SendError)
+    }
+    /// Create a receiver of the values sent from now on
+    pub fn subscribe(&self) -> Receiver<T> {
+        Receiver {
+            reader: self.publisher.subscribe_new(),
+        }
+    }
+    pub fn receiver_count(&self) -> usize {
+        self.publisher.reader_count()
+    }
+    /// Report the tasks waiting in [`recv`](Receiver::recv) to `hooks`, see
+    /// [`Publisher::set_hooks`]
+    pub fn set_hooks(&self, hooks: &'static dyn ReaderHooks) {
+        self.publisher.set_hooks(hooks);
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        Self {
+            publisher: self.publisher.clone(),
+        }
+    }
+}
+
+/// Receiving half of a [`channel`]
+pub struct Receiver<T> {
+    reader: StreamReader<T>,
+}
+
+impl<T> Receiver<T> {
+    /// Wait for the next value and borrow it, it counts as received once
+    /// the guard drops
+    pub async fn recv_ref(&mut self) -> Result<BorrowRead<'_, T>, RecvError> {
+        let reader = &self.reader;
+        poll_fn(|cx| reader.poll_read(cx)).await.map_err(recv_error)
+    }
+    /// Borrow the next value without waiting
+    pub fn try_recv_ref(&mut self) -> Result<BorrowRead<'_, T>, TryRecvError> {
+        match self.reader.try_read() {
+            Ok(Some(value)) => Ok(value),
+            Ok(None) => Err(TryRecvError::Empty),
+            Err(ReadError::Lagged(n)) => Err(TryRecvError::Lagged(n)),
+            Err(_) => Err(TryRecvError::Closed),
+        }
+    }
+    /// Wait for the next value
+    pub async fn recv(&mut self) -> Result<T, RecvError>
+    where
+        T: Clone,
+    {
+        self.recv_ref().await.map(|value| value.clone())
+    }
+    /// Take the next value without waiting
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError>
+    where
+        T: Clone,
+    {
+        self.try_recv_ref().map(|value| value.clone())
+    }
+    /// Block the thread until the next value, don't call this within a task
+    pub fn blocking_recv(&mut self) -> Result<T, RecvError>
+    where
+        T: Clone,
+    {
+        block_on(self.recv())
+    }
+    /// Create a receiver of the values sent from now on
+    pub fn resubscribe(&self) -> Self {
+        Self {
+            reader: self.reader.resubscribe(),
+        }
+    }
+    /// Number of values sent but not received yet, including the ones
+    /// missed while lagging
+    pub fn len(&self) -> usize {
+        self.reader.len() + self.reader.missed() as usize
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Whether every sender was dropped
+    pub fn is_closed(&self) -> bool {
+        self.reader.is_closed()
+    }
+}
+
+/// Wakes a thread parked in [`block_on`]
+struct Unpark(thread::Thread);
+
+impl Wake for Unpark {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Run `future` on this thread, parking it while the future is pending
+fn block_on<F: Future>(future: F) -> F::Output {
+    let waker = Waker::from(Arc::new(Unpark(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    let mut future = pin!(future);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        thread::park();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::block_on;
+    use std::{thread, time::Duration};
+
+    /// The same checks against our channel and tokio's
+    macro_rules! both {
+        ($name:ident, |$channel:ident, $error:ident| $body:block) => {
+            #[test]
+            fn $name() {
+                {
+                    use super::channel as $channel;
+                    use super::error as $error;
+                    $body
+                }
+                {
+                    use tokio::sync::broadcast::channel as $channel;
+                    use tokio::sync::broadcast::error as $error;
+                    $body
+                }
+            }
+        };
+    }
+
+    both!(lags_and_closes, |channel, error| {
+        let (tx, mut rx) = channel(2);
+        assert_eq!(rx.try_recv(), Err(error::TryRecvError::Empty));
+        for i in 0..3 {
+            assert_eq!(tx.send(i).ok(), Some(1));
+        }
+        assert_eq!(rx.len(), 3);
+        assert_eq!(rx.try_recv(), Err(error::TryRecvError::Lagged(1)));
+        assert_eq!(rx.try_recv(), Ok(1));
+
+        let mut late = tx.subscribe();
+        assert_eq!(tx.receiver_count(), 2);
+        assert_eq!(tx.send(3).ok(), Some(2));
+        assert_eq!(late.try_recv(), Ok(3));
+        assert_eq!(block_on(rx.recv()), Ok(2));
+        assert_eq!(block_on(rx.recv()), Ok(3));
+        drop(tx);
+        assert!(rx.is_closed());
+        assert_eq!(rx.try_recv(), Err(error::TryRecvError::Closed));
+        assert_eq!(block_on(late.recv()), Err(error::RecvError::Closed));
+
+        let (tx, rx) = channel(1);
+        drop(rx);
+        let error::SendError(value) = tx.send(4).unwrap_err();
+        assert_eq!(value, 4);
+    });
+
+    both!(wakes_receivers, |channel, error| {
+        let (tx, mut rx) = channel(16);
+        let mut other = rx.resubscribe();
+        let receivers = thread::spawn(move || {
+            let a: Vec<u32> = (0..3).map(|_| rx.blocking_recv().unwrap()).collect();
+            let b: Vec<u32> = (0..3).map(|_| block_on(other.recv()).unwrap()).collect();
+            assert_eq!(rx.blocking_recv(), Err(error::RecvError::Closed));
+            (a, b)
+        });
+        for i in 0..3 {
+            thread::sleep(Duration::from_millis(1));
+            tx.send(i).unwrap();
+        }
+        drop(tx);
+        assert_eq!(receivers.join().unwrap(), (vec![0, 1, 2], vec![0, 1, 2]));
+    });
+
+    #[test]
+    fn borrows_values() {
+        let (tx, mut rx) = super::channel(4);
+        tx.send(vec![0u8; 1024]).unwrap();
+        let value = block_on(rx.recv_ref()).unwrap();
+        assert_eq!(value.len(), 1024);
+        drop(value);
+        assert!(rx.try_recv_ref().is_err());
+    }
+}
@@ -0,0 +1,424 @@
+//! Topics mirrored into an external pub/sub broker
+//!
+//! A [`TopicBridge`] routes byte messages by topic name to local readers,
+//! each topic being a publisher of its own, and mirrors the topics into a
+//! [`Broker`]: everything published locally goes out to the broker, and
+//! once a topic has a local reader the bridge subscribes to it at the
+//! broker and publishes what others sent there locally on
+//! [`poll`](TopicBridge::poll). Producers thus use one API whether their
+//! readers are in-process or elsewhere in the fleet.
+//!
+//! [`MqttClient`] is a minimal MQTT 3.1.1 client at QoS 0 implementing
+//! [`Broker`]. Topics are matched by name, wildcard filters are not
+//! supported. MQTT 3.1.1 can't tell the broker to leave out a client's own
+//! messages, so the client recognizes them by topic and payload for a few
+//! seconds after publishing. An identical message from elsewhere within
+//! that window is taken for the echo.
+
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    time::{Duration, Instant},
+};
+
+use crate::{Counter, Publisher, StreamReader};
+
+/// Connection to a pub/sub broker
+pub trait Broker {
+    /// Send `payload` to the subscribers of `topic`
+    fn publish(&mut self, topic: &str, payload: &[u8]) -> io::Result<()>;
+    /// Receive the messages others publish to `topic`
+    fn subscribe(&mut self, topic: &str) -> io::Result<()>;
+    /// The next message received from the broker, without blocking
+    ///
+    /// Messages this connection published itself are not returned.
+    fn receive(&mut self) -> io::Result<Option<(String, Vec<u8>)>>;
+}
+
+/// One local topic
+struct Topic {
+    name: String,
+    // boxed, the readers keep its address
+    publisher: Box<Publisher<Box<[u8]>>>,
+    subscribed: bool,
+}
+
+/// Local topic router mirrored into a broker
+pub struct TopicBridge<B> {
+    broker: B,
+    topics: Vec<Topic>,
+}
+
+impl<B: Broker> TopicBridge<B> {
+    pub fn new(broker: B) -> Self {
+        Self {
+            broker,
+            topics: Vec::new(),
+        }
+    }
+    pub fn broker(&mut self) -> &mut B {
+        &mut self.broker
+    }
+    fn topic(&mut self, name: &str) -> &mut Topic {
+        let index = match self.topics.iter().position(|t| t.name == name) {
+            Some(index) => index,
+            None => {
+                self.topics.push(Topic {
+                    name: name.into(),
+                    publisher: Box::new(Publisher::new()),
+                    subscribed: false,
+                });
+                self.topics.len() - 1
+            }
+        };
+        &mut self.topics[index]
+    }
+    /// Publish to the local readers of `topic` and to the broker
+    ///
+    /// Returns the local sequence number, the message is published locally
+    /// even if the broker fails.
+    pub fn publish(&mut self, topic: &str, payload: &[u8]) -> io::Result<Counter> {
+        let seq = self.topic(topic).publisher.publish(payload.into());
+        self.broker.publish(topic, payload)?;
+        Ok(seq)
+    }
+    /// Add a local reader of `topic`, which also receives what others
+    /// publish to it at the broker
+    pub fn subscribe(
+        &mut self,
+        topic: &str,
+        reader: &mut StreamReader<Box<[u8]>>,
+    ) -> io::Result<()> {
+        let entry = self.topic(topic);
+        entry.publisher.add_stream_reader(reader);
+        if !entry.subscribed {
+            self.broker.subscribe(topic)?;
+            self.topic(topic).subscribed = true;
+        }
+        Ok(())
+    }
+    /// Publish the messages received from the broker to the local readers,
+    /// returns how many
+    ///
+    /// Messages to topics without a local reader are dropped.
+    pub fn poll(&mut self) -> io::Result<usize> {
+        let mut received = 0;
+        while let Some((topic, payload)) = self.broker.receive()? {
+            let local = self.topics.iter_mut().find(|t| t.name == topic);
+            if let Some(local) = local.filter(|t| t.subscribed) {
+                local.publisher.publish(payload.into());
+                received += 1;
+            }
+        }
+        Ok(received)
+    }
+    /// Signal the local readers of `topic` that the stream ended
+    pub fn close(&mut self, topic: &str) {
+        self.topic(topic).publisher.close();
+    }
+}
+
+/// Append the MQTT variable length encoding of `len`
+fn remaining_length(packet: &mut Vec<u8>, mut len: usize) {
+    loop {
+        let byte = (len % 128) as u8;
+        len /= 128;
+        if len == 0 {
+            packet.push(byte);
+            return;
+        }
+        packet.push(byte | 0x80);
+    }
+}
+
+/// A control packet of `kind` with `body`
+fn finish(kind: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![kind];
+    remaining_length(&mut packet, body.len());
+    packet.extend_from_slice(body);
+    packet
+}
+
+/// Append a length prefixed string, fails if it is longer than MQTT allows
+fn string(body: &mut Vec<u8>, s: &str) -> io::Result<()> {
+    let len = u16::try_from(s.len()).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    body.extend_from_slice(&len.to_be_bytes());
+    body.extend_from_slice(s.as_bytes());
+    Ok(())
+}
+
+/// How long an own message is expected back from the broker
+const ECHO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Own messages awaiting their echo at most, older ones are forgotten
+const MAX_ECHOES: usize = 256;
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const SUBSCRIBE: u8 = 0x82;
+const PINGREQ: u8 = 0xc0;
+const DISCONNECT: u8 = 0xe0;
+
+/// MQTT 3.1.1 connection publishing and subscribing at QoS 0
+pub struct MqttClient {
+    stream: TcpStream,
+    keep_alive: Duration,
+    last_sent: Instant,
+    /// received bytes of incomplete packets
+    inbound: Vec<u8>,
+    subscriptions: Vec<String>,
+    /// own messages to subscribed topics, which the broker echoes back,
+    /// and when they were sent
+    echoes: VecDeque<(Instant, String, Box<[u8]>)>,
+    packet_id: u16,
+}
+
+impl MqttClient {
+    /// Connect with a clean session as `client_id`
+    ///
+    /// Waits for the broker to accept the connection, and pings it when
+    /// nothing was sent for half of `keep_alive`.
+    pub fn connect(
+        addr: impl ToSocketAddrs,
+        client_id: &str,
+        keep_alive: Duration,
+    ) -> io::Result<Self> {
+        let mut stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        let mut body = Vec::new();
+        string(&mut body, "MQTT")?;
+        // protocol level 4, clean session
+        body.extend_from_slice(&[4, 0x02]);
+        body.extend_from_slice(&(keep_alive.as_secs().min(u16::MAX.into()) as u16).to_be_bytes());
+        string(&mut body, client_id)?;
+        stream.write_all(&finish(CONNECT, &body))?;
+        let mut connack = [0; 4];
+        stream.read_exact(&mut connack)?;
+        if connack[..2] != [CONNACK, 2] || connack[3] != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                "broker refused the connection",
+            ));
+        }
+        stream.set_nonblocking(true)?;
+        Ok(Self {
+            stream,
+            keep_alive,
+            last_sent: Instant::now(),
+            inbound: Vec::new(),
+            subscriptions: Vec::new(),
+            echoes: VecDeque::new(),
+            packet_id: 0,
+        })
+    }
+    fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        self.stream.set_nonblocking(false)?;
+        let result = self.stream.write_all(packet);
+        self.stream.set_nonblocking(true)?;
+        self.last_sent = Instant::now();
+        result
+    }
+    /// Forget the own messages whose echo is overdue
+    fn expire_echoes(&mut self) {
+        let now = Instant::now();
+        while self.echoes.len() > MAX_ECHOES
+            || self
+                .echoes
+                .front()
+                .is_some_and(|(sent, ..)| now.duration_since(*sent) > ECHO_TIMEOUT)
+        {
+            self.echoes.pop_front();
+        }
+    }
+}
+
+/// Split off the next complete packet of `inbound`, its first byte and its
+/// body
+///
+/// Fails on a remaining length longer than the four bytes MQTT allows.
+fn split_packet(inbound: &mut Vec<u8>) -> io::Result<Option<(u8, Vec<u8>)>> {
+    let mut len = 0;
+    let mut header = 1;
+    loop {
+        if header > 4 {
+            return Err(io::ErrorKind::InvalidData.into());
+        }
+        let Some(&byte) = inbound.get(header) else {
+            return Ok(None);
+        };
+        len |= usize::from(byte & 0x7f) << (7 * (header - 1));
+        header += 1;
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+    let end = header.checked_add(len).ok_or(io::ErrorKind::InvalidData)?;
+    if inbound.len() < end {
+        return Ok(None);
+    }
+    let kind = inbound[0];
+    let body = inbound[header..end].to_vec();
+    inbound.drain(..end);
+    Ok(Some((kind, body)))
+}
+
+impl Broker for MqttClient {
+    fn publish(&mut self, topic: &str, payload: &[u8]) -> io::Result<()> {
+        let mut body = Vec::with_capacity(2 + topic.len() + payload.len());
+        string(&mut body, topic)?;
+        body.extend_from_slice(payload);
+        self.send(&finish(PUBLISH, &body))?;
+        if self.subscriptions.iter().any(|s| s == topic) {
+            self.echoes
+                .push_back((Instant::now(), topic.into(), payload.into()));
+            self.expire_echoes();
+        }
+        Ok(())
+    }
+    fn subscribe(&mut self, topic: &str) -> io::Result<()> {
+        self.packet_id = self.packet_id.wrapping_add(1).max(1);
+        let mut body = self.packet_id.to_be_bytes().to_vec();
+        string(&mut body, topic)?;
+        body.push(0);
+        self.send(&finish(SUBSCRIBE, &body))?;
+        self.subscriptions.push(topic.into());
+        Ok(())
+    }
+    fn receive(&mut self) -> io::Result<Option<(String, Vec<u8>)>> {
+        if self.last_sent.elapsed() >= self.keep_alive / 2 {
+            self.send(&[PINGREQ, 0])?;
+        }
+        self.expire_echoes();
+        loop {
+            while let Some((kind, body)) = split_packet(&mut self.inbound)? {
+                if kind & 0xf0 != PUBLISH || body.len() < 2 {
+                    // acknowledgements and ping responses
+                    continue;
+                }
+                let len = usize::from(u16::from_be_bytes([body[0], body[1]]));
+                // a packet identifier follows the topic above QoS 0
+                let skip = if kind & 0x06 != 0 { 2 } else { 0 };
+                let Some(payload) = body.get(2 + len + skip..) else {
+                    continue;
+                };
+                let Ok(topic) = core::str::from_utf8(&body[2..2 + len]) else {
+                    continue;
+                };
+                if let Some(n) = self
+                    .echoes
+                    .iter()
+                    .position(|(_, t, p)| t == topic && **p == *payload)
+                {
+                    self.echoes.remove(n);
+                    continue;
+                }
+                return Ok(Some((topic.into(), payload.to_vec())));
+            }
+            let mut buf = [0; 4096];
+            match self.stream.read(&mut buf) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => self.inbound.extend_from_slice(&buf[..n]),
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+                Err(error) => return Err(error),
+            }
+        }
+    }
+}
+
+impl Drop for MqttClient {
+    fn drop(&mut self) {
+        let _ = self.send(&[DISCONNECT, 0]);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{MqttClient, TopicBridge, remaining_length, split_packet, string};
+    use crate::StreamReader;
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        thread,
+        time::Duration,
+    };
+
+    #[test]
+    fn length_encoding() {
+        let mut encoded = Vec::new();
+        remaining_length(&mut encoded, 321);
+        assert_eq!(encoded, [0xc1, 0x02]);
+        encoded.clear();
+        remaining_length(&mut encoded, 127);
+        assert_eq!(encoded, [0x7f]);
+        encoded.clear();
+        assert!(string(&mut encoded, &"x".repeat(usize::from(u16::MAX) + 1)).is_err());
+        assert!(encoded.is_empty());
+    }
+
+    #[test]
+    fn packet_splitting() {
+        let mut inbound = vec![0x30, 0xc1];
+        assert!(split_packet(&mut inbound).unwrap().is_none());
+        inbound.push(0x02);
+        inbound.extend([7; 321]);
+        inbound.push(0xd0);
+        let (kind, body) = split_packet(&mut inbound).unwrap().unwrap();
+        assert_eq!((kind, body.len()), (0x30, 321));
+        assert_eq!(inbound, [0xd0]);
+        // more than four length bytes
+        let mut inbound = vec![0x30, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01];
+        assert!(split_packet(&mut inbound).is_err());
+    }
+
+    #[test]
+    fn mirrors_topics() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        // a broker which accepts, echoes the first publication and then
+        // forwards one from elsewhere
+        let broker = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let packet = |stream: &mut std::net::TcpStream| {
+                let mut header = [0; 2];
+                stream.read_exact(&mut header).unwrap();
+                let mut body = vec![0; header[1].into()];
+                stream.read_exact(&mut body).unwrap();
+                (header, body)
+            };
+            let (connect, _) = packet(&mut stream);
+            assert_eq!(connect[0], 0x10);
+            stream.write_all(&[0x20, 2, 0, 0]).unwrap();
+            let (subscribe, body) = packet(&mut stream);
+            assert_eq!(subscribe[0], 0x82);
+            assert_eq!(&body[2..], b"\0\x04temp\0");
+            stream.write_all(&[0x90, 3, body[0], body[1], 0]).unwrap();
+            let (publish, body) = packet(&mut stream);
+            assert_eq!(publish[0], 0x30);
+            assert_eq!(body, b"\0\x04temp21");
+            stream.write_all(&publish).unwrap();
+            stream.write_all(&body).unwrap();
+            // no local reader, so no local topic for it
+            stream.write_all(b"\x30\x08\0\x04rain01").unwrap();
+            stream.write_all(b"\x30\x08\0\x04temp19").unwrap();
+            let (disconnect, _) = packet(&mut stream);
+            assert_eq!(disconnect[0], 0xe0);
+        });
+        let client = MqttClient::connect(addr, "test", Duration::from_secs(60)).unwrap();
+        let mut bridge = TopicBridge::new(client);
+        let mut reader = StreamReader::new();
+        bridge.subscribe("temp", &mut reader).unwrap();
+        bridge.publish("temp", b"21").unwrap();
+        while bridge.poll().unwrap() == 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(&**reader.read().unwrap(), b"21");
+        assert_eq!(&**reader.read().unwrap(), b"19");
+        assert!(reader.read().is_none());
+        assert_eq!(bridge.topics.len(), 1);
+        drop(bridge);
+        broker.join().unwrap();
+    }
+}
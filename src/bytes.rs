@@ -0,0 +1,449 @@
+//! Byte ring handing out contiguous regions, e.g. for DMA
+//!
+//! The writer asks for a grant of contiguous free bytes, fills it, possibly
+//! by a DMA transfer, and commits how many of them it used. A grant lives
+//! inside the ring, so its address stays put until it is committed. Readers
+//! borrow committed regions in place and release what they processed.
+//!
+//! A grant which doesn't fit before the end of the ring starts over at its
+//! beginning, the bytes skipped at the end are never handed to readers.
+//! Grants then start at the offset where the previous commit ended; with
+//! commits in multiples of the DMA alignment they stay aligned, as the ring
+//! itself is aligned to 32 bytes.
+//!
+//! With the `postcard` feature items of any serde type can pass the ring,
+//! each encoded into a grant of its own.
+
+use core::{
+    cell::{Cell, UnsafeCell},
+    ops::{Deref, DerefMut},
+    slice,
+};
+
+use crate::{Counter, fixed::exclusive, seq};
+
+/// Reasons why an item didn't pass the ring
+#[cfg(feature = "postcard")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CodecError {
+    /// There is no contiguous free region for the encoded item
+    Full,
+    /// The item failed to encode or decode
+    Postcard(postcard::Error),
+}
+
+#[cfg(feature = "postcard")]
+impl core::fmt::Display for CodecError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            CodecError::Full => f.write_str("no room for the encoded item"),
+            CodecError::Postcard(error) => error.fmt(f),
+        }
+    }
+}
+
+#[cfg(feature = "postcard")]
+impl core::error::Error for CodecError {}
+
+#[repr(align(32))]
+struct Buffer<const N: usize>(UnsafeCell<[u8; N]>);
+
+/// Ring of `N` bytes, a power of two, for up to `R` readers
+///
+/// Like [`StaticPublisher`](crate::fixed::StaticPublisher) it keeps
+/// committed bytes until every reader released them, and shares between
+/// interrupt handlers with the `critical-section` feature.
+pub struct ByteRing<const N: usize, const R: usize = 4> {
+    buf: Buffer<N>,
+    /// position of the oldest retained byte
+    first: Cell<Counter>,
+    /// position after the last committed byte
+    end: Cell<Counter>,
+    /// bytes skipped before the end of the ring by a wrapping grant, as
+    /// start and end position, empty if both are equal
+    pad: Cell<(Counter, Counter)>,
+    /// whether a write grant is outstanding
+    granted: Cell<bool>,
+    /// next byte to read per reader, `None` for a free reader slot
+    cursors: [Cell<Option<Counter>>; R],
+}
+
+impl<const N: usize, const R: usize> ByteRing<N, R> {
+    pub const fn new() -> Self {
+        const { assert!(N.is_power_of_two(), "the ring size must be a power of two") };
+        Self {
+            buf: Buffer(UnsafeCell::new([0; N])),
+            first: Cell::new(0),
+            end: Cell::new(0),
+            pad: Cell::new((0, 0)),
+            granted: Cell::new(false),
+            cursors: [const { Cell::new(None) }; R],
+        }
+    }
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+    /// Committed bytes not yet released by every reader
+    pub fn len(&self) -> usize {
+        exclusive(|| seq::distance(self.first.get(), self.end.get()))
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    fn offset(&self, pos: Counter) -> usize {
+        seq::masked(pos, N - 1)
+    }
+    /// Free bytes in total and contiguous ones at the write position
+    fn free(&self) -> (usize, usize) {
+        let free = N - seq::distance(self.first.get(), self.end.get());
+        (free, N - self.offset(self.end.get()))
+    }
+    /// Hand out `len` contiguous free bytes
+    ///
+    /// Returns `None` if there are not that many, or if another grant is
+    /// still outstanding.
+    pub fn grant_exact(&self, len: usize) -> Option<WriteGrant<'_, N, R>> {
+        exclusive(|| {
+            if self.granted.get() || len == 0 {
+                return None;
+            }
+            let (free, tail) = self.free();
+            let (start, pad) = if len <= free.min(tail) {
+                (self.offset(self.end.get()), 0)
+            } else if len > tail && tail + len <= free {
+                (0, tail)
+            } else {
+                return None;
+            };
+            self.granted.set(true);
+            Some(self.grant(start, len, pad))
+        })
+    }
+    /// Hand out as many contiguous free bytes as there are at the write
+    /// position, up to `max`
+    ///
+    /// Returns `None` if the ring is full, or if another grant is still
+    /// outstanding.
+    pub fn grant_max(&self, max: usize) -> Option<WriteGrant<'_, N, R>> {
+        exclusive(|| {
+            let (free, tail) = self.free();
+            let len = max.min(free).min(tail);
+            if self.granted.get() || len == 0 {
+                return None;
+            }
+            self.granted.set(true);
+            Some(self.grant(self.offset(self.end.get()), len, 0))
+        })
+    }
+    fn grant(&self, start: usize, len: usize, pad: usize) -> WriteGrant<'_, N, R> {
+        // outside of committed data and only handed out once
+        let region =
+            unsafe { slice::from_raw_parts_mut(self.buf.0.get().cast::<u8>().add(start), len) };
+        WriteGrant {
+            ring: self,
+            region,
+            pad,
+            used: 0,
+        }
+    }
+    /// Add a reader which starts with all retained bytes
+    ///
+    /// Returns `None` once `R` readers exist.
+    pub fn subscribe(&self) -> Option<ByteReader<'_, N, R>> {
+        let index = exclusive(|| {
+            let index = self.cursors.iter().position(|c| c.get().is_none())?;
+            self.cursors[index].set(Some(self.first.get()));
+            Some(index)
+        })?;
+        Some(ByteReader { ring: self, index })
+    }
+    /// Drop every leading byte which all readers are past
+    fn release(&self) {
+        let first = self.first.get();
+        let used = self
+            .cursors
+            .iter()
+            .filter_map(Cell::get)
+            .map(|c| seq::distance(first, c))
+            .min();
+        if let Some(used) = used {
+            let first = seq::advance(first, used);
+            self.first.set(first);
+            let (start, end) = self.pad.get();
+            if start != end && !seq::precedes(first, end) {
+                self.pad.set((0, 0));
+            }
+        }
+    }
+}
+
+#[cfg(feature = "postcard")]
+impl<const N: usize, const R: usize> ByteRing<N, R> {
+    /// Encode `item` with postcard into a grant of its exact size
+    ///
+    /// Items encoding to no bytes at all, like `()`, can't be told apart
+    /// from an empty ring and are not published.
+    pub fn publish_encoded<T: serde::Serialize + ?Sized>(
+        &self,
+        item: &T,
+    ) -> Result<(), CodecError> {
+        let size = postcard::serialize_with_flavor(item, postcard::ser_flavors::Size::default())
+            .map_err(CodecError::Postcard)?;
+        if size == 0 {
+            return Ok(());
+        }
+        let mut grant = self.grant_exact(size).ok_or(CodecError::Full)?;
+        postcard::to_slice(item, &mut grant).map_err(CodecError::Postcard)?;
+        grant.commit(size);
+        Ok(())
+    }
+}
+
+impl<const N: usize, const R: usize> Default for ByteRing<N, R> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// see the `Sync` of `StaticPublisher`, grants and read regions never overlap
+#[cfg(feature = "critical-section")]
+unsafe impl<const N: usize, const R: usize> Sync for ByteRing<N, R> {}
+
+/// Contiguous free region of a [`ByteRing`]
+///
+/// Readers see nothing of it before [`commit`](Self::commit), dropping it
+/// commits nothing.
+pub struct WriteGrant<'a, const N: usize, const R: usize> {
+    ring: &'a ByteRing<N, R>,
+    region: &'a mut [u8],
+    /// bytes to skip before the end of the ring when committing
+    pad: usize,
+    used: usize,
+}
+
+impl<'a, const N: usize, const R: usize> WriteGrant<'a, N, R> {
+    /// Make the first `used` bytes of the region visible to readers
+    pub fn commit(mut self, used: usize) {
+        self.used = used.min(self.region.len());
+    }
+}
+
+impl<'a, const N: usize, const R: usize> Deref for WriteGrant<'a, N, R> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.region
+    }
+}
+
+impl<'a, const N: usize, const R: usize> DerefMut for WriteGrant<'a, N, R> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        self.region
+    }
+}
+
+impl<'a, const N: usize, const R: usize> Drop for WriteGrant<'a, N, R> {
+    fn drop(&mut self) {
+        let ring = self.ring;
+        exclusive(|| {
+            let mut end = ring.end.get();
+            if self.used > 0 && self.pad > 0 {
+                let skipped = seq::advance(end, self.pad);
+                ring.pad.set((end, skipped));
+                end = skipped;
+            }
+            ring.end.set(seq::advance(end, self.used));
+            ring.granted.set(false);
+        })
+    }
+}
+
+/// Consumer of a [`ByteRing`]
+pub struct ByteReader<'a, const N: usize, const R: usize> {
+    ring: &'a ByteRing<N, R>,
+    index: usize,
+}
+
+impl<'a, const N: usize, const R: usize> ByteReader<'a, N, R> {
+    /// Move past bytes skipped by a wrapping grant, returns the next byte
+    /// to read
+    fn skip_pad(&self) -> Counter {
+        let ring = self.ring;
+        let cursor = ring.cursors[self.index].get().unwrap_or(ring.first.get());
+        let (start, end) = ring.pad.get();
+        if cursor == start && start != end {
+            ring.cursors[self.index].set(Some(end));
+            return end;
+        }
+        cursor
+    }
+    /// Borrow the committed bytes up to the end of the ring, they are
+    /// released when the guard drops
+    pub fn read(&mut self) -> Option<ReadGrant<'_, 'a, N, R>> {
+        let ring = self.ring;
+        let region = exclusive(|| {
+            let cursor = self.skip_pad();
+            let offset = ring.offset(cursor);
+            let len = seq::distance(cursor, ring.end.get()).min(N - offset);
+            if len == 0 {
+                return None;
+            }
+            // committed and kept until this reader moves past it
+            Some(unsafe { slice::from_raw_parts(ring.buf.0.get().cast::<u8>().add(offset), len) })
+        })?;
+        let used = region.len();
+        Some(ReadGrant {
+            reader: self,
+            region,
+            used,
+        })
+    }
+    /// Decode the next item published by
+    /// [`publish_encoded`](ByteRing::publish_encoded)
+    ///
+    /// Bytes which fail to decode are skipped up to the end of the ring or
+    /// the newest committed byte, whichever comes first.
+    #[cfg(feature = "postcard")]
+    pub fn read_decoded<T: serde::de::DeserializeOwned>(
+        &mut self,
+    ) -> Result<Option<T>, CodecError> {
+        let Some(region) = self.read() else {
+            return Ok(None);
+        };
+        let (item, rest) = postcard::take_from_bytes(&region).map_err(CodecError::Postcard)?;
+        let used = region.len() - rest.len();
+        region.release(used);
+        Ok(Some(item))
+    }
+    /// Number of committed bytes still to read
+    pub fn len(&self) -> usize {
+        exclusive(|| seq::distance(self.skip_pad(), self.ring.end.get()))
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<'a, const N: usize, const R: usize> Drop for ByteReader<'a, N, R> {
+    fn drop(&mut self) {
+        exclusive(|| {
+            self.ring.cursors[self.index].set(None);
+            self.ring.release();
+        })
+    }
+}
+
+/// Committed region of a [`ByteRing`] borrowed by a reader
+pub struct ReadGrant<'r, 'a, const N: usize, const R: usize> {
+    reader: &'r mut ByteReader<'a, N, R>,
+    region: &'r [u8],
+    used: usize,
+}
+
+impl<'r, 'a, const N: usize, const R: usize> ReadGrant<'r, 'a, N, R> {
+    /// Release only the first `used` bytes, the rest is read again
+    pub fn release(mut self, used: usize) {
+        self.used = used.min(self.region.len());
+    }
+}
+
+impl<'r, 'a, const N: usize, const R: usize> Deref for ReadGrant<'r, 'a, N, R> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.region
+    }
+}
+
+impl<'r, 'a, const N: usize, const R: usize> Drop for ReadGrant<'r, 'a, N, R> {
+    fn drop(&mut self) {
+        let ring = self.reader.ring;
+        let index = self.reader.index;
+        exclusive(|| {
+            let cursor = ring.cursors[index].get().unwrap_or(ring.first.get());
+            ring.cursors[index].set(Some(seq::advance(cursor, self.used)));
+            ring.release();
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ByteRing;
+
+    #[test]
+    fn wrapping_grant() {
+        let ring: ByteRing<8, 2> = ByteRing::new();
+        let mut a = ring.subscribe().unwrap();
+        let mut b = ring.subscribe().unwrap();
+        let mut grant = ring.grant_exact(6).unwrap();
+        assert!(ring.grant_max(1).is_none());
+        grant.copy_from_slice(b"abcdef");
+        grant.commit(5);
+        a.read().unwrap().release(4);
+        assert_eq!(&*b.read().unwrap(), b"abcde");
+        // three bytes left at the end, four at the start as `a` still holds one
+        assert!(ring.grant_exact(5).is_none());
+        assert!(ring.grant_exact(3).is_some());
+        assert_eq!(&*a.read().unwrap(), b"e");
+        let start = ring.buf.0.get().cast::<u8>();
+        let mut grant = ring.grant_exact(4).unwrap();
+        assert_eq!(grant.as_ptr(), start.cast_const());
+        grant.copy_from_slice(b"wxyz");
+        grant.commit(4);
+        assert_eq!(a.len(), 4);
+        assert_eq!(&*a.read().unwrap(), b"wxyz");
+        assert_eq!(&*b.read().unwrap(), b"wxyz");
+        assert!(ring.is_empty());
+        assert_eq!(ring.grant_max(16).unwrap().len(), 4);
+    }
+
+    #[cfg(feature = "postcard")]
+    #[test]
+    fn encoded() {
+        use super::CodecError;
+
+        #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+        enum Event {
+            Started { id: u16 },
+            Samples([i32; 3]),
+            Stopped,
+        }
+
+        let ring: ByteRing<16, 1> = ByteRing::new();
+        let mut r = ring.subscribe().unwrap();
+        ring.publish_encoded(&Event::Started { id: 300 }).unwrap();
+        ring.publish_encoded(&Event::Samples([1, -1, 1000]))
+            .unwrap();
+        ring.publish_encoded(&Event::Stopped).unwrap();
+        assert_eq!(
+            ring.publish_encoded(&Event::Samples([i32::MAX; 3])),
+            Err(CodecError::Full)
+        );
+        assert_eq!(r.read_decoded(), Ok(Some(Event::Started { id: 300 })));
+        assert_eq!(r.read_decoded(), Ok(Some(Event::Samples([1, -1, 1000]))));
+        // wraps to the start of the ring
+        let wrapped = Event::Samples([i32::MAX, 0, 0]);
+        ring.publish_encoded(&wrapped).unwrap();
+        assert_eq!(r.read_decoded(), Ok(Some(Event::Stopped)));
+        assert_eq!(r.read_decoded(), Ok(Some(wrapped)));
+        assert_eq!(r.read_decoded::<Event>(), Ok(None));
+    }
+
+    #[test]
+    fn retains_until_released() {
+        let ring: ByteRing<4, 1> = ByteRing::new();
+        let mut r = ring.subscribe().unwrap();
+        ring.grant_max(4).unwrap().commit(4);
+        let held = r.read().unwrap();
+        assert!(ring.grant_max(1).is_none());
+        held.release(2);
+        assert_eq!(ring.grant_max(4).unwrap().len(), 2);
+        drop(r);
+        // kept for the next reader
+        assert_eq!(ring.len(), 2);
+        let mut r = ring.subscribe().unwrap();
+        assert_eq!(r.read().unwrap().len(), 2);
+        assert!(ring.is_empty());
+    }
+}
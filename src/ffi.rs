@@ -0,0 +1,480 @@
+//! C interface over opaque handles
+//!
+//! Messages are byte strings, every reader receives each message published
+//! after it subscribed. `include/multiple_consumers.h` declares the
+//! functions for C; handles are only valid until they are passed to their
+//! `free` or `unsubscribe` function, and none of them may be used from two
+//! threads at once.
+//!
+//! Reads return one of the `MC_` codes below. Once the publisher was closed
+//! its readers drain the remaining messages and then report [`MC_CLOSED`],
+//! a freed publisher takes its messages along.
+//!
+//! The structs passed to C start with their `size`, which the caller sets
+//! to the size it was compiled with. New fields are only ever appended, so
+//! the functions fill in just the fields both sides know and an older
+//! library works with newer headers and the other way round.
+
+use alloc::boxed::Box;
+use core::{
+    ffi::{c_char, c_int},
+    mem, ptr, slice,
+};
+
+use crate::{Publisher, ReadError, StreamReader, seq};
+
+/// Version of the layouts below, counted up whenever a field is appended
+pub const MC_ABI_VERSION: u32 = 1;
+
+/// A message was copied out
+pub const MC_OK: c_int = 0;
+/// No message is queued right now
+pub const MC_EMPTY: c_int = 1;
+/// The publisher was closed or freed and every message was read
+pub const MC_CLOSED: c_int = -1;
+/// Messages were lost, reading continues with the oldest retained one
+pub const MC_LAGGED: c_int = -2;
+/// The publisher discarded its retained messages
+pub const MC_RESET: c_int = -3;
+/// The reader was idle for too long and got unsubscribed
+pub const MC_TIMED_OUT: c_int = -4;
+/// The buffer is too small, the message stays queued
+pub const MC_TOO_SMALL: c_int = -5;
+/// A null handle was passed
+pub const MC_INVALID: c_int = -6;
+
+/// Opaque publisher of byte messages
+pub struct McPublisher(Publisher<Box<[u8]>>);
+
+/// Opaque reader of byte messages
+pub struct McReader(StreamReader<Box<[u8]>>);
+
+/// Outcome of [`mc_read_result`]
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct McReadResult {
+    pub size: u32,
+    /// one of the `MC_` codes, as also returned
+    pub status: c_int,
+    /// length of the message, also set for [`MC_TOO_SMALL`]
+    pub len: usize,
+    /// sequence number of the message
+    pub sequence: u64,
+    /// messages lost, for [`MC_LAGGED`]
+    pub lagged: u64,
+}
+
+/// Counters of a reader, see [`ReaderStats`](crate::ReaderStats)
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct McReaderStats {
+    pub size: u32,
+    /// [`MC_ABI_VERSION`] of the library which filled it in
+    pub version: u32,
+    pub delivered: u64,
+    pub read: u64,
+    pub missed: u64,
+    pub max_lag: u64,
+    pub current_lag: u64,
+}
+
+/// State of a publisher
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct McPublisherStats {
+    pub size: u32,
+    /// [`MC_ABI_VERSION`] of the library which filled it in
+    pub version: u32,
+    /// messages retained for readers
+    pub retained: u64,
+    /// messages which fit without growing the buffer
+    pub capacity: u64,
+    /// heap bytes held, see [`MemoryUsage`](crate::MemoryUsage)
+    pub memory: u64,
+}
+
+/// Copy `value` into `out` as far as the `size` set by the caller covers,
+/// which is then set to the bytes filled in
+///
+/// # Safety
+///
+/// `out` points to a struct starting with a `u32` size, writable for that
+/// many bytes.
+unsafe fn fill<T>(out: *mut T, value: T) -> c_int {
+    let Some(declared) = (unsafe { declared_size(out) }) else {
+        return MC_INVALID;
+    };
+    let len = declared.min(mem::size_of::<T>());
+    let src = ptr::from_ref(&value).cast::<u8>();
+    unsafe {
+        ptr::copy_nonoverlapping(src, out.cast::<u8>(), len);
+        out.cast::<u32>().write(len as u32);
+    }
+    MC_OK
+}
+
+/// The `size` a caller declared for `out`, if it is usable
+///
+/// # Safety
+///
+/// As for [`fill`].
+unsafe fn declared_size<T>(out: *const T) -> Option<usize> {
+    if out.is_null() {
+        return None;
+    }
+    let declared = unsafe { out.cast::<u32>().read() } as usize;
+    (declared >= mem::size_of::<u32>()).then_some(declared)
+}
+
+fn code(error: ReadError) -> c_int {
+    match error {
+        ReadError::TimedOut => MC_TIMED_OUT,
+        ReadError::Lagged(_) => MC_LAGGED,
+        ReadError::Reset => MC_RESET,
+        ReadError::Closed => MC_CLOSED,
+    }
+}
+
+/// [`MC_ABI_VERSION`] of the library
+#[unsafe(no_mangle)]
+pub extern "C" fn mc_abi_version() -> u32 {
+    MC_ABI_VERSION
+}
+
+/// Describe an `MC_` code, as a static nul terminated string
+#[unsafe(no_mangle)]
+pub extern "C" fn mc_status_message(status: c_int) -> *const c_char {
+    let message: &'static [u8] = match status {
+        MC_OK => b"ok\0",
+        MC_EMPTY => b"no message queued\0",
+        MC_CLOSED => b"stream is closed\0",
+        MC_LAGGED => b"reader missed messages\0",
+        MC_RESET => b"stream was reset\0",
+        MC_TIMED_OUT => b"reader was idle for too long\0",
+        MC_TOO_SMALL => b"buffer is too small\0",
+        MC_INVALID => b"invalid argument\0",
+        _ => b"unknown status\0",
+    };
+    message.as_ptr().cast()
+}
+
+/// Create a publisher, free it with [`mc_publisher_free`]
+#[unsafe(no_mangle)]
+pub extern "C" fn mc_publisher_new() -> *mut McPublisher {
+    Box::into_raw(Box::new(McPublisher(Publisher::new())))
+}
+
+/// Free a publisher and its messages, its readers report [`MC_CLOSED`]
+///
+/// # Safety
+///
+/// `publisher` is null or was returned by [`mc_publisher_new`] and not
+/// freed yet.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn mc_publisher_free(publisher: *mut McPublisher) {
+    if !publisher.is_null() {
+        drop(unsafe { Box::from_raw(publisher) });
+    }
+}
+
+/// Copy `len` bytes at `data` into a new message, returns its sequence
+/// number
+///
+/// Returns `u64::MAX` if `publisher` is null.
+///
+/// # Safety
+///
+/// `publisher` is null or a live handle and `data` points to `len` readable
+/// bytes, it may be null if `len` is zero.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn mc_publish(
+    publisher: *mut McPublisher,
+    data: *const u8,
+    len: usize,
+) -> u64 {
+    let Some(McPublisher(publisher)) = (unsafe { publisher.as_mut() }) else {
+        return u64::MAX;
+    };
+    let message = if len == 0 {
+        &[][..]
+    } else {
+        unsafe { slice::from_raw_parts(data, len) }
+    };
+    publisher.publish(message.into()) as u64
+}
+
+/// Signal readers that no further messages will be published
+///
+/// Returns [`MC_INVALID`] if `publisher` is null.
+///
+/// # Safety
+///
+/// `publisher` is null or a live handle.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn mc_publisher_close(publisher: *mut McPublisher) -> c_int {
+    let Some(McPublisher(publisher)) = (unsafe { publisher.as_mut() }) else {
+        return MC_INVALID;
+    };
+    publisher.close();
+    MC_OK
+}
+
+/// Fill in the state of a publisher
+///
+/// # Safety
+///
+/// `publisher` is a live handle and `stats` points to a [`McPublisherStats`]
+/// with its `size` set.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn mc_publisher_stats(
+    publisher: *const McPublisher,
+    stats: *mut McPublisherStats,
+) -> c_int {
+    let Some(McPublisher(publisher)) = (unsafe { publisher.as_ref() }) else {
+        return MC_INVALID;
+    };
+    let retained = publisher.retained();
+    let value = McPublisherStats {
+        size: 0,
+        version: MC_ABI_VERSION,
+        retained: seq::distance(retained.start, retained.end) as u64,
+        capacity: publisher.capacity() as u64,
+        memory: publisher.memory_usage().total() as u64,
+    };
+    unsafe { fill(stats, value) }
+}
+
+/// Add a reader, free it with [`mc_unsubscribe`]
+///
+/// Returns null if `publisher` is null.
+///
+/// # Safety
+///
+/// `publisher` is null or a live handle.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn mc_subscribe(publisher: *mut McPublisher) -> *mut McReader {
+    let Some(publisher) = (unsafe { publisher.as_mut() }) else {
+        return ptr::null_mut();
+    };
+    // boxed first, the publisher keeps the address of the reader
+    let reader = Box::into_raw(Box::new(McReader(StreamReader::new())));
+    publisher.0.add_stream_reader(unsafe { &mut (*reader).0 });
+    reader
+}
+
+/// Remove a reader and free it
+///
+/// # Safety
+///
+/// `reader` is null or was returned by [`mc_subscribe`] and not freed yet.
+/// Its publisher may have been freed already.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn mc_unsubscribe(reader: *mut McReader) {
+    if !reader.is_null() {
+        drop(unsafe { Box::from_raw(reader) });
+    }
+}
+
+/// Copy the next message into `buf` and consume it
+///
+/// Stores the length of the message in `len` unless that is null, also
+/// when `cap` is too small for it. Errors are reported once, the next read
+/// goes on with the following messages.
+///
+/// # Safety
+///
+/// `reader` is null or a live handle, `buf` points to `cap` writable bytes
+/// and `len` is null or points to a writable `size_t`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn mc_read(
+    reader: *mut McReader,
+    buf: *mut u8,
+    cap: usize,
+    len: *mut usize,
+) -> c_int {
+    let result = unsafe { read(reader, buf, cap) };
+    if !len.is_null() && (result.status == MC_OK || result.status == MC_TOO_SMALL) {
+        unsafe { len.write(result.len) };
+    }
+    result.status
+}
+
+/// Like [`mc_read`], also reporting the sequence number of the message and
+/// the number of lost ones
+///
+/// # Safety
+///
+/// As for [`mc_read`], `result` points to a [`McReadResult`] with its `size`
+/// set.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn mc_read_result(
+    reader: *mut McReader,
+    buf: *mut u8,
+    cap: usize,
+    result: *mut McReadResult,
+) -> c_int {
+    // checked first, a read which can't be reported must not consume anything
+    if unsafe { declared_size(result) }.is_none() {
+        return MC_INVALID;
+    }
+    let value = unsafe { read(reader, buf, cap) };
+    match unsafe { fill(result, value) } {
+        MC_OK => value.status,
+        error => error,
+    }
+}
+
+/// Fill in the counters of a reader
+///
+/// # Safety
+///
+/// `reader` is a live handle and `stats` points to a [`McReaderStats`] with
+/// its `size` set.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn mc_reader_stats(
+    reader: *const McReader,
+    stats: *mut McReaderStats,
+) -> c_int {
+    let Some(McReader(reader)) = (unsafe { reader.as_ref() }) else {
+        return MC_INVALID;
+    };
+    let counters = reader.stats();
+    let value = McReaderStats {
+        size: 0,
+        version: MC_ABI_VERSION,
+        delivered: counters.delivered,
+        read: counters.read,
+        missed: counters.missed,
+        max_lag: counters.max_lag as u64,
+        current_lag: counters.current_lag as u64,
+    };
+    unsafe { fill(stats, value) }
+}
+
+/// The read shared by [`mc_read`] and [`mc_read_result`]
+unsafe fn read(reader: *mut McReader, buf: *mut u8, cap: usize) -> McReadResult {
+    let mut result = McReadResult {
+        size: 0,
+        status: MC_OK,
+        len: 0,
+        sequence: 0,
+        lagged: 0,
+    };
+    let Some(McReader(reader)) = (unsafe { reader.as_mut() }) else {
+        result.status = MC_INVALID;
+        return result;
+    };
+    if let Some(error) = reader.pending_error() {
+        if let ReadError::Lagged(missed) = error {
+            result.lagged = missed;
+        }
+        result.status = code(error);
+        return result;
+    }
+    let closed = reader.is_closed();
+    let Some((sequence, message)) = reader.peek() else {
+        result.status = if closed { MC_CLOSED } else { MC_EMPTY };
+        return result;
+    };
+    result.len = message.len();
+    result.sequence = sequence as u64;
+    if message.len() > cap {
+        result.status = MC_TOO_SMALL;
+        return result;
+    }
+    if !message.is_empty() {
+        unsafe { ptr::copy_nonoverlapping(message.as_ptr(), buf, message.len()) };
+    }
+    drop(reader.read());
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        unsafe {
+            let p = mc_publisher_new();
+            let a = mc_subscribe(p);
+            let b = mc_subscribe(p);
+            let mut buf = [0u8; 4];
+            let mut len = 0;
+            assert_eq!(mc_read(a, buf.as_mut_ptr(), 4, &mut len), MC_EMPTY);
+            assert_eq!(mc_publish(p, b"hello".as_ptr(), 5), 0);
+            assert_eq!(mc_publish(p, ptr::null(), 0), 1);
+            assert_eq!(mc_read(a, buf.as_mut_ptr(), 4, &mut len), MC_TOO_SMALL);
+            assert_eq!(len, 5);
+            let mut big = [0u8; 8];
+            assert_eq!(mc_read(a, big.as_mut_ptr(), 8, &mut len), MC_OK);
+            assert_eq!(&big[..len], b"hello");
+            assert_eq!(mc_read(a, big.as_mut_ptr(), 8, &mut len), MC_OK);
+            assert_eq!(len, 0);
+            mc_unsubscribe(a);
+            mc_publisher_free(p);
+            assert_eq!(mc_read(b, big.as_mut_ptr(), 8, ptr::null_mut()), MC_CLOSED);
+            mc_unsubscribe(b);
+            assert_eq!(
+                mc_read(ptr::null_mut(), ptr::null_mut(), 0, &mut len),
+                MC_INVALID
+            );
+            assert_eq!(mc_publish(ptr::null_mut(), b"x".as_ptr(), 1), u64::MAX);
+            assert_eq!(mc_publisher_close(ptr::null_mut()), MC_INVALID);
+        }
+    }
+
+    #[test]
+    fn versioned_structs() {
+        unsafe {
+            let p = mc_publisher_new();
+            let r = mc_subscribe(p);
+            mc_publish(p, b"ab".as_ptr(), 2);
+            mc_publish(p, b"cde".as_ptr(), 3);
+            let mut buf = [0u8; 4];
+            let mut result = McReadResult::default();
+            // a result without its size set is refused before reading
+            assert_eq!(
+                mc_read_result(r, buf.as_mut_ptr(), 4, &mut result),
+                MC_INVALID
+            );
+            assert_eq!(
+                mc_read_result(r, buf.as_mut_ptr(), 4, ptr::null_mut()),
+                MC_INVALID
+            );
+            result.size = mem::size_of::<McReadResult>() as u32;
+            assert_eq!(
+                mc_read_result(r, buf.as_mut_ptr(), 1, &mut result),
+                MC_TOO_SMALL
+            );
+            assert_eq!((result.len, result.sequence), (2, 0));
+            assert_eq!(mc_read_result(r, buf.as_mut_ptr(), 4, &mut result), MC_OK);
+            assert_eq!(&buf[..result.len], b"ab");
+
+            // a caller built against a layout which ends after `delivered`
+            let mut stats = McReaderStats {
+                size: 16,
+                max_lag: 99,
+                ..Default::default()
+            };
+            assert_eq!(mc_reader_stats(r, &mut stats), MC_OK);
+            assert_eq!((stats.size, stats.version), (16, MC_ABI_VERSION));
+            assert_eq!((stats.delivered, stats.read, stats.max_lag), (2, 0, 99));
+            stats.size = mem::size_of::<McReaderStats>() as u32;
+            assert_eq!(mc_reader_stats(r, &mut stats), MC_OK);
+            assert_eq!((stats.read, stats.current_lag), (1, 1));
+
+            let mut stats = McPublisherStats {
+                size: u32::MAX,
+                ..Default::default()
+            };
+            assert_eq!(mc_publisher_stats(p, &mut stats), MC_OK);
+            assert_eq!(stats.size as usize, mem::size_of::<McPublisherStats>());
+            assert_eq!(stats.retained, 1);
+            let message = core::ffi::CStr::from_ptr(mc_status_message(MC_TOO_SMALL));
+            assert_eq!(message.to_str(), Ok("buffer is too small"));
+            mc_unsubscribe(r);
+            mc_publisher_free(p);
+        }
+    }
+}
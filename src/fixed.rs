@@ -0,0 +1,594 @@
+//! Publisher with inline storage which never allocates
+//!
+//! The capacity `N` and the maximum number of readers `R` are part of the
+//! type, so the whole stream lives wherever the publisher is placed, e.g. on
+//! the stack or in a static. Readers only carry a reference and an index.
+//!
+//! This is all that is left without the `alloc` feature, the crate then
+//! doesn't link `alloc` either, so bare-metal firmware needs no global
+//! allocator to use it.
+//!
+//! With the `critical-section` feature every access runs in a critical
+//! section and the publisher is `Sync`, so a
+//! `static STREAM: StaticPublisher<Frame, 32> = StaticPublisher::new();` at
+//! file scope can be shared between interrupt handlers and thread mode.
+//!
+//! The `embassy` feature lets readers register a waker per reader slot, as
+//! embassy-sync channels do, so an async task can `await` the next item.
+//! `ReaderHooks` then observe how the waiting tasks are parked, woken and
+//! polled again.
+
+use core::{
+    cell::{Cell, UnsafeCell},
+    mem::MaybeUninit,
+    ops::Deref,
+};
+
+#[cfg(feature = "embassy")]
+use core::{future::poll_fn, task::Poll};
+#[cfg(feature = "embassy")]
+use embassy_sync::waitqueue::AtomicWaker;
+
+use crate::{Counter, Full, ReadError, seq::Sequence};
+
+/// Fixed-capacity publisher for up to `N` retained items and `R` readers
+///
+/// Publishing into a full buffer drops the oldest item, readers which didn't
+/// read it yet see [`ReadError::Lagged`]. An item currently borrowed by a
+/// guard is never dropped, publishing fails instead.
+///
+/// Sequence numbers are of type `C`, for a handful of slots `u8` keeps the
+/// state of a reader at four bytes. `N` must stay within a quarter of the
+/// counter range, and the missed items reported per `try_read` saturate at
+/// its maximum.
+pub struct StaticPublisher<T, const N: usize, const R: usize = 4, C: Sequence = Counter> {
+    slots: [UnsafeCell<MaybeUninit<T>>; N],
+    /// ring position of the oldest retained item
+    head: Cell<usize>,
+    len: Cell<usize>,
+    /// sequence number of the oldest retained item
+    first: Cell<C>,
+    /// next item to read per reader, `None` for a free reader slot
+    cursors: [Cell<Option<C>>; R],
+    /// whether the reader currently holds a guard on its next item
+    borrowed: [Cell<bool>; R],
+    /// items lost to overruns since the last `try_read`, saturating
+    lagged: [Cell<C>; R],
+    /// task waiting in [`StaticReader::ready`], per reader
+    #[cfg(feature = "embassy")]
+    wakers: [AtomicWaker; R],
+    /// whether the task of the reader waits since its last poll
+    #[cfg(feature = "embassy")]
+    parked: [Cell<bool>; R],
+    #[cfg(feature = "embassy")]
+    hooks: Cell<Option<&'static dyn ReaderHooks>>,
+}
+
+/// Reports the tasks awaiting [`StaticReader::ready`], naming the reader
+/// slot
+///
+/// `woken` runs within the critical section of publishing, keep it short.
+#[cfg(feature = "embassy")]
+pub use crate::ReaderHooks;
+
+impl<T, const N: usize, const R: usize, C: Sequence> StaticPublisher<T, N, R, C> {
+    pub const fn new() -> Self {
+        const {
+            assert!(
+                N <= C::WINDOW / 2,
+                "the counter is too small for this capacity"
+            )
+        };
+        Self {
+            slots: [const { UnsafeCell::new(MaybeUninit::uninit()) }; N],
+            head: Cell::new(0),
+            len: Cell::new(0),
+            first: Cell::new(C::ZERO),
+            cursors: [const { Cell::new(None) }; R],
+            borrowed: [const { Cell::new(false) }; R],
+            lagged: [const { Cell::new(C::ZERO) }; R],
+            #[cfg(feature = "embassy")]
+            wakers: [const { AtomicWaker::new() }; R],
+            #[cfg(feature = "embassy")]
+            parked: [const { Cell::new(false) }; R],
+            #[cfg(feature = "embassy")]
+            hooks: Cell::new(None),
+        }
+    }
+    /// Report the waits of the async readers to `hooks` from now on
+    #[cfg(feature = "embassy")]
+    pub fn set_hooks(&self, hooks: &'static dyn ReaderHooks) {
+        exclusive(|| self.hooks.set(Some(hooks)));
+    }
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+    /// Number of retained items
+    pub fn len(&self) -> usize {
+        exclusive(|| self.len.get())
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    fn end(&self) -> C {
+        self.first.get().advance(self.len.get())
+    }
+    fn slot(&self, seq: C) -> &UnsafeCell<MaybeUninit<T>> {
+        let offset = self.first.get().distance(seq);
+        &self.slots[(self.head.get() + offset) % N]
+    }
+    /// Publish an item, returns its sequence number
+    ///
+    /// Gives the item back if the buffer is full and its oldest item is
+    /// borrowed, or if `N` is zero.
+    pub fn publish(&self, obj: T) -> Result<C, T> {
+        exclusive(|| {
+            if self.len.get() == N {
+                let oldest = self.first.get();
+                let held =
+                    (0..R).any(|r| self.borrowed[r].get() && self.cursors[r].get() == Some(oldest));
+                if N == 0 || held {
+                    return Err(obj);
+                }
+                self.drop_front(1);
+            }
+            Ok(self.push(obj))
+        })
+    }
+    /// Publish an item only if a slot is free, never dropping an older one
+    ///
+    /// Does nothing but write the item into its slot, so it neither blocks,
+    /// allocates nor runs the drop of another item. That makes it the one to
+    /// call from an interrupt handler.
+    pub fn try_publish(&self, obj: T) -> Result<C, Full<T>> {
+        exclusive(|| {
+            if self.len.get() == N {
+                return Err(Full(obj));
+            }
+            Ok(self.push(obj))
+        })
+    }
+    /// Write an item into the next free slot, there must be one
+    fn push(&self, obj: T) -> C {
+        let seq = self.end();
+        self.len.set(self.len.get() + 1);
+        unsafe { (*self.slot(seq).get()).write(obj) };
+        #[cfg(feature = "embassy")]
+        {
+            if let Some(hooks) = self.hooks.get() {
+                for (reader, parked) in self.parked.iter().enumerate() {
+                    if parked.replace(false) {
+                        hooks.woken(reader);
+                    }
+                }
+            }
+            self.wakers.iter().for_each(AtomicWaker::wake);
+        }
+        seq
+    }
+    /// Add a reader which starts with all retained items
+    ///
+    /// Returns `None` once `R` readers exist.
+    pub fn subscribe(&self) -> Option<StaticReader<'_, T, N, R, C>> {
+        let index = exclusive(|| {
+            let index = self.cursors.iter().position(|c| c.get().is_none())?;
+            self.cursors[index].set(Some(self.first.get()));
+            self.lagged[index].set(C::ZERO);
+            Some(index)
+        })?;
+        Some(StaticReader {
+            publisher: self,
+            index,
+        })
+    }
+    /// Drop the `count` oldest items
+    fn drop_front(&self, count: usize) {
+        for _ in 0..count {
+            let seq = self.first.get();
+            unsafe { (*self.slot(seq).get()).assume_init_drop() };
+            self.first.set(seq.advance(1));
+            self.head.set((self.head.get() + 1) % N);
+            self.len.set(self.len.get() - 1);
+            if C::ZERO
+                .distance(self.first.get())
+                .is_multiple_of(C::REBASE_INTERVAL)
+            {
+                self.rebase();
+            }
+        }
+    }
+    /// Move readers which fell far behind up to the oldest retained item,
+    /// before their cursors drift out of the window [`Sequence::precedes`]
+    /// handles
+    fn rebase(&self) {
+        for r in 0..R {
+            if self.cursors[r].get().is_some() {
+                self.catch_up(r);
+            }
+        }
+    }
+    /// Move reader `r` past items dropped by overruns, returns the next
+    /// item it reads
+    fn catch_up(&self, r: usize) -> C {
+        let first = self.first.get();
+        let cursor = self.cursors[r].get().unwrap_or(first);
+        if !cursor.precedes(first) {
+            return cursor;
+        }
+        let behind = cursor.distance(first);
+        self.cursors[r].set(Some(first));
+        self.lagged[r].set(self.lagged[r].get().saturating_advance(behind));
+        first
+    }
+    /// Drop every leading item which all readers are past
+    fn release(&self) {
+        let first = self.first.get();
+        // readers not yet caught up with an overrun are past nothing
+        let used = self
+            .cursors
+            .iter()
+            .filter_map(Cell::get)
+            .map(|c| {
+                if c.precedes(first) {
+                    0
+                } else {
+                    first.distance(c).min(self.len.get())
+                }
+            })
+            .min();
+        if let Some(used) = used {
+            self.drop_front(used);
+        }
+    }
+}
+
+// every access to the cells happens in a critical section, and readers in
+// different contexts may borrow the same item
+#[cfg(feature = "critical-section")]
+unsafe impl<T: Send + Sync, const N: usize, const R: usize, C: Sequence + Send> Sync
+    for StaticPublisher<T, N, R, C>
+{
+}
+
+/// Run `f` without any other access to a publisher in between, with the
+/// `critical-section` feature by running it in a critical section
+pub(crate) fn exclusive<U>(f: impl FnOnce() -> U) -> U {
+    #[cfg(feature = "critical-section")]
+    return critical_section::with(|_| f());
+    #[cfg(not(feature = "critical-section"))]
+    f()
+}
+
+impl<T, const N: usize, const R: usize, C: Sequence> Default for StaticPublisher<T, N, R, C> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize, const R: usize, C: Sequence> Drop for StaticPublisher<T, N, R, C> {
+    fn drop(&mut self) {
+        self.drop_front(self.len.get());
+    }
+}
+
+/// Consumer of a [`StaticPublisher`]
+pub struct StaticReader<'a, T, const N: usize, const R: usize, C: Sequence = Counter> {
+    publisher: &'a StaticPublisher<T, N, R, C>,
+    index: usize,
+}
+
+impl<'a, T, const N: usize, const R: usize, C: Sequence> StaticReader<'a, T, N, R, C> {
+    /// Move past items dropped by overruns, returns the next item to read
+    fn catch_up(&self) -> C {
+        self.publisher.catch_up(self.index)
+    }
+    /// Borrow the next item, reporting items lost to overruns first
+    pub fn try_read(&mut self) -> Result<Option<StaticRead<'_, 'a, T, N, R, C>>, ReadError> {
+        let lagged = exclusive(|| {
+            self.catch_up();
+            self.publisher.lagged[self.index].replace(C::ZERO)
+        });
+        if lagged != C::ZERO {
+            return Err(ReadError::Lagged(C::ZERO.distance(lagged) as u64));
+        }
+        Ok(self.read())
+    }
+    /// Borrow the next item, it is consumed when the guard drops
+    pub fn read(&mut self) -> Option<StaticRead<'_, 'a, T, N, R, C>> {
+        let p = self.publisher;
+        let obj = exclusive(|| {
+            let cursor = self.catch_up();
+            if cursor == p.end() {
+                return None;
+            }
+            // the item stays put until the flag is cleared again
+            p.borrowed[self.index].set(true);
+            Some(unsafe { (*p.slot(cursor).get()).assume_init_ref() })
+        })?;
+        Some(StaticRead { reader: self, obj })
+    }
+    /// Copy out the next item and consume it right away, without a guard
+    pub fn recv_copy(&mut self) -> Option<T>
+    where
+        T: Copy,
+    {
+        self.read().map(|item| *item)
+    }
+    /// Number of items still to read
+    pub fn len(&self) -> usize {
+        let p = self.publisher;
+        exclusive(|| {
+            let cursor = p.cursors[self.index].get().unwrap_or(p.first.get());
+            cursor.distance(p.end()).min(p.len.get())
+        })
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Wait until there is an item to read
+    #[cfg(feature = "embassy")]
+    pub async fn ready(&mut self) {
+        poll_fn(|cx| {
+            let p = self.publisher;
+            let hooks = exclusive(|| p.hooks.get());
+            if let Some(hooks) = hooks {
+                hooks.polled(self.index);
+            }
+            // registered before looking, so a publish in between still wakes
+            p.wakers[self.index].register(cx.waker());
+            if !self.is_empty() {
+                return Poll::Ready(());
+            }
+            if let Some(hooks) = hooks {
+                exclusive(|| p.parked[self.index].set(true));
+                hooks.pending(self.index);
+            }
+            Poll::Pending
+        })
+        .await
+    }
+    /// Wait for the next item and copy it out, like receiving from an
+    /// embassy-sync channel
+    #[cfg(feature = "embassy")]
+    pub async fn receive(&mut self) -> T
+    where
+        T: Copy,
+    {
+        loop {
+            self.ready().await;
+            if let Some(obj) = self.recv_copy() {
+                return obj;
+            }
+        }
+    }
+}
+
+impl<'a, T, const N: usize, const R: usize, C: Sequence> Drop for StaticReader<'a, T, N, R, C> {
+    fn drop(&mut self) {
+        exclusive(|| {
+            self.publisher.cursors[self.index].set(None);
+            #[cfg(feature = "embassy")]
+            self.publisher.parked[self.index].set(false);
+            self.publisher.release();
+        })
+    }
+}
+
+/// Reader-side lock into a [`StaticPublisher`] slot
+pub struct StaticRead<'r, 'a, T, const N: usize, const R: usize, C: Sequence = Counter> {
+    reader: &'r mut StaticReader<'a, T, N, R, C>,
+    obj: &'r T,
+}
+
+impl<'r, 'a, T, const N: usize, const R: usize, C: Sequence> Deref
+    for StaticRead<'r, 'a, T, N, R, C>
+{
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.obj
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl<'r, 'a, T: defmt::Format, const N: usize, const R: usize, C: Sequence> defmt::Format
+    for StaticRead<'r, 'a, T, N, R, C>
+{
+    fn format(&self, f: defmt::Formatter<'_>) {
+        (**self).format(f)
+    }
+}
+
+impl<'r, 'a, T, const N: usize, const R: usize, C: Sequence> Drop
+    for StaticRead<'r, 'a, T, N, R, C>
+{
+    fn drop(&mut self) {
+        let p = self.reader.publisher;
+        let index = self.reader.index;
+        exclusive(|| {
+            p.borrowed[index].set(false);
+            let cursor = p.cursors[index].get().unwrap_or(p.first.get());
+            p.cursors[index].set(Some(cursor.advance(1)));
+            p.release();
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::StaticPublisher;
+    use crate::{Counter, Full, ReadError};
+    use std::rc::Rc;
+
+    #[test]
+    fn overrun() {
+        let p: StaticPublisher<u32, 3, 2> = StaticPublisher::new();
+        let mut a = p.subscribe().unwrap();
+        let mut b = p.subscribe().unwrap();
+        assert!(p.subscribe().is_none());
+        for i in 0..3 {
+            p.publish(i).unwrap();
+        }
+        assert_eq!(*a.read().unwrap(), 0);
+        let held = b.read().unwrap();
+        assert_eq!(p.publish(3), Err(3));
+        drop(held);
+        p.publish(3).unwrap();
+        p.publish(4).unwrap();
+        assert_eq!(a.try_read().err(), Some(ReadError::Lagged(1)));
+        assert_eq!(a.recv_copy(), Some(2));
+        drop(b);
+        assert_eq!(p.len(), 2);
+        assert_eq!(a.len(), 2);
+    }
+
+    #[test]
+    fn try_publish() {
+        let p: StaticPublisher<u32, 2, 1> = StaticPublisher::new();
+        let mut r = p.subscribe().unwrap();
+        assert_eq!(p.try_publish(0), Ok(0));
+        assert_eq!(p.try_publish(1), Ok(1));
+        assert_eq!(p.try_publish(2), Err(Full(2)));
+        assert_eq!(r.recv_copy(), Some(0));
+        assert_eq!(p.try_publish(2), Ok(2));
+        assert_eq!(r.try_read().map(|item| item.map(|v| *v)), Ok(Some(1)));
+    }
+
+    #[test]
+    fn wraps() {
+        let p: StaticPublisher<u32, 2, 1> = StaticPublisher::new();
+        p.first.set(Counter::MAX - 1);
+        let mut r = p.subscribe().unwrap();
+        for i in 0..4 {
+            p.publish(i).unwrap();
+        }
+        // the overrun across zero moved the reader up
+        assert_eq!(p.cursors[0].get(), Some(0));
+        assert_eq!(r.try_read().err(), Some(ReadError::Lagged(2)));
+        assert_eq!(r.recv_copy(), Some(2));
+        assert_eq!(r.len(), 1);
+    }
+
+    #[test]
+    fn tiny_counter() {
+        let p: StaticPublisher<u8, 16, 2, u8> = StaticPublisher::new();
+        let mut fast = p.subscribe().unwrap();
+        let mut slow = p.subscribe().unwrap();
+        for i in 0..=255u8 {
+            p.publish(i).unwrap();
+            assert_eq!(fast.recv_copy(), Some(i));
+        }
+        // wrapped around, the slow reader lost all but the last 16
+        assert_eq!(p.publish(0), Ok(0));
+        assert_eq!(slow.try_read().err(), Some(ReadError::Lagged(241)));
+        assert_eq!(slow.recv_copy(), Some(241));
+        assert_eq!(slow.len(), 15);
+        assert_eq!(size_of_val(&p.cursors), 4);
+    }
+
+    #[test]
+    fn drops_items() {
+        let item = Rc::new(());
+        {
+            let p: StaticPublisher<Rc<()>, 2> = StaticPublisher::new();
+            let mut r = p.subscribe().unwrap();
+            p.publish(item.clone()).unwrap();
+            p.publish(item.clone()).unwrap();
+            assert!(r.read().is_some());
+            assert_eq!(Rc::strong_count(&item), 2);
+            p.publish(item.clone()).unwrap();
+            assert_eq!(Rc::strong_count(&item), 3);
+        }
+        assert_eq!(Rc::strong_count(&item), 1);
+    }
+
+    #[cfg(feature = "embassy")]
+    #[test]
+    fn receive() {
+        use std::{
+            pin::pin,
+            sync::{
+                Arc,
+                atomic::{AtomicBool, Ordering},
+            },
+            task::{Context, Poll, Wake, Waker},
+        };
+
+        struct Flag(AtomicBool);
+        impl Wake for Flag {
+            fn wake(self: Arc<Self>) {
+                self.0.store(true, Ordering::Relaxed);
+            }
+        }
+        let flag = Arc::new(Flag(AtomicBool::new(false)));
+        let waker = Waker::from(flag.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        let p: StaticPublisher<u32, 4, 1> = StaticPublisher::new();
+        let mut r = p.subscribe().unwrap();
+        let mut next = pin!(r.receive());
+        assert_eq!(next.as_mut().poll(&mut cx), Poll::Pending);
+        p.publish(5).unwrap();
+        assert!(flag.0.load(Ordering::Relaxed));
+        assert_eq!(next.as_mut().poll(&mut cx), Poll::Ready(5));
+    }
+
+    #[cfg(feature = "embassy")]
+    #[test]
+    fn hooks() {
+        use super::ReaderHooks;
+        use std::{pin::pin, sync::Mutex, task::Context};
+
+        struct Log(Mutex<Vec<(&'static str, usize)>>);
+        impl ReaderHooks for Log {
+            fn polled(&self, reader: usize) {
+                self.0.lock().unwrap().push(("polled", reader));
+            }
+            fn pending(&self, reader: usize) {
+                self.0.lock().unwrap().push(("pending", reader));
+            }
+            fn woken(&self, reader: usize) {
+                self.0.lock().unwrap().push(("woken", reader));
+            }
+        }
+        static LOG: Log = Log(Mutex::new(Vec::new()));
+
+        let mut cx = Context::from_waker(std::task::Waker::noop());
+        let p: StaticPublisher<u32, 4, 2> = StaticPublisher::new();
+        p.set_hooks(&LOG);
+        let _idle = p.subscribe().unwrap();
+        let mut r = p.subscribe().unwrap();
+        let mut next = pin!(r.receive());
+        assert!(next.as_mut().poll(&mut cx).is_pending());
+        p.publish(5).unwrap();
+        p.publish(6).unwrap();
+        assert!(next.as_mut().poll(&mut cx).is_ready());
+        assert_eq!(
+            *LOG.0.lock().unwrap(),
+            [("polled", 1), ("pending", 1), ("woken", 1), ("polled", 1)]
+        );
+    }
+
+    #[cfg(feature = "critical-section")]
+    #[test]
+    fn shared_static() {
+        static STREAM: StaticPublisher<u32, 4, 2> = StaticPublisher::new();
+        let mut r = STREAM.subscribe().unwrap();
+        let producer = std::thread::spawn(|| {
+            for i in 0..100 {
+                while STREAM.try_publish(i).is_err() {
+                    std::thread::yield_now();
+                }
+            }
+        });
+        let mut next = 0;
+        while next < 100 {
+            if let Some(v) = r.recv_copy() {
+                assert_eq!(v, next);
+                next += 1;
+            }
+        }
+        producer.join().unwrap();
+        assert!(STREAM.is_empty());
+    }
+}
@@ -0,0 +1,460 @@
+//! Discrete messages over a byte stream
+//!
+//! A [`FramePublisher`] writes every message as a little endian `u32`
+//! length followed by the payload, in a single delivery pass, and refuses
+//! messages above its maximum frame size. A [`FrameReader`] collects the
+//! bytes of the next frame, however they are split up in the buffer, and
+//! hands out whole frames only.
+//!
+//! Frame boundaries are only known by counting from the start of the
+//! stream. A reader which lost bytes, or read a length above its own
+//! maximum, can't find the next frame and reports [`FrameError::Desynced`]
+//! until the publisher resets the stream with [`FramePublisher::clear`].
+//!
+//! With the `crc32` or `xxhash` feature a [`Checksum`] of the header and
+//! payload can follow each frame. Publisher and readers have to agree on
+//! it, readers drop frames which don't match as [`FrameError::Corrupt`].
+//!
+//! With the `lz4` feature the publisher can also [compress](Compression)
+//! frames, which keeps more of them within the same capacity. A frame is
+//! only stored compressed if that makes it smaller, the highest bit of its
+//! length says which, so readers need no setting for it.
+
+use alloc::{boxed::Box, vec::Vec};
+use core::{fmt, ops::Deref};
+
+use crate::{BytePublisher, ByteSubscriber, ReadError};
+
+/// Bytes of the length prefix in front of each frame
+pub const HEADER: usize = 4;
+
+/// Bit of the length prefix marking a compressed frame
+const COMPRESSED: u32 = 1 << 31;
+
+/// Checksum behind each frame, covering its header and payload
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Checksum {
+    #[default]
+    None,
+    /// CRC-32 as used by Ethernet and zlib
+    #[cfg(feature = "crc32")]
+    Crc32,
+    /// 32 bit xxHash with a seed of zero
+    #[cfg(feature = "xxhash")]
+    XxHash32,
+}
+
+impl Checksum {
+    /// Bytes taken behind each frame
+    pub fn size(self) -> usize {
+        if self == Checksum::None { 0 } else { 4 }
+    }
+    #[cfg_attr(
+        not(any(feature = "crc32", feature = "xxhash")),
+        allow(unused_variables)
+    )]
+    fn compute(self, header: &[u8], payload: &[u8]) -> [u8; 4] {
+        let sum: u32 = match self {
+            Checksum::None => 0,
+            #[cfg(feature = "crc32")]
+            Checksum::Crc32 => {
+                let mut hasher = crc32fast::Hasher::new();
+                hasher.update(header);
+                hasher.update(payload);
+                hasher.finalize()
+            }
+            #[cfg(feature = "xxhash")]
+            Checksum::XxHash32 => {
+                let mut hasher = xxhash_rust::xxh32::Xxh32::new(0);
+                hasher.update(header);
+                hasher.update(payload);
+                hasher.digest()
+            }
+        };
+        sum.to_le_bytes()
+    }
+}
+
+/// Compression of the frames on publish
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Compression {
+    #[default]
+    None,
+    /// LZ4 block format, preceded by the uncompressed size
+    #[cfg(feature = "lz4")]
+    Lz4,
+}
+
+/// Counters of a frame publisher, see [`FramePublisher::stats`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FrameStats {
+    /// frames published
+    pub frames: u64,
+    /// bytes of their payloads as passed to the publisher
+    pub payload: u64,
+    /// bytes of their payloads as stored, after compression
+    pub stored: u64,
+}
+
+impl FrameStats {
+    /// Stored bytes per payload byte, below one where compression helps
+    pub fn ratio(&self) -> f64 {
+        if self.payload == 0 {
+            return 1.0;
+        }
+        self.stored as f64 / self.payload as f64
+    }
+}
+
+/// Reasons why a frame was not published or read
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FrameError {
+    /// The frame is longer than the maximum frame size
+    TooLarge(usize),
+    /// Frame boundaries are lost until the stream is reset
+    Desynced,
+    /// The checksum didn't match or the frame didn't decompress, it was
+    /// dropped
+    Corrupt,
+    /// The frame is compressed, reading it needs the `lz4` feature
+    Unsupported,
+    /// The underlying byte reader failed
+    Read(ReadError),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::TooLarge(len) => write!(f, "frame of {len} bytes is too large"),
+            FrameError::Desynced => f.write_str("frame boundaries were lost"),
+            FrameError::Corrupt => f.write_str("frame is corrupt"),
+            FrameError::Unsupported => f.write_str("frame is compressed"),
+            FrameError::Read(error) => error.fmt(f),
+        }
+    }
+}
+
+impl core::error::Error for FrameError {}
+
+/// Publisher of length prefixed frames
+///
+/// Dereferences to the underlying byte publisher for inspection, writing to
+/// it directly would break the framing.
+pub struct FramePublisher {
+    bytes: BytePublisher,
+    max_frame: usize,
+    checksum: Checksum,
+    compression: Compression,
+    stats: FrameStats,
+}
+
+impl FramePublisher {
+    /// Create a publisher of frames up to `max_frame` bytes
+    pub fn new(max_frame: usize) -> Self {
+        Self::with_checksum(max_frame, Checksum::None)
+    }
+    /// Create a publisher which appends `checksum` to every frame
+    pub fn with_checksum(max_frame: usize, checksum: Checksum) -> Self {
+        assert!(max_frame < COMPRESSED as usize);
+        Self {
+            bytes: BytePublisher::new(),
+            max_frame,
+            checksum,
+            compression: Compression::None,
+            stats: FrameStats::default(),
+        }
+    }
+    /// Compress the frames published from now on
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.compression = compression;
+    }
+    pub fn stats(&self) -> FrameStats {
+        self.stats
+    }
+    pub fn max_frame(&self) -> usize {
+        self.max_frame
+    }
+    /// Bound the retained bytes, headers and checksums included
+    ///
+    /// Readers behind the oldest retained byte lag and desync, see
+    /// [`Publisher::set_capacity_limit`](crate::Publisher::set_capacity_limit).
+    pub fn set_capacity_limit(&mut self, bytes: usize) {
+        self.bytes.set_capacity_limit(bytes);
+    }
+    /// Add a reader, it receives the frames published from now on
+    pub fn subscribe(&mut self, reader: &mut FrameReader) {
+        self.bytes.add_stream_reader(&mut reader.bytes);
+    }
+    /// Publish one frame, readers are notified once
+    pub fn publish(&mut self, frame: &[u8]) -> Result<(), FrameError> {
+        if frame.len() > self.max_frame {
+            return Err(FrameError::TooLarge(frame.len()));
+        }
+        #[cfg(feature = "lz4")]
+        let compressed = (self.compression == Compression::Lz4)
+            .then(|| lz4_flex::compress_prepend_size(frame))
+            .filter(|compressed| compressed.len() < frame.len());
+        #[cfg(feature = "lz4")]
+        let (payload, flag) = match &compressed {
+            Some(compressed) => (&compressed[..], COMPRESSED),
+            None => (frame, 0),
+        };
+        #[cfg(not(feature = "lz4"))]
+        let (payload, flag) = (frame, 0);
+        let header = (payload.len() as u32 | flag).to_le_bytes();
+        let sum = self.checksum.compute(&header, payload);
+        let trailer = &sum[..self.checksum.size()];
+        self.bytes
+            .publish_parts([&header[..], payload, trailer].into_iter());
+        self.stats.frames += 1;
+        self.stats.payload += frame.len() as u64;
+        self.stats.stored += payload.len() as u64;
+        Ok(())
+    }
+    /// Discard the retained frames, readers continue with the next one
+    pub fn clear(&mut self) {
+        self.bytes.clear();
+    }
+    pub fn close(&mut self) {
+        self.bytes.close();
+    }
+}
+
+impl Deref for FramePublisher {
+    type Target = BytePublisher;
+
+    fn deref(&self) -> &BytePublisher {
+        &self.bytes
+    }
+}
+
+/// Reader of whole frames
+///
+/// Like a [`StreamReader`](crate::StreamReader) it must stay in place once
+/// subscribed.
+pub struct FrameReader {
+    bytes: ByteSubscriber,
+    max_frame: usize,
+    checksum: Checksum,
+    /// bytes of the incomplete frame, including its header
+    partial: Vec<u8>,
+    desynced: bool,
+}
+
+impl FrameReader {
+    /// Create a reader accepting frames up to `max_frame` bytes
+    pub fn new(max_frame: usize) -> Self {
+        Self::with_checksum(max_frame, Checksum::None)
+    }
+    /// Create a reader verifying `checksum` behind every frame
+    pub fn with_checksum(max_frame: usize, checksum: Checksum) -> Self {
+        Self {
+            bytes: ByteSubscriber::new(),
+            max_frame,
+            checksum,
+            partial: Vec::new(),
+            desynced: false,
+        }
+    }
+    /// Take the next complete frame
+    ///
+    /// Returns `Ok(None)` while the frame is still incomplete. Bytes lost
+    /// to the reader are reported once as the [`ReadError`], after that
+    /// reads fail with [`FrameError::Desynced`] until the stream is reset.
+    pub fn read_frame(&mut self) -> Result<Option<Vec<u8>>, FrameError> {
+        loop {
+            match self.bytes.pending_error() {
+                Some(ReadError::Reset) => {
+                    self.partial.clear();
+                    self.desynced = false;
+                }
+                Some(error @ ReadError::Lagged(_)) => {
+                    self.desync();
+                    return Err(FrameError::Read(error));
+                }
+                Some(error) => return Err(FrameError::Read(error)),
+                None => {}
+            }
+            if self.desynced {
+                while self.bytes.read_chunk(usize::MAX).is_some() {}
+                return Err(FrameError::Desynced);
+            }
+            let need = match self.frame_len() {
+                Some(len) => HEADER + len + self.checksum.size() - self.partial.len(),
+                None => HEADER - self.partial.len(),
+            };
+            if need == 0 {
+                return self.take_frame();
+            }
+            let closed = self.bytes.is_closed();
+            let Some(chunk) = self.bytes.read_chunk(need) else {
+                if closed {
+                    return Err(FrameError::Read(ReadError::Closed));
+                }
+                return Ok(None);
+            };
+            for slice in chunk.slices() {
+                self.partial.extend_from_slice(slice);
+            }
+            drop(chunk);
+            if let Some(len) = self.frame_len()
+                && len > self.max_frame
+            {
+                self.desync();
+                return Err(FrameError::TooLarge(len));
+            }
+        }
+    }
+    pub fn max_frame(&self) -> usize {
+        self.max_frame
+    }
+    pub fn is_closed(&self) -> bool {
+        self.bytes.is_closed()
+    }
+    pub fn set_notification(&mut self, n: Box<dyn Fn()>) {
+        self.bytes.set_notification(n);
+    }
+    /// The length prefix of the incomplete frame once it arrived
+    fn header(&self) -> Option<u32> {
+        let header = self.partial.first_chunk::<HEADER>()?;
+        Some(u32::from_le_bytes(*header))
+    }
+    /// Stored payload length of the incomplete frame
+    fn frame_len(&self) -> Option<usize> {
+        self.header().map(|header| (header & !COMPRESSED) as usize)
+    }
+    /// Split off the payload of the complete frame and verify it
+    fn take_frame(&mut self) -> Result<Option<Vec<u8>>, FrameError> {
+        let compressed = self.header().is_some_and(|header| header & COMPRESSED != 0);
+        let mut frame = self.partial.split_off(HEADER);
+        let trailer = frame.split_off(frame.len() - self.checksum.size());
+        let sum = self.checksum.compute(&self.partial, &frame);
+        self.partial.clear();
+        if trailer != sum[..self.checksum.size()] {
+            return Err(FrameError::Corrupt);
+        }
+        if compressed {
+            return self.decompress(&frame).map(Some);
+        }
+        Ok(Some(frame))
+    }
+    #[cfg(feature = "lz4")]
+    fn decompress(&self, frame: &[u8]) -> Result<Vec<u8>, FrameError> {
+        let (size, data) = frame.split_first_chunk::<4>().ok_or(FrameError::Corrupt)?;
+        let size = u32::from_le_bytes(*size) as usize;
+        if size > self.max_frame {
+            return Err(FrameError::TooLarge(size));
+        }
+        lz4_flex::decompress(data, size).map_err(|_| FrameError::Corrupt)
+    }
+    #[cfg(not(feature = "lz4"))]
+    fn decompress(&self, _: &[u8]) -> Result<Vec<u8>, FrameError> {
+        Err(FrameError::Unsupported)
+    }
+    fn desync(&mut self) {
+        self.partial.clear();
+        self.desynced = true;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{FrameError, FramePublisher, FrameReader};
+    use crate::ReadError;
+
+    #[test]
+    fn whole_frames() {
+        let mut p = FramePublisher::new(16);
+        let mut r = FrameReader::new(16);
+        p.subscribe(&mut r);
+        assert_eq!(r.read_frame(), Ok(None));
+        p.publish(b"hello").unwrap();
+        p.publish(b"").unwrap();
+        p.publish(b"world").unwrap();
+        assert_eq!(p.publish(&[0; 17]), Err(FrameError::TooLarge(17)));
+        assert_eq!(r.read_frame().unwrap().as_deref(), Some(&b"hello"[..]));
+        assert_eq!(r.read_frame().unwrap().as_deref(), Some(&b""[..]));
+        assert_eq!(r.read_frame().unwrap().as_deref(), Some(&b"world"[..]));
+        assert_eq!(r.read_frame(), Ok(None));
+        p.close();
+        assert_eq!(r.read_frame(), Err(FrameError::Read(ReadError::Closed)));
+    }
+
+    #[cfg(feature = "crc32")]
+    #[test]
+    fn checksums() {
+        use super::Checksum;
+
+        let mut p = FramePublisher::with_checksum(16, Checksum::Crc32);
+        let mut r = FrameReader::with_checksum(16, Checksum::Crc32);
+        p.subscribe(&mut r);
+        p.publish(b"hello").unwrap();
+        assert_eq!(p.retained().len(), 4 + 5 + 4);
+        p.bytes.publish_slice(&[2, 0, 0, 0, b'h', b'i', 0, 0, 0, 0]);
+        p.publish(b"world").unwrap();
+        assert_eq!(r.read_frame().unwrap().as_deref(), Some(&b"hello"[..]));
+        assert_eq!(r.read_frame(), Err(FrameError::Corrupt));
+        assert_eq!(r.read_frame().unwrap().as_deref(), Some(&b"world"[..]));
+    }
+
+    #[cfg(feature = "xxhash")]
+    #[test]
+    fn xxhash() {
+        use super::Checksum;
+
+        assert_eq!(
+            Checksum::XxHash32.compute(b"", b""),
+            0x02cc5d05u32.to_le_bytes()
+        );
+        let mut p = FramePublisher::with_checksum(16, Checksum::XxHash32);
+        let mut r = FrameReader::with_checksum(16, Checksum::XxHash32);
+        p.subscribe(&mut r);
+        p.publish(b"hello").unwrap();
+        assert_eq!(r.read_frame().unwrap().as_deref(), Some(&b"hello"[..]));
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn compression() {
+        use super::Compression;
+
+        let mut p = FramePublisher::new(256);
+        let mut r = FrameReader::new(256);
+        p.subscribe(&mut r);
+        p.set_compression(Compression::Lz4);
+        let text = [b'a'; 200];
+        p.publish(&text).unwrap();
+        p.publish(b"xyz").unwrap();
+        let stats = p.stats();
+        assert_eq!((stats.frames, stats.payload), (2, 203));
+        assert!(stats.stored < 50, "{stats:?}");
+        assert!(stats.ratio() < 0.25);
+        assert_eq!(r.read_frame().unwrap().as_deref(), Some(&text[..]));
+        assert_eq!(r.read_frame().unwrap().as_deref(), Some(&b"xyz"[..]));
+
+        let mut small = FrameReader::new(100);
+        p.subscribe(&mut small);
+        p.publish(&text).unwrap();
+        assert_eq!(small.read_frame(), Err(FrameError::TooLarge(200)));
+        p.publish(b"xyz").unwrap();
+        assert_eq!(small.read_frame().unwrap().as_deref(), Some(&b"xyz"[..]));
+    }
+
+    #[test]
+    fn resync_on_reset() {
+        let mut p = FramePublisher::new(16);
+        let mut r = FrameReader::new(4);
+        p.subscribe(&mut r);
+        p.publish(b"too long").unwrap();
+        assert_eq!(r.read_frame(), Err(FrameError::TooLarge(8)));
+        p.publish(b"ok").unwrap();
+        assert_eq!(r.read_frame(), Err(FrameError::Desynced));
+        p.clear();
+        p.publish(b"ok").unwrap();
+        assert_eq!(r.read_frame().unwrap().as_deref(), Some(&b"ok"[..]));
+    }
+}
@@ -0,0 +1,367 @@
+//! Broadcast to other processes through a shared memory mapping
+//!
+//! A [`SharedPublisher`] creates a file, typically below `/dev/shm`, and
+//! maps it. Each message is written into a ring of bytes in the mapping,
+//! behind a prefix with its length and sequence number. Any number of
+//! [`SharedReader`]s in other processes map the same file and copy the
+//! messages out, nothing passes through a pipe or the kernel.
+//!
+//! Readers don't hold the publisher back. It overwrites the oldest messages
+//! when the ring is full, a reader which fell behind notices that its next
+//! message was overwritten and reports [`ReadError::Lagged`]. Readers copy
+//! a message first and check for an overwrite afterwards, so they never
+//! return torn data.
+//!
+//! The mapping starts with a header carrying a magic number, the layout
+//! version and the ring size, which readers check before trusting the rest.
+//! A publisher which crashed can't mark the stream closed, its readers
+//! then simply see no further messages.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io,
+    mem::size_of,
+    path::Path,
+    ptr,
+    sync::atomic::{AtomicU32, AtomicU64, Ordering, fence},
+};
+
+use memmap2::{Mmap, MmapMut};
+
+use crate::{ReadError, framing::FrameError};
+
+/// Identifies a mapping written by this module
+const MAGIC: u64 = u64::from_le_bytes(*b"MCONSIPC");
+/// Counted up on every change of the layout below
+pub const LAYOUT_VERSION: u32 = 2;
+/// Length and sequence number in front of every message
+const PREFIX: u64 = 8;
+
+/// Start of the mapping, followed by the ring of bytes
+#[repr(C, align(64))]
+struct Header {
+    magic: AtomicU64,
+    version: u32,
+    /// odd while the publisher moves `first` and `first_seq`, counted up
+    /// by each move so readers notice they read a mix of both
+    first_lock: AtomicU32,
+    /// bytes in the ring
+    capacity: u64,
+    /// position of the oldest message which is still intact
+    first: AtomicU64,
+    /// position after the newest complete message
+    end: AtomicU64,
+    /// sequence number of the message at `first`
+    first_seq: AtomicU32,
+    closed: AtomicU32,
+}
+
+const DATA: usize = size_of::<Header>();
+
+/// Producer end, creates the mapping
+pub struct SharedPublisher {
+    map: MmapMut,
+    capacity: u64,
+    /// sequence number of the next message
+    seq: u32,
+}
+
+impl SharedPublisher {
+    /// Create the file at `path` with a ring of `capacity` bytes and map it
+    ///
+    /// An existing file is replaced rather than reused, readers still
+    /// mapping it keep seeing the old stream.
+    pub fn create(path: impl AsRef<Path>, capacity: usize) -> io::Result<Self> {
+        let path = path.as_ref();
+        if capacity <= PREFIX as usize {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        match fs::remove_file(path) {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+            _ => {}
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(path)?;
+        file.set_len((DATA + capacity) as u64)?;
+        // the file was just created by us, nothing else truncates it
+        let map = unsafe { MmapMut::map_mut(&file)? };
+        let publisher = Self {
+            map,
+            capacity: capacity as u64,
+            seq: 0,
+        };
+        let header = publisher.header_ptr();
+        unsafe {
+            ptr::addr_of_mut!((*header).version).write(LAYOUT_VERSION);
+            ptr::addr_of_mut!((*header).capacity).write(capacity as u64);
+            // readers only look at the rest once the magic is in place
+            (*header).magic.store(MAGIC, Ordering::Release);
+        }
+        Ok(publisher)
+    }
+    fn header_ptr(&self) -> *mut Header {
+        self.map.as_ptr().cast_mut().cast()
+    }
+    fn header(&self) -> &Header {
+        unsafe { &*self.header_ptr() }
+    }
+    /// Copy `bytes` into the ring at `pos`, wrapping at its end
+    fn write_at(&mut self, pos: u64, bytes: &[u8]) {
+        let offset = (pos % self.capacity) as usize;
+        let split = bytes.len().min(self.capacity as usize - offset);
+        let data = &mut self.map[DATA..];
+        data[offset..offset + split].copy_from_slice(&bytes[..split]);
+        data[..bytes.len() - split].copy_from_slice(&bytes[split..]);
+    }
+    /// Publish a copy of `message`, returns its sequence number
+    ///
+    /// Overwrites as many of the oldest messages as needed to make room.
+    pub fn publish(&mut self, message: &[u8]) -> Result<u32, FrameError> {
+        let len = PREFIX + message.len() as u64;
+        if len > self.capacity || message.len() > u32::MAX as usize {
+            return Err(FrameError::TooLarge(message.len()));
+        }
+        let header = self.header();
+        let end = header.end.load(Ordering::Relaxed);
+        let mut first = header.first.load(Ordering::Relaxed);
+        let mut first_seq = header.first_seq.load(Ordering::Relaxed);
+        while end + len - first > self.capacity {
+            let mut prefix = [0; PREFIX as usize];
+            read_at(&self.map[DATA..], first, &mut prefix);
+            first += PREFIX
+                + u64::from(u32::from_le_bytes([
+                    prefix[0], prefix[1], prefix[2], prefix[3],
+                ]));
+            first_seq = first_seq.wrapping_add(1);
+        }
+        // readers check `first` after copying, so it moves before the data
+        let lock = header.first_lock.load(Ordering::Relaxed);
+        header
+            .first_lock
+            .store(lock.wrapping_add(1), Ordering::Relaxed);
+        fence(Ordering::Release);
+        header.first_seq.store(first_seq, Ordering::Relaxed);
+        header.first.store(first, Ordering::Relaxed);
+        header
+            .first_lock
+            .store(lock.wrapping_add(2), Ordering::Release);
+        fence(Ordering::Release);
+        let seq = self.seq;
+        let mut prefix = [0; PREFIX as usize];
+        prefix[..4].copy_from_slice(&(message.len() as u32).to_le_bytes());
+        prefix[4..].copy_from_slice(&seq.to_le_bytes());
+        self.write_at(end, &prefix);
+        self.write_at(end + PREFIX, message);
+        self.header().end.store(end + len, Ordering::Release);
+        self.seq = seq.wrapping_add(1);
+        Ok(seq)
+    }
+    /// Signal readers that no further messages will be published
+    pub fn close(&mut self) {
+        self.header().closed.store(1, Ordering::Release);
+    }
+    pub fn capacity(&self) -> usize {
+        self.capacity as usize
+    }
+}
+
+impl Drop for SharedPublisher {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// Copy bytes out of the ring at `pos`, wrapping at its end
+fn read_at(data: &[u8], pos: u64, out: &mut [u8]) {
+    let capacity = data.len();
+    let offset = (pos % capacity as u64) as usize;
+    let split = out.len().min(capacity - offset);
+    let (head, tail) = out.split_at_mut(split);
+    // the publisher may overwrite the bytes meanwhile, which the caller
+    // detects afterwards, volatile keeps the compiler from assuming otherwise
+    for (n, byte) in head.iter_mut().enumerate() {
+        *byte = unsafe { ptr::read_volatile(&data[offset + n]) };
+    }
+    for (n, byte) in tail.iter_mut().enumerate() {
+        *byte = unsafe { ptr::read_volatile(&data[n]) };
+    }
+}
+
+/// Consumer end, maps a file created by a [`SharedPublisher`]
+pub struct SharedReader {
+    map: Mmap,
+    capacity: u64,
+    /// position of the next message
+    cursor: u64,
+    /// sequence number of the next message
+    seq: u32,
+}
+
+impl SharedReader {
+    /// Map the stream at `path`, reading starts with the oldest message
+    ///
+    /// Fails with [`InvalidData`](io::ErrorKind::InvalidData) if the file
+    /// is not a stream of this layout version.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let len = file.metadata()?.len();
+        if len < DATA as u64 {
+            return Err(io::ErrorKind::InvalidData.into());
+        }
+        // only the publisher writes, and it never shrinks the file
+        let map = unsafe { Mmap::map(&file)? };
+        let header = unsafe { &*map.as_ptr().cast::<Header>() };
+        if header.magic.load(Ordering::Acquire) != MAGIC
+            || header.version != LAYOUT_VERSION
+            || header.capacity != len - DATA as u64
+        {
+            return Err(io::ErrorKind::InvalidData.into());
+        }
+        let mut reader = Self {
+            capacity: header.capacity,
+            map,
+            cursor: 0,
+            seq: 0,
+        };
+        reader.catch_up();
+        Ok(reader)
+    }
+    fn header(&self) -> &Header {
+        unsafe { &*self.map.as_ptr().cast::<Header>() }
+    }
+    /// Continue with the oldest intact message, returns how many were lost
+    fn catch_up(&mut self) -> u32 {
+        loop {
+            let header = self.header();
+            let lock = header.first_lock.load(Ordering::Acquire);
+            if lock % 2 == 1 {
+                std::hint::spin_loop();
+                continue;
+            }
+            let first_seq = header.first_seq.load(Ordering::Relaxed);
+            let first = header.first.load(Ordering::Relaxed);
+            fence(Ordering::Acquire);
+            // both belong together unless the publisher moved them meanwhile
+            if header.first_lock.load(Ordering::Relaxed) == lock {
+                let lost = first_seq.wrapping_sub(self.seq);
+                self.cursor = first;
+                self.seq = first_seq;
+                return lost;
+            }
+        }
+    }
+    /// Copy the next message into `buf`, replacing its contents
+    ///
+    /// Returns `Ok(false)` while no message is queued.
+    pub fn read(&mut self, buf: &mut Vec<u8>) -> Result<bool, ReadError> {
+        let end = self.header().end.load(Ordering::Acquire);
+        if self.cursor == end {
+            if self.header().closed.load(Ordering::Acquire) != 0 {
+                return Err(ReadError::Closed);
+            }
+            return Ok(false);
+        }
+        let data = &self.map[DATA..];
+        let mut prefix = [0; PREFIX as usize];
+        read_at(data, self.cursor, &mut prefix);
+        let len = u32::from_le_bytes([prefix[0], prefix[1], prefix[2], prefix[3]]) as u64;
+        let intact = len + PREFIX <= self.capacity;
+        if intact {
+            buf.resize(len as usize, 0);
+            read_at(data, self.cursor + PREFIX, buf);
+        }
+        fence(Ordering::Acquire);
+        if !intact || self.header().first.load(Ordering::Relaxed) > self.cursor {
+            buf.clear();
+            let lost = self.catch_up();
+            return Err(ReadError::Lagged(lost.into()));
+        }
+        self.cursor += PREFIX + len;
+        self.seq = self.seq.wrapping_add(1);
+        Ok(true)
+    }
+    /// Sequence number of the next message
+    pub fn sequence(&self) -> u32 {
+        self.seq
+    }
+    pub fn is_closed(&self) -> bool {
+        self.header().closed.load(Ordering::Acquire) != 0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{SharedPublisher, SharedReader};
+    use crate::{ReadError, framing::FrameError};
+    use std::{fs, io, path::PathBuf};
+
+    fn path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("mc-ipc-{}-{name}", std::process::id()))
+    }
+
+    #[test]
+    fn broadcast() {
+        let path = path("broadcast");
+        let mut p = SharedPublisher::create(&path, 40).unwrap();
+        p.publish(b"early").unwrap();
+        let mut a = SharedReader::open(&path).unwrap();
+        let mut b = SharedReader::open(&path).unwrap();
+        let mut buf = Vec::new();
+        assert_eq!(a.read(&mut buf), Ok(true));
+        assert_eq!(buf, b"early");
+        assert_eq!(a.read(&mut buf), Ok(false));
+        // wraps around the end of the ring and overwrites "early"
+        assert_eq!(p.publish(b"0123456789"), Ok(1));
+        assert_eq!(p.publish(b"wrapped"), Ok(2));
+        assert_eq!(p.publish(&[0; 33]), Err(FrameError::TooLarge(33)));
+        assert_eq!(a.read(&mut buf), Ok(true));
+        assert_eq!(buf, b"0123456789");
+        assert_eq!(a.read(&mut buf), Ok(true));
+        assert_eq!(buf, b"wrapped");
+        assert_eq!(b.read(&mut buf), Err(ReadError::Lagged(1)));
+        assert_eq!(b.sequence(), 1);
+        assert_eq!(b.read(&mut buf), Ok(true));
+        drop(p);
+        assert_eq!(b.read(&mut buf), Ok(true));
+        assert_eq!(b.read(&mut buf), Err(ReadError::Closed));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn resumes_in_sequence() {
+        let path = path("resume");
+        let mut p = SharedPublisher::create(&path, 64).unwrap();
+        let mut r = SharedReader::open(&path).unwrap();
+        let publisher = std::thread::spawn(move || {
+            for seq in 0..100_000u32 {
+                p.publish(&seq.to_le_bytes()).unwrap();
+            }
+        });
+        let mut buf = Vec::new();
+        loop {
+            let expected = r.sequence();
+            match r.read(&mut buf) {
+                Ok(true) => assert_eq!(buf, expected.to_le_bytes()),
+                Ok(false) | Err(ReadError::Lagged(_)) => {}
+                Err(error) => {
+                    assert_eq!(error, ReadError::Closed);
+                    break;
+                }
+            }
+        }
+        publisher.join().unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_foreign_files() {
+        let path = path("foreign");
+        fs::write(&path, [0; 256]).unwrap();
+        let error = SharedReader::open(&path).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        fs::remove_file(&path).unwrap();
+    }
+}
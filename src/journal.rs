@@ -0,0 +1,324 @@
+//! Published items persisted in an append-only log file
+//!
+//! A [`Journal`] encodes every item it publishes with postcard and appends
+//! it to a file before handing it to the publisher. On startup
+//! [`Journal::open`] replays the file into a fresh publisher, so readers
+//! subscribing then start with every item ever journaled under the same
+//! sequence numbers, no matter how often the process restarted. Cursors
+//! saved by readers stay valid for [`Publisher::subscribe_from`].
+//!
+//! The file starts with a magic number and a format version. Each record
+//! is the little endian `u32` length of the payload, the `u64` sequence
+//! number, the payload and a CRC-32 of all of them. A record cut short by a
+//! crash, or failing its checksum, ends the replay: it and everything after
+//! it is truncated, see [`Journal::truncated`].
+//!
+//! Records are buffered, call [`sync`](Journal::sync) to make them durable.
+//! After a failed write the file may end in a partial record, the journal
+//! then refuses to publish until it is opened again.
+//! Publish only through the journal, items published to the publisher
+//! directly are not persisted and break the sequence of the file.
+
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    marker::PhantomData,
+    path::Path,
+};
+
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::{Counter, Publisher};
+
+/// First bytes of a journal file
+const MAGIC: [u8; 4] = *b"MCJL";
+
+/// Version of the record layout, files of other versions are refused
+pub const VERSION: u32 = 1;
+
+/// Magic number and version
+const FILE_HEADER: usize = 8;
+
+/// Length and sequence number in front of the payload
+const RECORD_HEADER: usize = 12;
+
+/// Reasons why the journal failed
+#[derive(Debug)]
+pub enum JournalError {
+    /// The file couldn't be read or written, or isn't a journal
+    Io(io::Error),
+    /// An item failed to encode, or a checksummed record to decode
+    Postcard(postcard::Error),
+    /// An earlier write failed, reopen the journal to continue
+    Poisoned,
+}
+
+impl fmt::Display for JournalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JournalError::Io(error) => error.fmt(f),
+            JournalError::Postcard(error) => error.fmt(f),
+            JournalError::Poisoned => f.write_str("journal poisoned by a failed write"),
+        }
+    }
+}
+
+impl std::error::Error for JournalError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            JournalError::Io(error) => Some(error),
+            JournalError::Postcard(error) => Some(error),
+            JournalError::Poisoned => None,
+        }
+    }
+}
+
+impl From<io::Error> for JournalError {
+    fn from(error: io::Error) -> Self {
+        JournalError::Io(error)
+    }
+}
+
+/// Checksum of a record header and its payload
+fn checksum(header: &[u8], payload: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(header);
+    hasher.update(payload);
+    hasher.finalize()
+}
+
+/// Writer of the log file of one publisher
+pub struct Journal<T> {
+    file: BufWriter<File>,
+    /// record bytes dropped by the last open
+    truncated: u64,
+    /// a write failed, the file may end in a partial record
+    poisoned: bool,
+    _items: PhantomData<fn(T)>,
+}
+
+impl<T: Serialize + DeserializeOwned> Journal<T> {
+    /// Open the journal at `path`, creating it if missing, and replay it
+    ///
+    /// The returned publisher retains every item of the file. Fails with
+    /// [`InvalidData`](io::ErrorKind::InvalidData) if the file is not a
+    /// journal of this version.
+    pub fn open(path: impl AsRef<Path>) -> Result<(Self, Publisher<T>), JournalError> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+        let mut publisher = Publisher::new();
+        let len = file.metadata()?.len();
+        // a crash while creating the file can leave part of its header
+        let valid = if len < FILE_HEADER as u64 {
+            file.set_len(0)?;
+            let mut header = [0; FILE_HEADER];
+            header[..4].copy_from_slice(&MAGIC);
+            header[4..].copy_from_slice(&VERSION.to_le_bytes());
+            file.write_all(&header)?;
+            FILE_HEADER as u64
+        } else {
+            replay(&mut file, &mut publisher)?
+        };
+        let truncated = len.saturating_sub(valid);
+        if truncated > 0 {
+            file.set_len(valid)?;
+        }
+        file.seek(SeekFrom::End(0))?;
+        Ok((
+            Self {
+                file: BufWriter::new(file),
+                truncated,
+                poisoned: false,
+                _items: PhantomData,
+            },
+            publisher,
+        ))
+    }
+}
+
+impl<T: Serialize> Journal<T> {
+    /// Append `item` to the file and publish it, returns its sequence number
+    ///
+    /// Fails with [`JournalError::Poisoned`] once a write failed.
+    pub fn publish(
+        &mut self,
+        publisher: &mut Publisher<T>,
+        item: T,
+    ) -> Result<Counter, JournalError> {
+        if self.poisoned {
+            return Err(JournalError::Poisoned);
+        }
+        let payload = postcard::to_allocvec(&item).map_err(JournalError::Postcard)?;
+        let len = u32::try_from(payload.len())
+            .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+        let seq = publisher.retained().end;
+        let mut record = Vec::with_capacity(RECORD_HEADER + payload.len() + 4);
+        record.extend_from_slice(&len.to_le_bytes());
+        record.extend_from_slice(&(seq as u64).to_le_bytes());
+        record.extend_from_slice(&payload);
+        let sum = checksum(&record[..RECORD_HEADER], &payload);
+        record.extend_from_slice(&sum.to_le_bytes());
+        if let Err(error) = self.file.write_all(&record) {
+            self.poisoned = true;
+            return Err(error.into());
+        }
+        Ok(publisher.publish(item))
+    }
+}
+
+impl<T> Journal<T> {
+    /// Write the buffered records and wait until the file reached the disk
+    ///
+    /// A failure poisons the journal like a failed publish.
+    pub fn sync(&mut self) -> io::Result<()> {
+        let result = self
+            .file
+            .flush()
+            .and_then(|()| self.file.get_ref().sync_data());
+        self.poisoned |= result.is_err();
+        result
+    }
+    /// Bytes of damaged or incomplete records dropped when opening the file
+    pub fn truncated(&self) -> u64 {
+        self.truncated
+    }
+}
+
+/// Publish the records of `file`, returns the length of its valid part
+fn replay<T: DeserializeOwned>(
+    file: &mut File,
+    publisher: &mut Publisher<T>,
+) -> Result<u64, JournalError> {
+    let mut reader = BufReader::new(&mut *file);
+    let mut header = [0; FILE_HEADER];
+    reader.read_exact(&mut header)?;
+    if header[..4] != MAGIC || header[4..] != VERSION.to_le_bytes() {
+        return Err(io::Error::from(io::ErrorKind::InvalidData).into());
+    }
+    let mut valid = FILE_HEADER as u64;
+    let mut payload = Vec::new();
+    loop {
+        let mut header = [0; RECORD_HEADER];
+        let mut sum = [0; 4];
+        let len = match reader.read_exact(&mut header) {
+            Ok(()) => u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize,
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(error) => return Err(error.into()),
+        };
+        let seq = u64::from_le_bytes(header[4..].try_into().unwrap()) as Counter;
+        payload.clear();
+        let read = (&mut reader).take(len as u64).read_to_end(&mut payload)?;
+        match reader.read_exact(&mut sum) {
+            Ok(()) if read == len => {}
+            Err(error) if error.kind() != io::ErrorKind::UnexpectedEof => return Err(error.into()),
+            _ => break,
+        }
+        if u32::from_le_bytes(sum) != checksum(&header, &payload) {
+            break;
+        }
+        if valid == FILE_HEADER as u64 {
+            publisher.start_at(seq);
+        } else if seq != publisher.retained().end {
+            break;
+        }
+        let item = postcard::from_bytes(&payload).map_err(JournalError::Postcard)?;
+        publisher.publish(item);
+        valid += (RECORD_HEADER + len + 4) as u64;
+    }
+    Ok(valid)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Journal, JournalError};
+    use crate::{Publisher, StreamReader};
+    use std::{
+        fs::{File, OpenOptions},
+        io::{BufWriter, Write},
+        marker::PhantomData,
+        path::PathBuf,
+    };
+
+    fn path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("mc-journal-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn survives_restarts() {
+        let path = path("restart");
+        {
+            let (mut journal, mut p) = Journal::<String>::open(&path).unwrap();
+            for word in ["one", "two"] {
+                journal.publish(&mut p, word.into()).unwrap();
+            }
+        }
+        let (mut journal, mut p) = Journal::<String>::open(&path).unwrap();
+        assert_eq!(journal.truncated(), 0);
+        assert_eq!(p.retained(), 0..2);
+        assert_eq!(journal.publish(&mut p, "three".into()).unwrap(), 2);
+        journal.sync().unwrap();
+        drop((journal, p));
+
+        // a record cut short by a crash
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(&[5, 0, 0, 0, 3])
+            .unwrap();
+        let (journal, mut p) = Journal::<String>::open(&path).unwrap();
+        assert_eq!(journal.truncated(), 5);
+        let mut r = StreamReader::new();
+        p.add_stream_reader(&mut r);
+        assert_eq!(r.snapshot(), ["one", "two", "three"]);
+        drop(r);
+        drop((journal, p));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn refuses_other_files() {
+        let path = path("other");
+        std::fs::write(&path, b"not a journal").unwrap();
+        assert!(Journal::<u32>::open(&path).is_err());
+
+        // a header cut short is started over
+        std::fs::write(&path, b"MCJ").unwrap();
+        let (mut journal, mut p) = Journal::<u32>::open(&path).unwrap();
+        assert_eq!(journal.publish(&mut p, 1).unwrap(), 0);
+        drop((journal, p));
+        assert_eq!(Journal::<u32>::open(&path).unwrap().1.retained(), 0..1);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn poisoned_by_failed_write() {
+        let path = path("poisoned");
+        drop(Journal::<String>::open(&path).unwrap());
+        let mut journal = Journal::<String> {
+            file: BufWriter::new(File::open(&path).unwrap()),
+            truncated: 0,
+            poisoned: false,
+            _items: PhantomData,
+        };
+        let mut p = Publisher::new();
+        // larger than the buffer, so the write reaches the read-only file
+        let large = "x".repeat(10_000);
+        assert!(matches!(
+            journal.publish(&mut p, large),
+            Err(JournalError::Io(_))
+        ));
+        assert!(matches!(
+            journal.publish(&mut p, "small".into()),
+            Err(JournalError::Poisoned)
+        ));
+        assert_eq!(p.retained(), 0..0);
+        let _ = std::fs::remove_file(&path);
+    }
+}
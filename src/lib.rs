@@ -1,232 +1,217 @@
-use std::{
-    collections::VecDeque,
-    marker::PhantomData,
-    mem::MaybeUninit,
-    ops::{Deref, DerefMut},
-};
-
-type Counter = usize;
-
-/// Subscriber information
-struct ConsumerInfo<T> {
-    unread: Counter,
-    reader: *mut StreamReader<T>,
-}
-
-impl<T> ConsumerInfo<T> {
-    fn from_reader(r: &mut StreamReader<T>) -> Self {
-        Self {
-            unread: Default::default(),
-            reader: r as *mut StreamReader<T>,
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
+use core::fmt;
+
+#[cfg(feature = "arrow")]
+pub mod arrow;
+#[cfg(feature = "alloc")]
+mod backlog;
+#[cfg(any(all(feature = "uds", unix), feature = "tcp"))]
+mod bridge;
+#[cfg(feature = "std")]
+pub mod broadcast;
+#[cfg(feature = "broker")]
+pub mod broker;
+pub mod bytes;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod fixed;
+#[cfg(feature = "alloc")]
+pub mod framing;
+#[cfg(feature = "ipc")]
+pub mod ipc;
+#[cfg(feature = "journal")]
+pub mod journal;
+#[cfg(feature = "std")]
+pub mod lockfree;
+#[cfg(feature = "mmap")]
+pub mod mapped;
+#[cfg(feature = "futures-core")]
+pub mod merge;
+#[cfg(feature = "std")]
+pub mod mpsc;
+#[cfg(feature = "alloc")]
+pub mod protocol;
+#[cfg(feature = "python")]
+mod python;
+#[cfg(feature = "alloc")]
+mod registry;
+pub mod seq;
+#[cfg(feature = "std")]
+pub mod sharded;
+#[cfg(feature = "alloc")]
+mod slots;
+#[cfg(feature = "snapshot")]
+pub mod snapshot;
+#[cfg(feature = "alloc")]
+mod stream;
+#[cfg(any(feature = "std", all(feature = "alloc", feature = "critical-section")))]
+pub mod sync;
+#[cfg(feature = "tcp")]
+pub mod tcp;
+#[cfg(not(feature = "std"))]
+mod time;
+#[cfg(all(feature = "uds", unix))]
+pub mod uds;
+#[cfg(feature = "wasm-bindgen")]
+pub mod wasm;
+#[cfg(feature = "websocket")]
+pub mod websocket;
+
+/// Shared ownership of items, e.g. as returned by `recv_arc`
+///
+/// With the `portable-atomic` feature this is the `Arc` of
+/// `portable-atomic-util`, which also works on targets without atomic
+/// compare-and-swap.
+#[cfg(all(feature = "alloc", not(feature = "portable-atomic")))]
+pub use alloc::sync::Arc;
+#[cfg(all(feature = "alloc", feature = "portable-atomic"))]
+pub use portable_atomic_util::Arc;
+#[cfg(feature = "std")]
+pub use std::time::Instant;
+#[cfg(feature = "alloc")]
+pub use stream::*;
+#[cfg(not(feature = "std"))]
+pub use time::Instant;
+
+/// Sequence number of a published item, wraps around on overflow
+///
+/// The `counter-u32` feature makes it 32 bits wide on every target, which
+/// halves the metadata kept per item and reader. Use the helpers in [`seq`]
+/// to compare sequence numbers, they stay correct across the wrap.
+#[cfg(not(feature = "counter-u32"))]
+pub type Counter = usize;
+#[cfg(feature = "counter-u32")]
+pub type Counter = u32;
+
+/// Reasons why a reader can no longer receive items
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ReadError {
+    /// The reader left its backlog unread for longer than its idle timeout
+    TimedOut,
+    /// The reader fell behind, this many items were released before it read them
+    Lagged(u64),
+    /// The publisher discarded its retained items, reading continues with
+    /// the next published one
+    Reset,
+    /// The publisher was closed or dropped and every remaining item was read
+    Closed,
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReadError::TimedOut => f.write_str("reader was idle for too long"),
+            ReadError::Lagged(n) => write!(f, "reader missed {n} items"),
+            ReadError::Reset => f.write_str("stream was reset"),
+            ReadError::Closed => f.write_str("stream is closed"),
         }
     }
 }
 
-/// Reader-side lock into data
-pub struct BorrowRead<'a, T> {
-    obj: &'a T,
-    reader: &'a mut StreamReader<T>,
-    source: *mut Publisher<T>,
-    counter: Counter,
-}
-
-impl<'a, T> Deref for BorrowRead<'a, T> {
-    type Target = T;
-
-    fn deref(&'_ self) -> &'_ Self::Target {
-        self.obj
-    }
-}
-
-impl<'a, T> Drop for BorrowRead<'a, T> {
-    fn drop(&mut self) {
-        unsafe { &mut *self.reader.source }.reader_done(ConsumerInfo {
-            unread: self.counter,
-            reader: self.reader,
-        });
+impl core::error::Error for ReadError {}
+
+/// Reasons why a fallible publisher operation refused to run
+///
+/// The `try_` methods return these where their counterparts would panic or
+/// abort.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PublishError {
+    /// Claimed slots are still outstanding
+    Claimed,
+    /// The ticket was handed out by another publisher
+    ForeignTicket,
+    /// The ticket was claimed before the publisher was cleared
+    StaleTicket,
+    /// The memory for the requested slots could not be allocated
+    OutOfMemory,
+    /// The publisher is in use further up the call stack
+    Busy,
+}
+
+impl fmt::Display for PublishError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PublishError::Claimed => f.write_str("claimed slots are outstanding"),
+            PublishError::ForeignTicket => f.write_str("ticket of another publisher"),
+            PublishError::StaleTicket => f.write_str("ticket claimed before a clear"),
+            PublishError::OutOfMemory => f.write_str("out of memory"),
+            PublishError::Busy => f.write_str("publisher is in use"),
+        }
     }
 }
 
-/// Write lock into data
-pub struct BorrowWrite<'a, T> {
-    obj: &'a mut MaybeUninit<T>,
-    writer: &'a mut Publisher<T>,
-    newcount: Counter,
-    written: bool,
-}
+impl core::error::Error for PublishError {}
 
-impl<'a, T> Deref for BorrowWrite<'a, T> {
-    type Target = MaybeUninit<T>;
+/// Publishing failed because every slot is taken, gives the item back
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Full<T>(pub T);
 
-    fn deref(&'_ self) -> &'_ Self::Target {
-        self.obj
+impl<T> fmt::Debug for Full<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Full(..)")
     }
 }
 
-impl<'a, T> DerefMut for BorrowWrite<'a, T> {
-    fn deref_mut(&'_ mut self) -> &'_ mut Self::Target {
-        self.obj
+impl<T> fmt::Display for Full<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("no free slot to publish into")
     }
 }
 
-impl<'a, T> Drop for BorrowWrite<'a, T> {
-    fn drop(&mut self) {
-        if !self.written {
-            todo!("cleanup");
-        }
-    }
-}
+impl<T> core::error::Error for Full<T> {}
 
-impl<'a, T> BorrowWrite<'a, T> {
-    pub fn finish(mut self) {
-        for i in self.writer.readers.iter_mut() {
-            unsafe { &mut *i.reader }.new_data(self.obj, self.newcount);
-        }
-        self.written = true;
+#[cfg(feature = "defmt")]
+impl<T> defmt::Format for Full<T> {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        defmt::write!(f, "Full(..)")
     }
 }
 
-/// Consumer object
-pub struct StreamReader<T> {
-    phantom: PhantomData<T>,
-    source: *mut Publisher<T>,
-    unread_data: VecDeque<(*const T, Counter)>,
-    notifier: Option<Box<dyn Fn()>>,
+/// Instrumentation of the tasks awaiting a reader
+///
+/// Implemented to observe the async readers of
+/// `fixed::StaticReader::ready`, `sync::StreamReader::poll_read`, which
+/// also drives the `broadcast` receivers, and `merge::Merge`. Every call
+/// names the reader as documented there. The crate has no clock without
+/// `std`, so timestamps, e.g. to forward the events as tokio-console style
+/// task spans, are up to the implementation. The time from
+/// [`woken`](Self::woken) to the next [`polled`](Self::polled) is the
+/// reader's time-to-first-poll, long ones mean the consumer is bound by
+/// scheduling, while long waits from [`pending`](Self::pending) to
+/// `woken` mean it is bound by the data.
+#[cfg(any(
+    feature = "embassy",
+    feature = "futures-core",
+    feature = "std",
+    all(feature = "alloc", feature = "critical-section")
+))]
+pub trait ReaderHooks: Sync {
+    /// The reader's task polled it
+    fn polled(&self, _reader: usize) {}
+    /// Nothing was there to read, the task waits
+    fn pending(&self, _reader: usize) {}
+    /// Publishing woke the waiting task
+    fn woken(&self, _reader: usize) {}
 }
 
-impl<T> StreamReader<T> {
-    fn new_data(&mut self, data: &MaybeUninit<T>, count: Counter) {
-        self.unread_data.push_back((data.as_ptr(), count));
-        if let Some(notifier) = &self.notifier {
-            notifier();
-        }
-    }
-    pub fn read(&mut self) -> Option<BorrowRead<'_, T>> {
-        let data = self.unread_data.pop_front();
-        data.map(|(ptr, counter)| BorrowRead {
-            source: self.source,
-            obj: unsafe { &*ptr },
-            reader: self,
-            counter,
-        })
-    }
-    pub fn set_notification(&mut self, n: Box<dyn Fn()>) {
-        self.notifier.replace(n);
-    }
-    pub fn new() -> Self {
-        Self {
-            phantom: PhantomData,
-            source: core::ptr::null_mut(),
-            unread_data: vec![].into(),
-            notifier: None,
-        }
-    }
-}
+/// Keeps a value on cache lines of its own
+///
+/// 128 bytes covers the adjacent line prefetcher pairs of common CPUs.
+#[cfg(feature = "std")]
+#[repr(align(128))]
+struct CachePadded<T>(T);
 
-impl<T> Drop for StreamReader<T> {
-    fn drop(&mut self) {
-        if !self.source.is_null() {
-            unsafe { &mut *self.source }.remove_reader(self);
-        }
-    }
-}
-
-/// Publisher object
-// aka StreamWriter
-pub struct Publisher<T> {
-    data: VecDeque<MaybeUninit<T>>,
-    first_count: Counter,
-    readers: Vec<ConsumerInfo<T>>,
-}
-
-impl<T> Publisher<T> {
-    pub fn publish(&mut self, obj: T) {
-        let newcount = self.first_count.wrapping_add(self.data.len());
-        self.data.push_back(MaybeUninit::new(obj));
-        if let Some(data) = self.data.back_mut() {
-            for i in self.readers.iter_mut() {
-                unsafe { &mut *i.reader }.new_data(data, newcount);
-            }
-        }
-    }
-    /// Add a new reader to the system
-    pub fn add_stream_reader(&mut self, reader: &mut StreamReader<T>) {
-        self.add_reader(ConsumerInfo::from_reader(reader))
-    }
-    /// Please prefer add_stream_reader because it is more simple
-    fn add_reader(&mut self, info: ConsumerInfo<T>) {
-        let reader = unsafe { &mut *info.reader };
-        reader.source = self as *mut _;
-        for (n, i) in self.data.iter().enumerate() {
-            reader.new_data(i, self.first_count.wrapping_add(n));
-        }
-        self.readers.push(info);
-    }
-    fn reader_done(&mut self, info: ConsumerInfo<T>) {
-        let mut min_used_minus_first = self.data.len();
-        for i in self.readers.iter_mut() {
-            if i.reader == info.reader {
-                i.unread = info.unread.wrapping_add(1);
-            }
-            if i.unread.wrapping_sub(self.first_count) < min_used_minus_first {
-                min_used_minus_first = i.unread.wrapping_sub(self.first_count);
-            }
-        }
-        if min_used_minus_first > 0 {
-            self.first_count += min_used_minus_first;
-            for _ in 0..min_used_minus_first {
-                self.data.pop_front();
-            }
-        }
-    }
-    fn remove_reader(&mut self, rd: &mut StreamReader<T>) {
-        let addr = rd as *mut _;
-        self.readers.retain(|e| e.reader != addr);
-    }
-
-    /// Allocate data for in-place writing
-    ///
-    /// Note: if you allocate multiple times, please finish in order
-    pub fn allocate(&mut self) -> BorrowWrite<T> {
-        let newcount = self.first_count.wrapping_add(self.data.len());
-        self.data.push_back(MaybeUninit::uninit());
-        let unbound_self_ref = unsafe { &mut *(self as *mut _) };
-        BorrowWrite {
-            obj: self.data.back_mut().unwrap(),
-            writer: unbound_self_ref,
-            newcount,
-            written: false,
-        }
-    }
-
-    pub fn new() -> Self {
-        Self {
-            data: VecDeque::new(),
-            first_count: Default::default(),
-            readers: vec![],
-        }
-    }
-}
+#[cfg(feature = "std")]
+impl<T> core::ops::Deref for CachePadded<T> {
+    type Target = T;
 
-#[cfg(test)]
-mod test {
-    use crate::{Publisher, StreamReader};
-    use std::ops::Deref;
-
-    #[test]
-    fn push() {
-        // this is not safe, it needs pinning
-        let mut p: Publisher<u32> = Publisher::new();
-        p.publish(1);
-        let mut r1 = StreamReader::new();
-        p.add_stream_reader(&mut r1);
-        assert!(r1.read().unwrap().deref() == &1);
-        let mut r2 = StreamReader::new();
-        p.add_stream_reader(&mut r2);
-        let mut w = p.allocate();
-        w.write(2);
-        w.finish();
-        assert!(r2.read().unwrap().deref() == &2);
-        assert!(r1.read().unwrap().deref() == &2);
+    fn deref(&self) -> &T {
+        &self.0
     }
 }
@@ -0,0 +1,494 @@
+//! Lock-free variant for a single producer thread and reader threads
+//!
+//! Items live in a power of two sized ring. Every reader announces the
+//! sequence number it is at, its epoch, in an atomic of its own. A slot
+//! becomes free for reuse once every announced epoch moved past it, so
+//! neither side ever takes a lock: the producer only looks at the epochs
+//! when the ring seems full and otherwise publishes with a single release
+//! store of its head counter.
+//!
+//! That store is what makes an item visible: the producer writes the slot
+//! first and then stores the new head with `Release`, a reader loads the
+//! head with `Acquire` before touching any slot below it, so it always sees
+//! the slot fully written. In the other direction a reader stores its epoch
+//! with `Release` once it is done with a slot, and the producer only writes
+//! a slot again after it loaded every epoch past it.
+//!
+//! Readers which would rather sleep than poll need a stream created with
+//! [`Publisher::with_wakeups`]. Publishing then adds a fence and a look at
+//! the number of sleeping readers, and only takes a lock when there is one.
+
+use std::{
+    cell::UnsafeCell,
+    mem::MaybeUninit,
+    ops::Deref,
+    sync::{
+        Arc, Condvar, Mutex, PoisonError,
+        atomic::{AtomicBool, AtomicUsize, Ordering, fence},
+    },
+};
+
+use crate::{CachePadded, Counter, seq};
+
+#[cfg(not(feature = "counter-u32"))]
+type AtomicCounter = AtomicUsize;
+#[cfg(feature = "counter-u32")]
+type AtomicCounter = std::sync::atomic::AtomicU32;
+
+/// Marks a reader slot which is not in use
+const FREE: Counter = Counter::MAX;
+
+/// The epoch a reader at `cursor` announces
+///
+/// A cursor which happens to equal [`FREE`] announces the item before it,
+/// which only keeps one more slot from being reused.
+fn epoch(cursor: Counter) -> Counter {
+    if cursor == FREE {
+        cursor.wrapping_sub(1)
+    } else {
+        cursor
+    }
+}
+
+struct Shared<T> {
+    slots: Box<[UnsafeCell<MaybeUninit<T>>]>,
+    mask: usize,
+    /// sequence number of the next item to publish
+    head: CachePadded<AtomicCounter>,
+    /// every slot before this sequence number may have been reused
+    tail: CachePadded<AtomicCounter>,
+    /// announced epoch per reader, the next item it will read
+    epochs: Box<[CachePadded<AtomicCounter>]>,
+    /// number of slots holding an item, stored when the producer drops
+    filled: AtomicUsize,
+    /// whether the producer is gone
+    closed: AtomicBool,
+    wake: Option<Wake>,
+}
+
+/// Lets readers sleep until the producer publishes
+struct Wake {
+    /// readers waiting or about to wait on `cond`
+    sleepers: CachePadded<AtomicUsize>,
+    lock: Mutex<()>,
+    cond: Condvar,
+}
+
+impl Wake {
+    /// Wake sleeping readers after the head counter was stored
+    fn notify(&self) {
+        // orders the head store before the look at the sleepers, pairs with
+        // the increment in `StreamReader::wait`
+        fence(Ordering::SeqCst);
+        if self.sleepers.load(Ordering::Relaxed) > 0 {
+            drop(self.lock.lock());
+            self.cond.notify_all();
+        }
+    }
+}
+
+// Readers on other threads share the items, the producer drops them
+unsafe impl<T: Send + Sync> Send for Shared<T> {}
+unsafe impl<T: Send + Sync> Sync for Shared<T> {}
+
+impl<T> Shared<T> {
+    fn slot(&self, seq: Counter) -> &UnsafeCell<MaybeUninit<T>> {
+        &self.slots[seq::masked(seq, self.mask)]
+    }
+    /// Oldest announced epoch, `head` if there are no readers
+    fn oldest_epoch(&self, head: Counter) -> Counter {
+        self.epochs
+            .iter()
+            .map(|e| e.load(Ordering::SeqCst))
+            .filter(|&e| e != FREE)
+            .max_by_key(|&e| seq::distance(e, head))
+            .unwrap_or(head)
+    }
+}
+
+impl<T> Drop for Shared<T> {
+    fn drop(&mut self) {
+        let end = seq::masked(*self.head.0.get_mut(), self.mask);
+        for n in 1..=*self.filled.get_mut() {
+            let slot = &mut self.slots[end.wrapping_sub(n) & self.mask];
+            unsafe { slot.get_mut().assume_init_drop() };
+        }
+    }
+}
+
+/// Producer side of the lock-free stream
+pub struct Publisher<T> {
+    shared: Arc<Shared<T>>,
+    /// producer's copy of the head counter
+    head: Counter,
+    /// every slot before this sequence number is free to reuse
+    reclaimed: Counter,
+    /// number of slots holding an item, only grows during the first lap
+    filled: usize,
+}
+
+impl<T: Send + Sync> Publisher<T> {
+    /// Create a stream for `capacity` items, rounded up to a power of two,
+    /// and up to `max_readers` readers at a time
+    pub fn new(capacity: usize, max_readers: usize) -> Self {
+        Self::build(capacity, max_readers, None)
+    }
+    /// Like [`new`](Self::new), but readers can block in
+    /// [`StreamReader::wait`] until an item arrives
+    pub fn with_wakeups(capacity: usize, max_readers: usize) -> Self {
+        let wake = Wake {
+            sleepers: CachePadded(AtomicUsize::new(0)),
+            lock: Mutex::new(()),
+            cond: Condvar::new(),
+        };
+        Self::build(capacity, max_readers, Some(wake))
+    }
+    fn build(capacity: usize, max_readers: usize, wake: Option<Wake>) -> Self {
+        let capacity = capacity.max(1).next_power_of_two();
+        let slots = (0..capacity)
+            .map(|_| UnsafeCell::new(MaybeUninit::uninit()))
+            .collect();
+        let epochs = (0..max_readers)
+            .map(|_| CachePadded(AtomicCounter::new(FREE)))
+            .collect();
+        Self {
+            shared: Arc::new(Shared {
+                slots,
+                mask: capacity - 1,
+                head: CachePadded(AtomicCounter::new(0)),
+                tail: CachePadded(AtomicCounter::new(0)),
+                epochs,
+                filled: AtomicUsize::new(0),
+                closed: AtomicBool::new(false),
+                wake,
+            }),
+            head: 0,
+            reclaimed: 0,
+            filled: 0,
+        }
+    }
+    pub fn capacity(&self) -> usize {
+        self.shared.slots.len()
+    }
+    /// Publish an item unless the slowest reader still needs its slot
+    ///
+    /// Gives the item back in that case, it never blocks.
+    pub fn try_publish(&mut self, obj: T) -> Result<Counter, T> {
+        let seq = self.head;
+        let capacity = self.capacity();
+        if seq::distance(self.reclaimed, seq) >= capacity && !self.reclaim(seq) {
+            return Err(obj);
+        }
+        let slot = unsafe { &mut *self.shared.slot(seq).get() };
+        if self.filled == capacity {
+            // the item published one lap ago
+            unsafe { slot.assume_init_drop() };
+        } else {
+            self.filled += 1;
+        }
+        slot.write(obj);
+        self.head = seq.wrapping_add(1);
+        self.shared.head.store(self.head, Ordering::Release);
+        if let Some(wake) = &self.shared.wake {
+            wake.notify();
+        }
+        Ok(seq)
+    }
+    /// Publish an item, waiting for the slowest reader if the ring is full
+    pub fn publish(&mut self, mut obj: T) -> Counter {
+        loop {
+            match self.try_publish(obj) {
+                Ok(seq) => return seq,
+                Err(back) => obj = back,
+            }
+            std::thread::yield_now();
+        }
+    }
+    /// Advance the reclaimed bound to the oldest announced epoch, returns
+    /// whether the slot for `seq` is free now
+    fn reclaim(&mut self, seq: Counter) -> bool {
+        let candidate = self.shared.oldest_epoch(seq);
+        self.shared.tail.store(candidate, Ordering::SeqCst);
+        // a reader which joined meanwhile either sees the new tail and moves
+        // up to it, or its older epoch shows up in this second look
+        let recheck = self.shared.oldest_epoch(seq);
+        self.reclaimed = if seq::precedes(candidate, recheck) {
+            candidate
+        } else {
+            recheck
+        };
+        seq::distance(self.reclaimed, seq) < self.capacity()
+    }
+    /// Add a reader which sees the items published from now on
+    ///
+    /// Returns `None` once `max_readers` readers exist.
+    pub fn subscribe(&self) -> Option<StreamReader<T>> {
+        StreamReader::join(&self.shared)
+    }
+}
+
+impl<T> Drop for Publisher<T> {
+    fn drop(&mut self) {
+        // published with the release of the Arc, `Shared` reads it when dropped
+        self.shared.filled.store(self.filled, Ordering::Relaxed);
+        self.shared.closed.store(true, Ordering::Release);
+        if let Some(wake) = &self.shared.wake {
+            drop(wake.lock.lock());
+            wake.cond.notify_all();
+        }
+    }
+}
+
+/// Consumer side of the lock-free stream, it can move to another thread
+pub struct StreamReader<T> {
+    shared: Arc<Shared<T>>,
+    index: usize,
+    /// the next item to read, mirrors the announced epoch
+    cursor: Counter,
+}
+
+impl<T> StreamReader<T> {
+    fn join(shared: &Arc<Shared<T>>) -> Option<Self> {
+        let head = shared.head.load(Ordering::Acquire);
+        let index = shared.epochs.iter().position(|e| {
+            e.compare_exchange(FREE, epoch(head), Ordering::SeqCst, Ordering::Relaxed)
+                .is_ok()
+        })?;
+        let mut cursor = head;
+        let tail = shared.tail.load(Ordering::SeqCst);
+        if seq::precedes(cursor, tail) {
+            cursor = tail;
+            shared.epochs[index].store(epoch(cursor), Ordering::SeqCst);
+        }
+        Some(Self {
+            shared: shared.clone(),
+            index,
+            cursor,
+        })
+    }
+    /// Another reader at the same position
+    pub fn try_clone(&self) -> Option<Self> {
+        let mut reader = Self::join(&self.shared)?;
+        reader.cursor = self.cursor;
+        self.shared.epochs[reader.index].store(epoch(self.cursor), Ordering::SeqCst);
+        Some(reader)
+    }
+    /// Borrow the next item, it is consumed when the guard drops
+    pub fn read(&mut self) -> Option<BorrowRead<'_, T>> {
+        let head = self.shared.head.load(Ordering::Acquire);
+        if head == self.cursor {
+            return None;
+        }
+        let obj = unsafe { (*self.shared.slot(self.cursor).get()).assume_init_ref() };
+        Some(BorrowRead { reader: self, obj })
+    }
+    /// Borrow up to `max` items with a single look at the head counter
+    ///
+    /// The items are consumed together when the batch drops, so the reader
+    /// announces its new epoch once per batch instead of once per item.
+    pub fn read_batch(&mut self, max: usize) -> Batch<'_, T> {
+        let head = self.shared.head.load(Ordering::Acquire);
+        let len = seq::distance(self.cursor, head).min(max);
+        Batch { reader: self, len }
+    }
+    /// Number of items published but not read yet
+    pub fn len(&self) -> usize {
+        let head = self.shared.head.load(Ordering::Acquire);
+        seq::distance(self.cursor, head)
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Block until an item is available, returns false if none will arrive
+    /// because the producer is gone
+    ///
+    /// Yields to other threads while polling if the stream was not created
+    /// with [`Publisher::with_wakeups`].
+    pub fn wait(&self) -> bool {
+        let closed = || self.shared.closed.load(Ordering::Acquire);
+        let Some(wake) = &self.shared.wake else {
+            while self.is_empty() && !closed() {
+                std::thread::yield_now();
+            }
+            return !self.is_empty();
+        };
+        wake.sleepers.fetch_add(1, Ordering::SeqCst);
+        let mut guard = wake.lock.lock().unwrap_or_else(PoisonError::into_inner);
+        while self.is_empty() && !closed() {
+            guard = wake
+                .cond
+                .wait(guard)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+        drop(guard);
+        wake.sleepers.fetch_sub(1, Ordering::Relaxed);
+        !self.is_empty()
+    }
+}
+
+impl<T> Drop for StreamReader<T> {
+    fn drop(&mut self) {
+        self.shared.epochs[self.index].store(FREE, Ordering::Release);
+    }
+}
+
+/// Reader-side lock into a slot of the lock-free stream
+pub struct BorrowRead<'a, T> {
+    reader: &'a mut StreamReader<T>,
+    obj: &'a T,
+}
+
+impl<'a, T> Deref for BorrowRead<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.obj
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl<'a, T: defmt::Format> defmt::Format for BorrowRead<'a, T> {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        (**self).format(f)
+    }
+}
+
+impl<'a, T> Drop for BorrowRead<'a, T> {
+    fn drop(&mut self) {
+        let reader = &mut *self.reader;
+        reader.cursor = reader.cursor.wrapping_add(1);
+        reader.shared.epochs[reader.index].store(epoch(reader.cursor), Ordering::Release);
+    }
+}
+
+/// Items consumed together, see [`StreamReader::read_batch`]
+pub struct Batch<'a, T> {
+    reader: &'a mut StreamReader<T>,
+    len: usize,
+}
+
+impl<'a, T> Batch<'a, T> {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        let shared = &self.reader.shared;
+        let first = self.reader.cursor;
+        (0..self.len)
+            .map(move |n| unsafe { (*shared.slot(seq::advance(first, n)).get()).assume_init_ref() })
+    }
+}
+
+impl<'a, T> Drop for Batch<'a, T> {
+    fn drop(&mut self) {
+        let reader = &mut *self.reader;
+        reader.cursor = seq::advance(reader.cursor, self.len);
+        reader.shared.epochs[reader.index].store(epoch(reader.cursor), Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{FREE, Publisher};
+    use std::{sync::Arc, thread};
+
+    #[test]
+    fn full_ring() {
+        let mut p = Publisher::new(2, 2);
+        let mut r = p.subscribe().unwrap();
+        let mut late = p.subscribe().unwrap();
+        assert!(p.subscribe().is_none());
+        assert_eq!(p.try_publish(1), Ok(0));
+        assert_eq!(p.try_publish(2), Ok(1));
+        assert_eq!(p.try_publish(3), Err(3));
+        assert_eq!(*r.read().unwrap(), 1);
+        assert_eq!(p.try_publish(3), Err(3));
+        assert_eq!(*late.read().unwrap(), 1);
+        assert_eq!(p.try_publish(3), Ok(2));
+        drop(late);
+        assert_eq!(r.len(), 2);
+        assert_eq!(p.try_publish(4), Err(4));
+        assert_eq!(*r.read().unwrap(), 2);
+        assert_eq!(p.try_publish(4), Ok(3));
+    }
+
+    #[test]
+    fn wraps() {
+        let start = FREE - 1;
+        let mut p = Publisher::new(2, 1);
+        let shared = Arc::get_mut(&mut p.shared).unwrap();
+        *shared.head.0.get_mut() = start;
+        *shared.tail.0.get_mut() = start;
+        (p.head, p.reclaimed) = (start, start);
+        let mut r = p.subscribe().unwrap();
+        let item = Arc::new(());
+        for _ in 0..2 {
+            p.try_publish(item.clone()).unwrap();
+        }
+        // a cursor at FREE announces the item before it
+        drop(r.read());
+        assert!(p.try_publish(item.clone()).is_err());
+        assert_eq!(r.read_batch(2).len(), 1);
+        assert_eq!(p.try_publish(item.clone()), Ok(0));
+        assert_eq!(Arc::strong_count(&item), 3);
+        drop(r);
+        drop(p);
+        assert_eq!(Arc::strong_count(&item), 1);
+    }
+
+    #[test]
+    fn wakeups() {
+        let mut p: Publisher<String> = Publisher::with_wakeups(4, 2);
+        let consumers: Vec<_> = (0..2)
+            .map(|_| {
+                let mut r = p.subscribe().unwrap();
+                thread::spawn(move || {
+                    let mut seen = vec![];
+                    while r.wait() {
+                        // the head store published the whole string
+                        seen.push(r.read().unwrap().clone());
+                    }
+                    seen
+                })
+            })
+            .collect();
+        for i in 0..100 {
+            p.publish(i.to_string());
+        }
+        drop(p);
+        let expected: Vec<_> = (0..100).map(|i| i.to_string()).collect();
+        for consumer in consumers {
+            assert_eq!(consumer.join().unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn threads() {
+        let mut p = Publisher::new(8, 4);
+        let consumers: Vec<_> = (0..4)
+            .map(|_| {
+                let mut r = p.subscribe().unwrap();
+                thread::spawn(move || {
+                    let mut sum = 0u64;
+                    while sum < 1000 * 999 / 2 {
+                        let batch = r.read_batch(3);
+                        if batch.is_empty() {
+                            thread::yield_now();
+                        }
+                        sum += batch.iter().sum::<u64>();
+                    }
+                    sum
+                })
+            })
+            .collect();
+        for i in 0..1000u64 {
+            p.publish(i);
+        }
+        for consumer in consumers {
+            assert_eq!(consumer.join().unwrap(), 1000 * 999 / 2);
+        }
+    }
+}
@@ -0,0 +1,191 @@
+//! Slot storage in a memory mapping, for retention larger than RAM
+//!
+//! A [`MappedAllocator`] hands out the blocks of a publisher's slots from
+//! one large mapping, reserved up front but only backed by memory once
+//! touched. Publishing and reading touch the blocks around the head of the
+//! stream, which stay resident, while the OS pages the cold blocks of old
+//! retained items out and faults them back in when a lagging reader or a
+//! new subscriber gets to them.
+//!
+//! Anonymous mappings page out to swap. A mapping of a scratch file pages
+//! out to that file instead, so hours of history fit on disk on hosts
+//! without swap; the file is sparse, taking only the space of the blocks
+//! written so far. Its contents are only meaningful to the running
+//! process, use the `journal` feature to keep items across restarts.
+//!
+//! ```text
+//! let alloc = MappedAllocator::file("/var/tmp/history", 64 << 30)?;
+//! let mut publisher = Publisher::with_allocator(alloc);
+//! publisher.set_capacity_limit(...);
+//! ```
+
+use core::{alloc::Layout, cell::RefCell, ptr::NonNull};
+use std::{
+    fs::{self, OpenOptions},
+    io,
+    path::{Path, PathBuf},
+};
+
+use memmap2::MmapMut;
+
+use crate::Allocator;
+
+/// Parts of the mapping handed out
+struct Regions {
+    /// offset after the last region ever handed out
+    end: usize,
+    /// regions given back, reused for the same layout
+    free: Vec<(Layout, usize)>,
+    /// bytes currently handed out
+    used: usize,
+}
+
+/// Source of slot blocks in a memory mapping, see the [module](self) docs
+///
+/// Allocations fail once the mapping is exhausted, so `capacity` bounds the
+/// memory of the publisher. Give it room for the capacity limit of the
+/// publisher, the slots come in blocks of 32.
+pub struct MappedAllocator {
+    map: MmapMut,
+    /// scratch file removed on drop
+    path: Option<PathBuf>,
+    regions: RefCell<Regions>,
+}
+
+impl MappedAllocator {
+    /// Map `capacity` bytes of anonymous memory
+    pub fn anonymous(capacity: usize) -> io::Result<Self> {
+        if capacity == 0 {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        Ok(Self::with_map(MmapMut::map_anon(capacity)?, None))
+    }
+    /// Create a sparse file of `capacity` bytes at `path` and map it
+    ///
+    /// An existing file is replaced. The file is removed when the allocator
+    /// is dropped along with its publisher.
+    pub fn file(path: impl AsRef<Path>, capacity: usize) -> io::Result<Self> {
+        let path = path.as_ref();
+        if capacity == 0 {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        match fs::remove_file(path) {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+            _ => {}
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(path)?;
+        file.set_len(capacity as u64)?;
+        // the file was just created by us, nothing else truncates it
+        let map = unsafe { MmapMut::map_mut(&file)? };
+        Ok(Self::with_map(map, Some(path.to_owned())))
+    }
+    fn with_map(map: MmapMut, path: Option<PathBuf>) -> Self {
+        Self {
+            map,
+            path,
+            regions: RefCell::new(Regions {
+                end: 0,
+                free: Vec::new(),
+                used: 0,
+            }),
+        }
+    }
+    /// Bytes of the mapping
+    pub fn capacity(&self) -> usize {
+        self.map.len()
+    }
+    /// Bytes handed out to the publisher
+    pub fn used(&self) -> usize {
+        self.regions.borrow().used
+    }
+}
+
+impl Allocator for MappedAllocator {
+    fn allocate(&self, layout: Layout) -> Option<NonNull<u8>> {
+        let mut regions = self.regions.borrow_mut();
+        let offset = match regions.free.iter().position(|&(l, _)| l == layout) {
+            Some(n) => regions.free.swap_remove(n).1,
+            None => {
+                // the mapping is page aligned, so aligning the offset suffices
+                let offset = regions.end.checked_next_multiple_of(layout.align())?;
+                let end = offset.checked_add(layout.size())?;
+                if end > self.map.len() {
+                    return None;
+                }
+                regions.end = end;
+                offset
+            }
+        };
+        regions.used += layout.size();
+        NonNull::new(self.map.as_ptr().wrapping_add(offset).cast_mut())
+    }
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        let mut regions = self.regions.borrow_mut();
+        let offset = ptr.as_ptr() as usize - self.map.as_ptr() as usize;
+        regions.free.push((layout, offset));
+        regions.used -= layout.size();
+    }
+}
+
+impl Drop for MappedAllocator {
+    fn drop(&mut self) {
+        if let Some(path) = &self.path {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::MappedAllocator;
+    use crate::{Allocator, PublishError, Publisher, StreamReader};
+    use core::alloc::Layout;
+
+    #[test]
+    fn holds_the_slots() {
+        let path = std::env::temp_dir().join(format!("mc-mapped-{}", std::process::id()));
+        let alloc = MappedAllocator::file(&path, 4096).unwrap();
+        assert_eq!(alloc.capacity(), 4096);
+        let mut p: Publisher<u64> = Publisher::with_allocator(alloc);
+        let mut r = StreamReader::new();
+        p.add_stream_reader(&mut r);
+        // two blocks of 32 slots
+        p.publish_iter(0..40);
+        assert!(path.exists());
+        assert_eq!(r.drain_owned(), (0..40).collect::<Vec<u64>>());
+        // 16 blocks fit in the mapping
+        assert_eq!(p.try_reserve(1000), Err(PublishError::OutOfMemory));
+        p.try_reserve(400).unwrap();
+        drop(r);
+        drop(p);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn reuses_blocks() {
+        let alloc = MappedAllocator::anonymous(4096).unwrap();
+        let layout = Layout::from_size_align(1024, 64).unwrap();
+        let a = alloc.allocate(layout).unwrap();
+        let b = alloc.allocate(layout).unwrap();
+        assert_eq!(alloc.used(), 2048);
+        unsafe { alloc.deallocate(a, layout) };
+        assert_eq!(alloc.used(), 1024);
+        assert_eq!(alloc.allocate(layout), Some(a));
+        unsafe { alloc.deallocate(b, layout) };
+        assert_eq!(alloc.allocate(layout), Some(b));
+
+        // room for 8 blocks, each round takes 4 and gives them back
+        let alloc = MappedAllocator::anonymous(8 * 32 * 64).unwrap();
+        let mut p: Publisher<[u8; 64]> = Publisher::with_allocator(alloc);
+        for n in 0..100 {
+            p.try_reserve(4 * 32).unwrap();
+            p.publish_iter((0..4 * 32).map(|_| [n; 64]));
+            p.clear();
+            p.shrink_to_fit();
+        }
+    }
+}
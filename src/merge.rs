@@ -0,0 +1,221 @@
+//! Fan-in of several readers as one async stream
+//!
+//! A [`Merge`] owns readers of the same item type, which may belong to
+//! different publishers, and yields their items as a `futures_core::Stream`.
+//! It takes turns between the readers like [`MergedReader`] does for
+//! shards, starting after the reader which yielded last, so a busy
+//! publisher doesn't starve the others: while several readers have items
+//! queued, each gets one item in per round.
+//!
+//! [`MergedReader`]: crate::sharded::MergedReader
+
+use alloc::{boxed::Box, rc::Rc, vec::Vec};
+use core::{
+    cell::Cell,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+use futures_core::Stream;
+
+use crate::{ReadError, ReaderHooks, StreamReader};
+
+/// Readers merged into one stream of items and errors
+///
+/// Every item comes with the index of its reader, in the order the readers
+/// were added. An error a reader reports, such as [`ReadError::Lagged`], is
+/// passed on the same way; a reader which reported [`ReadError::Closed`]
+/// leaves the rotation, and the stream ends once all did.
+pub struct Merge<T> {
+    /// `None` once the reader is closed
+    readers: Vec<Option<Box<StreamReader<T>>>>,
+    /// reader to look at first on the next poll
+    next: usize,
+    wakeup: Rc<Wakeup>,
+}
+
+/// Shared with the notifications of the readers
+#[derive(Default)]
+struct Wakeup {
+    /// task waiting for any of the readers
+    waker: Cell<Option<Waker>>,
+    hooks: Cell<Option<&'static dyn ReaderHooks>>,
+}
+
+impl<T> Merge<T> {
+    pub fn new() -> Self {
+        Self {
+            readers: Vec::new(),
+            next: 0,
+            wakeup: Rc::default(),
+        }
+    }
+    /// Report the polls of the stream to `hooks` from now on
+    ///
+    /// The task waits on all readers at once, so `polled` and `pending` are
+    /// called for every open reader and `woken` for the one whose publisher
+    /// woke the task, each with the index its items come with.
+    pub fn set_hooks(&self, hooks: &'static dyn ReaderHooks) {
+        self.wakeup.hooks.set(Some(hooks));
+    }
+    /// Call `hook` with the index of every open reader
+    fn report(&self, hook: impl Fn(&dyn ReaderHooks, usize)) {
+        if let Some(hooks) = self.wakeup.hooks.get() {
+            for (index, _) in self.readers.iter().enumerate().filter(|(_, r)| r.is_some()) {
+                hook(hooks, index);
+            }
+        }
+    }
+    /// Add a subscribed reader, returns the index its items come with
+    ///
+    /// Replaces the notification of the reader.
+    pub fn push(&mut self, mut reader: Box<StreamReader<T>>) -> usize {
+        let wakeup = self.wakeup.clone();
+        let index = self.readers.len();
+        reader.set_notification(Box::new(move || {
+            if let Some(waker) = wakeup.waker.take() {
+                if let Some(hooks) = wakeup.hooks.get() {
+                    hooks.woken(index);
+                }
+                waker.wake();
+            }
+        }));
+        self.readers.push(Some(reader));
+        index
+    }
+    /// The next item or error in turn, `None` while nothing is queued
+    pub fn try_next(&mut self) -> Option<(usize, Result<T, ReadError>)>
+    where
+        T: Clone,
+    {
+        let count = self.readers.len();
+        for n in 0..count {
+            let index = (self.next + n) % count;
+            let Some(reader) = &mut self.readers[index] else {
+                continue;
+            };
+            let result = match reader.try_read() {
+                Ok(None) => continue,
+                Ok(Some(item)) => Ok(item.clone()),
+                Err(error) => Err(error),
+            };
+            if let Err(ReadError::Closed) = result {
+                self.readers[index] = None;
+            }
+            self.next = (index + 1) % count;
+            return Some((index, result));
+        }
+        None
+    }
+    /// Whether every reader reported that it is closed
+    pub fn is_terminated(&self) -> bool {
+        self.readers.iter().all(Option::is_none)
+    }
+}
+
+impl<T> Default for Merge<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Clone> Stream for Merge<T> {
+    type Item = (usize, Result<T, ReadError>);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.report(|hooks, index| hooks.polled(index));
+        // registered before looking, so a publish in between still wakes
+        self.wakeup.waker.set(Some(cx.waker().clone()));
+        if let Some(next) = self.try_next() {
+            return Poll::Ready(Some(next));
+        }
+        if self.is_terminated() {
+            return Poll::Ready(None);
+        }
+        self.report(|hooks, index| hooks.pending(index));
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Merge;
+    use crate::{Publisher, ReadError, ReaderHooks, StreamReader};
+    use core::{
+        pin::Pin,
+        task::{Context, Poll},
+    };
+    use futures_core::Stream;
+    use std::{
+        sync::{
+            Arc, Mutex,
+            atomic::{AtomicBool, Ordering},
+        },
+        task::{Wake, Waker},
+    };
+
+    struct Flag(AtomicBool);
+    impl Wake for Flag {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, Ordering::Relaxed);
+        }
+    }
+
+    struct Log(Mutex<Vec<(&'static str, usize)>>);
+    impl ReaderHooks for Log {
+        fn polled(&self, reader: usize) {
+            self.0.lock().unwrap().push(("polled", reader));
+        }
+        fn pending(&self, reader: usize) {
+            self.0.lock().unwrap().push(("pending", reader));
+        }
+        fn woken(&self, reader: usize) {
+            self.0.lock().unwrap().push(("woken", reader));
+        }
+    }
+
+    #[test]
+    fn takes_turns() {
+        let flag = Arc::new(Flag(AtomicBool::new(false)));
+        let waker = Waker::from(flag.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        let mut busy = Publisher::new();
+        let mut quiet = Publisher::new();
+        let mut merge = Merge::new();
+        for p in [&mut busy, &mut quiet] {
+            let mut reader = Box::new(StreamReader::new());
+            p.add_stream_reader(&mut reader);
+            merge.push(reader);
+        }
+        static LOG: Log = Log(Mutex::new(Vec::new()));
+        merge.set_hooks(&LOG);
+        let mut next = || Pin::new(&mut merge).poll_next(&mut cx);
+        assert_eq!(next(), Poll::Pending);
+        quiet.publish(10);
+        assert!(flag.0.load(Ordering::Relaxed));
+        assert_eq!(
+            *LOG.0.lock().unwrap(),
+            [
+                ("polled", 0),
+                ("polled", 1),
+                ("pending", 0),
+                ("pending", 1),
+                ("woken", 1)
+            ]
+        );
+        busy.publish_iter([1, 2, 3]);
+        quiet.publish(20);
+        let order: Vec<_> = (0..5).map(|_| next()).collect();
+        assert_eq!(
+            order,
+            [(0, 1), (1, 10), (0, 2), (1, 20), (0, 3)]
+                .map(|(index, item)| Poll::Ready(Some((index, Ok(item)))))
+        );
+        busy.close();
+        drop(quiet);
+        assert_eq!(next(), Poll::Ready(Some((1, Err(ReadError::Closed)))));
+        assert_eq!(next(), Poll::Ready(Some((0, Err(ReadError::Closed)))));
+        assert_eq!(next(), Poll::Ready(None));
+    }
+}
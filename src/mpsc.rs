@@ -0,0 +1,271 @@
+//! Drop-in replacement for `std::sync::mpsc`
+//!
+//! [`channel`] returns a [`Sender`] and a [`Receiver`] with the methods and
+//! error types of the standard library, backed by the thread-safe
+//! [`Publisher`]. After switching the imports, code can move on to multiple
+//! consumers step by step: [`Receiver::into_reader`] turns the receiving
+//! end into a [`StreamReader`] which keeps being fed by the senders.
+//!
+//! The stream shares the items between threads, so they have to be `Sync`
+//! as well as `Send`. There is no bounded `sync_channel`.
+
+use core::{cell::Cell, fmt, marker::PhantomData, time::Duration};
+pub use std::sync::mpsc::{RecvError, RecvTimeoutError, SendError, TryRecvError};
+
+use crate::{
+    Arc,
+    sync::{BorrowRead, Publisher, StreamReader},
+};
+
+/// Create an unbounded channel
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let publisher = Publisher::new();
+    let reader = publisher.subscribe();
+    (
+        Sender { publisher },
+        Receiver {
+            reader,
+            _not_sync: PhantomData,
+        },
+    )
+}
+
+/// Sending half of a [`channel`], clones feed the same receiver
+pub struct Sender<T> {
+    publisher: Publisher<T>,
+}
+
+impl<T> Sender<T> {
+    /// Queue `t` for the receiver, fails once the receiver was dropped
+    pub fn send(&self, t: T) -> Result<(), SendError<T>> {
+        self.publisher
+            .publish_if_read(t)
+            .map(drop)
+            .map_err(SendError)
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        Self {
+            publisher: self.publisher.clone(),
+        }
+    }
+}
+
+impl<T> fmt::Debug for Sender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Sender").finish_non_exhaustive()
+    }
+}
+
+/// Receiving half of a [`channel`]
+pub struct Receiver<T> {
+    reader: StreamReader<T>,
+    /// one thread receives at a time, like the standard receiver
+    _not_sync: PhantomData<Cell<()>>,
+}
+
+/// The item without the stream's reference, the receiver is its only reader
+fn take<T>(item: BorrowRead<'_, T>) -> T {
+    let mut item = item.into_arc();
+    loop {
+        // the stream releases consumed items before the guard drops its
+        // own reference, another one can only be on its way out
+        match Arc::try_unwrap(item) {
+            Ok(item) => return item,
+            Err(shared) => item = shared,
+        }
+        std::thread::yield_now();
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Wait for an item, fails once every sender was dropped
+    pub fn recv(&self) -> Result<T, RecvError> {
+        self.reader.iter().next().map(take).ok_or(RecvError)
+    }
+    /// Take an item without waiting
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        if let Some(item) = self.reader.read() {
+            return Ok(take(item));
+        }
+        if !self.reader.is_closed() {
+            return Err(TryRecvError::Empty);
+        }
+        // the last sender may have sent right before closing
+        self.reader
+            .read()
+            .map(take)
+            .ok_or(TryRecvError::Disconnected)
+    }
+    /// Wait for an item up to `timeout`
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        if let Some(item) = self.reader.read_timeout(timeout) {
+            return Ok(take(item));
+        }
+        match self.reader.is_closed() {
+            true => self
+                .reader
+                .read()
+                .map(take)
+                .ok_or(RecvTimeoutError::Disconnected),
+            false => Err(RecvTimeoutError::Timeout),
+        }
+    }
+    /// Iterate over the items, waiting for each until every sender was dropped
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter { rx: self }
+    }
+    /// Iterate over the items available without waiting
+    pub fn try_iter(&self) -> TryIter<'_, T> {
+        TryIter { rx: self }
+    }
+    /// Continue as a reader of the thread-safe stream
+    ///
+    /// The senders keep publishing to it, and further readers can
+    /// subscribe via [`Publisher::subscribe`] on a publisher of the stream.
+    pub fn into_reader(self) -> StreamReader<T> {
+        self.reader
+    }
+}
+
+impl<T> fmt::Debug for Receiver<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Receiver").finish_non_exhaustive()
+    }
+}
+
+/// Blocking iterator, see [`Receiver::iter`]
+pub struct Iter<'a, T> {
+    rx: &'a Receiver<T>,
+}
+
+impl<'a, T> Iterator for Iter<'a, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.rx.recv().ok()
+    }
+}
+
+/// Non-blocking iterator, see [`Receiver::try_iter`]
+pub struct TryIter<'a, T> {
+    rx: &'a Receiver<T>,
+}
+
+impl<'a, T> Iterator for TryIter<'a, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.rx.try_recv().ok()
+    }
+}
+
+/// Owning blocking iterator, see [`Receiver::iter`]
+pub struct IntoIter<T> {
+    rx: Receiver<T>,
+}
+
+impl<T> Iterator for IntoIter<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.rx.recv().ok()
+    }
+}
+
+impl<'a, T> IntoIterator for &'a Receiver<T> {
+    type Item = T;
+    type IntoIter = Iter<'a, T>;
+
+    fn into_iter(self) -> Iter<'a, T> {
+        self.iter()
+    }
+}
+
+impl<T> IntoIterator for Receiver<T> {
+    type Item = T;
+    type IntoIter = IntoIter<T>;
+
+    fn into_iter(self) -> IntoIter<T> {
+        IntoIter { rx: self }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{thread, time::Duration};
+
+    /// The same checks against our channel and the standard one
+    macro_rules! both {
+        ($name:ident, |$channel:ident, $errors:ident| $body:block) => {
+            #[test]
+            fn $name() {
+                {
+                    use super as $errors;
+                    use super::channel as $channel;
+                    $body
+                }
+                {
+                    use std::sync::mpsc as $errors;
+                    use std::sync::mpsc::channel as $channel;
+                    $body
+                }
+            }
+        };
+    }
+
+    both!(fan_in, |channel, mpsc| {
+        let (tx, rx) = channel();
+        let senders: Vec<_> = (0..4)
+            .map(|n| {
+                let tx = tx.clone();
+                thread::spawn(move || {
+                    for i in 0..100 {
+                        tx.send(n * 100 + i).unwrap();
+                    }
+                })
+            })
+            .collect();
+        drop(tx);
+        let mut received: Vec<u32> = rx.iter().collect();
+        received.sort_unstable();
+        assert_eq!(received, (0..400).collect::<Vec<_>>());
+        assert_eq!(rx.recv(), Err(mpsc::RecvError));
+        for sender in senders {
+            sender.join().unwrap();
+        }
+    });
+
+    both!(disconnects, |channel, mpsc| {
+        let (tx, rx) = channel();
+        assert_eq!(rx.try_recv(), Err(mpsc::TryRecvError::Empty));
+        assert_eq!(
+            rx.recv_timeout(Duration::from_millis(5)),
+            Err(mpsc::RecvTimeoutError::Timeout)
+        );
+        tx.send(String::from("last")).unwrap();
+        drop(tx);
+        assert_eq!(rx.try_recv().as_deref(), Ok("last"));
+        assert_eq!(rx.try_recv(), Err(mpsc::TryRecvError::Disconnected));
+        assert_eq!(
+            rx.recv_timeout(Duration::MAX),
+            Err(mpsc::RecvTimeoutError::Disconnected)
+        );
+
+        let (tx, rx) = channel();
+        drop(rx);
+        assert_eq!(tx.send(1), Err(mpsc::SendError(1)));
+    });
+
+    #[test]
+    fn into_reader() {
+        let (tx, rx) = super::channel();
+        tx.send(1).unwrap();
+        let reader = rx.into_reader();
+        tx.send(2).unwrap();
+        drop(tx);
+        assert_eq!(reader.iter().map(|v| *v).collect::<Vec<_>>(), [1, 2]);
+    }
+}
@@ -0,0 +1,400 @@
+//! The remote reader protocol without any I/O
+//!
+//! A remote reader connects, sends the [`Start`] it wants as a tag byte and
+//! a little endian `u32`, and then receives whole frames, each as a little
+//! endian `u32` length followed by the payload. The publishing side of one
+//! connection is a [`Session`], the reading side a [`Client`]. Both are
+//! plain state machines: the embedder feeds them the bytes it received,
+//! sends the bytes they hand out, and tells them the time when their
+//! [timeout](Session::poll_timeout) is due. So they fit into any event loop
+//! and need neither `std` nor a runtime; the `tcp` and `uds` bridges are
+//! drivers of them over non-blocking sockets.
+//!
+//! ```text
+//! loop {
+//!     let n = socket.read(&mut buf)?;
+//!     session.handle_input(&buf[..n], &mut frames)?;
+//!     let n = socket.write(session.poll_transmit()?)?;
+//!     session.advance(n);
+//! }
+//! ```
+
+use alloc::{boxed::Box, collections::VecDeque, vec::Vec};
+use core::fmt;
+
+use crate::{
+    Instant, ReadError,
+    framing::{FrameError, FramePublisher, FrameReader},
+};
+
+/// Request of a client, a tag byte and a little endian `u32`
+pub const HANDSHAKE: usize = 5;
+
+/// Length prefix of the frames
+const HEADER: usize = 4;
+
+/// Frames a session collects into one transmit, unless a single one is larger
+const TRANSMIT_BATCH: usize = 64 * 1024;
+
+/// Where a client starts reading
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Start {
+    /// with the oldest retained frame
+    Oldest,
+    /// with the newest retained frames, up to the given number
+    Latest(u32),
+}
+
+impl Start {
+    /// Only frames published after the client subscribed
+    pub const NEW: Self = Self::Latest(0);
+
+    pub fn encode(self) -> [u8; HANDSHAKE] {
+        let (tag, count) = match self {
+            Self::Oldest => (0, 0),
+            Self::Latest(count) => (1, count),
+        };
+        let mut handshake = [tag; HANDSHAKE];
+        handshake[1..].copy_from_slice(&count.to_le_bytes());
+        handshake
+    }
+    /// The start a client requested, `None` for an unknown tag
+    pub fn decode(handshake: [u8; HANDSHAKE]) -> Option<Self> {
+        let count = u32::from_le_bytes([handshake[1], handshake[2], handshake[3], handshake[4]]);
+        match handshake[0] {
+            0 => Some(Self::Oldest),
+            1 => Some(Self::Latest(count)),
+            _ => None,
+        }
+    }
+}
+
+/// Reasons why a session ended, the connection should be closed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SessionError {
+    /// The publisher closed and every frame was transmitted
+    Finished,
+    /// The client requested an unknown start
+    InvalidStart,
+    /// The client didn't send its start before the deadline
+    TimedOut,
+    /// The client fell behind the capacity limit, or the frame boundaries
+    /// were lost
+    Lagged,
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SessionError::Finished => f.write_str("stream finished"),
+            SessionError::InvalidStart => f.write_str("client requested an invalid start"),
+            SessionError::TimedOut => f.write_str("client didn't send its start in time"),
+            SessionError::Lagged => f.write_str("client fell behind"),
+        }
+    }
+}
+
+impl core::error::Error for SessionError {}
+
+/// Append `frame` with its length prefix
+fn encode(pending: &mut Vec<u8>, frame: &[u8]) {
+    pending.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+    pending.extend_from_slice(frame);
+}
+
+enum State {
+    /// waiting for the start of the client
+    Handshake {
+        received: [u8; HANDSHAKE],
+        len: usize,
+        deadline: Option<Instant>,
+    },
+    // boxed, the publisher keeps its address
+    Streaming(Box<FrameReader>),
+}
+
+/// Publishing side of one connection
+pub struct Session {
+    state: State,
+    /// encoded frames, of which the first `written` bytes were sent
+    pending: Vec<u8>,
+    written: usize,
+}
+
+impl Session {
+    /// A session which waits for the client's [`Start`], until `deadline`
+    /// if there is one
+    pub fn accept(deadline: Option<Instant>) -> Self {
+        Self {
+            state: State::Handshake {
+                received: [0; HANDSHAKE],
+                len: 0,
+                deadline,
+            },
+            pending: Vec::new(),
+            written: 0,
+        }
+    }
+    /// A session which streams right away, for transports without a handshake
+    pub fn subscribe(frames: &mut FramePublisher, start: Start) -> Self {
+        let mut session = Self::accept(None);
+        session.start(frames, start);
+        session
+    }
+    fn start(&mut self, frames: &mut FramePublisher, start: Start) {
+        let mut reader = Box::new(FrameReader::new(frames.max_frame()));
+        frames.subscribe(&mut reader);
+        if let Start::Latest(keep) = start {
+            let keep = keep as usize;
+            let mut latest = VecDeque::new();
+            while let Ok(Some(frame)) = reader.read_frame() {
+                if latest.len() == keep {
+                    latest.pop_front();
+                }
+                if keep > 0 {
+                    latest.push_back(frame);
+                }
+            }
+            for frame in latest {
+                encode(&mut self.pending, &frame);
+            }
+        }
+        self.state = State::Streaming(reader);
+    }
+    /// Whether the client's start arrived and frames are transmitted
+    pub fn is_streaming(&self) -> bool {
+        matches!(self.state, State::Streaming(_))
+    }
+    /// Process bytes received from the client
+    ///
+    /// Subscribes to `frames` once the start is complete. Anything the client
+    /// sends after it is ignored.
+    pub fn handle_input(
+        &mut self,
+        bytes: &[u8],
+        frames: &mut FramePublisher,
+    ) -> Result<(), SessionError> {
+        let State::Handshake { received, len, .. } = &mut self.state else {
+            return Ok(());
+        };
+        let take = bytes.len().min(HANDSHAKE - *len);
+        received[*len..][..take].copy_from_slice(&bytes[..take]);
+        *len += take;
+        if *len < HANDSHAKE {
+            return Ok(());
+        }
+        let start = Start::decode(*received).ok_or(SessionError::InvalidStart)?;
+        self.start(frames, start);
+        Ok(())
+    }
+    /// When [`handle_timeout`](Self::handle_timeout) is due next
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        match self.state {
+            State::Handshake { deadline, .. } => deadline,
+            State::Streaming(_) => None,
+        }
+    }
+    /// Let the session know the time, fails once the handshake is overdue
+    pub fn handle_timeout(&mut self, now: Instant) -> Result<(), SessionError> {
+        match self.poll_timeout() {
+            Some(deadline) if deadline <= now => Err(SessionError::TimedOut),
+            _ => Ok(()),
+        }
+    }
+    /// The bytes to send next, empty while there is nothing to send
+    ///
+    /// Call [`advance`](Self::advance) with how many of them were sent.
+    pub fn poll_transmit(&mut self) -> Result<&[u8], SessionError> {
+        if self.written == self.pending.len() {
+            self.pending.clear();
+            self.written = 0;
+            if let State::Streaming(reader) = &mut self.state {
+                while self.pending.len() < TRANSMIT_BATCH {
+                    match reader.read_frame() {
+                        Ok(Some(frame)) => encode(&mut self.pending, &frame),
+                        Ok(None) => break,
+                        Err(FrameError::Corrupt | FrameError::Unsupported) => {}
+                        Err(FrameError::Read(ReadError::Closed)) if self.pending.is_empty() => {
+                            return Err(SessionError::Finished);
+                        }
+                        Err(FrameError::Read(ReadError::Closed)) => break,
+                        Err(_) => return Err(SessionError::Lagged),
+                    }
+                }
+            }
+        }
+        Ok(&self.pending[self.written..])
+    }
+    /// `n` bytes of the last [`poll_transmit`](Self::poll_transmit) were sent
+    pub fn advance(&mut self, n: usize) {
+        self.written = (self.written + n).min(self.pending.len());
+    }
+}
+
+/// Reading side of one connection
+pub struct Client {
+    max_frame: usize,
+    /// the handshake, of which the first `written` bytes were sent
+    handshake: Option<[u8; HANDSHAKE]>,
+    written: usize,
+    /// received bytes of frames not taken yet
+    received: VecDeque<u8>,
+}
+
+impl Client {
+    /// A client requesting to read from `start`, refusing frames above
+    /// `max_frame` bytes
+    pub fn new(start: Start, max_frame: usize) -> Self {
+        Self {
+            max_frame,
+            handshake: Some(start.encode()),
+            written: 0,
+            received: VecDeque::new(),
+        }
+    }
+    /// A client for transports without a handshake
+    pub fn without_handshake(max_frame: usize) -> Self {
+        Self {
+            handshake: None,
+            ..Self::new(Start::Oldest, max_frame)
+        }
+    }
+    /// The bytes to send next, empty once the handshake was sent
+    pub fn poll_transmit(&self) -> &[u8] {
+        self.handshake
+            .as_ref()
+            .map_or(&[], |handshake| &handshake[self.written..])
+    }
+    /// `n` bytes of the last [`poll_transmit`](Self::poll_transmit) were sent
+    pub fn advance(&mut self, n: usize) {
+        self.written += n;
+        if self.written >= HANDSHAKE {
+            self.handshake = None;
+        }
+    }
+    /// Process bytes received from the publisher
+    pub fn handle_input(&mut self, bytes: &[u8]) {
+        self.received.extend(bytes);
+    }
+    /// Number of received bytes not taken as a frame yet
+    pub fn buffered(&self) -> usize {
+        self.received.len()
+    }
+    /// Take the next complete frame, `None` while it is incomplete
+    ///
+    /// Fails on a frame above the maximum, the connection can't continue.
+    pub fn poll_frame(&mut self) -> Result<Option<Vec<u8>>, FrameError> {
+        if self.received.len() < HEADER {
+            return Ok(None);
+        }
+        let mut header = [0; HEADER];
+        for (byte, received) in header.iter_mut().zip(&self.received) {
+            *byte = *received;
+        }
+        let len = u32::from_le_bytes(header) as usize;
+        if len > self.max_frame {
+            return Err(FrameError::TooLarge(len));
+        }
+        if self.received.len() < HEADER + len {
+            return Ok(None);
+        }
+        self.received.drain(..HEADER);
+        Ok(Some(self.received.drain(..len).collect()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Client, Session, SessionError, Start};
+    use crate::{Instant, framing::FramePublisher};
+    use core::time::Duration;
+
+    /// Move the bytes of `session` to `client`, `chunk` at a time
+    fn transfer(session: &mut Session, client: &mut Client, chunk: usize) -> SessionError {
+        loop {
+            let bytes = match session.poll_transmit() {
+                Ok([]) => return SessionError::Lagged,
+                Ok(bytes) => &bytes[..bytes.len().min(chunk)],
+                Err(error) => return error,
+            };
+            client.handle_input(bytes);
+            let n = bytes.len();
+            session.advance(n);
+        }
+    }
+
+    #[test]
+    fn handshake_and_frames() {
+        let mut frames = FramePublisher::new(16);
+        for frame in [&b"old"[..], b"new"] {
+            frames.publish(frame).unwrap();
+        }
+        let mut session = Session::accept(None);
+        let mut client = Client::new(Start::Latest(1), 16);
+        // the start arrives in two pieces
+        let handshake = client.poll_transmit().to_vec();
+        session.handle_input(&handshake[..2], &mut frames).unwrap();
+        client.advance(2);
+        assert!(!session.is_streaming());
+        assert_eq!(session.poll_transmit(), Ok(&[][..]));
+        session
+            .handle_input(client.poll_transmit(), &mut frames)
+            .unwrap();
+        client.advance(3);
+        assert!(session.is_streaming());
+        assert!(client.poll_transmit().is_empty());
+
+        frames.publish(b"next").unwrap();
+        frames.close();
+        assert_eq!(
+            transfer(&mut session, &mut client, 3),
+            SessionError::Finished
+        );
+        assert_eq!(client.poll_frame().unwrap().as_deref(), Some(&b"new"[..]));
+        assert_eq!(client.poll_frame().unwrap().as_deref(), Some(&b"next"[..]));
+        assert_eq!(client.poll_frame(), Ok(None));
+        assert_eq!(client.buffered(), 0);
+    }
+
+    #[test]
+    fn handshake_failures() {
+        let mut frames = FramePublisher::new(16);
+        let mut session = Session::accept(None);
+        assert_eq!(
+            session.handle_input(&[7; 5], &mut frames),
+            Err(SessionError::InvalidStart)
+        );
+
+        #[cfg(feature = "std")]
+        let now = Instant::now();
+        #[cfg(not(feature = "std"))]
+        let now = Instant::from_start(Duration::ZERO);
+        let deadline = now + Duration::from_secs(1);
+        let mut session = Session::accept(Some(deadline));
+        assert_eq!(session.poll_timeout(), Some(deadline));
+        assert_eq!(
+            session.handle_timeout(deadline - Duration::from_millis(1)),
+            Ok(())
+        );
+        assert_eq!(
+            session.handle_timeout(deadline),
+            Err(SessionError::TimedOut)
+        );
+        session
+            .handle_input(&Start::NEW.encode(), &mut frames)
+            .unwrap();
+        assert_eq!(session.poll_timeout(), None);
+    }
+
+    #[test]
+    fn refuses_large_frames() {
+        let mut frames = FramePublisher::new(16);
+        let mut session = Session::subscribe(&mut frames, Start::Oldest);
+        frames.publish(&[0; 16]).unwrap();
+        let mut client = Client::without_handshake(8);
+        transfer(&mut session, &mut client, usize::MAX);
+        assert!(client.poll_frame().is_err());
+    }
+}
@@ -0,0 +1,162 @@
+//! Python bindings
+//!
+//! Items are `bytes`, or any picklable object with `publish_object` and
+//! `read_object`, which go through `pickle`. The classes are neither
+//! thread-safe nor shareable with other interpreters, use them from the
+//! thread which created them, like the Rust types they wrap.
+//!
+//! Build the extension with maturin, enabling `python` and
+//! `pyo3/extension-module`.
+
+use pyo3::{create_exception, exceptions::PyException, prelude::*, types::PyBytes};
+
+use crate::{Publisher, ReadError, StreamReader};
+
+create_exception!(
+    component_model_multiple_consumers,
+    StreamError,
+    PyException,
+    "A subscriber can no longer receive items"
+);
+
+fn to_py(error: ReadError) -> PyErr {
+    StreamError::new_err(error.to_string())
+}
+
+/// Broadcasts every item to each of its subscribers
+#[pyclass(name = "Publisher", unsendable)]
+struct PyPublisher {
+    inner: Publisher<Box<[u8]>>,
+}
+
+#[pymethods]
+impl PyPublisher {
+    #[new]
+    fn new() -> Self {
+        Self {
+            inner: Publisher::new(),
+        }
+    }
+    /// Add a subscriber, it receives the items published from now on
+    fn subscribe(&mut self, py: Python<'_>) -> PyResult<Py<PySubscriber>> {
+        let subscriber = Py::new(
+            py,
+            PySubscriber {
+                reader: StreamReader::new(),
+            },
+        )?;
+        // the object keeps its address, the publisher refers to it
+        self.inner
+            .add_stream_reader(&mut subscriber.borrow_mut(py).reader);
+        Ok(subscriber)
+    }
+    /// Publish a copy of the bytes, returns the sequence number
+    fn publish(&mut self, item: &[u8]) -> u64 {
+        self.inner.publish(item.into()) as u64
+    }
+    /// Publish a pickled object
+    fn publish_object(&mut self, item: &Bound<'_, PyAny>) -> PyResult<u64> {
+        let pickled = item.py().import("pickle")?.call_method1("dumps", (item,))?;
+        Ok(self.publish(pickled.cast::<PyBytes>()?.as_bytes()))
+    }
+    /// Signal subscribers that no further items will be published
+    fn close(&mut self) {
+        self.inner.close();
+    }
+}
+
+/// Receives the items of a publisher
+///
+/// Iterating yields the queued items and stops once none is left.
+#[pyclass(name = "Subscriber", unsendable)]
+struct PySubscriber {
+    reader: StreamReader<Box<[u8]>>,
+}
+
+#[pymethods]
+impl PySubscriber {
+    /// The next item, or `None` while nothing is queued
+    ///
+    /// Raises `StreamError` once the stream is closed, and once after items
+    /// were lost.
+    fn read<'py>(&mut self, py: Python<'py>) -> PyResult<Option<Bound<'py, PyBytes>>> {
+        let item = self.reader.try_read().map_err(to_py)?;
+        Ok(item.map(|item| PyBytes::new(py, &item)))
+    }
+    /// The next item unpickled, or `None` while nothing is queued
+    fn read_object<'py>(&mut self, py: Python<'py>) -> PyResult<Option<Bound<'py, PyAny>>> {
+        let Some(item) = self.read(py)? else {
+            return Ok(None);
+        };
+        let pickle = py.import("pickle")?;
+        pickle.call_method1("loads", (item,)).map(Some)
+    }
+    fn is_closed(&self) -> bool {
+        self.reader.is_closed()
+    }
+    /// Number of items waiting to be read
+    fn __len__(&self) -> usize {
+        self.reader.stats().current_lag
+    }
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+    fn __next__<'py>(&mut self, py: Python<'py>) -> PyResult<Option<Bound<'py, PyBytes>>> {
+        match self.reader.try_read() {
+            Err(ReadError::Closed) => Ok(None),
+            result => Ok(result.map_err(to_py)?.map(|item| PyBytes::new(py, &item))),
+        }
+    }
+}
+
+#[pymodule]
+fn component_model_multiple_consumers(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyPublisher>()?;
+    m.add_class::<PySubscriber>()?;
+    m.add("StreamError", m.py().get_type::<StreamError>())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{PyPublisher, StreamError};
+    use pyo3::{prelude::*, types::PyDict};
+
+    #[test]
+    fn from_python() {
+        Python::initialize();
+        Python::attach(|py| {
+            let locals = PyDict::new(py);
+            locals
+                .set_item("p", Py::new(py, PyPublisher::new()).unwrap())
+                .unwrap();
+            locals
+                .set_item("StreamError", py.get_type::<StreamError>())
+                .unwrap();
+            py.run(
+                cr#"
+import pickle
+
+a = p.subscribe()
+b = p.subscribe()
+assert a.read() is None
+p.publish(b"raw")
+p.publish_object({"x": [1, 2]})
+assert len(a) == 2
+assert a.read() == b"raw"
+assert a.read_object() == {"x": [1, 2]}
+p.close()
+assert list(b) == [b"raw", pickle.dumps({"x": [1, 2]})]
+try:
+    a.read()
+    raise AssertionError
+except StreamError as e:
+    assert "closed" in str(e)
+"#,
+                None,
+                Some(&locals),
+            )
+            .unwrap();
+        });
+    }
+}
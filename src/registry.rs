@@ -0,0 +1,191 @@
+//! Slot map holding the readers of a publisher
+//!
+//! Every entry is addressed by a key combining its slot with a generation
+//! count, so adding and removing a reader takes constant time while a stale
+//! key never finds the reader which later reused the slot.
+
+use alloc::vec::Vec;
+
+/// Number of slots stored inline before spilling onto the heap
+const INLINE: usize = 4;
+
+/// Marks the end of the free list
+const NONE: usize = usize::MAX;
+
+/// Address of an entry, stays unique over the lifetime of the registry
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Key {
+    index: usize,
+    generation: usize,
+}
+
+struct Slot<V> {
+    generation: usize,
+    value: Option<V>,
+    /// next vacant slot while this one is vacant
+    next_free: usize,
+}
+
+impl<V> Slot<V> {
+    fn new() -> Self {
+        Self {
+            generation: 0,
+            value: None,
+            next_free: NONE,
+        }
+    }
+}
+
+/// The first few slots live inside the registry itself, so a publisher
+/// with a handful of readers doesn't allocate for them.
+pub(crate) struct Registry<V> {
+    inline: [Slot<V>; INLINE],
+    spill: Vec<Slot<V>>,
+    /// slots ever handed out, the ones after them were never used
+    used: usize,
+    /// first vacant slot, reused last in first out
+    free: usize,
+    len: usize,
+}
+
+impl<V> Registry<V> {
+    pub(crate) fn new() -> Self {
+        Self {
+            inline: core::array::from_fn(|_| Slot::new()),
+            spill: Vec::new(),
+            used: 0,
+            free: NONE,
+            len: 0,
+        }
+    }
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    /// Bytes of the slots beyond the inline ones
+    pub(crate) fn heap_size(&self) -> usize {
+        self.spill.capacity() * size_of::<Slot<V>>()
+    }
+    fn slot_mut(&mut self, index: usize) -> Option<&mut Slot<V>> {
+        if index >= self.used {
+            None
+        } else if index < INLINE {
+            Some(&mut self.inline[index])
+        } else {
+            self.spill.get_mut(index - INLINE)
+        }
+    }
+    fn slots(&self) -> impl Iterator<Item = &Slot<V>> + Clone {
+        self.inline.iter().chain(self.spill.iter()).take(self.used)
+    }
+    /// The key the next [`insert`](Self::insert) will return
+    pub(crate) fn next_key(&self) -> Key {
+        match self.free {
+            NONE => Key {
+                index: self.used,
+                generation: 0,
+            },
+            index => Key {
+                index,
+                generation: self.slots().nth(index).map_or(0, |slot| slot.generation),
+            },
+        }
+    }
+    pub(crate) fn insert(&mut self, value: V) -> Key {
+        let key = self.next_key();
+        if key.index == self.used {
+            if self.used >= INLINE {
+                self.spill.push(Slot::new());
+            }
+            self.used += 1;
+        }
+        let slot = self.slot_mut(key.index).expect("slot was just handed out");
+        let next_free = core::mem::replace(&mut slot.next_free, NONE);
+        slot.value = Some(value);
+        self.free = next_free;
+        self.len += 1;
+        key
+    }
+    pub(crate) fn get(&self, key: Key) -> Option<&V> {
+        let slot = if key.index >= self.used {
+            None
+        } else if key.index < INLINE {
+            Some(&self.inline[key.index])
+        } else {
+            self.spill.get(key.index - INLINE)
+        };
+        slot.filter(|slot| slot.generation == key.generation)
+            .and_then(|slot| slot.value.as_ref())
+    }
+    pub(crate) fn get_mut(&mut self, key: Key) -> Option<&mut V> {
+        self.slot_mut(key.index)
+            .filter(|slot| slot.generation == key.generation)
+            .and_then(|slot| slot.value.as_mut())
+    }
+    pub(crate) fn remove(&mut self, key: Key) -> Option<V> {
+        let free = self.free;
+        let slot = self
+            .slot_mut(key.index)
+            .filter(|slot| slot.generation == key.generation)?;
+        let value = slot.value.take()?;
+        slot.generation = slot.generation.wrapping_add(1);
+        slot.next_free = free;
+        self.free = key.index;
+        self.len -= 1;
+        Some(value)
+    }
+    /// Remove and return all values
+    pub(crate) fn drain(&mut self) -> impl Iterator<Item = V> + use<V> {
+        let all = core::mem::replace(self, Self::new());
+        all.inline
+            .into_iter()
+            .chain(all.spill)
+            .filter_map(|slot| slot.value)
+    }
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &V> + Clone {
+        self.slots().filter_map(|slot| slot.value.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{INLINE, Registry};
+
+    #[test]
+    fn reuse() {
+        let mut registry = Registry::new();
+        let a = registry.insert('a');
+        let b = registry.insert('b');
+        assert_eq!(registry.remove(a), Some('a'));
+        assert_eq!(registry.remove(a), None);
+        let c = registry.insert('c');
+        assert_ne!(a, c);
+        assert_eq!(registry.get_mut(a), None);
+        assert_eq!(registry.get_mut(c), Some(&mut 'c'));
+        assert_eq!(registry.get(a), None);
+        assert_eq!(registry.get(c), Some(&'c'));
+        registry.remove(b);
+        assert_eq!(registry.get_mut(b), None);
+        assert_ne!(registry.next_key(), b);
+        assert_eq!(registry.iter().collect::<Vec<_>>(), vec![&'c']);
+        assert_eq!(registry.drain().count(), 1);
+        assert!(registry.is_empty());
+    }
+
+    #[test]
+    fn spill() {
+        let mut registry = Registry::new();
+        let keys: Vec<_> = (0..INLINE).map(|n| registry.insert(n)).collect();
+        registry.remove(keys[1]);
+        registry.insert(INLINE);
+        assert_eq!(registry.spill.capacity(), 0);
+        registry.insert(INLINE + 1);
+        assert_eq!(registry.len(), INLINE + 1);
+        assert_eq!(registry.spill.len(), 1);
+        let mut values: Vec<_> = registry.iter().copied().collect();
+        values.sort_unstable();
+        assert_eq!(values, vec![0, 2, 3, 4, 5]);
+    }
+}
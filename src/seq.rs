@@ -0,0 +1,128 @@
+//! Wrap-safe arithmetic on sequence numbers
+//!
+//! Sequence numbers wrap around, with the `counter-u32` feature after about
+//! four billion items. Two of them are compared by their distance modulo
+//! the counter width instead of by value, which gives the right answer as
+//! long as they are less than half the counter range apart. The publishers
+//! keep every sequence number they store within that window.
+
+use crate::Counter;
+
+#[cfg(not(feature = "counter-u32"))]
+type Signed = isize;
+#[cfg(feature = "counter-u32")]
+type Signed = i32;
+
+/// Half the counter range, the largest distance [`precedes`] can tell apart
+#[allow(clippy::unnecessary_cast)]
+pub const WINDOW: usize = (Counter::MAX >> 1) as usize;
+
+/// Items released between two rebases of lagging cursors, a fraction of
+/// [`WINDOW`] so that no stored cursor ever leaves it
+#[cfg(feature = "alloc")]
+pub(crate) const REBASE_INTERVAL: usize = WINDOW / 4;
+
+/// Whether `a` was published before `b`
+pub fn precedes(a: Counter, b: Counter) -> bool {
+    (b.wrapping_sub(a) as Signed) > 0
+}
+
+/// Number of items from `from` up to, but not including, `to`
+///
+/// `to` must not precede `from`.
+#[allow(clippy::unnecessary_cast)]
+pub fn distance(from: Counter, to: Counter) -> usize {
+    to.wrapping_sub(from) as usize
+}
+
+/// The sequence number `n` items after `seq`
+pub fn advance(seq: Counter, n: usize) -> Counter {
+    seq.wrapping_add(n as Counter)
+}
+
+/// Position of `seq` in a power of two sized ring, `mask` is its length minus one
+#[allow(clippy::unnecessary_cast)]
+pub(crate) fn masked(seq: Counter, mask: usize) -> usize {
+    seq as usize & mask
+}
+
+/// The earlier of two sequence numbers
+pub fn earliest(a: Counter, b: Counter) -> Counter {
+    if precedes(b, a) { b } else { a }
+}
+
+/// Unsigned integers which work as sequence numbers
+///
+/// The functions above are for [`Counter`], this lets the fixed publisher
+/// pick a narrower type as well. With a `u8` a tiny buffer spends only a
+/// few bytes per reader, at the price of a capacity of at most a quarter
+/// of the counter range.
+pub trait Sequence: Copy + Eq {
+    const ZERO: Self;
+    /// Half the range of the type, the largest distance
+    /// [`precedes`](Self::precedes) can tell apart
+    const WINDOW: usize;
+    /// Items released between two rebases of lagging cursors
+    const REBASE_INTERVAL: usize = Self::WINDOW / 4;
+    /// Number of items from `self` up to, but not including, `to`
+    fn distance(self, to: Self) -> usize;
+    /// The sequence number `n` items later
+    fn advance(self, n: usize) -> Self;
+    /// Add `n` like a plain number, stopping at the largest value
+    fn saturating_advance(self, n: usize) -> Self;
+    /// Whether `self` was published before `later`
+    fn precedes(self, later: Self) -> bool {
+        (1..=Self::WINDOW).contains(&self.distance(later))
+    }
+}
+
+macro_rules! sequence {
+    ($($t:ty),*) => {$(
+        #[allow(clippy::unnecessary_cast)]
+        impl Sequence for $t {
+            const ZERO: Self = 0;
+            const WINDOW: usize = (<$t>::MAX >> 1) as usize;
+
+            fn distance(self, to: Self) -> usize {
+                to.wrapping_sub(self) as usize
+            }
+            fn advance(self, n: usize) -> Self {
+                self.wrapping_add(n as $t)
+            }
+            fn saturating_advance(self, n: usize) -> Self {
+                <$t>::try_from(n).map_or(<$t>::MAX, |n| self.saturating_add(n))
+            }
+        }
+    )*};
+}
+
+sequence!(u8, u16, u32, usize);
+
+#[cfg(test)]
+mod test {
+    use super::{Sequence, advance, distance, earliest, precedes};
+    use crate::Counter;
+
+    #[test]
+    fn wraps() {
+        let last = Counter::MAX;
+        let next = advance(last, 3);
+        assert_eq!(next, 2);
+        assert!(precedes(last, next));
+        assert!(!precedes(next, last));
+        assert!(!precedes(next, next));
+        assert_eq!(distance(last, next), 3);
+        assert_eq!(earliest(next, last), last);
+    }
+
+    #[test]
+    fn narrow() {
+        assert_eq!(250u8.advance(10), 4);
+        assert_eq!(250u8.distance(4), 10);
+        assert!(250u8.precedes(4));
+        assert!(!4u8.precedes(250));
+        assert_eq!(250u8.saturating_advance(10), u8::MAX);
+        assert_eq!(1u16.saturating_advance(1 << 20), u16::MAX);
+        assert_eq!(<u8 as Sequence>::WINDOW, 127);
+    }
+}
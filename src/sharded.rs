@@ -0,0 +1,150 @@
+//! Thread-safe publisher split into independent shards
+//!
+//! Every item goes to the shard its key hashes to, and each shard is a
+//! [`sync::Publisher`] with a lock and a sequence counter of its own.
+//! Producers publishing different keys from different cores thus rarely
+//! meet, while items with the same key keep their publish order. There is
+//! no order between items of different shards.
+
+use std::hash::{BuildHasher, Hash, RandomState};
+
+use crate::{Arc, Counter, sync};
+
+/// Publisher hashing items by key across a fixed number of shards
+///
+/// Clones use the same hashing, so a key ends up in the same shard no
+/// matter which clone publishes it.
+pub struct ShardedPublisher<T> {
+    shards: Box<[sync::Publisher<T>]>,
+    hasher: RandomState,
+}
+
+impl<T> ShardedPublisher<T> {
+    /// Create a publisher with `shards` shards, at least one
+    pub fn new(shards: usize) -> Self {
+        Self {
+            shards: (0..shards.max(1)).map(|_| sync::Publisher::new()).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+    pub fn shards(&self) -> usize {
+        self.shards.len()
+    }
+    /// The shard items with this key are published to
+    pub fn shard_of<K: Hash + ?Sized>(&self, key: &K) -> usize {
+        (self.hasher.hash_one(key) % self.shards.len() as u64) as usize
+    }
+    /// Publish an item to the shard of `key`, returns the shard and the
+    /// sequence number within it
+    pub fn publish<K: Hash + ?Sized>(&mut self, key: &K, obj: T) -> (usize, Counter) {
+        let shard = self.shard_of(key);
+        (shard, self.shards[shard].publish(obj))
+    }
+    /// Add a reader which only sees the items of one shard
+    ///
+    /// Create one per shard to process the shards in parallel, each on a
+    /// thread of its own.
+    pub fn subscribe_shard(&self, shard: usize) -> sync::StreamReader<T> {
+        self.shards[shard].subscribe()
+    }
+    /// Add a reader which sees the items of all shards
+    pub fn subscribe(&self) -> MergedReader<T> {
+        MergedReader {
+            readers: self.shards.iter().map(sync::Publisher::subscribe).collect(),
+            next: 0,
+        }
+    }
+    /// End the stream of every shard for all clones
+    pub fn close(&mut self) {
+        self.shards.iter_mut().for_each(sync::Publisher::close);
+    }
+}
+
+impl<T> Clone for ShardedPublisher<T> {
+    fn clone(&self) -> Self {
+        Self {
+            shards: self.shards.clone(),
+            hasher: self.hasher.clone(),
+        }
+    }
+}
+
+/// Reader of all shards of a [`ShardedPublisher`]
+///
+/// Takes turns between the shards, so a busy shard doesn't starve the
+/// others.
+pub struct MergedReader<T> {
+    readers: Box<[sync::StreamReader<T>]>,
+    /// shard to look at first on the next read
+    next: usize,
+}
+
+impl<T> MergedReader<T> {
+    /// Borrow the oldest unconsumed item of the next shard which has one
+    pub fn read(&mut self) -> Option<sync::BorrowRead<'_, T>> {
+        let count = self.readers.len();
+        let start = self.next;
+        let shard = (0..count)
+            .map(|n| (start + n) % count)
+            .find(|&shard| !self.readers[shard].is_empty())?;
+        self.next = (shard + 1) % count;
+        self.readers[shard].read()
+    }
+    /// Consume the next item of any shard and keep sharing it
+    pub fn recv_arc(&mut self) -> Option<Arc<T>> {
+        self.read().map(sync::BorrowRead::into_arc)
+    }
+    /// Whether every shard was closed
+    ///
+    /// Items published before that may still be unread.
+    pub fn is_closed(&self) -> bool {
+        self.readers.iter().all(sync::StreamReader::is_closed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ShardedPublisher;
+    use std::thread;
+
+    #[test]
+    fn per_key_order() {
+        let p = ShardedPublisher::new(4);
+        let mut merged = p.subscribe();
+        let producers: Vec<_> = (0..4u32)
+            .map(|t| {
+                let mut p = p.clone();
+                thread::spawn(move || {
+                    for i in 0..25u32 {
+                        p.publish(&t, (t, i));
+                    }
+                })
+            })
+            .collect();
+        for producer in producers {
+            producer.join().unwrap();
+        }
+        let mut items = vec![];
+        while let Some(item) = merged.read() {
+            items.push(*item);
+        }
+        assert_eq!(items.len(), 100);
+        for t in 0..4 {
+            let own: Vec<u32> = items.iter().filter(|v| v.0 == t).map(|v| v.1).collect();
+            assert_eq!(own, (0..25).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn shard_readers() {
+        let mut p = ShardedPublisher::new(2);
+        let readers: Vec<_> = (0..2).map(|s| p.subscribe_shard(s)).collect();
+        let (shard, _) = p.publish("key", 7);
+        assert_eq!(shard, p.shard_of("key"));
+        assert_eq!(*readers[shard].read().unwrap(), 7);
+        assert!(readers[1 - shard].read().is_none());
+        let merged = p.subscribe();
+        p.close();
+        assert!(merged.is_closed());
+    }
+}
@@ -0,0 +1,376 @@
+//! Item storage whose slots never move while they are retained
+//!
+//! Slots live in fixed-size blocks, so growing the buffer only allocates a
+//! new block instead of reallocating and moving the existing items. This is
+//! what allows guards to keep plain references into the buffer while the
+//! publisher keeps on writing.
+//!
+//! The blocks form a power of two sized ring, so a slot is found by masking
+//! instead of handling the wrap around. With a capacity limit the ring
+//! settles at a fixed size and publishing just cycles through its blocks.
+
+use alloc::{boxed::Box, vec::Vec};
+use core::{
+    alloc::Layout,
+    mem::MaybeUninit,
+    ops::{Index, IndexMut, Range},
+    ptr::NonNull,
+};
+
+use crate::{Allocator, SystemAllocator};
+
+/// Number of slots per block, a power of two so positions split by masking
+const BLOCK: usize = 32;
+
+/// Start of `BLOCK` slots obtained from the allocator
+type Block<T> = NonNull<MaybeUninit<T>>;
+
+pub(crate) struct Slots<T> {
+    alloc: Box<dyn Allocator>,
+    /// power of two sized ring of blocks, `blocks` of them starting at
+    /// `first` hold slots and the others are kept for reuse
+    ring: Vec<Option<Block<T>>>,
+    first: usize,
+    blocks: usize,
+    /// position of the first slot within the first block
+    head: usize,
+    len: usize,
+    /// entries of the ring which hold a block
+    allocated: usize,
+}
+
+impl<T> Slots<T> {
+    pub(crate) fn new() -> Self {
+        Self::with_allocator(Box::new(SystemAllocator))
+    }
+    pub(crate) fn with_allocator(alloc: Box<dyn Allocator>) -> Self {
+        Self {
+            alloc,
+            ring: Vec::new(),
+            first: 0,
+            blocks: 0,
+            head: 0,
+            len: 0,
+            allocated: 0,
+        }
+    }
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    /// Number of slots available without allocating
+    pub(crate) fn capacity(&self) -> usize {
+        self.allocated * BLOCK - self.head
+    }
+    /// Bytes of the allocator, boxed as it is chosen at runtime
+    pub(crate) fn allocator_size(&self) -> usize {
+        size_of_val(&*self.alloc)
+    }
+    /// Bytes of all allocated blocks and of the ring pointing to them
+    pub(crate) fn heap_size(&self) -> usize {
+        self.allocated * BLOCK * size_of::<T>()
+            + self.ring.capacity() * size_of::<Option<Block<T>>>()
+    }
+    /// Ring entry of the `n`th block counting from the first one
+    fn entry(&self, n: usize) -> usize {
+        (self.first + n) & (self.ring.len() - 1)
+    }
+    fn block(&self, n: usize) -> &[MaybeUninit<T>] {
+        match self.ring[self.entry(n)] {
+            Some(block) => unsafe { core::slice::from_raw_parts(block.as_ptr(), BLOCK) },
+            None => unreachable!("block {n} not allocated"),
+        }
+    }
+    fn block_mut(&mut self, n: usize) -> &mut [MaybeUninit<T>] {
+        match self.ring[self.entry(n)] {
+            Some(block) => unsafe { core::slice::from_raw_parts_mut(block.as_ptr(), BLOCK) },
+            None => unreachable!("block {n} not allocated"),
+        }
+    }
+    pub(crate) fn get(&self, index: usize) -> Option<&MaybeUninit<T>> {
+        (index < self.len).then(|| &self[index])
+    }
+    pub(crate) fn back(&self) -> Option<&MaybeUninit<T>> {
+        self.len.checked_sub(1).and_then(|index| self.get(index))
+    }
+    pub(crate) fn back_mut(&mut self) -> Option<&mut MaybeUninit<T>> {
+        let index = self.len.checked_sub(1)?;
+        Some(&mut self[index])
+    }
+    /// The slots of a range as contiguous runs, at most one per block
+    pub(crate) fn chunks(&self, range: Range<usize>) -> impl Iterator<Item = &[MaybeUninit<T>]> {
+        assert!(
+            range.end <= self.len,
+            "slot {} out of {}",
+            range.end,
+            self.len
+        );
+        let (start, end) = (self.head + range.start, self.head + range.end);
+        let blocks = if start < end {
+            start / BLOCK..end.div_ceil(BLOCK)
+        } else {
+            0..0
+        };
+        blocks.map(move |n| {
+            let from = start.max(n * BLOCK) - n * BLOCK;
+            let to = end.min((n + 1) * BLOCK) - n * BLOCK;
+            &self.block(n)[from..to]
+        })
+    }
+    pub(crate) fn range(&self, range: Range<usize>) -> impl Iterator<Item = &MaybeUninit<T>> {
+        self.chunks(range).flatten()
+    }
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &MaybeUninit<T>> {
+        self.range(0..self.len)
+    }
+    /// Make sure the block holding the slot after the last one exists
+    fn grow(&mut self) {
+        if (self.head + self.len) / BLOCK == self.blocks {
+            self.add_block();
+            self.blocks += 1;
+        }
+    }
+    /// Provide an allocated block right after the ones in use
+    fn add_block(&mut self) {
+        if self.blocks == self.ring.len() {
+            self.grow_ring(self.blocks + 1);
+        }
+        let next = self.entry(self.blocks);
+        if self.ring[next].is_some() {
+            return;
+        }
+        // take a block kept further along the ring before allocating
+        let spare = (self.blocks + 1..self.ring.len())
+            .map(|n| self.entry(n))
+            .find(|&entry| self.ring[entry].is_some());
+        match spare {
+            Some(entry) => self.ring.swap(next, entry),
+            None => {
+                self.ring[next] = Some(self.new_block());
+                self.allocated += 1;
+            }
+        }
+    }
+    /// Resize the ring to hold at least `entries` blocks, the first one at entry 0
+    fn grow_ring(&mut self, entries: usize) {
+        if !self.try_grow_ring(entries) {
+            alloc::alloc::handle_alloc_error(Layout::new::<Option<Block<T>>>());
+        }
+    }
+    /// Like [`grow_ring`](Self::grow_ring), returns false if there is no
+    /// memory for the larger ring
+    fn try_grow_ring(&mut self, entries: usize) -> bool {
+        let Some(size) = entries.checked_next_power_of_two() else {
+            return false;
+        };
+        let size = size.max(4);
+        if size == self.ring.len() {
+            return true;
+        }
+        let mut ring = Vec::new();
+        if ring.try_reserve_exact(size).is_err() {
+            return false;
+        }
+        ring.extend(core::iter::repeat_with(|| None).take(size));
+        let old = core::mem::take(&mut self.ring);
+        let (first, len) = (self.first, old.len());
+        for (n, block) in old.into_iter().enumerate() {
+            ring[(n + len - first) % len] = block;
+        }
+        self.ring = ring;
+        self.first = 0;
+        true
+    }
+    fn layout() -> Layout {
+        Layout::array::<T>(BLOCK).expect("block size overflows")
+    }
+    fn new_block(&self) -> Block<T> {
+        match self.try_new_block() {
+            Some(block) => block,
+            None => alloc::alloc::handle_alloc_error(Self::layout()),
+        }
+    }
+    fn try_new_block(&self) -> Option<Block<T>> {
+        let layout = Self::layout();
+        if layout.size() == 0 {
+            return Some(NonNull::dangling());
+        }
+        self.alloc.allocate(layout).map(NonNull::cast)
+    }
+    fn free_block(&self, block: Block<T>) {
+        let layout = Self::layout();
+        if layout.size() != 0 {
+            unsafe { self.alloc.deallocate(block.cast(), layout) };
+        }
+    }
+    pub(crate) fn push_back(&mut self, slot: MaybeUninit<T>) {
+        self.grow();
+        self.len += 1;
+        let index = self.len - 1;
+        self[index] = slot;
+    }
+    /// Copy items into the consecutive slots after the last one
+    pub(crate) fn extend_copy(&mut self, mut items: &[T])
+    where
+        T: Copy,
+    {
+        while !items.is_empty() {
+            self.grow();
+            let pos = self.head + self.len;
+            let offset = pos % BLOCK;
+            let n = items.len().min(BLOCK - offset);
+            // MaybeUninit<T> has the layout of T, this allows a plain copy
+            let src = unsafe { &*(&items[..n] as *const [T] as *const [MaybeUninit<T>]) };
+            self.block_mut(pos / BLOCK)[offset..offset + n].copy_from_slice(src);
+            self.len += n;
+            items = &items[n..];
+        }
+    }
+    /// Forget the newest slot, a value in it is not dropped
+    pub(crate) fn pop_back(&mut self) {
+        if self.len > 0 {
+            self.len -= 1;
+            if self.is_empty() {
+                self.recycle();
+            } else if (self.head + self.len).is_multiple_of(BLOCK) {
+                self.blocks -= 1;
+            }
+        }
+    }
+    /// Forget the `count` oldest slots, values in them are not dropped
+    pub(crate) fn discard_front(&mut self, count: usize) {
+        let count = count.min(self.len);
+        self.len -= count;
+        self.head += count;
+        if self.is_empty() {
+            self.recycle();
+            return;
+        }
+        // blocks leaving the front wrap around to be reused at the back
+        let freed = self.head / BLOCK;
+        self.first = self.entry(freed);
+        self.blocks -= freed;
+        self.head %= BLOCK;
+    }
+    fn recycle(&mut self) {
+        self.blocks = 0;
+        self.head = 0;
+    }
+    /// Allocate blocks up front so that `additional` more slots fit
+    pub(crate) fn reserve(&mut self, additional: usize) {
+        if !self.try_reserve(additional) {
+            alloc::alloc::handle_alloc_error(Self::layout());
+        }
+    }
+    /// Like [`reserve`](Self::reserve), returns false if the memory could
+    /// not be allocated or the size overflows
+    pub(crate) fn try_reserve(&mut self, additional: usize) -> bool {
+        let Some(end) = (self.head + self.len).checked_add(additional) else {
+            return false;
+        };
+        let blocks = end.div_ceil(BLOCK);
+        if blocks > self.ring.len() && !self.try_grow_ring(blocks) {
+            return false;
+        }
+        for n in self.blocks..blocks {
+            let entry = self.entry(n);
+            if self.ring[entry].is_none() {
+                let Some(block) = self.try_new_block() else {
+                    return false;
+                };
+                self.ring[entry] = Some(block);
+                self.allocated += 1;
+            }
+        }
+        true
+    }
+    /// Free all blocks which hold no slot
+    pub(crate) fn shrink_to_fit(&mut self) {
+        for n in self.blocks..self.ring.len() {
+            let entry = self.entry(n);
+            if let Some(block) = self.ring[entry].take() {
+                self.free_block(block);
+                self.allocated -= 1;
+            }
+        }
+        if self.blocks == 0 {
+            self.ring = Vec::new();
+            self.first = 0;
+        }
+    }
+}
+
+impl<T> Drop for Slots<T> {
+    fn drop(&mut self) {
+        // values are dropped by the owner, only the blocks are freed here
+        for block in core::mem::take(&mut self.ring).into_iter().flatten() {
+            self.free_block(block);
+        }
+    }
+}
+
+impl<T> Index<usize> for Slots<T> {
+    type Output = MaybeUninit<T>;
+
+    fn index(&self, index: usize) -> &Self::Output {
+        assert!(index < self.len, "slot {index} out of {}", self.len);
+        let pos = self.head + index;
+        &self.block(pos / BLOCK)[pos % BLOCK]
+    }
+}
+
+impl<T> IndexMut<usize> for Slots<T> {
+    fn index_mut(&mut self, index: usize) -> &mut Self::Output {
+        assert!(index < self.len, "slot {index} out of {}", self.len);
+        let pos = self.head + index;
+        &mut self.block_mut(pos / BLOCK)[pos % BLOCK]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{BLOCK, Slots};
+    use std::mem::MaybeUninit;
+
+    #[test]
+    fn stable_addresses() {
+        let mut slots = Slots::new();
+        slots.push_back(MaybeUninit::new(0u32));
+        let first = slots[0].as_ptr();
+        slots.extend_copy(&[1; 3 * BLOCK]);
+        assert_eq!(slots.len(), 3 * BLOCK + 1);
+        assert_eq!(slots[0].as_ptr(), first);
+        slots.discard_front(BLOCK + 1);
+        assert_eq!(unsafe { slots[0].assume_init() }, 1);
+        let capacity = slots.capacity();
+        slots.pop_back();
+        assert_eq!(slots.capacity(), capacity);
+        slots.discard_front(slots.len());
+        assert!(slots.is_empty());
+        slots.shrink_to_fit();
+        assert_eq!(slots.capacity(), 0);
+        slots.reserve(BLOCK + 1);
+        assert_eq!(slots.capacity(), 2 * BLOCK);
+    }
+
+    #[test]
+    fn ring() {
+        let mut slots = Slots::new();
+        slots.extend_copy(&[0u32; 3 * BLOCK]);
+        let ring = slots.ring.len();
+        // cycling through a bounded window reuses the blocks in place
+        for n in 0..10 * BLOCK as u32 {
+            slots.push_back(MaybeUninit::new(n));
+            slots.discard_front(1);
+        }
+        assert_eq!(slots.ring.len(), ring);
+        assert_eq!(slots.capacity() + slots.head, ring * BLOCK);
+        let chunks: Vec<usize> = slots.chunks(1..2 * BLOCK + 1).map(<[_]>::len).collect();
+        assert_eq!(chunks.iter().sum::<usize>(), 2 * BLOCK);
+        assert!(chunks.len() <= 3);
+        let last = unsafe { slots.back().unwrap().assume_init() };
+        assert_eq!(last, 10 * BLOCK as u32 - 1);
+    }
+}
@@ -0,0 +1,106 @@
+//! Checkpoints of a publisher in a file
+//!
+//! [`Publisher::save`] writes the checkpoint of
+//! [`serialize_state`](Publisher::serialize_state) to a file with postcard,
+//! [`Publisher::load`] restores it, e.g. to capture the exact state of a
+//! stream in production and replay it in a test harness. The file starts
+//! with a magic number and a format version, files of other versions are
+//! refused rather than misread.
+
+use std::{fs, io, path::Path};
+
+use postcard::ser_flavors::{AllocVec, Flavor};
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::{Counter, Publisher};
+
+/// First bytes of a snapshot file
+const MAGIC: [u8; 4] = *b"MCSS";
+
+/// Version of the checkpoint layout
+pub const VERSION: u32 = 1;
+
+/// Magic number and version
+const HEADER: usize = 8;
+
+fn invalid(error: postcard::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+impl<T> Publisher<T> {
+    /// Write the checkpoint of the publisher to `path`, replacing the file
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()>
+    where
+        T: Serialize,
+    {
+        let mut output = AllocVec::new();
+        output.try_extend(&MAGIC).map_err(invalid)?;
+        output.try_extend(&VERSION.to_le_bytes()).map_err(invalid)?;
+        let mut serializer = postcard::Serializer { output };
+        self.serialize_state(&mut serializer).map_err(invalid)?;
+        fs::write(path, serializer.output.finalize().map_err(invalid)?)
+    }
+    /// Rebuild a publisher from a file written by [`save`](Self::save)
+    ///
+    /// Returns the saved reader cursors like
+    /// [`restore_state`](Self::restore_state). Fails with
+    /// [`InvalidData`](io::ErrorKind::InvalidData) if the file is not a
+    /// snapshot of this version.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<(Self, Vec<Counter>)>
+    where
+        T: DeserializeOwned,
+    {
+        let bytes = fs::read(path)?;
+        if bytes.len() < HEADER || bytes[..4] != MAGIC || bytes[4..HEADER] != VERSION.to_le_bytes()
+        {
+            return Err(io::ErrorKind::InvalidData.into());
+        }
+        let mut deserializer = postcard::Deserializer::from_bytes(&bytes[HEADER..]);
+        Self::restore_state(&mut deserializer).map_err(invalid)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{Publisher, StreamReader};
+    use std::{fs, io, path::PathBuf};
+
+    fn path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("mc-snapshot-{}-{name}", std::process::id()))
+    }
+
+    #[test]
+    fn round_trip() {
+        let path = path("round-trip");
+        let mut p: Publisher<String> = Publisher::new();
+        let mut r = StreamReader::new();
+        p.add_stream_reader(&mut r);
+        for word in ["one", "two", "three"] {
+            p.publish(word.into());
+        }
+        drop(r.read());
+        p.close();
+        p.save(&path).unwrap();
+
+        let (mut p, cursors) = Publisher::<String>::load(&path).unwrap();
+        assert_eq!(cursors, [1]);
+        assert_eq!(p.retained(), 1..3);
+        let mut r = StreamReader::new();
+        p.subscribe_from(&mut r, cursors[0]);
+        assert_eq!(r.snapshot(), ["two", "three"]);
+        assert!(r.is_closed());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn refuses_other_versions() {
+        let path = path("version");
+        Publisher::<u32>::new().save(&path).unwrap();
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[4] += 1;
+        fs::write(&path, bytes).unwrap();
+        let error = Publisher::<u32>::load(&path).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,4507 @@
+//! Single-threaded publisher and its readers
+//!
+//! Retained items live in heap blocks which never move, readers keep their
+//! backlog on the heap as well, so this needs the `alloc` feature.
+//!
+//! Operations which panic on misuse or abort when out of memory have a
+//! `try_` counterpart returning a [`PublishError`] instead, e.g.
+//! [`Publisher::try_reserve`] followed by [`Publisher::try_publish`] for
+//! firmware which must not panic.
+
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, BinaryHeap, VecDeque},
+    rc::Rc,
+    string::String,
+    vec,
+    vec::Vec,
+};
+use core::{
+    alloc::Layout,
+    any::Any,
+    cell::{Cell, RefCell},
+    cmp::Reverse,
+    fmt,
+    future::Future,
+    marker::PhantomData,
+    mem::MaybeUninit,
+    ops::{Deref, DerefMut, Index, IndexMut, Range},
+    pin::Pin,
+    ptr::NonNull,
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+#[cfg(feature = "std")]
+use std::{
+    io::{self, IoSlice},
+    panic::{AssertUnwindSafe, catch_unwind},
+};
+
+use crate::{
+    Arc, Counter, Full, Instant, PublishError, ReadError,
+    backlog::{Backlog, Fronts},
+    registry::{Key, Registry},
+    seq,
+    slots::Slots,
+};
+#[cfg(not(feature = "portable-atomic"))]
+use core::sync::atomic::{AtomicU32, Ordering};
+#[cfg(feature = "portable-atomic")]
+use portable_atomic::{AtomicU32, Ordering};
+
+/// Subscriber information
+struct ConsumerInfo<T> {
+    reader: *mut StreamReader<T>,
+    /// identifies the reader even after it was moved
+    id: Key,
+    /// index into the publisher's consumer groups
+    group: Option<usize>,
+}
+
+impl<T> ConsumerInfo<T> {
+    fn from_reader(r: &mut StreamReader<T>) -> Self {
+        Self {
+            reader: r as *mut StreamReader<T>,
+            id: r.id,
+            group: None,
+        }
+    }
+}
+
+/// Readers sharing the work of a single logical consumer
+struct ConsumerGroup {
+    name: String,
+    /// round robin position among the members
+    next: usize,
+    /// the members which can be handed work, weak ones can't
+    members: Vec<Key>,
+}
+
+/// How the publisher distributes items among its standalone readers
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Dispatch {
+    /// Every reader sees every item
+    #[default]
+    Broadcast,
+    /// Each item goes to exactly one reader, taking turns
+    RoundRobin,
+    /// Each item goes to the reader with the shortest backlog
+    LeastLoaded,
+}
+
+/// Source of the time used for timestamps, idle timeouts and dedup windows
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+/// The monotonic system clock, used by default
+#[cfg(feature = "std")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+#[cfg(feature = "std")]
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Source of the memory holding the slots of a publisher
+///
+/// Lets the embedder place the buffer in an arena, a pinned region or
+/// wherever its platform wants it, see [`Publisher::with_allocator`].
+pub trait Allocator {
+    /// Memory for `layout`, `None` if it is exhausted
+    fn allocate(&self, layout: Layout) -> Option<NonNull<u8>>;
+    /// Give back memory from [`allocate`](Self::allocate) with the same layout
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been returned by this allocator for `layout`.
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout);
+}
+
+/// The global allocator, used by default
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemAllocator;
+
+impl Allocator for SystemAllocator {
+    fn allocate(&self, layout: Layout) -> Option<NonNull<u8>> {
+        NonNull::new(unsafe { alloc::alloc::alloc(layout) })
+    }
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        unsafe { alloc::alloc::dealloc(ptr.as_ptr(), layout) }
+    }
+}
+
+/// Clock which only moves when told to, for tests
+///
+/// Clones share the same time, so a test can keep one to advance it.
+/// Without `std` it is the default clock, standing still at the start.
+#[derive(Clone, Debug)]
+pub struct ManualClock {
+    now: Rc<Cell<Instant>>,
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        Self {
+            now: Rc::new(Cell::new(start_time())),
+        }
+    }
+    pub fn advance(&self, by: Duration) {
+        self.now.set(self.now.get() + by);
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.now.get()
+    }
+}
+
+/// Time to start from when no clock is at hand
+fn start_time() -> Instant {
+    #[cfg(feature = "std")]
+    return Instant::now();
+    #[cfg(not(feature = "std"))]
+    return Instant::default();
+}
+
+/// The clock a publisher uses unless told otherwise
+fn default_clock() -> Box<dyn Clock> {
+    #[cfg(feature = "std")]
+    return Box::new(SystemClock);
+    #[cfg(not(feature = "std"))]
+    return Box::new(ManualClock::new());
+}
+
+/// How far back [`Publisher::set_dedup`] looks for an equal item
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DedupWindow {
+    /// Within this many preceding sequence numbers
+    Items(usize),
+    /// Published no longer than this ago
+    Duration(Duration),
+}
+
+/// Handle to a callback registered with [`Publisher::subscribe_with`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CallbackId(usize);
+
+/// Push-style subscription, invoked synchronously on publish
+struct Callback<T> {
+    id: CallbackId,
+    f: Box<dyn FnMut(&T)>,
+}
+
+/// Delivery state shared by publish and finish
+struct Routing<T> {
+    dispatch: Dispatch,
+    /// round robin position among the standalone readers
+    next: usize,
+    groups: Vec<ConsumerGroup>,
+    callbacks: Vec<Callback<T>>,
+    next_callback: usize,
+    /// readers holding back a notification until the end of a batch
+    wakeups: Vec<Key>,
+    /// standalone readers which get items queued, because of a filter or
+    /// because they share the work
+    queued: Vec<Key>,
+    /// the other readers with a notification, they follow their cursor
+    waiters: Vec<Key>,
+    /// readers with an idle timeout
+    timed: Vec<Key>,
+}
+
+impl<T> Routing<T> {
+    /// Forget a reader which left
+    fn delist(&mut self, key: Key) {
+        for list in [&mut self.queued, &mut self.waiters, &mut self.timed]
+            .into_iter()
+            .chain(self.groups.iter_mut().map(|g| &mut g.members))
+        {
+            list.retain(|k| *k != key);
+        }
+    }
+    /// Run the callbacks on a published item
+    ///
+    /// Called once the publisher is consistent again, without `std` a
+    /// panicking callback unwinds through the publish call.
+    fn call_back(&mut self, obj: &T) {
+        // a panicking callback is unsubscribed, the others still run
+        #[cfg(feature = "std")]
+        self.callbacks
+            .retain_mut(|c| catch_unwind(AssertUnwindSafe(|| (c.f)(obj))).is_ok());
+        #[cfg(not(feature = "std"))]
+        self.callbacks.iter_mut().for_each(|c| (c.f)(obj));
+    }
+}
+
+/// Add a key unless it is listed already
+fn enlist(list: &mut Vec<Key>, key: Key) {
+    if !list.contains(&key) {
+        list.push(key);
+    }
+}
+
+/// Select the reader which receives the next item out of `keys`
+fn pick<'a, T>(
+    keys: &[Key],
+    readers: &'a Registry<ConsumerInfo<T>>,
+    interested: impl Fn(&&'a ConsumerInfo<T>) -> bool,
+    dispatch: Dispatch,
+    next: &mut usize,
+) -> Option<&'a ConsumerInfo<T>> {
+    match dispatch {
+        Dispatch::LeastLoaded => keys
+            .iter()
+            .filter_map(|key| readers.get(*key))
+            .filter(interested)
+            .min_by_key(|i| unsafe { &*i.reader }.unread.len()),
+        _ => {
+            // take turns, passing over the readers which reject the item
+            let len = keys.len();
+            let (n, chosen) = (0..len)
+                .map(|n| next.wrapping_add(n) % len)
+                .filter_map(|n| Some((n, readers.get(keys[n])?)))
+                .find(|(_, i)| interested(i))?;
+            *next = n + 1;
+            Some(chosen)
+        }
+    }
+}
+
+/// Queue an item on a reader, noting it for a later wakeup if held back
+fn hand_over<T>(i: &ConsumerInfo<T>, count: Counter, notify: bool, wakeups: &mut Vec<Key>) {
+    if unsafe { &mut *i.reader }.new_data(count, notify) {
+        wakeups.push(i.id);
+    }
+}
+
+/// Hand a freshly published item to the readers which get items queued and
+/// wake the ones which follow their cursor
+///
+/// Readers which see every item and asked for no notification aren't visited.
+fn deliver<T>(
+    readers: &Registry<ConsumerInfo<T>>,
+    routing: &mut Routing<T>,
+    data: &MaybeUninit<T>,
+    count: Counter,
+    notify: bool,
+) {
+    #[cfg(feature = "tracing")]
+    tracing::trace!(seq = count, "deliver");
+    let obj = unsafe { data.assume_init_ref() };
+    let interested = |i: &&ConsumerInfo<T>| unsafe { &*i.reader }.accepts(obj);
+    if routing.dispatch == Dispatch::Broadcast {
+        let queued = routing.queued.iter().filter_map(|key| readers.get(*key));
+        for i in queued.filter(interested) {
+            hand_over(i, count, notify, &mut routing.wakeups);
+        }
+    } else if let Some(i) = pick(
+        &routing.queued,
+        readers,
+        interested,
+        routing.dispatch,
+        &mut routing.next,
+    ) {
+        hand_over(i, count, notify, &mut routing.wakeups);
+    }
+    for g in routing.groups.iter_mut() {
+        let chosen = pick(
+            &g.members,
+            readers,
+            interested,
+            Dispatch::RoundRobin,
+            &mut g.next,
+        );
+        if let Some(i) = chosen {
+            hand_over(i, count, notify, &mut routing.wakeups);
+        }
+    }
+    let waiters = routing.waiters.iter().filter_map(|key| readers.get(*key));
+    for i in waiters.filter(interested) {
+        if unsafe { &mut *i.reader }.signal(notify) {
+            routing.wakeups.push(i.id);
+        }
+    }
+}
+
+/// Reader-side lock into data
+pub struct BorrowRead<'a, T> {
+    obj: &'a T,
+    reader: &'a mut StreamReader<T>,
+    counter: Counter,
+}
+
+impl<'a, T> BorrowRead<'a, T> {
+    /// Sequence number assigned to this item on publish
+    pub fn sequence(&self) -> Counter {
+        self.counter
+    }
+    /// Publish time of this item, if the publisher records timestamps
+    pub fn timestamp(&self) -> Option<Instant> {
+        if self.reader.source.is_null() {
+            return None;
+        }
+        unsafe { &*self.reader.source }.timestamp(self.counter)
+    }
+    /// Metadata attached via [`Publisher::publish_with_meta`]
+    pub fn metadata(&self) -> Option<&Metadata> {
+        if self.reader.source.is_null() {
+            return None;
+        }
+        unsafe { &*self.reader.source }.metadata(self.counter)
+    }
+}
+
+impl<'a, U: ?Sized> BorrowRead<'a, Box<U>> {
+    /// Borrow the boxed contents, e.g. a `dyn Trait` or `[u8]`
+    pub fn contents(&self) -> &U {
+        self.obj
+    }
+}
+
+impl<'a> BorrowRead<'a, Box<dyn Any>> {
+    /// Borrow the contents if they are of type `U`
+    pub fn downcast_ref<U: Any>(&self) -> Option<&U> {
+        self.obj.downcast_ref()
+    }
+}
+
+impl<'a, T> Deref for BorrowRead<'a, T> {
+    type Target = T;
+
+    fn deref(&'_ self) -> &'_ Self::Target {
+        self.obj
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl<'a, T: defmt::Format> defmt::Format for BorrowRead<'a, T> {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        (**self).format(f)
+    }
+}
+
+impl<'a, T> Drop for BorrowRead<'a, T> {
+    fn drop(&mut self) {
+        self.reader.consume_front();
+    }
+}
+
+/// Reader-side lock into consecutive items, see [`StreamReader::read_chunk`]
+pub struct ChunkRead<'a, T> {
+    reader: &'a mut StreamReader<T>,
+    first: Counter,
+    len: usize,
+}
+
+impl<'a, T> ChunkRead<'a, T> {
+    /// Sequence number of the first item
+    pub fn sequence(&self) -> Counter {
+        self.first
+    }
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    /// The items as contiguous slices, several if the chunk spans storage blocks
+    pub fn slices(&self) -> impl Iterator<Item = &[T]> {
+        let source = unsafe { self.reader.source.as_ref() };
+        source.into_iter().flat_map(|source| {
+            let start = seq::distance(source.first_count, self.first);
+            source
+                .data
+                .chunks(start..start + self.len)
+                // every slot of the chunk is published and initialized
+                .map(|slots| unsafe { &*(slots as *const [MaybeUninit<T>] as *const [T]) })
+        })
+    }
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.slices().flatten()
+    }
+}
+
+impl<'a, T> Drop for ChunkRead<'a, T> {
+    fn drop(&mut self) {
+        let len = self.len;
+        self.reader.take_front(len);
+        self.reader.borrowed = 0;
+        self.reader.stats.read += len as u64;
+        if self.reader.idle_timeout.is_some() {
+            self.reader.idle_since = Some(self.reader.now());
+        }
+        if !self.reader.source.is_null() {
+            unsafe { &mut *self.reader.source }.read_done();
+        }
+    }
+}
+
+/// One of several simultaneous locks into data, see [`StreamReader::read_window`]
+pub struct WindowRead<'a, T> {
+    obj: &'a T,
+    reader: *mut StreamReader<T>,
+    counter: Counter,
+    phantom: PhantomData<&'a mut StreamReader<T>>,
+}
+
+impl<'a, T> Deref for WindowRead<'a, T> {
+    type Target = T;
+
+    fn deref(&'_ self) -> &'_ Self::Target {
+        self.obj
+    }
+}
+
+impl<'a, T> Drop for WindowRead<'a, T> {
+    fn drop(&mut self) {
+        let reader = unsafe { &mut *self.reader };
+        if reader.with_unread(|unread| unread.remove(self.counter)) {
+            reader.borrowed -= 1;
+            reader.stats.read += 1;
+        }
+        if reader.idle_timeout.is_some() {
+            reader.idle_since = Some(reader.now());
+        }
+        if !reader.source.is_null() {
+            unsafe { &mut *reader.source }.read_done();
+        }
+    }
+}
+
+/// Write lock into data
+pub struct BorrowWrite<'a, T> {
+    obj: &'a mut MaybeUninit<T>,
+    writer: &'a mut Publisher<T>,
+    newcount: Counter,
+    written: bool,
+}
+
+impl<'a, T> Deref for BorrowWrite<'a, T> {
+    type Target = MaybeUninit<T>;
+
+    fn deref(&'_ self) -> &'_ Self::Target {
+        self.obj
+    }
+}
+
+impl<'a, T> DerefMut for BorrowWrite<'a, T> {
+    fn deref_mut(&'_ mut self) -> &'_ mut Self::Target {
+        self.obj
+    }
+}
+
+impl<'a, T> Drop for BorrowWrite<'a, T> {
+    fn drop(&mut self) {
+        if !self.written {
+            self.release_slot();
+        }
+    }
+}
+
+impl<'a, T> BorrowWrite<'a, T> {
+    fn release_slot(&mut self) {
+        // the publisher stays borrowed by this guard, so its slot is the newest
+        self.writer.data.pop_back();
+        self.writer.pending -= 1;
+        self.written = true;
+    }
+    /// Give the slot back without publishing anything
+    ///
+    /// Readers never learn about the abandoned slot and the next allocation
+    /// reuses its sequence number. A value already written into the slot is
+    /// not dropped.
+    pub fn cancel(mut self) {
+        self.release_slot();
+    }
+    /// Publish the item, returns its sequence number
+    pub fn finish(mut self) -> Counter {
+        self.writer.expire_idle();
+        self.writer.pending -= 1;
+        self.written = true;
+        self.writer.stamp(1);
+        deliver(
+            &self.writer.readers,
+            &mut self.writer.routing,
+            self.obj,
+            self.newcount,
+            self.writer.bursts == 0,
+        );
+        let obj = unsafe { self.obj.assume_init_ref() };
+        self.writer.routing.call_back(obj);
+        self.newcount
+    }
+}
+
+/// Write lock into a contiguous run of slots, see [`Publisher::allocate_many`]
+pub struct BorrowWriteMany<'a, T> {
+    writer: &'a mut Publisher<T>,
+    newcount: Counter,
+    len: usize,
+    written: bool,
+}
+
+impl<'a, T> BorrowWriteMany<'a, T> {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    fn index(&self, n: usize) -> usize {
+        assert!(n < self.len, "slot {n} out of {} allocated", self.len);
+        seq::distance(self.writer.first_count, seq::advance(self.newcount, n))
+    }
+    /// Slot `n`, `None` if it is outside of the run instead of panicking
+    /// like indexing does
+    pub fn get_mut(&mut self, n: usize) -> Option<&mut MaybeUninit<T>> {
+        (n < self.len).then(|| &mut self[n])
+    }
+    /// Publish the first `count` slots, which must be initialized, and
+    /// release the remaining ones
+    pub fn finish(mut self, count: usize) {
+        let count = count.min(self.len);
+        let writer = &mut *self.writer;
+        writer.expire_idle();
+        for _ in count..self.len {
+            writer.data.pop_back();
+        }
+        writer.pending -= self.len;
+        self.written = true;
+        writer.stamp(count);
+        let first = seq::distance(writer.first_count, self.newcount);
+        for (n, data) in writer.data.range(first..first + count).enumerate() {
+            let count = seq::advance(self.newcount, n);
+            deliver(&writer.readers, &mut writer.routing, data, count, false);
+        }
+        writer.flush_notifications();
+        writer.call_back(first..first + count);
+    }
+}
+
+impl<'a, T> Index<usize> for BorrowWriteMany<'a, T> {
+    type Output = MaybeUninit<T>;
+
+    fn index(&self, n: usize) -> &Self::Output {
+        &self.writer.data[self.index(n)]
+    }
+}
+
+impl<'a, T> IndexMut<usize> for BorrowWriteMany<'a, T> {
+    fn index_mut(&mut self, n: usize) -> &mut Self::Output {
+        let index = self.index(n);
+        &mut self.writer.data[index]
+    }
+}
+
+impl<'a, T> Drop for BorrowWriteMany<'a, T> {
+    fn drop(&mut self) {
+        if !self.written {
+            for _ in 0..self.len {
+                self.writer.data.pop_back();
+            }
+            self.writer.pending -= self.len;
+        }
+    }
+}
+
+/// Future resolving once the readers are done with an item, see
+/// [`Publisher::wait_until_consumed`]
+pub struct Consumed<'a, T> {
+    writer: &'a mut Publisher<T>,
+    seq: Counter,
+}
+
+impl<'a, T> Future for Consumed<'a, T> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let seq = self.seq;
+        if self.writer.is_consumed(seq) {
+            return Poll::Ready(());
+        }
+        self.writer.fences.push((seq, cx.waker().clone()));
+        Poll::Pending
+    }
+}
+
+/// Claim on a slot which may be completed out of order, see [`Publisher::claim`]
+#[must_use = "the stream stalls until every claimed slot is completed"]
+#[derive(Debug, PartialEq, Eq)]
+pub struct Ticket {
+    seq: Counter,
+    publisher: u32,
+    generation: u32,
+}
+
+impl Ticket {
+    /// Sequence number the item will get
+    pub fn sequence(&self) -> Counter {
+        self.seq
+    }
+}
+
+/// Source of the publisher ids which tickets are checked against
+static PUBLISHERS: AtomicU32 = AtomicU32::new(0);
+
+/// Batch of items which become visible together, see [`Publisher::transaction`]
+pub struct Transaction<'a, T> {
+    writer: &'a mut Publisher<T>,
+    staged: Vec<T>,
+}
+
+impl<'a, T> Transaction<'a, T> {
+    /// Stage an item, readers don't see it before the commit
+    pub fn push(&mut self, obj: T) {
+        self.staged.push(obj);
+    }
+    pub fn len(&self) -> usize {
+        self.staged.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.staged.is_empty()
+    }
+    /// Publish all staged items at once
+    pub fn commit(mut self) {
+        let staged = core::mem::take(&mut self.staged);
+        self.writer.publish_iter(staged);
+    }
+    /// Drop all staged items, the same happens when the transaction is dropped
+    pub fn rollback(self) {}
+}
+
+/// Publisher access with coalesced notifications, see [`Publisher::burst`]
+pub struct Burst<'a, T> {
+    writer: &'a mut Publisher<T>,
+}
+
+impl<'a, T> Deref for Burst<'a, T> {
+    type Target = Publisher<T>;
+
+    fn deref(&self) -> &Self::Target {
+        self.writer
+    }
+}
+
+impl<'a, T> DerefMut for Burst<'a, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.writer
+    }
+}
+
+impl<'a, T> Drop for Burst<'a, T> {
+    fn drop(&mut self) {
+        self.writer.bursts -= 1;
+        self.writer.flush_notifications();
+    }
+}
+
+type Filter<T> = Box<dyn Fn(&T) -> bool>;
+/// Given an item and its prospective sequence number, finds an earlier duplicate
+type Dedup<T> = Box<dyn FnMut(&T, Counter, Instant) -> Option<Counter>>;
+
+/// What compaction made of a retained item
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Keyed {
+    Latest,
+    /// a newer item has the same key
+    Superseded,
+    /// superseded and dropped, the slot stays until the items before it go
+    Evicted,
+}
+
+/// Latest position per key, with the key type erased
+trait LatestByKey<T> {
+    /// Record `position` as the latest for the key of `item`, returns the
+    /// position it replaces
+    fn replace(&mut self, item: &T, position: u64) -> Option<u64>;
+    /// Forget the key of `item` if `position` is still its latest
+    fn forget(&mut self, item: &T, position: u64);
+    fn heap_size(&self) -> usize;
+}
+
+struct KeyMap<F, K> {
+    key: F,
+    latest: BTreeMap<K, u64>,
+}
+
+impl<T, K: Ord, F: Fn(&T) -> K> LatestByKey<T> for KeyMap<F, K> {
+    fn replace(&mut self, item: &T, position: u64) -> Option<u64> {
+        self.latest.insert((self.key)(item), position)
+    }
+    fn forget(&mut self, item: &T, position: u64) {
+        let key = (self.key)(item);
+        if self.latest.get(&key) == Some(&position) {
+            self.latest.remove(&key);
+        }
+    }
+    fn heap_size(&self) -> usize {
+        self.latest.len() * size_of::<(K, u64)>()
+    }
+}
+
+/// Retention of only the latest item per key, see [`Publisher::set_compaction`]
+///
+/// Positions count the items published since compaction was enabled.
+struct Compaction<T> {
+    latest: Box<dyn LatestByKey<T>>,
+    /// per published item
+    keyed: VecDeque<Keyed>,
+    /// position of the oldest retained item
+    first: u64,
+    /// positions of the superseded items which aren't evicted yet
+    superseded: BinaryHeap<Reverse<u64>>,
+}
+
+impl<T> Compaction<T> {
+    fn is_evicted(&self, index: usize) -> bool {
+        self.keyed[index] == Keyed::Evicted
+    }
+}
+
+/// Drop a retained item, or keep it for reuse while there is room
+fn dispose<T>(slot: &mut MaybeUninit<T>, recycled: &mut Vec<T>, recycle_max: usize) {
+    if recycled.len() < recycle_max {
+        recycled.push(unsafe { slot.assume_init_read() });
+    } else {
+        unsafe { slot.assume_init_drop() };
+    }
+}
+
+/// Context carried alongside a payload, see [`Publisher::publish_with_meta`]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Metadata {
+    pub headers: Vec<(String, String)>,
+    pub trace_id: Option<u128>,
+    pub origin: Option<String>,
+}
+
+impl Metadata {
+    /// Bytes of the boxed metadata and the strings it owns
+    fn heap_size(&self) -> usize {
+        size_of::<Self>()
+            + self.headers.capacity() * size_of::<(String, String)>()
+            + self
+                .headers
+                .iter()
+                .map(|(k, v)| k.capacity() + v.capacity())
+                .sum::<usize>()
+            + self.origin.as_ref().map_or(0, String::capacity)
+    }
+}
+
+/// Counters describing the progress of a single reader
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ReaderStats {
+    /// items queued for this reader
+    pub delivered: u64,
+    /// items read to completion
+    pub read: u64,
+    /// items discarded without being read
+    pub missed: u64,
+    /// largest backlog seen so far
+    pub max_lag: usize,
+    /// items currently waiting to be read
+    pub current_lag: usize,
+}
+
+/// Bytes held by a publisher, see [`Publisher::memory_usage`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct MemoryUsage {
+    /// the publisher itself with its clock and allocator, and the first few
+    /// reader registrations which live inline
+    pub publisher: usize,
+    /// item storage, every allocated slot whether in use or not
+    pub slots: usize,
+    /// registered readers with their queued sequence numbers, filters and
+    /// notifiers
+    pub readers: usize,
+    /// timestamps, metadata, pending claims, recycled items and the other
+    /// bookkeeping kept next to the items
+    pub queues: usize,
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> usize {
+        self.publisher + self.slots + self.readers + self.queues
+    }
+}
+
+/// Layout of a publisher and the position of its readers, see
+/// [`Publisher::dump`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Dump {
+    /// sequence numbers of the retained published items
+    pub retained: Range<Counter>,
+    /// slots after them which are allocated or claimed but not finished
+    pub pending: usize,
+    /// slots available without allocating, in use or not
+    pub slots: usize,
+    pub limit: Option<usize>,
+    pub closed: bool,
+    /// in the order the readers were added
+    pub readers: Vec<ReaderDump>,
+}
+
+/// One reader of a [`Dump`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReaderDump {
+    /// sequence number of the next item it reads, or of the next one to be
+    /// published if nothing is queued
+    pub cursor: Counter,
+    /// items queued for it
+    pub unread: usize,
+    /// queued items currently lent out to guards
+    pub borrowed: usize,
+    pub weak: bool,
+    pub priority: i32,
+    pub error: Option<ReadError>,
+    pub stats: ReaderStats,
+}
+
+/// Consumer object
+pub struct StreamReader<T> {
+    phantom: PhantomData<T>,
+    source: *mut Publisher<T>,
+    /// sequence numbers of the items still to read, the front entry stays
+    /// queued while it is borrowed; a reader following its cursor only
+    /// queues the items of a window here
+    unread: Backlog,
+    /// number of leading entries currently lent out to guards, for a reader
+    /// following its cursor with nothing queued the items from the cursor
+    borrowed: usize,
+    /// items are queued on publish, because of a filter or because the
+    /// reader shares the work; the others follow `cursor`
+    queued: bool,
+    /// assigned by the publisher on registration
+    id: Key,
+    /// overrun readers lose their oldest items first
+    priority: i32,
+    /// items lost to overruns since the last `try_read`
+    lagged: u64,
+    /// weak readers only queue the item they currently borrow
+    weak: bool,
+    /// next item to look at, for weak readers and the ones following it
+    cursor: Counter,
+    /// end of the stream when the reader subscribed, the items before it
+    /// which compaction superseded are skipped
+    joined: Counter,
+    notifier: Option<Box<dyn Fn()>>,
+    notify_pending: bool,
+    filter: Option<Filter<T>>,
+    idle_timeout: Option<Duration>,
+    /// last read, or when the backlog started waiting
+    idle_since: Option<Instant>,
+    error: Option<ReadError>,
+    /// the publisher was cleared since the last `try_read`
+    reset: bool,
+    stats: ReaderStats,
+}
+
+impl<T> StreamReader<T> {
+    /// Queue an item, with `notify` false the notification is only noted
+    ///
+    /// Returns true when a notification was newly held back.
+    fn new_data(&mut self, count: Counter, notify: bool) -> bool {
+        if self.idle_timeout.is_some() && self.unread.is_empty() {
+            self.idle_since = Some(self.now());
+        }
+        self.with_unread(|unread| unread.push(count));
+        self.stats.delivered += 1;
+        self.stats.max_lag = self.stats.max_lag.max(self.unread.len());
+        self.signal(notify)
+    }
+    /// Queue `len` consecutive items at once, the notification is only noted
+    fn new_run(&mut self, count: Counter, len: usize) -> bool {
+        if self.idle_timeout.is_some() && self.unread.is_empty() {
+            self.idle_since = Some(self.now());
+        }
+        self.with_unread(|unread| unread.push_run(count, len));
+        self.stats.delivered += len as u64;
+        self.stats.max_lag = self.stats.max_lag.max(self.unread.len());
+        self.signal(false)
+    }
+    /// Notify about a new item, with `notify` false the notification is only noted
+    ///
+    /// Returns true when a notification was newly held back.
+    fn signal(&mut self, notify: bool) -> bool {
+        if notify {
+            if let Some(notifier) = &self.notifier {
+                notifier();
+            }
+            false
+        } else {
+            self.notifier.is_some() && !core::mem::replace(&mut self.notify_pending, true)
+        }
+    }
+    /// Deliver a notification held back while items were queued in bulk
+    fn flush_notification(&mut self) {
+        if core::mem::take(&mut self.notify_pending)
+            && let Some(notifier) = &self.notifier
+        {
+            notifier();
+        }
+    }
+    /// Whether this reader follows its cursor instead of having items queued
+    fn follows(&self) -> bool {
+        !self.queued && !self.weak
+    }
+    /// Oldest item this reader keeps retained
+    fn pin(&self) -> Option<Counter> {
+        self.unread
+            .front()
+            .or_else(|| self.follows().then_some(self.cursor))
+    }
+    /// Modify the backlog and let the publisher track where it starts now
+    fn with_unread<R>(&mut self, f: impl FnOnce(&mut Backlog) -> R) -> R {
+        self.pinned(|reader| f(&mut reader.unread))
+    }
+    /// Modify the reader and let the publisher track what it retains now
+    fn pinned<R>(&mut self, f: impl FnOnce(&mut Self) -> R) -> R {
+        let before = self.pin();
+        let result = f(self);
+        let after = self.pin();
+        if before != after && !self.source.is_null() {
+            let source = unsafe { &mut *self.source };
+            if let Some(count) = before {
+                source.fronts.remove(count);
+            }
+            if let Some(count) = after {
+                source.fronts.insert(count);
+            }
+        }
+        result
+    }
+    /// Borrow a queued item from the publisher
+    ///
+    /// Queued items stay retained and their slots never move, so the
+    /// reference is valid until the entry is removed from the backlog.
+    fn item<'b>(&self, count: Counter) -> &'b T {
+        let source = unsafe { &*self.source };
+        let slot = source.slot(count).expect("queued item is retained");
+        unsafe { &*slot.as_ptr() }
+    }
+    /// Current time according to the publisher's clock
+    fn now(&self) -> Instant {
+        if self.source.is_null() {
+            start_time()
+        } else {
+            unsafe { &*self.source }.clock.now()
+        }
+    }
+    /// Number of items waiting to be read
+    fn lag(&self) -> usize {
+        let ahead = match unsafe { self.source.as_ref() } {
+            Some(source) if self.follows() => seq::distance(self.cursor, source.retained().end),
+            _ => 0,
+        };
+        self.unread.len() + ahead
+    }
+    /// Move the cursor of a following reader, the items passed count as delivered
+    fn advance(&mut self, cursor: Counter) {
+        self.stats.delivered += seq::distance(self.cursor, cursor) as u64;
+        self.pinned(|reader| reader.cursor = cursor);
+    }
+    /// Move a following reader past the items it doesn't see and return
+    /// the next one it reads
+    fn next(&mut self) -> Option<Counter> {
+        let source = unsafe { self.source.as_ref()? };
+        let end = source.retained().end;
+        let mut cursor = self.cursor;
+        while cursor != end && source.hides(cursor, self.joined) {
+            cursor = cursor.wrapping_add(1);
+        }
+        self.pinned(|reader| reader.cursor = cursor);
+        let lag = seq::distance(cursor, end);
+        self.stats.max_lag = self.stats.max_lag.max(lag);
+        (lag > 0).then_some(cursor)
+    }
+    /// Next item to read, without consuming it
+    fn front(&mut self) -> Option<Counter> {
+        if self.weak {
+            self.fetch_weak();
+        }
+        match self.unread.front() {
+            None if self.follows() => self.next(),
+            front => front,
+        }
+    }
+    /// Mark the `n` oldest unread items as read
+    fn take_front(&mut self, n: usize) {
+        if self.unread.is_empty() && self.follows() {
+            self.advance(seq::advance(self.cursor, n));
+        } else {
+            self.with_unread(|unread| unread.skip(n));
+        }
+    }
+    /// Queue up to `n` items at the cursor of a following reader, which
+    /// moves past them, so that they stay retained while lent out
+    fn lend(&mut self, n: usize) {
+        for _ in 0..n {
+            let Some(count) = self.next() else {
+                break;
+            };
+            self.with_unread(|unread| unread.push(count));
+            self.advance(count.wrapping_add(1));
+        }
+    }
+    /// Length of the run of items a following reader can borrow at once
+    fn run_ahead(&self, max: usize) -> usize {
+        let source = unsafe { &*self.source };
+        let ahead = seq::distance(self.cursor, source.retained().end).min(max);
+        if source.compaction.is_none() {
+            return ahead;
+        }
+        (0..ahead)
+            .take_while(|&n| !source.hides(seq::advance(self.cursor, n), self.joined))
+            .count()
+    }
+    /// Sequence numbers of the items still to read, oldest first
+    fn pending(&self) -> impl Iterator<Item = Counter> + '_ {
+        let ahead = self.lag() - self.unread.len();
+        let source = self.source;
+        let visible = move |count: &Counter| !unsafe { &*source }.hides(*count, self.joined);
+        self.unread.iter().chain(
+            (0..ahead)
+                .map(|n| seq::advance(self.cursor, n))
+                .filter(visible),
+        )
+    }
+    fn is_idle(&self, now: Instant) -> bool {
+        match (self.idle_timeout, self.idle_since) {
+            (Some(timeout), Some(since)) => {
+                self.borrowed == 0 && self.lag() > 0 && now.duration_since(since) >= timeout
+            }
+            _ => false,
+        }
+    }
+    /// Called by the publisher after unsubscribing this reader
+    fn terminate(&mut self, error: ReadError) {
+        self.stats.missed += self.lag() as u64;
+        if let Some(pin) = self.pin()
+            && let Some(source) = unsafe { self.source.as_mut() }
+        {
+            source.fronts.remove(pin);
+        }
+        self.unread.clear();
+        self.source = core::ptr::null_mut();
+        self.error = Some(error);
+        if let Some(notifier) = &self.notifier {
+            notifier();
+        }
+    }
+    /// Discard the backlog after the publisher was cleared, sparing borrowed items
+    fn reset(&mut self, live: Counter) {
+        if self.follows() && self.unread.is_empty() {
+            self.lend(self.borrowed);
+        }
+        let dropped = self.lag() - self.borrowed;
+        let borrowed = self.borrowed;
+        self.with_unread(|unread| unread.truncate(borrowed));
+        self.stats.missed += dropped as u64;
+        if self.follows() {
+            self.advance(live);
+        } else {
+            self.cursor = live;
+        }
+        self.reset = true;
+        if let Some(notifier) = &self.notifier {
+            notifier();
+        }
+    }
+    fn accepts(&self, obj: &T) -> bool {
+        self.filter.as_ref().is_none_or(|f| f(obj))
+    }
+    /// Drop queued items older than `cut` items after `first`, sparing borrowed ones
+    fn overrun(&mut self, first: Counter, cut: usize) {
+        if self.follows() && self.unread.is_empty() {
+            self.lend(self.borrowed);
+        }
+        let mut dropped = 0;
+        while let Some(count) = self.unread.get(self.borrowed) {
+            if seq::distance(first, count) >= cut {
+                break;
+            }
+            self.with_unread(|unread| unread.remove(count));
+            dropped += 1;
+        }
+        if self.follows() && seq::distance(first, self.cursor) < cut {
+            let cut = seq::advance(first, cut);
+            dropped += seq::distance(self.cursor, cut) as u64;
+            self.advance(cut);
+        }
+        self.stats.missed += dropped;
+        self.lagged += dropped;
+    }
+    /// Move a weak reader past items which were released in the meantime
+    fn catch_up(&mut self) -> u64 {
+        if self.source.is_null() {
+            return 0;
+        }
+        let first_count = unsafe { &*self.source }.first_count;
+        if !seq::precedes(self.cursor, first_count) {
+            return 0;
+        }
+        let behind = seq::distance(self.cursor, first_count) as u64;
+        self.cursor = first_count;
+        self.stats.missed += behind;
+        behind
+    }
+    /// Items missed since the last check
+    fn take_missed(&mut self) -> u64 {
+        let lagged = core::mem::take(&mut self.lagged);
+        if self.weak {
+            self.catch_up() + lagged
+        } else {
+            lagged
+        }
+    }
+    /// Queue the next retained item for a weak reader, which pins it while borrowed
+    fn fetch_weak(&mut self) {
+        if self.source.is_null() || !self.unread.is_empty() {
+            return;
+        }
+        self.catch_up();
+        let source = unsafe { &*self.source };
+        while let Some(slot) = source.slot(self.cursor) {
+            let count = self.cursor;
+            self.cursor = count.wrapping_add(1);
+            if source.is_evicted(count) {
+                continue;
+            }
+            if self.accepts(unsafe { slot.assume_init_ref() }) {
+                self.with_unread(|unread| unread.push(count));
+                self.stats.delivered += 1;
+                break;
+            }
+        }
+    }
+    /// Like [`read`](Self::read), but reports why a reader was unsubscribed
+    /// and whether a weak reader missed items
+    pub fn try_read(&mut self) -> Result<Option<BorrowRead<'_, T>>, ReadError> {
+        if let Some(error) = self.pending_error() {
+            return Err(error);
+        }
+        let closed = self.is_closed();
+        let item = self.read();
+        if item.is_none() && closed {
+            return Err(ReadError::Closed);
+        }
+        Ok(item)
+    }
+    /// Borrow the next item and its sequence number without consuming it
+    #[cfg(feature = "ffi")]
+    pub(crate) fn peek(&mut self) -> Option<(Counter, &T)> {
+        let counter = self.front()?;
+        Some((counter, self.item(counter)))
+    }
+    /// Take the condition [`try_read`](Self::try_read) reports before
+    /// reading, if any
+    pub(crate) fn pending_error(&mut self) -> Option<ReadError> {
+        if self.error.is_some() {
+            return self.error;
+        }
+        if core::mem::take(&mut self.reset) {
+            return Some(ReadError::Reset);
+        }
+        let missed = self.take_missed();
+        #[cfg(feature = "tracing")]
+        if missed > 0 {
+            let stream = unsafe { self.source.as_ref() }.map_or("", |p| &*p.name);
+            tracing::debug!(stream, reader = ?self.id, missed, "lagged");
+        }
+        (missed > 0).then_some(ReadError::Lagged(missed))
+    }
+    /// No further items will arrive, either because the publisher was closed
+    /// or dropped, or because this reader was unsubscribed
+    pub fn is_closed(&self) -> bool {
+        self.source.is_null() || unsafe { &*self.source }.closed
+    }
+    /// Whether this reader is still attached to a live publisher
+    pub fn is_publisher_alive(&self) -> bool {
+        !self.source.is_null()
+    }
+    /// Whether this reader was overrun since the last check
+    ///
+    /// This resets the condition, so a following [`try_read`](Self::try_read)
+    /// no longer reports it either.
+    pub fn has_lagged(&mut self) -> bool {
+        let missed = self.take_missed();
+        missed > 0
+    }
+    pub fn read(&mut self) -> Option<BorrowRead<'_, T>> {
+        let counter = self.front()?;
+        self.borrowed = 1;
+        Some(BorrowRead {
+            obj: self.item(counter),
+            reader: self,
+            counter,
+        })
+    }
+    /// Mark the oldest queued item as read
+    fn consume_front(&mut self) {
+        self.take_front(1);
+        self.borrowed = 0;
+        self.stats.read += 1;
+        if self.idle_timeout.is_some() {
+            self.idle_since = Some(self.now());
+        }
+        if !self.source.is_null() {
+            unsafe { &mut *self.source }.read_done();
+        }
+    }
+    /// Borrow up to `max` consecutive items with a single guard
+    ///
+    /// Together with [`Publisher::publish_slice`] this is meant for high
+    /// rates, the backlog and the wakeups are updated once per chunk instead
+    /// of once per item. The chunk ends early at a gap in the backlog, e.g.
+    /// where a filter skipped an item.
+    pub fn read_chunk(&mut self, max: usize) -> Option<ChunkRead<'_, T>> {
+        let first = self.front()?;
+        let len = match self.unread.front_run() {
+            Some((_, run)) => run.min(max),
+            None => self.run_ahead(max),
+        };
+        if len == 0 {
+            return None;
+        }
+        self.borrowed = len;
+        Some(ChunkRead {
+            reader: self,
+            first,
+            len,
+        })
+    }
+    /// Read the next item together with its sequence number and publish time
+    pub fn read_enriched(&mut self) -> Option<(Counter, Option<Instant>, BorrowRead<'_, T>)> {
+        self.read()
+            .map(|item| (item.sequence(), item.timestamp(), item))
+    }
+    /// Discard the backlog and borrow the most recently published unread item
+    ///
+    /// Returns `None` if this reader has already read everything.
+    pub fn latest(&mut self) -> Option<BorrowRead<'_, T>> {
+        if self.weak && !self.source.is_null() {
+            self.catch_up();
+            let end = unsafe { &*self.source }.retained().end;
+            let last = end.wrapping_sub(1);
+            if seq::precedes(self.cursor, last) {
+                self.stats.missed += seq::distance(self.cursor, last) as u64;
+                self.cursor = last;
+            }
+        }
+        let lag = self.lag();
+        self.stats.max_lag = self.stats.max_lag.max(lag);
+        let skip = lag.saturating_sub(1);
+        if skip > 0 {
+            self.take_front(skip);
+            self.stats.missed += skip as u64;
+            unsafe { &mut *self.source }.reader_done();
+        }
+        self.read()
+    }
+    /// Clone and consume the entire backlog of this reader
+    pub fn drain_owned(&mut self) -> Vec<T>
+    where
+        T: Clone,
+    {
+        let items = self.snapshot();
+        self.consume_all();
+        items
+    }
+    /// Move the entire backlog out of the publisher
+    ///
+    /// This is only possible while this is the only reader, otherwise `None`
+    /// is returned and the backlog is left untouched.
+    pub fn drain_moved(&mut self) -> Option<Vec<T>> {
+        if self.source.is_null() {
+            return None;
+        }
+        let source = unsafe { &mut *self.source };
+        source.reader_done();
+        // every retained item has to be queued here, so that all of them
+        // can be moved and none is left behind for anyone else
+        if source.readers.len() != 1
+            || source.compaction.is_some()
+            || self.lag() != source.published()
+            || self.pin().is_some_and(|c| c != source.first_count)
+        {
+            return None;
+        }
+        let items: Vec<T> = self
+            .pending()
+            .map(|count| unsafe { core::ptr::read(self.item(count)) })
+            .collect();
+        self.take_front(items.len());
+        self.stats.read += items.len() as u64;
+        source.discard(items.len());
+        Some(items)
+    }
+    /// Unsubscribe and keep the backlog in an independent buffer
+    ///
+    /// The items are moved out if this is the only reader, otherwise cloned.
+    pub fn detach(mut self) -> ReplayBuffer<T>
+    where
+        T: Clone,
+    {
+        if self.source.is_null() {
+            return ReplayBuffer::default();
+        }
+        unsafe { &mut *self.source }.relocate(&mut self);
+        let items = match self.drain_moved() {
+            Some(items) => items,
+            None => self.drain_owned(),
+        };
+        ReplayBuffer {
+            items: items.into(),
+        }
+    }
+    fn consume_all(&mut self) {
+        let lag = self.lag();
+        if lag > 0 {
+            self.stats.max_lag = self.stats.max_lag.max(lag);
+            self.stats.read += lag as u64;
+            self.take_front(lag);
+            unsafe { &mut *self.source }.reader_done();
+        }
+    }
+    /// Borrow up to the next `count` items at once
+    ///
+    /// The guards can be dropped in any order, but items are only released
+    /// for reuse once every earlier item of the window was dropped as well.
+    pub fn read_window(&mut self, count: usize) -> Vec<WindowRead<'_, T>> {
+        if self.follows() {
+            self.lend(count);
+        }
+        self.borrowed = count.min(self.unread.len());
+        let reader = self as *mut StreamReader<T>;
+        self.unread
+            .iter()
+            .take(count)
+            .map(|counter| WindowRead {
+                obj: self.item(counter),
+                reader,
+                counter,
+                phantom: PhantomData,
+            })
+            .collect()
+    }
+    /// Clone all items not yet read by this reader, without consuming them
+    pub fn snapshot(&self) -> Vec<T>
+    where
+        T: Clone,
+    {
+        self.pending()
+            .map(|count| self.item(count).clone())
+            .collect()
+    }
+    pub fn set_notification(&mut self, n: Box<dyn Fn()>) {
+        self.notifier.replace(n);
+        if let Some(source) = unsafe { self.source.as_mut() } {
+            source.enlist(self);
+        }
+    }
+    pub fn new() -> Self {
+        Self {
+            phantom: PhantomData,
+            source: core::ptr::null_mut(),
+            unread: Backlog::default(),
+            borrowed: 0,
+            queued: false,
+            id: Key::default(),
+            priority: 0,
+            lagged: 0,
+            weak: false,
+            cursor: 0,
+            joined: 0,
+            notifier: None,
+            notify_pending: false,
+            filter: None,
+            idle_timeout: None,
+            idle_since: None,
+            error: None,
+            reset: false,
+            stats: ReaderStats::default(),
+        }
+    }
+    pub fn stats(&self) -> ReaderStats {
+        let lag = self.lag();
+        ReaderStats {
+            // a following reader learns about the items ahead of its cursor
+            // when reading
+            delivered: self.stats.delivered + (lag - self.unread.len()) as u64,
+            max_lag: self.stats.max_lag.max(lag),
+            current_lag: lag,
+            ..self.stats
+        }
+    }
+    /// Sequence number of the next item to read, `end` if none is queued
+    fn next_unread(&self, end: Counter) -> Counter {
+        if self.weak {
+            self.cursor
+        } else if self.follows() {
+            self.unread.front().unwrap_or(self.cursor)
+        } else {
+            self.unread.front().unwrap_or(end)
+        }
+    }
+    /// Bytes of this reader, including what it keeps on the heap
+    fn memory_usage(&self) -> usize {
+        size_of::<Self>()
+            + self.unread.heap_size()
+            + self.notifier.as_ref().map_or(0, |f| size_of_val(&**f))
+            + self.filter.as_ref().map_or(0, |f| size_of_val(&**f))
+    }
+    /// Unsubscribe automatically once a backlog is left unread for `timeout`
+    ///
+    /// This protects the publisher against consumers which stopped reading
+    /// without dropping the reader, afterwards [`try_read`](Self::try_read)
+    /// reports [`ReadError::TimedOut`].
+    pub fn set_idle_timeout(&mut self, timeout: Duration) {
+        self.idle_timeout = Some(timeout);
+        self.idle_since = Some(self.now());
+        if let Some(source) = unsafe { self.source.as_mut() } {
+            source.enlist(self);
+        }
+    }
+    /// Readers with a lower priority lose their backlog first when a
+    /// publisher with a capacity limit runs out of room, the default is 0
+    pub fn set_priority(&mut self, priority: i32) {
+        self.priority = priority;
+    }
+    /// Create a best-effort reader which never extends the retention of items
+    ///
+    /// It only sees items which are still retained on behalf of other readers
+    /// at the time it reads, [`try_read`](Self::try_read) reports the gaps as
+    /// [`ReadError::Lagged`]. Weak readers don't support windows or draining.
+    pub fn weak() -> Self {
+        let mut reader = Self::new();
+        reader.weak = true;
+        reader
+    }
+    /// Create a reader which only receives items matching `filter`
+    ///
+    /// Rejected items never enter this reader's queue, so they are
+    /// not retained on its behalf either.
+    pub fn with_filter(filter: impl Fn(&T) -> bool + 'static) -> Self {
+        let mut reader = Self::new();
+        reader.filter = Some(Box::new(filter));
+        reader
+    }
+}
+
+impl<T> Default for StreamReader<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StreamReader<Box<dyn Any>> {
+    /// Reader of a heterogeneous stream which only receives items of type `U`
+    pub fn of_type<U: Any>() -> Self {
+        Self::with_filter(|item| item.is::<U>())
+    }
+}
+
+impl<T: Copy> StreamReader<T> {
+    /// Copy out the next item and consume it right away, without a guard
+    pub fn recv_copy(&mut self) -> Option<T> {
+        let count = self.front()?;
+        let obj = *self.item(count);
+        self.consume_front();
+        Some(obj)
+    }
+}
+
+impl<T> StreamReader<Arc<T>> {
+    /// Consume the next item, keeping a shared reference instead of copying it
+    pub fn recv_arc(&mut self) -> Option<Arc<T>> {
+        self.read().map(|item| Arc::clone(&item))
+    }
+}
+
+#[cfg(feature = "bytes")]
+impl StreamReader<bytes::Bytes> {
+    /// Consume the next chunk, keeping a handle to its storage instead of
+    /// copying it
+    pub fn recv_bytes(&mut self) -> Option<bytes::Bytes> {
+        self.read().map(|chunk| chunk.clone())
+    }
+}
+
+impl<T> Drop for StreamReader<T> {
+    fn drop(&mut self) {
+        if !self.source.is_null() {
+            unsafe { &mut *self.source }.remove_reader(self);
+        }
+    }
+}
+
+/// Items taken over from a detached reader, see [`StreamReader::detach`]
+pub struct ReplayBuffer<T> {
+    items: VecDeque<T>,
+}
+
+impl<T> ReplayBuffer<T> {
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+    /// Look at the remaining items without consuming them
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.items.iter()
+    }
+}
+
+impl<T> Default for ReplayBuffer<T> {
+    fn default() -> Self {
+        Self {
+            items: VecDeque::new(),
+        }
+    }
+}
+
+impl<T> Iterator for ReplayBuffer<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.items.pop_front()
+    }
+}
+
+/// Reader which yields a projection of each item, see [`Publisher::subscribe_map`]
+pub struct MappedReader<T, U> {
+    /// heap allocated, so that the registered address stays put
+    reader: NonNull<StreamReader<T>>,
+    map: Box<dyn Fn(&T) -> U>,
+}
+
+impl<T, U> MappedReader<T, U> {
+    /// Compute the projection of the next item and consume it
+    pub fn read(&mut self) -> Option<U> {
+        let reader = unsafe { self.reader.as_mut() };
+        reader.read().map(|obj| (self.map)(&obj))
+    }
+    pub fn set_notification(&mut self, n: Box<dyn Fn()>) {
+        unsafe { self.reader.as_mut() }.set_notification(n);
+    }
+}
+
+impl<T, U> Drop for MappedReader<T, U> {
+    fn drop(&mut self) {
+        drop(unsafe { Box::from_raw(self.reader.as_ptr()) });
+    }
+}
+
+/// Publisher object
+// aka StreamWriter
+pub struct Publisher<T> {
+    data: Slots<T>,
+    first_count: Counter,
+    /// `first_count` when the cursors of weak readers were last rebased
+    rebased: Counter,
+    readers: Registry<ConsumerInfo<T>>,
+    routing: Routing<T>,
+    /// allocated slots at the back which are not yet finished
+    pending: usize,
+    /// for claimed slots, which are the newest pending ones, whether they
+    /// were completed already
+    claims: VecDeque<bool>,
+    /// tells the tickets of this publisher apart from foreign ones
+    id: u32,
+    /// bumped when clearing gives up the outstanding claims
+    generation: u32,
+    /// publish time of each published item, if enabled
+    timestamps: Option<VecDeque<Instant>>,
+    /// per published item, created on first use; boxed so that guards can
+    /// borrow it while new entries are added
+    metadata: Option<VecDeque<Option<Box<Metadata>>>>,
+    /// maximum number of retained items
+    limit: Option<usize>,
+    compaction: Option<Compaction<T>>,
+    dedup: Option<Dedup<T>>,
+    clock: Box<dyn Clock>,
+    closed: bool,
+    /// tasks waiting in `wait_until_consumed`
+    fences: Vec<(Counter, Waker)>,
+    /// oldest queued item of every reader
+    fronts: Fronts,
+    /// completed reads between reclamation passes
+    reclaim_every: usize,
+    /// completed reads since the last reclamation pass
+    deferred: usize,
+    /// open bursts, notifications wait until the last one ends
+    bursts: usize,
+    /// released items kept for reuse, see `set_recycling`
+    recycled: Vec<T>,
+    recycle_max: usize,
+    /// items ever published, unlike the sequence numbers this never wraps
+    published_total: u64,
+    /// items missed by readers which are gone, they still count as dropped
+    departed_missed: u64,
+    /// identifies the stream in traces
+    #[cfg(feature = "tracing")]
+    name: Box<str>,
+}
+
+impl<T> Publisher<T> {
+    /// Publish a single item, returns its sequence number
+    pub fn publish(&mut self, obj: T) -> Counter {
+        self.publish_one(obj, true)
+    }
+    /// Publish all items, readers are notified once at the end
+    pub fn publish_iter(&mut self, items: impl IntoIterator<Item = T>) {
+        for obj in items {
+            self.publish_one(obj, false);
+        }
+        self.flush_notifications();
+    }
+    /// Copy a whole slice of items into the buffer at once
+    pub fn publish_slice(&mut self, items: &[T])
+    where
+        T: Copy,
+    {
+        self.publish_parts([items].into_iter())
+    }
+    /// Copy several slices into the buffer with a single delivery pass
+    pub(crate) fn publish_parts<'s>(&mut self, parts: impl Iterator<Item = &'s [T]> + Clone)
+    where
+        T: Copy + 's,
+    {
+        if !self.claims.is_empty() {
+            for obj in parts.flatten() {
+                self.publish_one(*obj, false);
+            }
+            self.flush_notifications();
+            return;
+        }
+        let len = parts.clone().map(<[T]>::len).sum();
+        self.expire_idle();
+        self.make_room(len);
+        let first = self.data.len();
+        for items in parts {
+            self.data.extend_copy(items);
+        }
+        self.stamp(len);
+        let newcount = seq::advance(self.first_count, first);
+        if self.delivers_whole_runs() {
+            let routing = &mut self.routing;
+            for key in routing.queued.iter().chain(&routing.waiters) {
+                let Some(i) = self.readers.get(*key) else {
+                    continue;
+                };
+                let reader = unsafe { &mut *i.reader };
+                let held = if reader.queued {
+                    reader.new_run(newcount, len)
+                } else {
+                    reader.signal(false)
+                };
+                if held {
+                    routing.wakeups.push(i.id);
+                }
+            }
+        } else {
+            for (n, data) in self.data.range(first..first + len).enumerate() {
+                let count = seq::advance(newcount, n);
+                deliver(&self.readers, &mut self.routing, data, count, false);
+            }
+        }
+        self.flush_notifications();
+        self.call_back(first..first + len);
+    }
+    /// Whether every current reader is done with the item `seq` and all before it
+    ///
+    /// Items a reader skipped because of its filter count as consumed, items
+    /// which were not published yet don't.
+    pub fn is_consumed(&self, seq: Counter) -> bool {
+        let end = seq::advance(self.first_count, self.published());
+        if !seq::precedes(seq, end) {
+            return false;
+        }
+        self.readers.iter().all(|i| {
+            let reader = unsafe { &*i.reader };
+            let next =
+                reader
+                    .unread
+                    .front()
+                    .unwrap_or(if reader.queued { end } else { reader.cursor });
+            seq::precedes(seq, next)
+        })
+    }
+    /// Wait until [`is_consumed`](Self::is_consumed) holds for `seq`, e.g. to
+    /// reuse external resources referenced by the items up to it
+    pub fn wait_until_consumed(&mut self, seq: Counter) -> Consumed<'_, T> {
+        Consumed { writer: self, seq }
+    }
+    /// Publish an item together with context which readers find on the guard
+    pub fn publish_with_meta(&mut self, obj: T, meta: Metadata) -> Counter {
+        let published = self.published();
+        let metadata = self
+            .metadata
+            .get_or_insert_with(|| core::iter::repeat_with(|| None).take(published).collect());
+        let len = metadata.len();
+        let seq = self.publish(obj);
+        if let Some(metadata) = &mut self.metadata
+            && metadata.len() > len
+            && let Some(slot) = metadata.back_mut()
+        {
+            *slot = Some(Box::new(meta));
+        }
+        seq
+    }
+    /// Hold back notifications until the returned guard drops
+    ///
+    /// Items published through the guard become readable right away, but each
+    /// reader is woken only once for the whole burst.
+    pub fn burst(&mut self) -> Burst<'_, T> {
+        self.bursts += 1;
+        Burst { writer: self }
+    }
+    /// Start staging items which readers observe all together on commit
+    pub fn transaction(&mut self) -> Transaction<'_, T> {
+        Transaction {
+            writer: self,
+            staged: Vec::new(),
+        }
+    }
+    /// Construct an item directly in its slot, it is published once `init` returns
+    ///
+    /// Returning the reference obtained from [`MaybeUninit::write`] proves the
+    /// slot was initialized. If `init` panics the slot is released again.
+    pub fn publish_with<F>(&mut self, init: F) -> Counter
+    where
+        F: for<'s> FnOnce(&'s mut MaybeUninit<T>) -> &'s mut T,
+    {
+        let mut slot = self.allocate();
+        init(&mut slot);
+        slot.finish()
+    }
+    fn publish_one(&mut self, obj: T, notify: bool) -> Counter {
+        if !self.claims.is_empty() {
+            // queue up behind the outstanding claims
+            let ticket = self.claim();
+            return self.complete(ticket, obj);
+        }
+        self.expire_idle();
+        let newcount = seq::advance(self.first_count, self.data.len());
+        if let Some(dedup) = &mut self.dedup
+            && let Some(earlier) = dedup(&obj, newcount, self.clock.now())
+        {
+            return earlier;
+        }
+        #[cfg(feature = "tracing")]
+        let _span = tracing::trace_span!("publish", stream = &*self.name, seq = newcount).entered();
+        self.make_room(1);
+        self.data.push_back(MaybeUninit::new(obj));
+        self.stamp(1);
+        if let Some(data) = self.data.back() {
+            let notify = notify && self.bursts == 0;
+            deliver(&self.readers, &mut self.routing, data, newcount, notify);
+            self.routing.call_back(unsafe { data.assume_init_ref() });
+        }
+        newcount
+    }
+    /// Whether every reader receives every item, so a run of items can be
+    /// queued without looking at the single items
+    fn delivers_whole_runs(&self) -> bool {
+        let routing = &self.routing;
+        routing.dispatch == Dispatch::Broadcast
+            && routing.callbacks.is_empty()
+            && self.compaction.is_none()
+            && routing.groups.iter().all(|g| g.members.is_empty())
+            && routing
+                .queued
+                .iter()
+                .chain(&routing.waiters)
+                .filter_map(|key| self.readers.get(*key))
+                .all(|i| unsafe { &*i.reader }.filter.is_none())
+    }
+    /// Run the callbacks on the published items at the indices of `range`
+    fn call_back(&mut self, range: Range<usize>) {
+        if self.routing.callbacks.is_empty() {
+            return;
+        }
+        for data in self.data.range(range) {
+            self.routing.call_back(unsafe { data.assume_init_ref() });
+        }
+    }
+    /// Notify the readers which were handed items in bulk, once each
+    fn flush_notifications(&mut self) {
+        if self.bursts > 0 {
+            return;
+        }
+        for key in core::mem::take(&mut self.routing.wakeups) {
+            if let Some(i) = self.readers.get_mut(key) {
+                unsafe { &mut *i.reader }.flush_notification();
+            }
+        }
+    }
+    /// Continue the sequence numbers at `first`, before anything was published
+    #[cfg(any(feature = "serde", feature = "journal"))]
+    pub(crate) fn start_at(&mut self, first: Counter) {
+        debug_assert_eq!(self.published(), 0);
+        self.first_count = first;
+        self.rebased = first;
+    }
+    /// Number of items ever published, it doesn't wrap around like the
+    /// sequence numbers
+    pub fn total_published(&self) -> u64 {
+        self.published_total
+    }
+    /// Number of items readers missed, including the readers which left
+    pub fn total_missed(&self) -> u64 {
+        self.departed_missed
+            + self
+                .readers
+                .iter()
+                .map(|i| unsafe { &*i.reader }.stats.missed)
+                .sum::<u64>()
+    }
+    /// Sequence numbers of the published items which are still retained
+    pub fn retained(&self) -> Range<Counter> {
+        self.first_count..seq::advance(self.first_count, self.published())
+    }
+    /// Borrow the retained items within a range of sequence numbers
+    ///
+    /// Parts of the range which were already released or not yet published
+    /// are skipped, no reader is needed to inspect the history.
+    pub fn range(&self, range: Range<Counter>) -> impl Iterator<Item = &T> {
+        self.items_in(range).map(|(_, item)| item)
+    }
+    /// Retained items within a range and their sequence numbers, skipping
+    /// the ones compaction evicted
+    fn items_in(&self, range: Range<Counter>) -> impl Iterator<Item = (Counter, &T)> {
+        let published = self.published();
+        let offset = |seq: Counter| {
+            if seq::precedes(seq, self.first_count) {
+                0
+            } else {
+                seq::distance(self.first_count, seq).min(published)
+            }
+        };
+        let (start, end) = (offset(range.start), offset(range.end));
+        let compaction = self.compaction.as_ref();
+        (start..end.max(start))
+            .filter(move |&n| !compaction.is_some_and(|c| c.is_evicted(n)))
+            .map(|n| {
+                let item = unsafe { self.data[n].assume_init_ref() };
+                (seq::advance(self.first_count, n), item)
+            })
+    }
+    /// Publish time of a retained item, if timestamps are recorded
+    pub fn timestamp(&self, seq: Counter) -> Option<Instant> {
+        let timestamps = self.timestamps.as_ref()?;
+        timestamps
+            .get(seq::distance(self.first_count, seq))
+            .copied()
+    }
+    /// Metadata of a retained item, if it was published with some
+    pub fn metadata(&self, seq: Counter) -> Option<&Metadata> {
+        let metadata = self.metadata.as_ref()?;
+        metadata
+            .get(seq::distance(self.first_count, seq))?
+            .as_deref()
+    }
+    /// Sequence number of the first retained item published at or after `at`
+    ///
+    /// Always `None` unless created via [`with_timestamps`](Self::with_timestamps).
+    pub fn find_at(&self, at: Instant) -> Option<Counter> {
+        let timestamps = self.timestamps.as_ref()?;
+        let pos = timestamps.partition_point(|t| *t < at);
+        (pos < timestamps.len()).then(|| seq::advance(self.first_count, pos))
+    }
+    /// Add a reader which starts with the retained items published at or after `since`
+    ///
+    /// Without timestamps the reader only receives future items.
+    pub fn subscribe_since(&mut self, reader: &mut StreamReader<T>, since: Instant) {
+        let start = self
+            .find_at(since)
+            .map_or(self.published(), |seq| seq::distance(self.first_count, seq));
+        self.add_reader_from(ConsumerInfo::from_reader(reader), start);
+    }
+    /// Add a reader which starts reading at the sequence number `from`
+    ///
+    /// Items released before it are skipped, a `from` past the newest item
+    /// starts with the next published one.
+    pub fn subscribe_from(&mut self, reader: &mut StreamReader<T>, from: Counter) {
+        let start = if seq::precedes(from, self.first_count) {
+            0
+        } else {
+            seq::distance(self.first_count, from).min(self.published())
+        };
+        self.add_reader_from(ConsumerInfo::from_reader(reader), start);
+    }
+    /// Signal readers that no further items will be published
+    ///
+    /// Readers can still consume the retained items, afterwards
+    /// [`try_read`](StreamReader::try_read) reports [`ReadError::Closed`].
+    pub fn close(&mut self) {
+        #[cfg(feature = "tracing")]
+        tracing::debug!(stream = &*self.name, end = self.retained().end, "close");
+        self.closed = true;
+        for i in self.readers.iter() {
+            if let Some(notifier) = &unsafe { &*i.reader }.notifier {
+                notifier();
+            }
+        }
+    }
+    /// Drop all retained items while keeping every subscription
+    ///
+    /// Readers continue with the next published item, their next
+    /// [`try_read`](StreamReader::try_read) reports [`ReadError::Reset`].
+    /// Items currently borrowed by a guard are released once it drops.
+    /// Outstanding claims are given up, completing their tickets fails with
+    /// [`PublishError::StaleTicket`].
+    pub fn clear(&mut self) {
+        while let Some(done) = self.claims.pop_back() {
+            if done && let Some(slot) = self.data.back_mut() {
+                unsafe { slot.assume_init_drop() };
+            }
+            self.data.pop_back();
+            self.pending -= 1;
+        }
+        self.generation = self.generation.wrapping_add(1);
+        let live = seq::advance(self.first_count, self.published());
+        for i in self.readers.iter() {
+            unsafe { &mut *i.reader }.reset(live);
+        }
+        self.release(self.unused(i32::MIN, self.published()));
+    }
+    /// Bound the number of retained items
+    ///
+    /// Once the limit is reached publishing drops the oldest item, readers which
+    /// didn't read it yet see [`ReadError::Lagged`]. The backlog of readers with
+    /// a low [priority](StreamReader::set_priority) is overrun first, higher
+    /// priority readers only lose items when that alone doesn't make room.
+    /// Items currently borrowed by a guard are never dropped, so the limit can
+    /// be exceeded while they are held.
+    pub fn set_capacity_limit(&mut self, limit: usize) {
+        self.limit = Some(limit.max(1));
+    }
+    /// Only retain the latest item per key once all readers are past the older ones
+    ///
+    /// Readers added later start with this compact log instead of the full
+    /// history. Superseded items are dropped as soon as no reader has them
+    /// queued, their slots are freed in order, so an old item which is still
+    /// the latest for its key keeps the slots after it allocated.
+    pub fn set_compaction<K: Ord + 'static>(&mut self, key: impl Fn(&T) -> K + 'static) {
+        // what an earlier compaction evicted is gone for good
+        let keyed = self.compaction.take().map_or_else(VecDeque::new, |c| {
+            c.keyed
+                .into_iter()
+                .map(|k| match k {
+                    Keyed::Evicted => k,
+                    _ => Keyed::Latest,
+                })
+                .collect()
+        });
+        self.compaction = Some(Compaction {
+            latest: Box::new(KeyMap {
+                key,
+                latest: BTreeMap::new(),
+            }),
+            keyed,
+            first: 0,
+            superseded: BinaryHeap::new(),
+        });
+        self.supersede(0..self.published());
+        self.reader_done();
+    }
+    /// Drop published items whose key equals that of a recent item
+    ///
+    /// Publishing a duplicate returns the sequence number of the earlier item.
+    /// This covers [`publish`](Self::publish), [`publish_iter`](Self::publish_iter)
+    /// and transactions, items written in place or copied as slices are not checked.
+    pub fn set_dedup<K: PartialEq + 'static>(
+        &mut self,
+        window: DedupWindow,
+        key: impl Fn(&T) -> K + 'static,
+    ) {
+        let mut recent: VecDeque<(K, Counter, Instant)> = VecDeque::new();
+        self.dedup = Some(Box::new(move |obj, seq, now| {
+            while let Some((_, earlier, at)) = recent.front() {
+                let expired = match window {
+                    DedupWindow::Items(n) => seq::distance(*earlier, seq) > n,
+                    DedupWindow::Duration(d) => now.duration_since(*at) > d,
+                };
+                if !expired {
+                    break;
+                }
+                recent.pop_front();
+            }
+            let key = key(obj);
+            if let Some((_, earlier, _)) = recent.iter().find(|e| e.0 == key) {
+                return Some(*earlier);
+            }
+            recent.push_back((key, seq, now));
+            None
+        }));
+    }
+    /// Name the stream in the spans and events of the `tracing` feature
+    #[cfg(feature = "tracing")]
+    pub fn set_name(&mut self, name: &str) {
+        self.name = name.into();
+    }
+    /// Replace the clock used for timestamps, idle timeouts and dedup windows
+    pub fn set_clock(&mut self, clock: impl Clock + 'static) {
+        self.clock = Box::new(clock);
+    }
+    /// Only release consumed items every `reads` completed reads
+    ///
+    /// Consumed items are also released before a publish would have to grow
+    /// the buffer, so batching retains a little longer but never allocates
+    /// more. A value of 1, the default, releases on every read.
+    pub fn set_reclaim_interval(&mut self, reads: usize) {
+        self.reclaim_every = reads.max(1);
+        self.reader_done();
+    }
+    /// Release every item no reader needs any more right away
+    pub fn reclaim(&mut self) {
+        self.reader_done();
+    }
+    /// Number of items the buffer holds without allocating
+    pub fn capacity(&self) -> usize {
+        self.data.capacity()
+    }
+    /// Like [`reserve`](Self::reserve), but reports a failed allocation
+    /// instead of aborting
+    pub fn try_reserve(&mut self, additional: usize) -> Result<(), PublishError> {
+        if !self.data.try_reserve(additional) {
+            return Err(PublishError::OutOfMemory);
+        }
+        let timestamps = match &mut self.timestamps {
+            Some(timestamps) => timestamps.try_reserve(additional),
+            None => Ok(()),
+        };
+        let metadata = match &mut self.metadata {
+            Some(metadata) => metadata.try_reserve(additional),
+            None => Ok(()),
+        };
+        timestamps
+            .and(metadata)
+            .map_err(|_| PublishError::OutOfMemory)
+    }
+    /// Publish an item only if a slot is free, gives it back otherwise
+    ///
+    /// Never grows the buffer, so after a [`try_reserve`](Self::try_reserve)
+    /// or with a [capacity limit](Self::set_capacity_limit) within the
+    /// reserved slots the item needs no new storage. Delivery may still
+    /// allocate, to queue the item for readers with a filter or in a group.
+    pub fn try_publish(&mut self, obj: T) -> Result<Counter, Full<T>> {
+        self.make_room(1);
+        if self.data.len() == self.data.capacity() {
+            return Err(Full(obj));
+        }
+        Ok(self.publish(obj))
+    }
+    /// Make room for at least `additional` more items ahead of a burst
+    pub fn reserve(&mut self, additional: usize) {
+        self.data.reserve(additional);
+        if let Some(timestamps) = &mut self.timestamps {
+            timestamps.reserve(additional);
+        }
+        if let Some(metadata) = &mut self.metadata {
+            metadata.reserve(additional);
+        }
+    }
+    /// Bytes used by the buffer, the readers and the bookkeeping around them
+    ///
+    /// Counts allocated capacity rather than what is currently in use, so
+    /// the figure stays put once a publisher with a limit reached a steady
+    /// state. Only the B-tree nodes behind the reader fronts are counted by
+    /// their entries.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let routing = &self.routing;
+        let metadata = self.metadata.as_ref().map_or(0, |metadata| {
+            metadata.capacity() * size_of::<Option<Box<Metadata>>>()
+                + metadata
+                    .iter()
+                    .flatten()
+                    .map(|m| m.heap_size())
+                    .sum::<usize>()
+        });
+        let queues = self.claims.capacity()
+            + self
+                .timestamps
+                .as_ref()
+                .map_or(0, |t| t.capacity() * size_of::<Instant>())
+            + metadata
+            + self.compaction.as_ref().map_or(0, |c| {
+                size_of::<Compaction<T>>()
+                    + c.latest.heap_size()
+                    + c.keyed.capacity() * size_of::<Keyed>()
+                    + c.superseded.capacity() * size_of::<u64>()
+            })
+            + self.dedup.as_ref().map_or(0, |f| size_of_val(&**f))
+            + self.fences.capacity() * size_of::<(Counter, Waker)>()
+            + self.fronts.heap_size()
+            + self.recycled.capacity() * size_of::<T>()
+            + routing.groups.capacity() * size_of::<ConsumerGroup>()
+            + routing
+                .groups
+                .iter()
+                .map(|g| g.name.capacity())
+                .sum::<usize>()
+            + routing.callbacks.capacity() * size_of::<Callback<T>>()
+            + routing
+                .callbacks
+                .iter()
+                .map(|c| size_of_val(&*c.f))
+                .sum::<usize>()
+            + (routing.wakeups.capacity()
+                + routing.queued.capacity()
+                + routing.waiters.capacity()
+                + routing.timed.capacity()
+                + routing
+                    .groups
+                    .iter()
+                    .map(|g| g.members.capacity())
+                    .sum::<usize>())
+                * size_of::<Key>();
+        MemoryUsage {
+            publisher: size_of::<Self>() + size_of_val(&*self.clock) + self.data.allocator_size(),
+            slots: self.data.heap_size(),
+            readers: self.readers.heap_size()
+                + self
+                    .readers
+                    .iter()
+                    .map(|i| unsafe { &*i.reader }.memory_usage())
+                    .sum::<usize>(),
+            queues,
+        }
+    }
+    /// Describe the slots, the sequence range and the cursor of every
+    /// reader, e.g. to log when retention misbehaves
+    ///
+    /// Items are left out, see [`dump_items`](Self::dump_items).
+    pub fn dump(&self) -> Dump {
+        let end = self.retained().end;
+        Dump {
+            retained: self.retained(),
+            pending: self.pending,
+            slots: self.data.capacity(),
+            limit: self.limit,
+            closed: self.closed,
+            readers: self
+                .readers
+                .iter()
+                .map(|i| {
+                    let reader = unsafe { &*i.reader };
+                    ReaderDump {
+                        cursor: reader.next_unread(end),
+                        unread: reader.lag(),
+                        borrowed: reader.borrowed,
+                        weak: reader.weak,
+                        priority: reader.priority,
+                        error: reader.error,
+                        stats: reader.stats(),
+                    }
+                })
+                .collect(),
+        }
+    }
+    /// The retained items by sequence number, for `{:?}`
+    pub fn dump_items(&self) -> impl fmt::Debug + '_
+    where
+        T: fmt::Debug,
+    {
+        DebugItems(self)
+    }
+    /// Keep up to `max` released items instead of dropping them
+    ///
+    /// Slots themselves are always reused in place, this also keeps what a
+    /// large item owns, e.g. the allocation of a frame buffer, so the
+    /// producer can refill one via [`take_recycled`](Self::take_recycled).
+    pub fn set_recycling(&mut self, max: usize) {
+        self.recycle_max = max;
+        self.recycled.truncate(max);
+        self.recycled.reserve_exact(max - self.recycled.len());
+    }
+    /// A released item to overwrite and publish again, the newest first
+    pub fn take_recycled(&mut self) -> Option<T> {
+        self.recycled.pop()
+    }
+    /// Give back spare capacity, e.g. after a backlog has been consumed
+    pub fn shrink_to_fit(&mut self) {
+        self.data.shrink_to_fit();
+        if let Some(timestamps) = &mut self.timestamps {
+            timestamps.shrink_to_fit();
+        }
+        if let Some(metadata) = &mut self.metadata {
+            metadata.shrink_to_fit();
+        }
+    }
+    /// Choose how items are distributed among standalone readers
+    ///
+    /// With anything but [`Dispatch::Broadcast`] the publisher acts as a work
+    /// queue and newly added readers no longer receive the retained backlog.
+    pub fn set_dispatch(&mut self, dispatch: Dispatch) {
+        self.routing.dispatch = dispatch;
+        if dispatch == Dispatch::Broadcast {
+            return;
+        }
+        // readers following their cursor become workers, keeping their backlog
+        let followers: Vec<_> = self
+            .readers
+            .iter()
+            .filter(|i| i.group.is_none() && unsafe { &*i.reader }.follows())
+            .map(|i| i.reader)
+            .collect();
+        for reader in followers {
+            let reader = unsafe { &mut *reader };
+            reader.lend(usize::MAX);
+            reader.pinned(|reader| reader.queued = true);
+            self.routing.delist(reader.id);
+            self.enlist(reader);
+        }
+    }
+    /// Register a closure which is called synchronously for each published item
+    ///
+    /// The closure neither queues items nor extends their retention, so it
+    /// gets the item itself rather than a read guard like readers do. It
+    /// runs once the readers have the item. Should it panic, it is
+    /// unsubscribed and publishing continues normally. Without `std` the
+    /// panic unwinds through the publish call, which leaves the publisher
+    /// consistent.
+    pub fn subscribe_with(&mut self, f: impl FnMut(&T) + 'static) -> CallbackId {
+        let id = CallbackId(self.routing.next_callback);
+        self.routing.next_callback += 1;
+        self.routing.callbacks.push(Callback { id, f: Box::new(f) });
+        id
+    }
+    /// Remove a callback, returns false if it was already gone
+    pub fn unsubscribe(&mut self, id: CallbackId) -> bool {
+        let len = self.routing.callbacks.len();
+        self.routing.callbacks.retain(|c| c.id != id);
+        self.routing.callbacks.len() != len
+    }
+    /// Subscribe a reader which yields `map` applied to each item on read
+    pub fn subscribe_map<U>(&mut self, map: impl Fn(&T) -> U + 'static) -> MappedReader<T, U> {
+        let reader = NonNull::from(Box::leak(Box::new(StreamReader::new())));
+        self.add_reader(ConsumerInfo {
+            reader: reader.as_ptr(),
+            id: Key::default(),
+            group: None,
+        });
+        MappedReader {
+            reader,
+            map: Box::new(map),
+        }
+    }
+    /// Add a new reader to the system
+    pub fn add_stream_reader(&mut self, reader: &mut StreamReader<T>) {
+        self.add_reader(ConsumerInfo::from_reader(reader))
+    }
+    /// Add a reader to a named consumer group
+    ///
+    /// Each item is delivered to exactly one member of the group, while
+    /// other groups and standalone readers still see every item.
+    /// Members only receive items published after they joined.
+    pub fn add_group_reader(&mut self, group: &str, reader: &mut StreamReader<T>) {
+        let groups = &mut self.routing.groups;
+        let index = match groups.iter().position(|g| g.name == group) {
+            Some(index) => index,
+            None => {
+                groups.push(ConsumerGroup {
+                    name: group.into(),
+                    next: 0,
+                    members: Vec::new(),
+                });
+                groups.len() - 1
+            }
+        };
+        let mut info = ConsumerInfo {
+            group: Some(index),
+            ..ConsumerInfo::from_reader(reader)
+        };
+        self.attach(&mut info);
+        self.readers.insert(info);
+        self.enlist(reader);
+    }
+    /// Please prefer add_stream_reader because it is more simple
+    fn add_reader(&mut self, info: ConsumerInfo<T>) {
+        self.add_reader_from(info, 0)
+    }
+    /// Register a reader and hand it the retained backlog, starting at an offset
+    fn add_reader_from(&mut self, mut info: ConsumerInfo<T>, start: usize) {
+        self.attach(&mut info);
+        #[cfg(feature = "tracing")]
+        tracing::debug!(
+            stream = &*self.name,
+            reader = ?info.id,
+            from = seq::advance(self.first_count, start),
+            "subscribe"
+        );
+        let reader = unsafe { &mut *info.reader };
+        if reader.follows() {
+            let end = self.retained().end;
+            reader.cursor = seq::advance(self.first_count, start);
+            reader.joined = end;
+            self.fronts.insert(reader.cursor);
+            if reader.cursor != end
+                && let Some(notifier) = &reader.notifier
+            {
+                notifier();
+            }
+        } else if reader.weak {
+            reader.cursor = seq::advance(self.first_count, start);
+        } else if self.routing.dispatch == Dispatch::Broadcast {
+            let backlog = self.data.iter().enumerate().take(self.published());
+            for (n, i) in backlog.skip(start) {
+                if self
+                    .compaction
+                    .as_ref()
+                    .is_some_and(|c| c.keyed[n] != Keyed::Latest)
+                {
+                    continue;
+                }
+                if reader.accepts(unsafe { i.assume_init_ref() }) {
+                    reader.new_data(seq::advance(self.first_count, n), false);
+                }
+            }
+            reader.flush_notification();
+        }
+        self.readers.insert(info);
+        self.enlist(reader);
+    }
+    /// Point the reader at this publisher and give it a unique id
+    fn attach(&mut self, info: &mut ConsumerInfo<T>) {
+        let reader = unsafe { &mut *info.reader };
+        reader.source = self as *mut _;
+        reader.id = self.readers.next_key();
+        info.id = reader.id;
+        // deciding about these takes a look at every item
+        reader.queued = !reader.weak
+            && (reader.filter.is_some()
+                || info.group.is_some()
+                || self.routing.dispatch != Dispatch::Broadcast);
+    }
+    /// Number of retained items which are completely written
+    fn published(&self) -> usize {
+        self.data.len() - self.pending
+    }
+    /// Retained and published slot with the given sequence number
+    fn slot(&self, count: Counter) -> Option<&MaybeUninit<T>> {
+        let index = seq::distance(self.first_count, count);
+        (index < self.published()).then(|| &self.data[index])
+    }
+    /// Whether compaction dropped the retained item with the given sequence number
+    fn is_evicted(&self, count: Counter) -> bool {
+        self.compaction
+            .as_ref()
+            .is_some_and(|c| c.is_evicted(seq::distance(self.first_count, count)))
+    }
+    /// Whether a reader which subscribed at `joined` passes over an item,
+    /// because compaction evicted it or superseded it before the reader came
+    fn hides(&self, count: Counter, joined: Counter) -> bool {
+        self.compaction.as_ref().is_some_and(|c| {
+            match c.keyed[seq::distance(self.first_count, count)] {
+                Keyed::Latest => false,
+                Keyed::Superseded => seq::precedes(count, joined),
+                Keyed::Evicted => true,
+            }
+        })
+    }
+    /// Put a reader on the lists publishing goes through, where it belongs
+    fn enlist(&mut self, reader: &StreamReader<T>) {
+        let key = reader.id;
+        let Some(group) = self.readers.get(key).map(|i| i.group) else {
+            return;
+        };
+        let routing = &mut self.routing;
+        match group {
+            Some(n) if reader.queued => enlist(&mut routing.groups[n].members, key),
+            None if reader.queued => enlist(&mut routing.queued, key),
+            _ if reader.notifier.is_some() => enlist(&mut routing.waiters, key),
+            _ => {}
+        }
+        if reader.idle_timeout.is_some() {
+            enlist(&mut routing.timed, key);
+        }
+    }
+    /// Record a common publish time for the `count` newest items and let
+    /// them supersede older items with the same key
+    fn stamp(&mut self, count: usize) {
+        self.published_total += count as u64;
+        if let Some(timestamps) = &mut self.timestamps {
+            let now = self.clock.now();
+            timestamps.extend(core::iter::repeat_n(now, count));
+        }
+        if let Some(metadata) = &mut self.metadata {
+            metadata.extend(core::iter::repeat_with(|| None).take(count));
+        }
+        let published = self.published();
+        self.supersede(published - count..published);
+    }
+    /// Mark the items which `new` items have the same key as
+    fn supersede(&mut self, new: Range<usize>) {
+        let Some(compaction) = &mut self.compaction else {
+            return;
+        };
+        for n in new {
+            if n == compaction.keyed.len() {
+                compaction.keyed.push_back(Keyed::Latest);
+            } else if compaction.is_evicted(n) {
+                continue;
+            }
+            let item = unsafe { self.data[n].assume_init_ref() };
+            let position = compaction.first + n as u64;
+            // the previous latest may already be released
+            if let Some(old) = compaction.latest.replace(item, position)
+                && old >= compaction.first
+            {
+                compaction.keyed[(old - compaction.first) as usize] = Keyed::Superseded;
+                compaction.superseded.push(Reverse(old));
+            }
+        }
+    }
+    /// Number of leading items which no reader with at least `priority` has queued
+    fn unused(&self, priority: i32, limit: usize) -> usize {
+        let mut min_used_minus_first = limit;
+        for i in self.readers.iter() {
+            let reader = unsafe { &*i.reader };
+            if reader.priority < priority {
+                continue;
+            }
+            if let Some(count) = reader.pin() {
+                min_used_minus_first =
+                    min_used_minus_first.min(seq::distance(self.first_count, count));
+            }
+        }
+        min_used_minus_first
+    }
+    /// Count a completed read and release items once enough accumulated
+    fn read_done(&mut self) {
+        self.deferred += 1;
+        if self.deferred >= self.reclaim_every {
+            self.reader_done();
+        }
+    }
+    /// Release every leading item which no reader has queued any more
+    fn reader_done(&mut self) {
+        self.deferred = 0;
+        let published = self.published();
+        let mut count = self
+            .fronts
+            .min(self.first_count)
+            .map_or(published, |front| {
+                seq::distance(self.first_count, front).min(published)
+            });
+        if let Some(compaction) = &mut self.compaction {
+            // no reader has the superseded items before `count` queued
+            let end = compaction.first + count as u64;
+            while let Some(&Reverse(position)) = compaction.superseded.peek()
+                && position < end
+            {
+                compaction.superseded.pop();
+                if position >= compaction.first {
+                    let n = (position - compaction.first) as usize;
+                    compaction.keyed[n] = Keyed::Evicted;
+                    dispose(&mut self.data[n], &mut self.recycled, self.recycle_max);
+                }
+            }
+            count = compaction
+                .keyed
+                .iter()
+                .take(count)
+                .take_while(|k| **k == Keyed::Evicted)
+                .count();
+        }
+        self.release(count);
+    }
+    /// Drop the `count` oldest items and forget their slots
+    fn release(&mut self, count: usize) {
+        for index in 0..count {
+            if let Some(compaction) = &mut self.compaction {
+                match compaction.keyed[index] {
+                    Keyed::Evicted => continue,
+                    Keyed::Latest => {
+                        let item = unsafe { self.data[index].assume_init_ref() };
+                        compaction
+                            .latest
+                            .forget(item, compaction.first + index as u64);
+                    }
+                    Keyed::Superseded => {}
+                }
+            }
+            dispose(&mut self.data[index], &mut self.recycled, self.recycle_max);
+        }
+        self.discard(count);
+    }
+    /// Forget the `count` oldest slots, their values were moved out or dropped
+    fn discard(&mut self, count: usize) {
+        if count > 0 {
+            self.first_count = seq::advance(self.first_count, count);
+            self.data.discard_front(count);
+            if let Some(timestamps) = &mut self.timestamps {
+                timestamps.drain(..count);
+            }
+            if let Some(metadata) = &mut self.metadata {
+                metadata.drain(..count);
+            }
+            if let Some(compaction) = &mut self.compaction {
+                compaction.keyed.drain(..count);
+                compaction.first += count as u64;
+            }
+            if seq::distance(self.rebased, self.first_count) >= seq::REBASE_INTERVAL {
+                self.rebase();
+            }
+        }
+        self.wake_fences();
+    }
+    /// Move weak readers which fell far behind up to the oldest retained
+    /// item, counting what they skipped as lagged
+    ///
+    /// A weak reader which never reads would otherwise drift more than
+    /// [`seq::WINDOW`] items behind, after which its cursor looks like it is
+    /// ahead of the stream.
+    fn rebase(&mut self) {
+        self.rebased = self.first_count;
+        for info in self.readers.iter() {
+            let reader = unsafe { &mut *info.reader };
+            if reader.weak {
+                let missed = reader.catch_up();
+                reader.lagged += missed;
+            }
+        }
+    }
+    fn wake_fences(&mut self) {
+        for (seq, waker) in core::mem::take(&mut self.fences) {
+            if self.is_consumed(seq) {
+                waker.wake();
+            } else {
+                self.fences.push((seq, waker));
+            }
+        }
+    }
+    /// Drop the oldest items to stay within the capacity limit, overrunning
+    /// the readers with the lowest priority first
+    fn make_room(&mut self, additional: usize) {
+        if self.deferred > 0 && self.data.len() + additional > self.data.capacity() {
+            self.reader_done();
+        }
+        let Some(limit) = self.limit else {
+            return;
+        };
+        let excess = (self.data.len() + additional)
+            .saturating_sub(limit)
+            .min(self.published());
+        if excess == 0 {
+            return;
+        }
+        #[cfg(feature = "tracing")]
+        tracing::debug!(
+            stream = &*self.name,
+            first = self.first_count,
+            dropped = excess,
+            "evict"
+        );
+        let mut levels: Vec<i32> = self
+            .readers
+            .iter()
+            .map(|i| unsafe { &*i.reader }.priority)
+            .collect();
+        levels.sort_unstable();
+        levels.dedup();
+        for level in levels {
+            // readers above this level keep everything they have queued
+            let kept = self.unused(level.saturating_add(1), excess);
+            for i in self.readers.iter() {
+                let reader = unsafe { &mut *i.reader };
+                if reader.priority <= level && !reader.weak {
+                    reader.overrun(self.first_count, kept);
+                }
+            }
+            if kept == excess {
+                break;
+            }
+        }
+        self.release(self.unused(i32::MIN, excess));
+    }
+    /// Unsubscribe readers which exceeded their idle timeout
+    fn expire_idle(&mut self) {
+        // only read the clock if needed, some targets have none
+        if self.routing.timed.is_empty() {
+            return;
+        }
+        let now = self.clock.now();
+        let mut expired = Vec::new();
+        for key in self.routing.timed.iter() {
+            let Some(i) = self.readers.get(*key) else {
+                continue;
+            };
+            let reader = unsafe { &mut *i.reader };
+            if reader.is_idle(now) {
+                expired.push(*key);
+            } else if reader.follows() && reader.lag() == 0 {
+                // the item about to be published starts the wait
+                reader.idle_since = Some(now);
+            }
+        }
+        if expired.is_empty() {
+            return;
+        }
+        for key in expired {
+            if let Some(i) = self.readers.remove(key) {
+                let reader = unsafe { &mut *i.reader };
+                reader.terminate(ReadError::TimedOut);
+                self.departed_missed += reader.stats.missed;
+                self.routing.delist(key);
+            }
+        }
+        self.reader_done();
+    }
+    fn remove_reader(&mut self, rd: &mut StreamReader<T>) {
+        if let Some(count) = rd.pin() {
+            self.fronts.remove(count);
+        }
+        self.readers.remove(rd.id);
+        self.routing.delist(rd.id);
+        self.departed_missed += rd.stats.missed;
+        self.reader_done();
+    }
+    /// Update the address of a reader which was moved
+    fn relocate(&mut self, rd: &mut StreamReader<T>) {
+        if let Some(info) = self.readers.get_mut(rd.id) {
+            info.reader = rd as *mut _;
+        }
+    }
+
+    /// Allocate data for in-place writing, without panicking
+    pub fn try_allocate(&mut self) -> Result<BorrowWrite<'_, T>, PublishError> {
+        if !self.claims.is_empty() {
+            return Err(PublishError::Claimed);
+        }
+        self.try_reserve(1)?;
+        Ok(self.allocate())
+    }
+    /// Allocate data for in-place writing
+    ///
+    /// Panics while [claimed](Self::claim) slots are outstanding.
+    pub fn allocate(&mut self) -> BorrowWrite<'_, T> {
+        assert!(
+            self.claims.is_empty(),
+            "complete all claims before allocating"
+        );
+        self.make_room(1);
+        let newcount = seq::advance(self.first_count, self.data.len());
+        self.data.push_back(MaybeUninit::uninit());
+        self.pending += 1;
+        let unbound_self_ref = unsafe { &mut *(self as *mut _) };
+        BorrowWrite {
+            obj: self.data.back_mut().unwrap(),
+            writer: unbound_self_ref,
+            newcount,
+            written: false,
+        }
+    }
+
+    /// Reserve `count` consecutive slots for in-place writing, without
+    /// panicking
+    pub fn try_allocate_many(
+        &mut self,
+        count: usize,
+    ) -> Result<BorrowWriteMany<'_, T>, PublishError> {
+        if !self.claims.is_empty() {
+            return Err(PublishError::Claimed);
+        }
+        self.try_reserve(count)?;
+        Ok(self.allocate_many(count))
+    }
+    /// Reserve `count` consecutive slots for in-place writing
+    ///
+    /// Panics while [claimed](Self::claim) slots are outstanding.
+    pub fn allocate_many(&mut self, count: usize) -> BorrowWriteMany<'_, T> {
+        assert!(
+            self.claims.is_empty(),
+            "complete all claims before allocating"
+        );
+        self.make_room(count);
+        let newcount = seq::advance(self.first_count, self.data.len());
+        for _ in 0..count {
+            self.data.push_back(MaybeUninit::uninit());
+        }
+        self.pending += count;
+        BorrowWriteMany {
+            writer: self,
+            newcount,
+            len: count,
+            written: false,
+        }
+    }
+
+    /// Claim the next sequence number for an item which is completed later
+    ///
+    /// Several claims can be outstanding and completed in any order, readers
+    /// still receive the items in claim order. Items published meanwhile are
+    /// queued behind the claims.
+    pub fn claim(&mut self) -> Ticket {
+        self.expire_idle();
+        self.make_room(1);
+        let seq = seq::advance(self.first_count, self.data.len());
+        self.data.push_back(MaybeUninit::uninit());
+        self.pending += 1;
+        self.claims.push_back(false);
+        Ticket {
+            seq,
+            publisher: self.id,
+            generation: self.generation,
+        }
+    }
+    /// Fill a claimed slot, it is published once all earlier claims are complete
+    ///
+    /// Panics on a ticket of another publisher or one claimed before a
+    /// [`clear`](Self::clear).
+    pub fn complete(&mut self, ticket: Ticket, obj: T) -> Counter {
+        match self.try_complete(ticket, obj) {
+            Ok(seq) => seq,
+            Err(err) => panic!("{err}"),
+        }
+    }
+    /// Like [`complete`](Self::complete), drops the item of a ticket of
+    /// another publisher or a stale one instead of panicking
+    pub fn try_complete(&mut self, ticket: Ticket, obj: T) -> Result<Counter, PublishError> {
+        if ticket.publisher != self.id {
+            return Err(PublishError::ForeignTicket);
+        }
+        if ticket.generation != self.generation {
+            return Err(PublishError::StaleTicket);
+        }
+        let index = seq::distance(self.first_count, ticket.seq);
+        let claim = index.checked_sub(self.data.len() - self.claims.len());
+        let Some(claim) = claim.filter(|&claim| self.claims.get(claim) == Some(&false)) else {
+            return Err(PublishError::ForeignTicket);
+        };
+        self.data[index] = MaybeUninit::new(obj);
+        self.claims[claim] = true;
+        while self.claims.front() == Some(&true) {
+            self.claims.pop_front();
+            self.pending -= 1;
+            self.stamp(1);
+            let index = self.published() - 1;
+            let count = seq::advance(self.first_count, index);
+            if let Some(data) = self.data.get(index) {
+                deliver(&self.readers, &mut self.routing, data, count, false);
+                self.routing.call_back(unsafe { data.assume_init_ref() });
+            }
+        }
+        Ok(ticket.seq)
+    }
+
+    pub fn new() -> Self {
+        Self {
+            data: Slots::new(),
+            first_count: Default::default(),
+            rebased: Default::default(),
+            readers: Registry::new(),
+            routing: Routing {
+                dispatch: Dispatch::Broadcast,
+                next: 0,
+                groups: vec![],
+                callbacks: vec![],
+                next_callback: 0,
+                wakeups: vec![],
+                queued: vec![],
+                waiters: vec![],
+                timed: vec![],
+            },
+            pending: 0,
+            claims: VecDeque::new(),
+            id: PUBLISHERS.fetch_add(1, Ordering::Relaxed),
+            generation: 0,
+            timestamps: None,
+            metadata: None,
+            limit: None,
+            compaction: None,
+            dedup: None,
+            clock: default_clock(),
+            closed: false,
+            fences: Vec::new(),
+            fronts: Fronts::default(),
+            reclaim_every: 1,
+            deferred: 0,
+            bursts: 0,
+            recycled: Vec::new(),
+            recycle_max: 0,
+            published_total: 0,
+            departed_missed: 0,
+            #[cfg(feature = "tracing")]
+            name: "".into(),
+        }
+    }
+    /// Create a publisher whose slots come from `alloc`
+    pub fn with_allocator(alloc: impl Allocator + 'static) -> Self {
+        let mut publisher = Self::new();
+        publisher.data = Slots::with_allocator(Box::new(alloc));
+        publisher
+    }
+    /// Create a publisher which records the publish time of every item
+    pub fn with_timestamps() -> Self {
+        let mut publisher = Self::new();
+        publisher.timestamps = Some(VecDeque::new());
+        publisher
+    }
+}
+
+impl<T> Default for Publisher<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Publisher<Arc<T>> {
+    /// Publish a shared item, readers receive it via [`StreamReader::recv_arc`]
+    /// without deep copies
+    pub fn publish_arc(&mut self, obj: impl Into<Arc<T>>) -> Counter {
+        self.publish(obj.into())
+    }
+}
+
+/// Checkpoint of a publisher, as read back by `restore_state`
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct State<T> {
+    first: Counter,
+    items: Vec<T>,
+    cursors: Vec<Counter>,
+    closed: bool,
+}
+
+/// Checkpoint of a publisher, as written by `serialize_state`
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize)]
+struct StateRef<'a, T: serde::Serialize> {
+    first: Counter,
+    items: Items<'a, T>,
+    cursors: Vec<Counter>,
+    closed: bool,
+}
+
+/// Serializes the published items without copying them
+#[cfg(feature = "serde")]
+struct Items<'a, T>(&'a Publisher<T>);
+
+#[cfg(feature = "serde")]
+impl<T: serde::Serialize> serde::Serialize for Items<'_, T> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // with the length up front, which formats like postcard require
+        use serde::ser::SerializeSeq;
+        let evicted = self.0.compaction.as_ref().map_or(0, |c| {
+            c.keyed.iter().filter(|k| **k == Keyed::Evicted).count()
+        });
+        let mut seq = serializer.serialize_seq(Some(self.0.published() - evicted))?;
+        for item in self.0.range(self.0.retained()) {
+            seq.serialize_element(item)?;
+        }
+        seq.end()
+    }
+}
+
+/// Timestamps, metadata and the settings of the publisher are not part of
+/// the checkpoint, neither are items still being written. Items compaction
+/// evicted are left out, the sequence numbers after them close the gap.
+#[cfg(feature = "serde")]
+impl<T> Publisher<T> {
+    /// Write the retained items, their sequence numbers and the next unread
+    /// sequence number of every reader
+    pub fn serialize_state<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        T: serde::Serialize,
+    {
+        let end = self.retained().end;
+        let cursors = self
+            .readers
+            .iter()
+            .map(|i| self.without_evicted(unsafe { &*i.reader }.next_unread(end)))
+            .collect();
+        serde::Serialize::serialize(
+            &StateRef {
+                first: self.first_count,
+                items: Items(self),
+                cursors,
+                closed: self.closed,
+            },
+            serializer,
+        )
+    }
+    /// Sequence number of `count` once the evicted items before it are left out
+    fn without_evicted(&self, count: Counter) -> Counter {
+        let Some(compaction) = &self.compaction else {
+            return count;
+        };
+        if seq::precedes(count, self.first_count) {
+            return count;
+        }
+        let index = seq::distance(self.first_count, count);
+        let evicted = compaction.keyed.iter().take(index);
+        count.wrapping_sub(evicted.filter(|k| **k == Keyed::Evicted).count() as Counter)
+    }
+    /// Rebuild a publisher from a checkpoint written by
+    /// [`serialize_state`](Self::serialize_state)
+    ///
+    /// Also returns the saved reader cursors, in the order the readers were
+    /// added. Resubscribe each reader with
+    /// [`subscribe_from`](Self::subscribe_from) to continue where it left off.
+    pub fn restore_state<'de, D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<(Self, Vec<Counter>), D::Error>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        let state: State<T> = serde::Deserialize::deserialize(deserializer)?;
+        let mut publisher = Self::new();
+        publisher.start_at(state.first);
+        publisher.publish_iter(state.items);
+        publisher.closed = state.closed;
+        Ok((publisher, state.cursors))
+    }
+}
+
+/// Shows the sequence range and the readers, never the items
+impl<T> fmt::Debug for Publisher<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Publisher")
+            .field("retained", &self.retained())
+            .field("readers", &self.readers.iter().count())
+            .field("closed", &self.closed)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Shows the position of the reader, never the items
+impl<T> fmt::Debug for StreamReader<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StreamReader")
+            .field("attached", &!self.source.is_null())
+            .field("unread", &self.lag())
+            .field("stats", &self.stats())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Retained items by sequence number, see [`Publisher::dump_items`]
+struct DebugItems<'a, T>(&'a Publisher<T>);
+
+impl<T: fmt::Debug> fmt::Debug for DebugItems<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map()
+            .entries(self.0.items_in(self.0.retained()))
+            .finish()
+    }
+}
+
+/// Stream health for the `metrics` facade, e.g. a Prometheus exporter
+///
+/// Nothing is recorded on the publish path, call these periodically or
+/// before each scrape. Every metric carries the `stream` label.
+#[cfg(feature = "metrics")]
+impl<T> Publisher<T> {
+    /// Record the publisher's counters and gauges under `stream`
+    ///
+    /// `stream_published_total` and `stream_dropped_total`, the items
+    /// readers missed including readers which left since, are counters,
+    /// `stream_depth` (retained items), `stream_readers`, `stream_max_lag`
+    /// and `stream_memory_bytes` gauges.
+    pub fn record_metrics(&self, stream: &str) {
+        let max_lag = self
+            .readers
+            .iter()
+            .map(|i| unsafe { &*i.reader }.stats().current_lag)
+            .max()
+            .unwrap_or(0);
+        let label = [("stream", stream.to_owned())];
+        metrics::counter!("stream_published_total", &label).absolute(self.total_published());
+        metrics::counter!("stream_dropped_total", &label).absolute(self.total_missed());
+        metrics::gauge!("stream_depth", &label).set(self.published() as f64);
+        metrics::gauge!("stream_readers", &label).set(self.readers.iter().count() as f64);
+        metrics::gauge!("stream_max_lag", &label).set(max_lag as f64);
+        metrics::gauge!("stream_memory_bytes", &label).set(self.memory_usage().total() as f64);
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl<T> StreamReader<T> {
+    /// Record this reader's lag and counters under `stream` and `reader`
+    ///
+    /// `stream_reader_read_total` and `stream_reader_missed_total` are
+    /// counters, `stream_reader_lag` a gauge.
+    pub fn record_metrics(&self, stream: &str, reader: &str) {
+        let stats = self.stats();
+        let labels = [("stream", stream.to_owned()), ("reader", reader.to_owned())];
+        metrics::counter!("stream_reader_read_total", &labels).absolute(stats.read);
+        metrics::counter!("stream_reader_missed_total", &labels).absolute(stats.missed);
+        metrics::gauge!("stream_reader_lag", &labels).set(stats.current_lag as f64);
+    }
+}
+
+/// Chunks of bytes whose storage is shared instead of copied
+///
+/// The publisher only retains a reference to each chunk, so the storage is
+/// freed once the publisher released the chunk and every handle returned by
+/// [`StreamReader::recv_bytes`] is dropped.
+#[cfg(feature = "bytes")]
+impl Publisher<bytes::Bytes> {
+    /// Publish a chunk, e.g. a `Vec<u8>` or a slice of a larger `Bytes`
+    pub fn publish_bytes(&mut self, chunk: impl Into<bytes::Bytes>) -> Counter {
+        self.publish(chunk.into())
+    }
+}
+
+#[cfg(feature = "std")]
+impl Publisher<u8> {
+    /// Gather several buffers into the stream, readers are notified once
+    pub fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> usize {
+        self.publish_parts(bufs.iter().map(|buf| &**buf));
+        bufs.iter().map(|buf| buf.len()).sum()
+    }
+    /// Publish without waking readers, for the async writers which leave
+    /// that to the flush
+    #[cfg(any(feature = "tokio", feature = "futures-io"))]
+    fn write_held(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        self.bursts += 1;
+        self.publish_slice(buf);
+        self.bursts -= 1;
+        Ok(buf.len())
+    }
+    #[cfg(any(feature = "tokio", feature = "futures-io"))]
+    fn shutdown(&mut self) {
+        self.flush_notifications();
+        self.close();
+    }
+}
+
+/// Producer of a byte stream, with `std` it implements `std::io::Write`
+pub type BytePublisher = Publisher<u8>;
+
+/// Consumer of a byte stream, with `std` it implements `std::io::Read`
+pub type ByteSubscriber = StreamReader<u8>;
+
+/// Writes never block or come up short, the buffer grows up to the
+/// capacity limit. Once closed the stream refuses further bytes.
+#[cfg(feature = "std")]
+impl io::Write for Publisher<u8> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        self.publish_slice(buf);
+        Ok(buf.len())
+    }
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        if self.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        Ok(Publisher::write_vectored(self, bufs))
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The inherent [`StreamReader::read`] takes precedence, call this one via
+/// the trait, e.g. from code generic over [`io::Read`].
+///
+/// Reads fail with [`WouldBlock`](io::ErrorKind::WouldBlock) while no bytes
+/// are queued and return end of stream once the publisher closed. Lost
+/// bytes are reported as an error wrapping the [`ReadError`], as a decoder
+/// would otherwise silently skip over them.
+#[cfg(feature = "std")]
+impl io::Read for StreamReader<u8> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.pending_error() {
+            Some(ReadError::Closed) => return Ok(0),
+            Some(error) => return Err(io::Error::other(error)),
+            None => {}
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let closed = self.is_closed();
+        let Some(chunk) = self.read_chunk(buf.len()) else {
+            if closed {
+                return Ok(0);
+            }
+            return Err(io::ErrorKind::WouldBlock.into());
+        };
+        let mut len = 0;
+        for slice in chunk.slices() {
+            buf[len..len + slice.len()].copy_from_slice(slice);
+            len += slice.len();
+        }
+        Ok(len)
+    }
+}
+
+/// Writes complete right away like the blocking ones, but the readers are
+/// only woken by the flush, so that a codec writing a frame in pieces wakes
+/// them once. Shutting down closes the stream.
+#[cfg(feature = "tokio")]
+impl tokio::io::AsyncWrite for Publisher<u8> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(self.get_mut().write_held(buf))
+    }
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().flush_notifications();
+        Poll::Ready(Ok(()))
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().shutdown();
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Like the tokio one, closing flushes and closes the stream
+#[cfg(feature = "futures-io")]
+impl futures_io::AsyncWrite for Publisher<u8> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(self.get_mut().write_held(buf))
+    }
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().flush_notifications();
+        Poll::Ready(Ok(()))
+    }
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().shutdown();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T> Extend<T> for Publisher<T> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        self.publish_iter(iter);
+    }
+}
+
+impl<T> Drop for Publisher<T> {
+    fn drop(&mut self) {
+        let this: *mut Self = self;
+        for i in self.readers.drain() {
+            let reader = unsafe { &mut *i.reader };
+            // moving the publisher into its drop leaves the readers pointing at the old place
+            reader.source = this;
+            reader.terminate(ReadError::Closed);
+        }
+        // completed claims wait behind unfinished ones as initialized slots
+        let claimed = self.data.len() - self.claims.len();
+        for (n, done) in self.claims.iter().enumerate() {
+            if *done {
+                unsafe { self.data[claimed + n].assume_init_drop() };
+            }
+        }
+        self.release(self.published());
+    }
+}
+
+/// Cloneable access to a single publisher shared by several producers
+///
+/// All clones publish into the same stream, the publisher is closed once the
+/// last handle drops. The handle is not thread-safe, see [`sync`](crate::sync) for that.
+pub struct PublishHandle<T> {
+    inner: Rc<RefCell<Publisher<T>>>,
+}
+
+impl<T> PublishHandle<T> {
+    pub fn new() -> Self {
+        Self::from(Publisher::new())
+    }
+    /// Publish a single item, returns its sequence number
+    pub fn publish(&self, obj: T) -> Counter {
+        self.inner.borrow_mut().publish(obj)
+    }
+    /// Publish all items, they are not interleaved with those of other handles
+    pub fn publish_iter(&self, items: impl IntoIterator<Item = T>) {
+        self.inner.borrow_mut().publish_iter(items);
+    }
+    pub fn add_stream_reader(&self, reader: &mut StreamReader<T>) {
+        self.inner.borrow_mut().add_stream_reader(reader);
+    }
+    /// Run `f` with exclusive access to the underlying publisher
+    ///
+    /// Calling back into this handle from `f` panics.
+    pub fn with<R>(&self, f: impl FnOnce(&mut Publisher<T>) -> R) -> R {
+        f(&mut self.inner.borrow_mut())
+    }
+    /// Like [`with`](Self::with), fails instead of panicking when called
+    /// back from `f`
+    pub fn try_with<R>(&self, f: impl FnOnce(&mut Publisher<T>) -> R) -> Result<R, PublishError> {
+        let mut publisher = self
+            .inner
+            .try_borrow_mut()
+            .map_err(|_| PublishError::Busy)?;
+        Ok(f(&mut publisher))
+    }
+    /// Take over a publisher without readers, gives it back if it has any
+    pub fn try_new(publisher: Box<Publisher<T>>) -> Result<Self, Box<Publisher<T>>> {
+        if !publisher.readers.is_empty() {
+            return Err(publisher);
+        }
+        Ok(Self::from(*publisher))
+    }
+}
+
+impl<T> From<Publisher<T>> for PublishHandle<T> {
+    /// Take over a publisher, it must not have any readers yet as they
+    /// refer to its current location
+    fn from(publisher: Publisher<T>) -> Self {
+        assert!(publisher.readers.is_empty());
+        Self {
+            inner: Rc::new(RefCell::new(publisher)),
+        }
+    }
+}
+
+impl<T> Clone for PublishHandle<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T> Default for PublishHandle<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        Allocator, Counter, DedupWindow, Dispatch, Full, ManualClock, Metadata, PublishError,
+        PublishHandle, Publisher, ReadError, ReaderStats, StreamReader, SystemAllocator, seq,
+    };
+    use std::{
+        alloc::Layout,
+        any::Any,
+        cell::{Cell, RefCell},
+        future::Future,
+        ops::Deref,
+        pin::pin,
+        ptr::NonNull,
+        rc::Rc,
+        sync::{
+            Arc,
+            atomic::{AtomicBool, Ordering},
+        },
+        task::{Context, Poll, Wake, Waker},
+        thread,
+        time::Duration,
+    };
+
+    #[test]
+    fn push() {
+        // this is not safe, it needs pinning
+        let mut p: Publisher<u32> = Publisher::new();
+        assert_eq!(p.publish(1), 0);
+        let mut r1 = StreamReader::new();
+        p.add_stream_reader(&mut r1);
+        assert!(r1.read().unwrap().deref() == &1);
+        let mut r2 = StreamReader::new();
+        p.add_stream_reader(&mut r2);
+        let mut w = p.allocate();
+        w.write(2);
+        assert_eq!(w.finish(), 1);
+        assert!(r2.read().unwrap().deref() == &2);
+        assert!(r1.read().unwrap().deref() == &2);
+    }
+
+    #[test]
+    fn capacity() {
+        let mut p: Publisher<u32> = Publisher::new();
+        p.publish(1);
+        let mut r = StreamReader::new();
+        p.add_stream_reader(&mut r);
+        p.reserve(1000);
+        assert!(p.capacity() >= 1001);
+        for i in 2..500 {
+            p.publish(i);
+        }
+        assert_eq!(r.snapshot().len(), 499);
+        while r.read().is_some() {}
+        p.shrink_to_fit();
+        assert!(p.capacity() < 1000);
+        p.publish(7);
+        assert!(r.read().unwrap().deref() == &7);
+    }
+
+    #[test]
+    fn memory_usage() {
+        let mut p: Publisher<u64> = Publisher::new();
+        let empty = p.memory_usage();
+        assert_eq!(empty.slots, 0);
+        assert_eq!(empty.readers, 0);
+        assert_eq!(empty.total(), empty.publisher);
+        p.set_capacity_limit(64);
+        let mut r = StreamReader::new();
+        p.add_stream_reader(&mut r);
+        for i in 0..100 {
+            p.publish(i);
+        }
+        let full = p.memory_usage();
+        assert!(full.slots >= 64 * size_of::<u64>());
+        assert!(full.readers >= size_of::<StreamReader<u64>>());
+        // with a limit the footprint stops growing
+        for i in 0..1000 {
+            p.publish(i);
+        }
+        assert_eq!(p.memory_usage(), full);
+    }
+
+    #[test]
+    fn dump() {
+        // no Debug, so the items never show up
+        struct Secret(u32);
+        let mut p = Publisher::new();
+        let mut a = StreamReader::new();
+        let mut b = StreamReader::new();
+        p.add_stream_reader(&mut a);
+        p.add_stream_reader(&mut b);
+        p.publish_iter([1, 2, 3].map(Secret));
+        assert_eq!(a.read().unwrap().0, 1);
+        let dump = p.dump();
+        assert_eq!(dump.retained, 0..3);
+        assert_eq!(dump.limit, None);
+        let cursors: Vec<_> = dump.readers.iter().map(|r| (r.cursor, r.unread)).collect();
+        assert_eq!(cursors, [(1, 2), (0, 3)]);
+        assert_eq!(
+            format!("{p:?}"),
+            "Publisher { retained: 0..3, readers: 2, closed: false, .. }"
+        );
+        assert!(format!("{a:?}").starts_with("StreamReader { attached: true, unread: 2,"));
+
+        let mut p = Publisher::new();
+        p.publish_iter(["x", "y"]);
+        assert_eq!(format!("{:?}", p.dump_items()), r#"{0: "x", 1: "y"}"#);
+    }
+
+    #[test]
+    fn clear() {
+        let mut p: Publisher<u32> = Publisher::new();
+        let mut r = StreamReader::new();
+        p.add_stream_reader(&mut r);
+        p.publish(1);
+        p.publish(2);
+        p.clear();
+        assert_eq!(p.retained(), 2..2);
+        assert_eq!(r.try_read().err(), Some(ReadError::Reset));
+        assert!(r.read().is_none());
+        p.publish(3);
+        assert!(r.try_read().unwrap().unwrap().deref() == &3);
+        assert_eq!(r.stats().missed, 2);
+    }
+
+    #[test]
+    fn cancel() {
+        let mut p: Publisher<u32> = Publisher::new();
+        let mut r = StreamReader::new();
+        p.add_stream_reader(&mut r);
+        let mut w = p.allocate();
+        w.write(1);
+        w.cancel();
+        assert_eq!(p.retained(), 0..0);
+        assert!(p.data.is_empty());
+        let mut w = p.allocate();
+        w.write(2);
+        assert_eq!(w.finish(), 0);
+        assert_eq!(r.snapshot(), vec![2]);
+    }
+
+    #[test]
+    fn claim() {
+        let mut p: Publisher<u32> = Publisher::new();
+        let mut r = StreamReader::new();
+        p.add_stream_reader(&mut r);
+        let first = p.claim();
+        let second = p.claim();
+        assert_eq!(p.publish(3), 2);
+        assert_eq!(p.complete(second, 2), 1);
+        assert!(r.read().is_none());
+        assert_eq!(first.sequence(), 0);
+        p.complete(first, 1);
+        assert_eq!(r.snapshot(), vec![1, 2, 3]);
+        while r.read().is_some() {}
+        p.publish_slice(&[4, 5]);
+        assert_eq!(r.snapshot(), vec![4, 5]);
+
+        // the same slot is outstanding in both, only the owner fills it
+        let mut other: Publisher<u32> = Publisher::new();
+        let mine = p.claim();
+        let theirs = other.claim();
+        assert_eq!(
+            other.try_complete(mine, 6),
+            Err(PublishError::ForeignTicket)
+        );
+        assert_eq!(other.try_complete(theirs, 7), Ok(0));
+        let stale = p.claim();
+        p.clear();
+        let fresh = p.claim();
+        assert_eq!(p.try_complete(stale, 8), Err(PublishError::StaleTicket));
+        assert_eq!(p.complete(fresh, 9), 5);
+        assert_eq!(r.snapshot(), vec![9]);
+    }
+
+    #[test]
+    fn long_backlog() {
+        let mut p: Publisher<u32> = Publisher::new();
+        let mut r = StreamReader::new();
+        p.add_stream_reader(&mut r);
+        p.publish(0);
+        let first = r.read().unwrap();
+        for i in 1..1000 {
+            p.publish(i);
+        }
+        assert_eq!(*first, 0);
+        drop(first);
+        assert_eq!(r.stats().current_lag, 999);
+        assert_eq!(r.snapshot(), (1..1000).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn snapshot() {
+        let mut p: Publisher<u32> = Publisher::new();
+        p.publish(1);
+        p.publish(2);
+        let mut r = StreamReader::new();
+        p.add_stream_reader(&mut r);
+        assert_eq!(r.snapshot(), vec![1, 2]);
+        assert!(r.read().unwrap().deref() == &1);
+        assert_eq!(r.snapshot(), vec![2]);
+    }
+
+    #[test]
+    fn group() {
+        let mut p: Publisher<u32> = Publisher::new();
+        let mut all = StreamReader::new();
+        let mut a = StreamReader::new();
+        let mut b = StreamReader::new();
+        p.add_stream_reader(&mut all);
+        p.add_group_reader("workers", &mut a);
+        p.add_group_reader("workers", &mut b);
+        p.publish(1);
+        p.publish(2);
+        p.publish(3);
+        assert_eq!(a.snapshot(), vec![1, 3]);
+        assert_eq!(b.snapshot(), vec![2]);
+        assert_eq!(all.snapshot(), vec![1, 2, 3]);
+        while all.read().is_some() {}
+        assert!(b.read().unwrap().deref() == &2);
+        // item 1 is still queued in a
+        assert_eq!(p.data.len(), 3);
+        while a.read().is_some() {}
+        assert!(p.data.is_empty());
+    }
+
+    #[test]
+    fn anycast() {
+        let mut p: Publisher<u32> = Publisher::new();
+        p.set_dispatch(Dispatch::RoundRobin);
+        let mut a = StreamReader::new();
+        let mut b = StreamReader::new();
+        p.add_stream_reader(&mut a);
+        p.add_stream_reader(&mut b);
+        p.publish(1);
+        p.publish(2);
+        p.publish(3);
+        assert_eq!(a.snapshot(), vec![1, 3]);
+        assert_eq!(b.snapshot(), vec![2]);
+        p.set_dispatch(Dispatch::LeastLoaded);
+        p.publish(4);
+        assert_eq!(b.snapshot(), vec![2, 4]);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn callback() {
+        let mut p: Publisher<u32> = Publisher::new();
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let log = seen.clone();
+        let good = p.subscribe_with(move |v| log.borrow_mut().push(*v));
+        let bad = p.subscribe_with(|v| assert!(*v < 2));
+        p.publish(1);
+        p.publish(2);
+        let mut w = p.allocate();
+        w.write(3);
+        w.finish();
+        assert_eq!(*seen.borrow(), vec![1, 2, 3]);
+        assert!(!p.unsubscribe(bad));
+        assert!(p.unsubscribe(good));
+        p.publish(4);
+        assert_eq!(seen.borrow().len(), 3);
+    }
+
+    #[test]
+    fn batch() {
+        let mut p: Publisher<u32> = Publisher::new();
+        let mut r = StreamReader::new();
+        let wakeups = Rc::new(RefCell::new(0));
+        let counter = wakeups.clone();
+        r.set_notification(Box::new(move || *counter.borrow_mut() += 1));
+        p.add_stream_reader(&mut r);
+        p.publish_iter([1, 2]);
+        p.extend([3]);
+        assert_eq!(r.snapshot(), vec![1, 2, 3]);
+        assert_eq!(*wakeups.borrow(), 2);
+        {
+            let mut burst = p.burst();
+            burst.publish(4);
+            let mut inner = burst.burst();
+            let mut w = inner.allocate();
+            w.write(5);
+            w.finish();
+            drop(inner);
+            burst.publish_slice(&[6]);
+            assert_eq!(*wakeups.borrow(), 2);
+        }
+        assert_eq!(*wakeups.borrow(), 3);
+        assert_eq!(r.snapshot().len(), 6);
+    }
+
+    #[test]
+    fn chunk() {
+        let mut p: Publisher<u32> = Publisher::new();
+        let mut r = StreamReader::new();
+        let mut odd = StreamReader::with_filter(|v| v % 2 == 1);
+        p.add_stream_reader(&mut r);
+        p.publish_slice(&(0..100).collect::<Vec<_>>());
+        {
+            let chunk = r.read_chunk(64).unwrap();
+            assert_eq!((chunk.sequence(), chunk.len()), (0, 64));
+            assert!(chunk.slices().count() > 1);
+            assert!(chunk.iter().copied().eq(0..64));
+        }
+        assert_eq!(p.retained(), 64..100);
+        p.add_stream_reader(&mut odd);
+        let chunk = odd.read_chunk(10).unwrap();
+        assert_eq!(chunk.iter().collect::<Vec<_>>(), vec![&65]);
+        drop(chunk);
+        assert_eq!(r.read_chunk(usize::MAX).unwrap().len(), 36);
+        assert!(r.read_chunk(1).is_none());
+        assert_eq!(r.stats().read, 100);
+    }
+
+    #[test]
+    fn recv_copy() {
+        let mut p: Publisher<u64> = Publisher::new();
+        let mut r = StreamReader::new();
+        p.add_stream_reader(&mut r);
+        p.publish_iter([1, 2]);
+        assert_eq!(r.recv_copy(), Some(1));
+        assert_eq!(p.retained(), 1..2);
+        assert_eq!(*r.read().unwrap(), 2);
+        assert_eq!(r.recv_copy(), None);
+        assert_eq!(r.stats().read, 2);
+    }
+
+    #[test]
+    fn slice() {
+        let mut p: Publisher<u8> = Publisher::new();
+        p.set_capacity_limit(4);
+        let mut r = StreamReader::new();
+        p.add_stream_reader(&mut r);
+        p.publish(0);
+        p.publish_slice(&[1, 2, 3]);
+        assert_eq!(r.snapshot(), vec![0, 1, 2, 3]);
+        assert!(r.read().unwrap().deref() == &0);
+        p.publish_slice(&[4, 5]);
+        assert_eq!(r.snapshot(), vec![2, 3, 4, 5]);
+        assert_eq!(p.retained(), 2..6);
+    }
+
+    #[test]
+    fn publish_with() {
+        let mut p: Publisher<[u8; 64]> = Publisher::new();
+        let mut r = StreamReader::new();
+        p.add_stream_reader(&mut r);
+        p.publish_with(|slot| slot.write([1; 64]));
+        let failed = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            p.publish_with(|_| panic!("construction failed"));
+        }));
+        assert!(failed.is_err());
+        p.publish_with(|slot| slot.write([2; 64]));
+        assert_eq!(p.retained(), 0..2);
+        assert!(r.read().unwrap()[0] == 1);
+        assert!(r.read().unwrap()[0] == 2);
+    }
+
+    #[test]
+    fn allocate_many() {
+        let mut p: Publisher<u32> = Publisher::new();
+        let mut r = StreamReader::new();
+        p.add_stream_reader(&mut r);
+        let mut w = p.allocate_many(3);
+        w[0].write(1);
+        w[1].write(2);
+        w.finish(2);
+        assert_eq!(r.snapshot(), vec![1, 2]);
+        drop(p.allocate_many(2));
+        p.publish(3);
+        assert_eq!(r.snapshot(), vec![1, 2, 3]);
+        assert_eq!(p.retained(), 0..3);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn vectored() {
+        let mut p: Publisher<u8> = Publisher::new();
+        let mut r = StreamReader::new();
+        p.add_stream_reader(&mut r);
+        let bufs = [std::io::IoSlice::new(b"ab"), std::io::IoSlice::new(b"cde")];
+        let written = p.write_vectored(&bufs);
+        assert_eq!(written, 5);
+        assert_eq!(r.snapshot(), b"abcde");
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn byte_io() {
+        use crate::{BytePublisher, ByteSubscriber};
+        use std::io::{ErrorKind, Read, Write};
+
+        let mut p = BytePublisher::new();
+        let mut a = ByteSubscriber::new();
+        let mut b = ByteSubscriber::new();
+        p.add_stream_reader(&mut a);
+        p.add_stream_reader(&mut b);
+        let mut buf = [0; 4];
+        assert_eq!(
+            Read::read(&mut a, &mut buf).unwrap_err().kind(),
+            ErrorKind::WouldBlock
+        );
+        write!(p, "hello {}", 42).unwrap();
+        assert_eq!(Read::read(&mut a, &mut buf).unwrap(), 4);
+        assert_eq!(&buf, b"hell");
+        p.close();
+        assert!(p.write(b"!").is_err());
+        let mut rest = String::new();
+        a.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "o 42");
+        let mut all = vec![];
+        b.read_to_end(&mut all).unwrap();
+        assert_eq!(all, b"hello 42");
+
+        let mut p = BytePublisher::new();
+        p.set_capacity_limit(4);
+        let mut r = ByteSubscriber::new();
+        p.add_stream_reader(&mut r);
+        p.write_all(b"abcd").unwrap();
+        p.write_all(b"ef").unwrap();
+        let err = Read::read(&mut r, &mut buf).unwrap_err();
+        assert_eq!(
+            err.get_ref().and_then(|e| e.downcast_ref::<ReadError>()),
+            Some(&ReadError::Lagged(2))
+        );
+        assert_eq!(Read::read(&mut r, &mut buf).unwrap(), 4);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn async_write() {
+        use std::pin::Pin;
+        use tokio::io::AsyncWrite;
+
+        let mut p = crate::BytePublisher::new();
+        let mut r = crate::ByteSubscriber::new();
+        p.add_stream_reader(&mut r);
+        let woken = Rc::new(Cell::new(0));
+        let w = woken.clone();
+        r.set_notification(Box::new(move || w.set(w.get() + 1)));
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut p = Pin::new(&mut p);
+        assert!(matches!(
+            p.as_mut().poll_write(&mut cx, b"ab"),
+            Poll::Ready(Ok(2))
+        ));
+        assert!(matches!(
+            p.as_mut().poll_write(&mut cx, b"cde"),
+            Poll::Ready(Ok(3))
+        ));
+        assert_eq!(woken.get(), 0);
+        assert!(p.as_mut().poll_flush(&mut cx).is_ready());
+        assert_eq!(woken.get(), 1);
+        assert!(p.as_mut().poll_shutdown(&mut cx).is_ready());
+        assert!(matches!(
+            p.as_mut().poll_write(&mut cx, b"f"),
+            Poll::Ready(Err(_))
+        ));
+        assert_eq!(r.snapshot(), b"abcde");
+        assert!(r.is_closed());
+    }
+
+    #[cfg(feature = "bytes")]
+    #[test]
+    fn shared_bytes() {
+        let mut p = Publisher::new();
+        let mut a = StreamReader::new();
+        let mut b = StreamReader::new();
+        p.add_stream_reader(&mut a);
+        p.add_stream_reader(&mut b);
+        p.publish_bytes(b"hello".to_vec());
+        let kept = a.recv_bytes().unwrap();
+        let other = b.recv_bytes().unwrap();
+        assert_eq!(kept.as_ptr(), other.as_ptr());
+        p.clear();
+        drop(p);
+        assert_eq!(kept, &b"hello"[..]);
+        assert!(a.recv_bytes().is_none());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn checkpoint_closes_evicted_gaps() {
+        let mut p: Publisher<u32> = Publisher::new();
+        p.set_compaction(|v| v / 10);
+        let mut r = StreamReader::new();
+        p.add_stream_reader(&mut r);
+        p.publish_iter([1, 11, 12]);
+        drop(r.read());
+        drop(r.read());
+        let saved = p.serialize_state(serde_json::value::Serializer).unwrap();
+        let (mut p, cursors) = Publisher::<u32>::restore_state(saved).unwrap();
+        assert_eq!((p.retained(), cursors.clone()), (0..2, vec![1]));
+        let mut r = StreamReader::new();
+        p.subscribe_from(&mut r, cursors[0]);
+        assert_eq!(r.snapshot(), [12]);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn checkpoint() {
+        let saved = {
+            let mut p: Publisher<String> = Publisher::new();
+            let mut a = StreamReader::new();
+            let mut b = StreamReader::new();
+            p.add_stream_reader(&mut a);
+            p.add_stream_reader(&mut b);
+            for word in ["one", "two", "three"] {
+                p.publish(word.into());
+            }
+            drop(a.read());
+            drop(b.read());
+            drop(b.read());
+            p.serialize_state(serde_json::value::Serializer).unwrap()
+        };
+
+        let (mut p, cursors) = Publisher::<String>::restore_state(saved).unwrap();
+        assert_eq!(cursors, [1, 2]);
+        assert_eq!(p.retained(), 1..3);
+        let mut a = StreamReader::new();
+        let mut b = StreamReader::new();
+        p.subscribe_from(&mut a, cursors[0]);
+        p.subscribe_from(&mut b, cursors[1]);
+        assert_eq!(a.snapshot(), ["two", "three"]);
+        assert_eq!(b.snapshot(), ["three"]);
+        p.publish("four".into());
+        assert_eq!(
+            b.read().map(|item| (item.sequence(), item.clone())),
+            Some((2, "three".into()))
+        );
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn record_metrics() {
+        use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        let mut p: Publisher<u32> = Publisher::new();
+        let mut r = StreamReader::new();
+        p.add_stream_reader(&mut r);
+        p.set_capacity_limit(2);
+        p.publish_iter([1, 2, 3]);
+        drop(r.read());
+        metrics::with_local_recorder(&recorder, || {
+            p.record_metrics("numbers");
+            r.record_metrics("numbers", "r");
+        });
+        let values: Vec<_> = snapshotter
+            .snapshot()
+            .into_vec()
+            .into_iter()
+            .map(|(key, _, _, value)| (key.key().name().to_owned(), value))
+            .collect();
+        let value = |name: &str| &values.iter().find(|(n, _)| n == name).unwrap().1;
+        assert_eq!(*value("stream_published_total"), DebugValue::Counter(3));
+        assert_eq!(*value("stream_dropped_total"), DebugValue::Counter(1));
+        assert_eq!(*value("stream_depth"), DebugValue::Gauge(1.0.into()));
+        assert_eq!(*value("stream_reader_read_total"), DebugValue::Counter(1));
+        assert_eq!(*value("stream_reader_lag"), DebugValue::Gauge(1.0.into()));
+        // the misses of a reader which left still count
+        drop(r);
+        assert_eq!((p.total_published(), p.total_missed()), (3, 1));
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn traces() {
+        use std::sync::{Arc, Mutex};
+        use tracing::{
+            Event, Metadata, Subscriber,
+            field::{Field, Visit},
+            span,
+        };
+
+        /// Collects the message and fields of every event
+        #[derive(Default)]
+        struct Events(Arc<Mutex<Vec<String>>>);
+        struct Fields<'a>(&'a mut String);
+        impl Visit for Fields<'_> {
+            fn record_debug(&mut self, field: &Field, value: &dyn core::fmt::Debug) {
+                match field.name() {
+                    "message" => self.0.insert_str(0, &format!("{value:?}")),
+                    "reader" => {}
+                    name => self.0.push_str(&format!(" {name}={value:?}")),
+                }
+            }
+        }
+        impl Subscriber for Events {
+            fn enabled(&self, _: &Metadata<'_>) -> bool {
+                true
+            }
+            fn new_span(&self, _: &span::Attributes<'_>) -> span::Id {
+                span::Id::from_u64(1)
+            }
+            fn record(&self, _: &span::Id, _: &span::Record<'_>) {}
+            fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+            fn event(&self, event: &Event<'_>) {
+                let mut line = String::new();
+                event.record(&mut Fields(&mut line));
+                self.0.lock().unwrap().push(line);
+            }
+            fn enter(&self, _: &span::Id) {}
+            fn exit(&self, _: &span::Id) {}
+        }
+
+        let events = Events::default();
+        let lines = events.0.clone();
+        tracing::subscriber::with_default(events, || {
+            let mut p: Publisher<u32> = Publisher::new();
+            p.set_name("numbers");
+            p.set_capacity_limit(1);
+            let mut r = StreamReader::new();
+            p.add_stream_reader(&mut r);
+            p.publish(1);
+            p.publish(2);
+            assert_eq!(r.try_read().err(), Some(ReadError::Lagged(1)));
+            p.close();
+        });
+        assert_eq!(
+            *lines.lock().unwrap(),
+            [
+                "subscribe stream=\"numbers\" from=0",
+                "deliver seq=0",
+                "evict stream=\"numbers\" first=0 dropped=1",
+                "deliver seq=1",
+                "lagged stream=\"numbers\" missed=1",
+                "close stream=\"numbers\" end=2",
+            ]
+        );
+    }
+
+    #[test]
+    fn transaction() {
+        let mut p: Publisher<u32> = Publisher::new();
+        let mut r = StreamReader::new();
+        p.add_stream_reader(&mut r);
+        let mut t = p.transaction();
+        t.push(1);
+        t.push(2);
+        drop(t);
+        assert!(r.read().is_none());
+        let mut t = p.transaction();
+        t.push(3);
+        t.push(4);
+        t.commit();
+        assert_eq!(r.snapshot(), vec![3, 4]);
+    }
+
+    #[test]
+    fn filter() {
+        let mut p: Publisher<u32> = Publisher::new();
+        p.publish(1);
+        p.publish(2);
+        let mut even = StreamReader::with_filter(|v| v % 2 == 0);
+        let mut all = StreamReader::new();
+        p.add_stream_reader(&mut even);
+        p.add_stream_reader(&mut all);
+        p.publish(3);
+        p.publish(4);
+        assert_eq!(even.snapshot(), vec![2, 4]);
+        while all.read().is_some() {}
+        // only 1 is released, 3 is kept as it lies between 2 and 4
+        assert_eq!(p.data.len(), 3);
+        while even.read().is_some() {}
+        assert!(p.data.is_empty());
+    }
+
+    #[test]
+    fn map() {
+        let mut p: Publisher<String> = Publisher::new();
+        p.publish("one".into());
+        let mut lengths = p.subscribe_map(|s| s.len());
+        p.publish("three".into());
+        assert_eq!(lengths.read(), Some(3));
+        assert_eq!(lengths.read(), Some(5));
+        assert_eq!(lengths.read(), None);
+        assert!(p.data.is_empty());
+    }
+
+    #[test]
+    fn window() {
+        let mut p: Publisher<u32> = Publisher::new();
+        let mut r = StreamReader::new();
+        p.add_stream_reader(&mut r);
+        p.publish(1);
+        p.publish(2);
+        p.publish(3);
+        let mut window = r.read_window(3);
+        assert_eq!(
+            window.iter().map(|g| **g).collect::<Vec<_>>(),
+            vec![1, 2, 3]
+        );
+        let third = window.pop().unwrap();
+        let second = window.pop().unwrap();
+        drop(second);
+        assert_eq!(p.data.len(), 3);
+        drop(window);
+        assert_eq!(p.data.len(), 1);
+        drop(third);
+        assert!(p.data.is_empty());
+    }
+
+    #[test]
+    fn range() {
+        let mut p: Publisher<u32> = Publisher::new();
+        let mut r = StreamReader::new();
+        p.add_stream_reader(&mut r);
+        p.publish(10);
+        p.publish(11);
+        p.publish(12);
+        assert!(r.read().is_some());
+        assert_eq!(p.retained(), 1..3);
+        assert_eq!(p.range(0..3).copied().collect::<Vec<_>>(), vec![11, 12]);
+        assert_eq!(p.range(2..10).count(), 1);
+        assert_eq!(p.range(5..10).count(), 0);
+    }
+
+    #[test]
+    fn latest() {
+        let mut p: Publisher<u32> = Publisher::new();
+        let mut r = StreamReader::new();
+        p.add_stream_reader(&mut r);
+        assert!(r.latest().is_none());
+        p.publish(1);
+        p.publish(2);
+        p.publish(3);
+        assert!(r.latest().unwrap().deref() == &3);
+        assert!(r.latest().is_none());
+        assert!(p.data.is_empty());
+        p.publish(4);
+        assert_eq!(
+            r.stats(),
+            ReaderStats {
+                delivered: 4,
+                read: 1,
+                missed: 2,
+                max_lag: 3,
+                current_lag: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn drain() {
+        let mut p: Publisher<String> = Publisher::new();
+        let mut b = StreamReader::new();
+        p.add_stream_reader(&mut b);
+        {
+            let mut a = StreamReader::new();
+            p.add_stream_reader(&mut a);
+            p.publish("x".into());
+            p.publish("y".into());
+            assert_eq!(a.drain_moved(), None);
+            assert_eq!(a.drain_owned(), vec!["x", "y"]);
+            assert!(a.read().is_none());
+        }
+        assert_eq!(b.drain_moved(), Some(vec!["x".into(), "y".into()]));
+        assert!(p.data.is_empty());
+    }
+
+    #[test]
+    fn weak() {
+        let mut p: Publisher<u32> = Publisher::new();
+        let mut strong = StreamReader::new();
+        let mut tap = StreamReader::weak();
+        p.add_stream_reader(&mut strong);
+        p.add_stream_reader(&mut tap);
+        p.publish(1);
+        p.publish(2);
+        assert!(tap.try_read().unwrap().unwrap().deref() == &1);
+        assert!(strong.read().is_some());
+        assert!(strong.read().is_some());
+        assert!(p.data.is_empty());
+        p.publish(3);
+        assert_eq!(tap.try_read().err(), Some(ReadError::Lagged(1)));
+        assert!(tap.try_read().unwrap().unwrap().deref() == &3);
+        assert!(tap.read().is_none());
+        p.publish(4);
+        p.publish(5);
+        assert!(tap.latest().unwrap().deref() == &5);
+        assert_eq!(tap.stats().missed, 2);
+    }
+
+    #[test]
+    fn wraps() {
+        let mut p: Publisher<u32> = Publisher::new();
+        p.first_count = Counter::MAX - 1;
+        p.rebased = p
+            .first_count
+            .wrapping_sub(seq::REBASE_INTERVAL as Counter - 2);
+        let mut strong = StreamReader::new();
+        let mut tap = StreamReader::weak();
+        p.add_stream_reader(&mut strong);
+        p.add_stream_reader(&mut tap);
+        for i in 0..4 {
+            p.publish(i);
+        }
+        let retained = p.retained();
+        assert_eq!((retained.start, retained.end), (Counter::MAX - 1, 2));
+        let wrapped = Counter::MAX..retained.end;
+        assert_eq!(p.range(wrapped).count(), 3);
+        for i in 0..3 {
+            assert_eq!(strong.recv_copy(), Some(i));
+        }
+        // the release crossing the interval moved the idle weak reader up
+        assert_eq!(tap.cursor, 0);
+        assert_eq!(tap.try_read().err(), Some(ReadError::Lagged(3)));
+        assert_eq!(tap.recv_copy(), Some(3));
+    }
+
+    #[test]
+    fn priority() {
+        let mut p: Publisher<u32> = Publisher::new();
+        p.set_capacity_limit(3);
+        let mut high = StreamReader::new();
+        let mut low = StreamReader::new();
+        high.set_priority(1);
+        p.add_stream_reader(&mut high);
+        p.add_stream_reader(&mut low);
+        p.publish(1);
+        p.publish(2);
+        p.publish(3);
+        assert!(high.read().is_some());
+        p.publish(4);
+        // only the low priority reader is overrun, item 2 stays for high
+        assert_eq!(low.try_read().err(), Some(ReadError::Lagged(1)));
+        assert_eq!(low.snapshot(), vec![2, 3, 4]);
+        let guard = high.read();
+        p.publish(5);
+        assert_eq!(low.snapshot(), vec![3, 4, 5]);
+        drop(guard);
+        p.publish(6);
+        assert_eq!(high.snapshot(), vec![4, 5, 6]);
+        assert_eq!(high.try_read().err(), Some(ReadError::Lagged(1)));
+        assert_eq!(p.data.len(), 3);
+    }
+
+    #[test]
+    fn state_queries() {
+        let mut r = StreamReader::new();
+        {
+            let mut p: Publisher<u32> = Publisher::new();
+            p.set_capacity_limit(1);
+            p.add_stream_reader(&mut r);
+            p.publish(1);
+            p.publish(2);
+            assert!(r.has_lagged());
+            assert!(!r.has_lagged());
+            p.close();
+            assert!(r.is_closed() && r.is_publisher_alive());
+            assert!(r.try_read().unwrap().is_some());
+            assert_eq!(r.try_read().err(), Some(ReadError::Closed));
+        }
+        assert!(r.is_closed() && !r.is_publisher_alive());
+    }
+
+    #[test]
+    fn detach() {
+        let mut p: Publisher<String> = Publisher::new();
+        let mut a = StreamReader::new();
+        let mut b = StreamReader::new();
+        p.add_stream_reader(&mut a);
+        p.add_stream_reader(&mut b);
+        p.publish("x".into());
+        p.publish("y".into());
+        let replay = a.detach();
+        assert_eq!(replay.len(), 2);
+        assert_eq!(p.readers.len(), 1);
+        assert!(b.read().unwrap().deref() == "x");
+        let moved = b.detach();
+        assert_eq!(moved.collect::<Vec<_>>(), vec!["y"]);
+        assert!(p.readers.is_empty() && p.data.is_empty());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn since() {
+        let mut p: Publisher<u32> = Publisher::with_timestamps();
+        p.publish(1);
+        thread::sleep(Duration::from_millis(5));
+        let cut = std::time::Instant::now();
+        p.publish(2);
+        p.publish(3);
+        assert_eq!(p.find_at(cut), Some(1));
+        assert_eq!(p.find_at(std::time::Instant::now()), None);
+        let mut r = StreamReader::new();
+        p.subscribe_since(&mut r, cut);
+        assert_eq!(r.snapshot(), vec![2, 3]);
+        let (seq, time, item) = r.read_enriched().unwrap();
+        assert_eq!((seq, *item), (1, 2));
+        assert!(time.unwrap() >= cut);
+    }
+
+    #[test]
+    fn idle_timeout() {
+        let clock = ManualClock::new();
+        let mut p: Publisher<u32> = Publisher::new();
+        p.set_clock(clock.clone());
+        let mut alive = StreamReader::new();
+        let mut stuck = StreamReader::new();
+        stuck.set_idle_timeout(Duration::from_millis(10));
+        p.add_stream_reader(&mut alive);
+        p.add_stream_reader(&mut stuck);
+        p.publish(1);
+        assert!(stuck.try_read().unwrap().is_some());
+        p.publish(2);
+        clock.advance(Duration::from_millis(20));
+        p.publish(3);
+        assert_eq!(stuck.try_read().err(), Some(ReadError::TimedOut));
+        assert_eq!(p.readers.len(), 1);
+        while alive.read().is_some() {}
+        assert!(p.data.is_empty());
+    }
+
+    #[test]
+    fn wait_until_consumed() {
+        struct Flag(AtomicBool);
+        impl Wake for Flag {
+            fn wake(self: Arc<Self>) {
+                self.0.store(true, Ordering::SeqCst);
+            }
+        }
+        let flag = Arc::new(Flag(AtomicBool::new(false)));
+        let waker = Waker::from(flag.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        let mut p: Publisher<u32> = Publisher::new();
+        let mut r = StreamReader::new();
+        p.add_stream_reader(&mut r);
+        p.publish(1);
+        let seq = p.publish(2);
+        assert!(!p.is_consumed(seq + 1));
+        let mut fence = pin!(p.wait_until_consumed(seq));
+        assert_eq!(fence.as_mut().poll(&mut cx), Poll::Pending);
+        assert!(r.read().is_some());
+        assert!(!flag.0.load(Ordering::SeqCst));
+        assert!(r.read().is_some());
+        assert!(flag.0.load(Ordering::SeqCst));
+        assert_eq!(fence.as_mut().poll(&mut cx), Poll::Ready(()));
+        assert!(p.is_consumed(seq));
+    }
+
+    #[test]
+    fn publish_handle() {
+        let a = PublishHandle::new();
+        let b = a.clone();
+        let mut r = StreamReader::new();
+        a.add_stream_reader(&mut r);
+        assert_eq!(a.publish(1), 0);
+        assert_eq!(b.publish(2), 1);
+        b.publish_iter([3, 4]);
+        assert_eq!(r.snapshot(), vec![1, 2, 3, 4]);
+        assert_eq!(a.with(|p| p.retained()), 0..4);
+        drop(a);
+        assert!(!r.is_closed());
+        drop(b);
+        assert!(r.is_closed());
+    }
+
+    #[test]
+    fn compaction() {
+        let mut p: Publisher<u32> = Publisher::new();
+        p.set_compaction(|v| v / 10);
+        let mut r = StreamReader::new();
+        p.add_stream_reader(&mut r);
+        p.publish_iter([1, 11, 2, 12, 21]);
+        while r.read().is_some() {}
+        assert_eq!(p.retained(), 2..5);
+        {
+            let mut late = StreamReader::new();
+            p.add_stream_reader(&mut late);
+            assert_eq!(late.snapshot(), vec![2, 12, 21]);
+        }
+        p.publish(3);
+        p.publish(13);
+        while r.read().is_some() {}
+        let mut late = StreamReader::new();
+        p.add_stream_reader(&mut late);
+        assert_eq!(late.snapshot(), vec![21, 3, 13]);
+    }
+
+    #[test]
+    fn compaction_evicts() {
+        let mut p: Publisher<Rc<u32>> = Publisher::new();
+        let items: Vec<_> = [1, 11, 12, 13].map(Rc::new).into();
+        p.publish_iter(items.iter().cloned());
+        p.set_compaction(|v| **v / 10);
+        let mut r = StreamReader::new();
+        p.add_stream_reader(&mut r);
+        p.publish(Rc::new(14));
+        // 13 is queued, 1 keeps the slots but the superseded items go
+        drop(r.read());
+        assert_eq!(Rc::strong_count(&items[1]), 1);
+        assert_eq!(Rc::strong_count(&items[2]), 1);
+        assert_eq!(Rc::strong_count(&items[3]), 2);
+        assert_eq!(p.retained(), 0..5);
+        assert_eq!(p.range(0..5).map(|v| **v).collect::<Vec<_>>(), [1, 13, 14]);
+        while r.read().is_some() {}
+        assert_eq!(Rc::strong_count(&items[3]), 1);
+        let mut late = StreamReader::new();
+        p.add_stream_reader(&mut late);
+        assert_eq!(
+            late.drain_owned().iter().map(|v| **v).collect::<Vec<_>>(),
+            [1, 14]
+        );
+    }
+
+    #[test]
+    fn dedup() {
+        let mut p: Publisher<u32> = Publisher::new();
+        p.set_dedup(DedupWindow::Items(2), |v| *v);
+        let mut r = StreamReader::new();
+        p.add_stream_reader(&mut r);
+        assert_eq!(p.publish(1), 0);
+        assert_eq!(p.publish(2), 1);
+        assert_eq!(p.publish(1), 0);
+        assert_eq!(p.publish(3), 2);
+        assert_eq!(p.publish(1), 3);
+        assert_eq!(r.snapshot(), vec![1, 2, 3, 1]);
+    }
+
+    #[test]
+    fn metadata() {
+        let mut p: Publisher<u32> = Publisher::new();
+        let mut r = StreamReader::new();
+        p.add_stream_reader(&mut r);
+        p.publish(1);
+        let meta = Metadata {
+            trace_id: Some(7),
+            ..Default::default()
+        };
+        let seq = p.publish_with_meta(2, meta.clone());
+        p.publish(3);
+        assert_eq!(p.metadata(seq), Some(&meta));
+        assert!(r.read().unwrap().metadata().is_none());
+        assert_eq!(r.read().unwrap().metadata(), Some(&meta));
+        assert!(r.read().unwrap().metadata().is_none());
+    }
+
+    #[test]
+    fn arc() {
+        let mut p: Publisher<crate::Arc<Vec<u8>>> = Publisher::new();
+        let mut r = StreamReader::new();
+        p.add_stream_reader(&mut r);
+        let payload = crate::Arc::new(vec![0; 1024]);
+        p.publish_arc(payload.clone());
+        p.publish_arc(vec![1]);
+        let first = r.recv_arc().unwrap();
+        assert!(crate::Arc::ptr_eq(&first, &payload));
+        assert_eq!(*r.recv_arc().unwrap(), vec![1]);
+        assert!(r.recv_arc().is_none());
+        let handle = thread::spawn(move || first.len());
+        assert_eq!(handle.join().unwrap(), 1024);
+    }
+
+    #[test]
+    fn drop_on_release() {
+        let item = Rc::new(());
+        {
+            let mut p: Publisher<Rc<()>> = Publisher::new();
+            let mut r = StreamReader::new();
+            p.add_stream_reader(&mut r);
+            p.publish(item.clone());
+            p.publish(item.clone());
+            assert_eq!(Rc::strong_count(&item), 3);
+            assert!(r.read().is_some());
+            assert_eq!(Rc::strong_count(&item), 2);
+            p.publish(item.clone());
+        }
+        assert_eq!(Rc::strong_count(&item), 1);
+    }
+
+    #[test]
+    fn reclaim_interval() {
+        let item = Rc::new(());
+        let mut p: Publisher<Rc<()>> = Publisher::new();
+        let mut r = StreamReader::new();
+        p.add_stream_reader(&mut r);
+        p.set_reclaim_interval(3);
+        for _ in 0..4 {
+            p.publish(item.clone());
+        }
+        assert!(r.read().is_some());
+        assert!(r.read().is_some());
+        assert_eq!(Rc::strong_count(&item), 5);
+        assert!(r.read().is_some());
+        assert_eq!(Rc::strong_count(&item), 2);
+        assert!(r.read().is_some());
+        assert_eq!(Rc::strong_count(&item), 2);
+        p.reclaim();
+        assert_eq!(Rc::strong_count(&item), 1);
+        // filling the buffer releases deferred items instead of growing it
+        let capacity = p.capacity();
+        for _ in 0..3 * capacity {
+            p.publish(item.clone());
+            while r.read().is_some() {}
+        }
+        assert_eq!(p.capacity(), capacity);
+    }
+
+    #[test]
+    fn recycling() {
+        let mut p: Publisher<Vec<u8>> = Publisher::new();
+        p.set_recycling(1);
+        let mut r = StreamReader::new();
+        p.add_stream_reader(&mut r);
+        p.publish(Vec::with_capacity(4096));
+        p.publish(vec![1]);
+        assert!(p.take_recycled().is_none());
+        while r.read().is_some() {}
+        let mut frame = p.take_recycled().unwrap();
+        assert!(frame.capacity() >= 4096);
+        assert!(p.take_recycled().is_none());
+        frame.clear();
+        frame.extend_from_slice(b"next");
+        p.publish(frame);
+        assert_eq!(r.read().unwrap().as_slice(), b"next");
+    }
+
+    /// Counts the bytes it has handed out
+    #[derive(Clone, Default)]
+    struct Arena(Rc<Cell<usize>>);
+
+    impl Allocator for Arena {
+        fn allocate(&self, layout: Layout) -> Option<NonNull<u8>> {
+            self.0.set(self.0.get() + layout.size());
+            SystemAllocator.allocate(layout)
+        }
+        unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+            self.0.set(self.0.get() - layout.size());
+            unsafe { SystemAllocator.deallocate(ptr, layout) }
+        }
+    }
+
+    #[test]
+    fn allocator() {
+        let arena = Arena::default();
+        {
+            let mut p: Publisher<u64> = Publisher::with_allocator(arena.clone());
+            p.publish_slice(&[7; 100]);
+            assert!(arena.0.get() >= 100 * 8);
+            p.clear();
+            p.shrink_to_fit();
+            assert_eq!(arena.0.get(), 0);
+            p.publish(1);
+        }
+        assert_eq!(arena.0.get(), 0);
+    }
+
+    #[test]
+    fn fallible() {
+        struct Exhausted;
+        impl Allocator for Exhausted {
+            fn allocate(&self, _: Layout) -> Option<NonNull<u8>> {
+                None
+            }
+            unsafe fn deallocate(&self, _: NonNull<u8>, _: Layout) {}
+        }
+        let mut p: Publisher<u32> = Publisher::with_allocator(Exhausted);
+        assert_eq!(p.try_reserve(1), Err(PublishError::OutOfMemory));
+        assert_eq!(p.try_publish(1), Err(Full(1)));
+        assert!(p.try_allocate_many(2).is_err());
+
+        let mut p: Publisher<u32> = Publisher::new();
+        let mut r = StreamReader::new();
+        p.add_stream_reader(&mut r);
+        p.try_reserve(4).unwrap();
+        let capacity = p.capacity();
+        for i in 0..capacity as u32 {
+            assert_eq!(p.try_publish(i), Ok(i as Counter));
+        }
+        assert_eq!(p.try_publish(0), Err(Full(0)));
+        assert_eq!(p.capacity(), capacity);
+        let ticket = p.claim();
+        assert!(matches!(p.try_allocate(), Err(PublishError::Claimed)));
+        let mut other: Publisher<u32> = Publisher::new();
+        assert_eq!(
+            other.try_complete(ticket, 1),
+            Err(PublishError::ForeignTicket)
+        );
+
+        let handle = PublishHandle::<u32>::new();
+        let nested = handle.try_with(|_| handle.try_with(|_| ()));
+        assert_eq!(nested, Ok(Err(PublishError::Busy)));
+        let mut r = StreamReader::new();
+        let mut p: Publisher<u32> = Publisher::new();
+        p.add_stream_reader(&mut r);
+        assert!(PublishHandle::try_new(Box::new(p)).is_err());
+    }
+
+    #[test]
+    fn boxed() {
+        let mut p: Publisher<Box<dyn Any>> = Publisher::new();
+        let mut numbers = StreamReader::of_type::<u32>();
+        let mut all = StreamReader::new();
+        p.add_stream_reader(&mut numbers);
+        p.add_stream_reader(&mut all);
+        p.publish(Box::new(String::from("event")));
+        p.publish(Box::new(7u32));
+        assert_eq!(numbers.read().unwrap().downcast_ref::<u32>(), Some(&7));
+        assert!(numbers.read().is_none());
+        let item = all.read().unwrap();
+        assert_eq!(item.downcast_ref::<String>().unwrap(), "event");
+        assert!(item.contents().is::<String>());
+        drop(item);
+
+        let mut bytes: Publisher<Box<[u8]>> = Publisher::new();
+        let mut r = StreamReader::new();
+        bytes.add_stream_reader(&mut r);
+        bytes.publish(vec![1, 2, 3].into_boxed_slice());
+        assert_eq!(r.read().unwrap().contents(), &[1, 2, 3]);
+    }
+}
@@ -0,0 +1,932 @@
+//! Thread-safe variant of the publisher and its readers
+//!
+//! Items are shared via reference counting, so a read guard stays valid
+//! even while the publisher keeps on writing from another thread.
+//!
+//! With the `critical-section` feature the state is guarded by
+//! `critical_section::acquire` instead of a mutex, which on a single-core
+//! microcontroller masks interrupts. That allows an interrupt handler and
+//! thread mode to share a stream. Waiting for items or consumers then
+//! polls, so never block in an interrupt handler.
+
+use alloc::{collections::VecDeque, vec, vec::Vec};
+#[cfg(feature = "critical-section")]
+use core::{cell::RefCell, mem::ManuallyDrop, ops::DerefMut};
+use core::{
+    ops::Deref,
+    task::{Context, Poll, Waker},
+};
+#[cfg(not(feature = "critical-section"))]
+use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
+
+use crate::{Arc, Counter, ReadError, ReaderHooks, seq};
+
+/// Position of a single reader
+struct ReaderState {
+    id: usize,
+    /// next item this reader has not consumed yet
+    cursor: Counter,
+    /// next item to hand out, ahead of `cursor` while guards are alive
+    lent: Counter,
+    /// first item of each live guard or batch, they may drop in any order
+    held: Vec<Counter>,
+    /// items dropped by the capacity limit before it consumed them
+    missed: u64,
+}
+
+struct State<T> {
+    data: VecDeque<Arc<T>>,
+    first_count: Counter,
+    readers: Vec<ReaderState>,
+    next_id: usize,
+    /// number of publisher clones feeding this stream
+    publishers: usize,
+    closed: bool,
+    limit: Option<usize>,
+    /// tasks waiting in [`StreamReader::poll_read`], by reader id
+    wakers: Vec<(usize, Waker)>,
+    hooks: Option<&'static dyn ReaderHooks>,
+}
+
+impl<T> State<T> {
+    fn lent(&self, id: usize) -> Counter {
+        self.readers
+            .iter()
+            .find(|r| r.id == id)
+            .map_or(self.first_count, |r| r.lent)
+    }
+    /// Hand out the next item of a reader, it is consumed once returned to
+    /// [`reader_done`](Self::reader_done)
+    fn lend(&mut self, id: usize) -> Option<(Counter, Arc<T>)> {
+        let next = self.hand_out(id)?;
+        self.hold(id, next.0);
+        Some(next)
+    }
+    /// The next item of a reader, without recording a borrow
+    fn hand_out(&mut self, id: usize) -> Option<(Counter, Arc<T>)> {
+        let r = self.readers.iter_mut().find(|r| r.id == id)?;
+        let counter = r.lent;
+        let obj = self.data.get(seq::distance(self.first_count, counter))?;
+        r.lent = counter.wrapping_add(1);
+        Some((counter, obj.clone()))
+    }
+    /// Record a borrow starting at `first`, it lasts until the next one
+    fn hold(&mut self, id: usize, first: Counter) {
+        if let Some(r) = self.readers.iter_mut().find(|r| r.id == id) {
+            r.held.push(first);
+        }
+    }
+    /// Append an item, dropping the oldest ones beyond the capacity limit
+    fn push(&mut self, obj: T) -> Counter {
+        let count = seq::advance(self.first_count, self.data.len());
+        self.data.push_back(Arc::new(obj));
+        if let Some(limit) = self.limit
+            && self.data.len() > limit
+        {
+            self.evict(self.data.len() - limit);
+        }
+        count
+    }
+    /// Drop the `n` oldest items, readers which didn't consume them miss them
+    fn evict(&mut self, n: usize) {
+        let first = seq::advance(self.first_count, n);
+        for r in self.readers.iter_mut() {
+            if seq::precedes(r.lent, first) {
+                r.missed += seq::distance(r.lent, first) as u64;
+                r.lent = first;
+            }
+            if seq::precedes(r.cursor, first) {
+                r.cursor = first;
+            }
+        }
+        self.data.drain(..n);
+        self.first_count = first;
+    }
+    /// The next item of a reader, or why there is none
+    fn next(&mut self, id: usize) -> Result<Option<(Counter, Arc<T>)>, ReadError> {
+        if let Some(r) = self.readers.iter_mut().find(|r| r.id == id)
+            && r.missed > 0
+        {
+            return Err(ReadError::Lagged(core::mem::take(&mut r.missed)));
+        }
+        match self.lend(id) {
+            Some(next) => Ok(Some(next)),
+            None if self.closed => Err(ReadError::Closed),
+            None => Ok(None),
+        }
+    }
+    fn is_consumed(&self, seq: Counter) -> bool {
+        let end = seq::advance(self.first_count, self.data.len());
+        seq::precedes(seq, end) && self.readers.iter().all(|r| seq::precedes(seq, r.cursor))
+    }
+    /// End the borrow starting at `first`
+    ///
+    /// The cursor only moves up to the oldest borrow still alive, guards
+    /// dropped out of order are consumed once the ones before them are.
+    fn reader_done(&mut self, id: usize, first: Counter) {
+        if let Some(r) = self.readers.iter_mut().find(|r| r.id == id)
+            && let Some(n) = r.held.iter().position(|&count| count == first)
+        {
+            r.held.swap_remove(n);
+            let from = r.cursor;
+            let oldest = r
+                .held
+                .iter()
+                .copied()
+                .filter(|&count| !seq::precedes(count, from))
+                .min_by_key(|&count| seq::distance(from, count));
+            let cursor = oldest.unwrap_or(r.lent);
+            // items dropped by the capacity limit may still be borrowed
+            if seq::precedes(r.cursor, cursor) {
+                r.cursor = cursor;
+            }
+        }
+        self.trim();
+    }
+    /// Drop the items every reader consumed
+    fn trim(&mut self) {
+        if self.readers.is_empty() {
+            // retained for the next subscriber
+            return;
+        }
+        let first_count = self.first_count;
+        let min_used_minus_first = self
+            .readers
+            .iter()
+            .map(|r| seq::distance(first_count, r.cursor))
+            .fold(self.data.len(), usize::min);
+        self.first_count = seq::advance(first_count, min_used_minus_first);
+        self.data.drain(..min_used_minus_first);
+    }
+}
+
+#[cfg(not(feature = "critical-section"))]
+type Lock<T> = Mutex<State<T>>;
+#[cfg(not(feature = "critical-section"))]
+type Guard<'a, T> = MutexGuard<'a, State<T>>;
+
+/// State only touched inside a critical section
+#[cfg(feature = "critical-section")]
+struct Lock<T>(RefCell<State<T>>);
+
+// all access happens in a critical section, which excludes every other context
+#[cfg(feature = "critical-section")]
+unsafe impl<T: Send> Sync for Lock<T> {}
+
+/// Access to the state, the critical section ends when it drops
+#[cfg(feature = "critical-section")]
+struct Guard<'a, T> {
+    state: ManuallyDrop<core::cell::RefMut<'a, State<T>>>,
+    restore: critical_section::RestoreState,
+}
+
+#[cfg(feature = "critical-section")]
+impl<'a, T> Deref for Guard<'a, T> {
+    type Target = State<T>;
+
+    fn deref(&self) -> &State<T> {
+        &self.state
+    }
+}
+
+#[cfg(feature = "critical-section")]
+impl<'a, T> DerefMut for Guard<'a, T> {
+    fn deref_mut(&mut self) -> &mut State<T> {
+        &mut self.state
+    }
+}
+
+#[cfg(feature = "critical-section")]
+impl<'a, T> Drop for Guard<'a, T> {
+    fn drop(&mut self) {
+        unsafe {
+            ManuallyDrop::drop(&mut self.state);
+            critical_section::release(self.restore);
+        }
+    }
+}
+
+struct Shared<T> {
+    state: Lock<T>,
+    /// signalled on publish, consume and close
+    #[cfg(not(feature = "critical-section"))]
+    cond: Condvar,
+}
+
+impl<T> Shared<T> {
+    fn new(state: State<T>) -> Self {
+        Self {
+            #[cfg(not(feature = "critical-section"))]
+            state: Mutex::new(state),
+            #[cfg(feature = "critical-section")]
+            state: Lock(RefCell::new(state)),
+            #[cfg(not(feature = "critical-section"))]
+            cond: Condvar::new(),
+        }
+    }
+    #[cfg(not(feature = "critical-section"))]
+    fn lock(&self) -> Guard<'_, T> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+    #[cfg(feature = "critical-section")]
+    fn lock(&self) -> Guard<'_, T> {
+        let restore = unsafe { critical_section::acquire() };
+        Guard {
+            state: ManuallyDrop::new(self.state.0.borrow_mut()),
+            restore,
+        }
+    }
+    /// Give up the lock until the state may have changed
+    fn wait<'a>(&'a self, guard: Guard<'a, T>) -> Guard<'a, T> {
+        #[cfg(not(feature = "critical-section"))]
+        return self
+            .cond
+            .wait(guard)
+            .unwrap_or_else(PoisonError::into_inner);
+        #[cfg(feature = "critical-section")]
+        {
+            // let the other side in, there is nothing to wait on
+            drop(guard);
+            #[cfg(feature = "std")]
+            std::thread::yield_now();
+            #[cfg(not(feature = "std"))]
+            core::hint::spin_loop();
+            self.lock()
+        }
+    }
+    /// Like [`wait`](Self::wait), but no longer than `timeout`
+    #[cfg(feature = "std")]
+    fn wait_timeout<'a>(&'a self, guard: Guard<'a, T>, timeout: Duration) -> Guard<'a, T> {
+        #[cfg(not(feature = "critical-section"))]
+        return self
+            .cond
+            .wait_timeout(guard, timeout)
+            .unwrap_or_else(PoisonError::into_inner)
+            .0;
+        #[cfg(feature = "critical-section")]
+        {
+            let _ = timeout;
+            self.wait(guard)
+        }
+    }
+    /// Release the lock and wake the threads and tasks waiting for items
+    fn wake(&self, mut guard: Guard<'_, T>) {
+        let wakers = core::mem::take(&mut guard.wakers);
+        let hooks = guard.hooks;
+        drop(guard);
+        self.notify();
+        for (reader, waker) in wakers {
+            if let Some(hooks) = hooks {
+                hooks.woken(reader);
+            }
+            waker.wake();
+        }
+    }
+    /// Wake everyone in [`wait`](Self::wait)
+    fn notify(&self) {
+        #[cfg(not(feature = "critical-section"))]
+        self.cond.notify_all();
+    }
+}
+
+/// Publisher which can be read from other threads
+///
+/// Clones feed the same stream, each item gets its sequence number when it
+/// is added so all readers observe a single global order. The stream closes
+/// once every clone was dropped or [`close`](Self::close) is called.
+pub struct Publisher<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Publisher<T> {
+    pub fn new() -> Self {
+        Self {
+            shared: Arc::new(Shared::new(State {
+                data: VecDeque::new(),
+                first_count: Default::default(),
+                readers: vec![],
+                next_id: 0,
+                publishers: 1,
+                closed: false,
+                limit: None,
+                wakers: Vec::new(),
+                hooks: None,
+            })),
+        }
+    }
+    /// Publish a single item, returns its sequence number
+    pub fn publish(&mut self, obj: T) -> Counter {
+        let mut state = self.shared.lock();
+        let count = state.push(obj);
+        self.shared.wake(state);
+        count
+    }
+    /// Publish unless every reader was dropped, then hand the item back
+    ///
+    /// Returns the number of readers.
+    #[cfg(feature = "std")]
+    pub(crate) fn publish_if_read(&self, obj: T) -> Result<usize, T> {
+        let mut state = self.shared.lock();
+        let readers = state.readers.len();
+        if readers == 0 {
+            return Err(obj);
+        }
+        state.push(obj);
+        self.shared.wake(state);
+        Ok(readers)
+    }
+    /// Bound the number of retained items
+    ///
+    /// Once the limit is reached publishing drops the oldest item. Readers
+    /// which didn't consume it yet skip it, [`try_read`](StreamReader::try_read)
+    /// reports [`ReadError::Lagged`] for them.
+    pub fn set_capacity_limit(&mut self, limit: usize) {
+        let limit = limit.max(1);
+        let mut state = self.shared.lock();
+        state.limit = Some(limit);
+        if let Some(excess) = state.data.len().checked_sub(limit) {
+            state.evict(excess);
+        }
+    }
+    /// Create a new reader, it starts with all retained items
+    pub fn subscribe(&self) -> StreamReader<T> {
+        subscribe(&self.shared, false)
+    }
+    /// Create a new reader which skips the retained items
+    pub fn subscribe_new(&self) -> StreamReader<T> {
+        subscribe(&self.shared, true)
+    }
+    /// Report the tasks waiting in [`StreamReader::poll_read`] to `hooks`
+    /// from now on
+    ///
+    /// The calls name the [`id`](StreamReader::id) of the reader and run
+    /// while the stream is locked, except for `woken`. Keep them short.
+    pub fn set_hooks(&self, hooks: &'static dyn ReaderHooks) {
+        self.shared.lock().hooks = Some(hooks);
+    }
+    /// Number of readers subscribed to the stream
+    pub fn reader_count(&self) -> usize {
+        self.shared.lock().readers.len()
+    }
+    /// Block until every current reader consumed the item `seq` and all before it
+    ///
+    /// Returns immediately if `seq` was not published yet.
+    pub fn wait_until_consumed(&self, seq: Counter) {
+        let mut state = self.shared.lock();
+        let end = seq::advance(state.first_count, state.data.len());
+        if !seq::precedes(seq, end) {
+            return;
+        }
+        while !state.is_consumed(seq) {
+            state = self.shared.wait(state);
+        }
+    }
+    /// End the stream for all clones, readers still see the items published so far
+    pub fn close(&mut self) {
+        let mut state = self.shared.lock();
+        state.closed = true;
+        self.shared.wake(state);
+    }
+}
+
+impl<T> Default for Publisher<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Clone for Publisher<T> {
+    fn clone(&self) -> Self {
+        self.shared.lock().publishers += 1;
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Publisher<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        state.publishers -= 1;
+        if state.publishers == 0 {
+            drop(state);
+            self.close();
+        }
+    }
+}
+
+/// Add a reader at the oldest retained item, or after the newest one
+fn subscribe<T>(shared: &Arc<Shared<T>>, skip_retained: bool) -> StreamReader<T> {
+    let mut state = shared.lock();
+    let id = state.next_id;
+    state.next_id += 1;
+    let cursor = match skip_retained {
+        true => seq::advance(state.first_count, state.data.len()),
+        false => state.first_count,
+    };
+    state.readers.push(ReaderState {
+        id,
+        cursor,
+        lent: cursor,
+        held: Vec::new(),
+        missed: 0,
+    });
+    StreamReader {
+        shared: shared.clone(),
+        id,
+    }
+}
+
+/// Consumer object for the thread-safe publisher
+pub struct StreamReader<T> {
+    shared: Arc<Shared<T>>,
+    id: usize,
+}
+
+impl<T> StreamReader<T> {
+    /// Borrow the oldest item not handed out yet, it is consumed when the
+    /// guard drops
+    ///
+    /// While a guard is alive the next read continues with the item after
+    /// it, so no item is handed out twice.
+    pub fn read(&self) -> Option<BorrowRead<'_, T>> {
+        let (counter, obj) = self.shared.lock().lend(self.id)?;
+        Some(BorrowRead {
+            obj,
+            reader: self,
+            counter,
+        })
+    }
+    /// Borrow the oldest unconsumed item, or report why there is none
+    ///
+    /// Reports [`ReadError::Lagged`] once after the capacity limit dropped
+    /// items this reader didn't consume, and [`ReadError::Closed`] once the
+    /// stream is closed and every item was read.
+    pub fn try_read(&self) -> Result<Option<BorrowRead<'_, T>>, ReadError> {
+        let next = self.shared.lock().next(self.id)?;
+        Ok(next.map(|(counter, obj)| BorrowRead {
+            obj,
+            reader: self,
+            counter,
+        }))
+    }
+    /// Like [`try_read`](Self::try_read), `cx` is woken once there is
+    /// something to report
+    pub fn poll_read(&self, cx: &mut Context<'_>) -> Poll<Result<BorrowRead<'_, T>, ReadError>> {
+        let mut state = self.shared.lock();
+        let hooks = state.hooks;
+        if let Some(hooks) = hooks {
+            hooks.polled(self.id);
+        }
+        match state.next(self.id) {
+            Ok(Some((counter, obj))) => Poll::Ready(Ok(BorrowRead {
+                obj,
+                reader: self,
+                counter,
+            })),
+            Ok(None) => {
+                if !state.wakers.iter().any(|(_, w)| w.will_wake(cx.waker())) {
+                    state.wakers.push((self.id, cx.waker().clone()));
+                }
+                if let Some(hooks) = hooks {
+                    hooks.pending(self.id);
+                }
+                Poll::Pending
+            }
+            Err(error) => Poll::Ready(Err(error)),
+        }
+    }
+    /// Identifies the reader to the [`ReaderHooks`] of the stream
+    pub fn id(&self) -> usize {
+        self.id
+    }
+    /// Create another reader of the stream, it starts with the next published item
+    pub fn resubscribe(&self) -> Self {
+        subscribe(&self.shared, true)
+    }
+    /// Borrow the oldest unconsumed item, waiting up to `timeout` for one
+    ///
+    /// Returns `None` once the timeout elapsed or the stream is closed.
+    #[cfg(feature = "std")]
+    pub fn read_timeout(&self, timeout: Duration) -> Option<BorrowRead<'_, T>> {
+        let deadline = Instant::now().checked_add(timeout);
+        let mut state = self.shared.lock();
+        loop {
+            if let Some((counter, obj)) = state.lend(self.id) {
+                return Some(BorrowRead {
+                    obj,
+                    reader: self,
+                    counter,
+                });
+            }
+            if state.closed {
+                return None;
+            }
+            state = match deadline {
+                Some(deadline) => {
+                    let left = deadline.saturating_duration_since(Instant::now());
+                    if left.is_zero() {
+                        return None;
+                    }
+                    self.shared.wait_timeout(state, left)
+                }
+                None => self.shared.wait(state),
+            };
+        }
+    }
+    /// Iterate over the items, blocking for new ones until the publisher closes
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter { reader: self }
+    }
+    /// Take up to `max` available items under a single lock
+    ///
+    /// They are consumed together when the batch drops, which locks once more.
+    pub fn read_batch(&self, max: usize) -> Batch<'_, T> {
+        let mut state = self.shared.lock();
+        let first = state.lent(self.id);
+        let items: Vec<_> = (0..max)
+            .map_while(|_| state.hand_out(self.id).map(|(_, obj)| obj))
+            .collect();
+        if !items.is_empty() {
+            state.hold(self.id, first);
+        }
+        Batch {
+            items,
+            reader: self,
+            first,
+        }
+    }
+    /// Run `f` on every available item in parallel, returns how many there were
+    ///
+    /// The items are taken like a [`read_batch`](Self::read_batch) and only
+    /// consumed once `f` finished for all of them, so the publisher keeps
+    /// them until the whole batch is done.
+    #[cfg(feature = "rayon")]
+    pub fn process_parallel(&self, f: impl Fn(&T) + Send + Sync) -> usize
+    where
+        T: Send + Sync,
+    {
+        use rayon::prelude::*;
+
+        let batch = self.read_batch(usize::MAX);
+        batch.items.par_iter().for_each(|item| f(item));
+        batch.len()
+    }
+    /// Consume the next item and keep sharing it, e.g. to hand it to another thread
+    pub fn recv_arc(&self) -> Option<Arc<T>> {
+        self.read().map(BorrowRead::into_arc)
+    }
+    /// Number of items published but not handed out yet
+    pub fn len(&self) -> usize {
+        let state = self.shared.lock();
+        let end = seq::advance(state.first_count, state.data.len());
+        seq::distance(state.lent(self.id), end)
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Number of items dropped before this reader consumed them, not
+    /// reported by [`try_read`](Self::try_read) yet
+    #[cfg(feature = "std")]
+    pub(crate) fn missed(&self) -> u64 {
+        let state = self.shared.lock();
+        state
+            .readers
+            .iter()
+            .find(|r| r.id == self.id)
+            .map_or(0, |r| r.missed)
+    }
+    pub fn is_closed(&self) -> bool {
+        self.shared.lock().closed
+    }
+}
+
+impl<'a, T> IntoIterator for &'a StreamReader<T> {
+    type Item = BorrowRead<'a, T>;
+    type IntoIter = Iter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<T> Drop for StreamReader<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        state.readers.retain(|r| r.id != self.id);
+        state.trim();
+        drop(state);
+        self.shared.notify();
+    }
+}
+
+/// Blocking iterator, see [`StreamReader::iter`]
+pub struct Iter<'a, T> {
+    reader: &'a StreamReader<T>,
+}
+
+impl<'a, T> Iterator for Iter<'a, T> {
+    type Item = BorrowRead<'a, T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let shared = &self.reader.shared;
+        let mut state = shared.lock();
+        loop {
+            if let Some((counter, obj)) = state.lend(self.reader.id) {
+                return Some(BorrowRead {
+                    obj,
+                    reader: self.reader,
+                    counter,
+                });
+            }
+            if state.closed {
+                return None;
+            }
+            state = shared.wait(state);
+        }
+    }
+}
+
+/// Reader-side lock into data
+pub struct BorrowRead<'a, T> {
+    obj: Arc<T>,
+    reader: &'a StreamReader<T>,
+    counter: Counter,
+}
+
+impl<'a, T> BorrowRead<'a, T> {
+    /// Consume the item, the returned reference keeps it alive independently
+    pub fn into_arc(self) -> Arc<T> {
+        self.obj.clone()
+    }
+}
+
+impl<'a, T> Deref for BorrowRead<'a, T> {
+    type Target = T;
+
+    fn deref(&'_ self) -> &'_ Self::Target {
+        &self.obj
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl<'a, T: defmt::Format> defmt::Format for BorrowRead<'a, T> {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        (**self).format(f)
+    }
+}
+
+impl<'a, T> Drop for BorrowRead<'a, T> {
+    fn drop(&mut self) {
+        let shared = &self.reader.shared;
+        shared.lock().reader_done(self.reader.id, self.counter);
+        shared.notify();
+    }
+}
+
+/// Items consumed together, see [`StreamReader::read_batch`]
+pub struct Batch<'a, T> {
+    items: Vec<Arc<T>>,
+    reader: &'a StreamReader<T>,
+    first: Counter,
+}
+
+impl<'a, T> Batch<'a, T> {
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.items.iter().map(|obj| &**obj)
+    }
+}
+
+impl<'a, T> Drop for Batch<'a, T> {
+    fn drop(&mut self) {
+        if !self.items.is_empty() {
+            let shared = &self.reader.shared;
+            shared.lock().reader_done(self.reader.id, self.first);
+            shared.notify();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Publisher;
+    use crate::ReadError;
+    use std::thread;
+
+    #[test]
+    fn iterate_until_close() {
+        let mut p = Publisher::new();
+        let r = p.subscribe();
+        let consumer = thread::spawn(move || r.iter().map(|v| *v).collect::<Vec<u32>>());
+        for i in 0..100 {
+            p.publish(i);
+        }
+        drop(p);
+        assert_eq!(consumer.join().unwrap(), (0..100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn recv_arc() {
+        let mut p = Publisher::new();
+        let r = p.subscribe();
+        p.publish(String::from("shared"));
+        let item = r.recv_arc().unwrap();
+        assert!(r.recv_arc().is_none());
+        drop(p);
+        assert_eq!(*item, "shared");
+    }
+
+    #[test]
+    fn wait_until_consumed() {
+        let mut p = Publisher::new();
+        let r = p.subscribe();
+        let last = (0..10).map(|i| p.publish(i)).last().unwrap();
+        let consumer = thread::spawn(move || r.iter().take(10).map(|v| *v).sum::<u32>());
+        p.wait_until_consumed(last);
+        assert_eq!(consumer.join().unwrap(), 45);
+    }
+
+    #[test]
+    fn capacity_limit() {
+        let mut p = Publisher::new();
+        let r = p.subscribe();
+        p.set_capacity_limit(2);
+        for i in 0..5 {
+            p.publish(i);
+        }
+        let late = p.subscribe_new();
+        assert_eq!(p.reader_count(), 2);
+        assert_eq!(r.try_read().err(), Some(ReadError::Lagged(3)));
+        assert_eq!(r.iter().take(2).map(|v| *v).collect::<Vec<u32>>(), [3, 4]);
+        assert!(late.try_read().unwrap().is_none());
+        p.close();
+        assert_eq!(late.try_read().err(), Some(ReadError::Closed));
+    }
+
+    #[test]
+    fn lends_each_item_once() {
+        let mut p = Publisher::new();
+        let r = p.subscribe();
+        for i in 0..4 {
+            p.publish(i);
+        }
+        let a = r.read().unwrap();
+        let b = r.read().unwrap();
+        assert_eq!((*a, *b), (0, 1));
+        assert_eq!(r.len(), 2);
+        let next: Vec<u32> = r.iter().take(1).map(|v| *v).collect();
+        assert_eq!(next, [2]);
+        assert_eq!(r.read_batch(10).iter().copied().collect::<Vec<_>>(), [3]);
+        drop((a, b));
+        assert!(r.read().is_none());
+        p.wait_until_consumed(3);
+
+        // from several threads at once, every item reaches exactly one
+        let r = p.subscribe_new();
+        for i in 0..1000 {
+            p.publish(i);
+        }
+        p.close();
+        let mut seen: Vec<u32> = thread::scope(|s| {
+            let readers: Vec<_> = (0..4)
+                .map(|_| s.spawn(|| r.iter().map(|v| *v).collect::<Vec<_>>()))
+                .collect();
+            readers
+                .into_iter()
+                .flat_map(|t| t.join().unwrap())
+                .collect()
+        });
+        seen.sort_unstable();
+        assert_eq!(seen, (0..1000).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn hooks() {
+        use crate::ReaderHooks;
+        use std::{
+            sync::Mutex,
+            task::{Context, Waker},
+        };
+
+        struct Log(Mutex<Vec<(&'static str, usize)>>);
+        impl ReaderHooks for Log {
+            fn polled(&self, reader: usize) {
+                self.0.lock().unwrap().push(("polled", reader));
+            }
+            fn pending(&self, reader: usize) {
+                self.0.lock().unwrap().push(("pending", reader));
+            }
+            fn woken(&self, reader: usize) {
+                self.0.lock().unwrap().push(("woken", reader));
+            }
+        }
+        static LOG: Log = Log(Mutex::new(Vec::new()));
+
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut p = Publisher::new();
+        p.set_hooks(&LOG);
+        let _idle = p.subscribe();
+        let r = p.subscribe();
+        assert!(r.poll_read(&mut cx).is_pending());
+        p.publish(5);
+        assert!(r.poll_read(&mut cx).is_ready());
+        let id = r.id();
+        assert_eq!(
+            *LOG.0.lock().unwrap(),
+            [
+                ("polled", id),
+                ("pending", id),
+                ("woken", id),
+                ("polled", id)
+            ]
+        );
+    }
+
+    #[test]
+    fn read_batch() {
+        let mut p = Publisher::new();
+        let r = p.subscribe();
+        for i in 0..5 {
+            p.publish(i);
+        }
+        let batch = r.read_batch(3);
+        assert_eq!(batch.iter().copied().collect::<Vec<u32>>(), vec![0, 1, 2]);
+        drop(batch);
+        assert_eq!(r.read_batch(10).len(), 2);
+        assert!(r.read_batch(10).is_empty());
+    }
+
+    #[test]
+    fn guards_dropped_out_of_order() {
+        let mut p = Publisher::new();
+        let r = p.subscribe();
+        let other = p.subscribe();
+        for i in 0..3 {
+            p.publish(i);
+        }
+        let a = r.read().unwrap();
+        let b = r.read().unwrap();
+        drop(b);
+        // the first item is still borrowed
+        assert!(!p.shared.lock().is_consumed(1));
+        drop(a);
+        assert_eq!(r.read_batch(10).len(), 1);
+        drop(other.read_batch(2));
+        assert_eq!(p.shared.lock().data.len(), 1);
+        // a dropped reader no longer holds back what the others consumed
+        drop(other);
+        assert!(p.shared.lock().is_consumed(2));
+        assert!(p.shared.lock().data.is_empty());
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn process_parallel() {
+        use std::sync::atomic::{AtomicU32, Ordering};
+
+        let mut p = Publisher::new();
+        let r = p.subscribe();
+        let last = (0..100).map(|i| p.publish(i)).last().unwrap();
+        let sum = AtomicU32::new(0);
+        let count = r.process_parallel(|v| {
+            sum.fetch_add(*v, Ordering::Relaxed);
+        });
+        assert_eq!((count, sum.into_inner()), (100, 4950));
+        p.wait_until_consumed(last);
+        assert_eq!(r.process_parallel(|_| ()), 0);
+    }
+
+    #[test]
+    fn multiple_producers() {
+        let p = Publisher::new();
+        let r = p.subscribe();
+        let producers: Vec<_> = (0..4)
+            .map(|t| {
+                let mut p = p.clone();
+                thread::spawn(move || {
+                    for i in 0..25 {
+                        p.publish((t, i));
+                    }
+                })
+            })
+            .collect();
+        drop(p);
+        for producer in producers {
+            producer.join().unwrap();
+        }
+        let items: Vec<(u32, u32)> = r.iter().map(|v| *v).collect();
+        assert_eq!(items.len(), 100);
+        for t in 0..4 {
+            let own: Vec<u32> = items.iter().filter(|v| v.0 == t).map(|v| v.1).collect();
+            assert_eq!(own, (0..25).collect::<Vec<_>>());
+        }
+    }
+}
@@ -0,0 +1,187 @@
+//! Remote readers over TCP
+//!
+//! A [`TcpBridge`] re-broadcasts the frames it publishes to every connected
+//! [`TcpClient`]. On connecting a client sends the [`Start`] it wants, the
+//! bridge subscribes it once that arrived and writes whole frames, each as a
+//! little endian `u32` length followed by the payload.
+//!
+//! A client which doesn't keep up leaves its frames retained in the bridge,
+//! the socket buffers filling up are the only backpressure. Within the
+//! [capacity limit](TcpBridge::set_capacity_limit) the oldest frames are
+//! dropped, a client which lagged that far behind is disconnected and can
+//! reconnect with a new start.
+//!
+//! Like the `uds` bridge nothing here spawns threads or needs a runtime,
+//! call [`poll`](TcpBridge::poll) after publishing and periodically. The
+//! protocol itself lives in [`protocol`](crate::protocol), without sockets,
+//! for event loops of other transports such as QUIC.
+
+use std::{
+    io,
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    time::{Duration, Instant},
+};
+
+pub use crate::protocol::Start;
+use crate::{
+    bridge::{self, Connection},
+    framing::{FrameError, FramePublisher},
+    protocol::Client,
+};
+
+/// Publisher of frames to the clients of a TCP listener
+pub struct TcpBridge {
+    listener: TcpListener,
+    // boxed, the readers of the clients keep its address
+    frames: Box<FramePublisher>,
+    handshake_timeout: Option<Duration>,
+    clients: Vec<Connection<TcpStream>>,
+}
+
+impl TcpBridge {
+    /// Listen at `addr` for clients
+    pub fn bind(addr: impl ToSocketAddrs, max_frame: usize) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            listener,
+            frames: Box::new(FramePublisher::new(max_frame)),
+            handshake_timeout: None,
+            clients: Vec::new(),
+        })
+    }
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+    /// Bound the bytes retained for clients which didn't take them yet
+    pub fn set_capacity_limit(&mut self, bytes: usize) {
+        self.frames.set_capacity_limit(bytes);
+    }
+    /// Drop clients accepted from now on which didn't send their start within `timeout`
+    pub fn set_handshake_timeout(&mut self, timeout: Option<Duration>) {
+        self.handshake_timeout = timeout;
+    }
+    /// Publish one frame to every subscribed client
+    ///
+    /// It is sent on the next [`poll`](Self::poll).
+    pub fn publish(&mut self, frame: &[u8]) -> Result<(), FrameError> {
+        self.frames.publish(frame)
+    }
+    /// Accept and subscribe new clients and write the frames published so far
+    ///
+    /// Clients which disconnected, lagged or sent an invalid start are
+    /// dropped. Fails only if the listening socket does.
+    pub fn poll(&mut self) -> io::Result<()> {
+        let now = Instant::now();
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    if stream.set_nonblocking(true).is_ok() {
+                        let _ = stream.set_nodelay(true);
+                        let deadline = self.handshake_timeout.and_then(|t| now.checked_add(t));
+                        self.clients.push(Connection::accept(stream, deadline));
+                    }
+                }
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => break,
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+                Err(error) => return Err(error),
+            }
+        }
+        let frames = &mut self.frames;
+        self.clients.retain_mut(|client| client.poll(frames, now));
+        Ok(())
+    }
+    /// Number of subscribed clients, as of the last poll
+    pub fn clients(&self) -> usize {
+        self.clients.iter().filter(|c| c.is_streaming()).count()
+    }
+    /// Signal clients the end of the stream once they received every frame
+    pub fn close(&mut self) {
+        self.frames.close();
+    }
+}
+
+/// Reads the frames of a bridge, blocking until each arrived
+pub struct TcpClient {
+    stream: TcpStream,
+    client: Client,
+}
+
+impl TcpClient {
+    /// Connect to the bridge at `addr` and request to read from `start`
+    ///
+    /// Frames above `max_frame` bytes are refused.
+    pub fn connect(addr: impl ToSocketAddrs, start: Start, max_frame: usize) -> io::Result<Self> {
+        let mut stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        let mut client = Client::new(start, max_frame);
+        bridge::connect(&mut stream, &mut client)?;
+        Ok(Self { stream, client })
+    }
+    /// The next frame, or `None` once the bridge ended the stream
+    ///
+    /// Fails with [`InvalidData`](io::ErrorKind::InvalidData) on a frame above
+    /// the maximum.
+    pub fn recv(&mut self) -> io::Result<Option<Vec<u8>>> {
+        bridge::recv(&mut self.stream, &mut self.client)
+    }
+    pub fn stream(&self) -> &TcpStream {
+        &self.stream
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Start, TcpBridge, TcpClient};
+    use std::{thread, time::Duration};
+
+    /// Poll until `clients` completed their handshake
+    fn wait_for(bridge: &mut TcpBridge, clients: usize) {
+        while bridge.clients() < clients {
+            bridge.poll().unwrap();
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    /// Frames received when starting at `start`, with three retained
+    fn received(start: Start) -> Vec<u8> {
+        let mut bridge = TcpBridge::bind("127.0.0.1:0", 64).unwrap();
+        for frame in [&b"a"[..], b"b", b"c"] {
+            bridge.publish(frame).unwrap();
+        }
+        let mut client = TcpClient::connect(bridge.local_addr().unwrap(), start, 64).unwrap();
+        wait_for(&mut bridge, 1);
+        bridge.publish(b"d").unwrap();
+        bridge.close();
+        bridge.poll().unwrap();
+        assert_eq!(bridge.clients(), 0);
+        let mut frames = Vec::new();
+        while let Some(frame) = client.recv().unwrap() {
+            frames.push(frame);
+        }
+        frames.concat()
+    }
+
+    #[test]
+    fn start_policies() {
+        assert_eq!(received(Start::Oldest), b"abcd");
+        assert_eq!(received(Start::Latest(2)), b"bcd");
+        assert_eq!(received(Start::Latest(5)), b"abcd");
+        assert_eq!(received(Start::NEW), b"d");
+    }
+
+    #[test]
+    fn lagging_client_is_dropped() {
+        let mut bridge = TcpBridge::bind("127.0.0.1:0", 64).unwrap();
+        bridge.set_capacity_limit(16);
+        let addr = bridge.local_addr().unwrap();
+        let mut slow = TcpClient::connect(addr, Start::NEW, 64).unwrap();
+        wait_for(&mut bridge, 1);
+        for _ in 0..4 {
+            bridge.publish(b"0123456789").unwrap();
+        }
+        bridge.poll().unwrap();
+        assert_eq!(bridge.clients(), 0);
+        assert_eq!(slow.recv().unwrap(), None);
+    }
+}
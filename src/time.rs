@@ -0,0 +1,72 @@
+//! Points in time for targets without `std`
+//!
+//! There is no operating system clock to read, so an [`Instant`] is just
+//! the time passed since some start, e.g. the boot of the device. It only
+//! moves as the [`Clock`](crate::Clock) set on the publisher says, which
+//! would typically read a hardware timer.
+
+use core::{
+    ops::{Add, AddAssign, Sub},
+    time::Duration,
+};
+
+/// A point in time, measured from the start of the clock
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(Duration);
+
+impl Instant {
+    /// The point in time `since_start` after the start of the clock
+    pub const fn from_start(since_start: Duration) -> Self {
+        Self(since_start)
+    }
+    /// Time passed since the start of the clock
+    pub const fn since_start(&self) -> Duration {
+        self.0
+    }
+    /// Time passed since `earlier`, zero if it is later than `self`
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        self.0.saturating_sub(earlier.0)
+    }
+    pub fn checked_duration_since(&self, earlier: Instant) -> Option<Duration> {
+        self.0.checked_sub(earlier.0)
+    }
+    pub fn saturating_duration_since(&self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        self.0.checked_add(duration).map(Self)
+    }
+    pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        self.0.checked_sub(duration).map(Self)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        Self(self.0 + duration)
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, duration: Duration) {
+        self.0 += duration;
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, duration: Duration) -> Instant {
+        Self(self.0 - duration)
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
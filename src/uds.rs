@@ -0,0 +1,187 @@
+//! Local remote readers over Unix domain sockets
+//!
+//! A [`UdsBridge`] listens on a socket path and streams the frames it
+//! publishes to every connected client, so a sidecar process can tap the
+//! stream without mapping shared memory. Each client gets a
+//! [`FrameReader`](crate::framing::FrameReader) of its own, starting with the retained frames. The
+//! bridge writes whole frames to the socket as far as the client keeps up,
+//! each as a little endian `u32` length followed by the payload. [`UdsClient`] reads them back.
+//!
+//! The bridge never blocks on a client. Frames a client didn't take yet are
+//! retained like for any other reader, within the
+//! [capacity limit](UdsBridge::set_capacity_limit) if there is one. A
+//! client which lagged behind that limit is disconnected, it sees the end
+//! of the stream and can connect again.
+//!
+//! Nothing here spawns threads, call [`poll`](UdsBridge::poll) from the
+//! loop of the publishing thread or whenever frames were published.
+
+use std::{
+    fs, io,
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use crate::{
+    bridge::{self, Connection},
+    framing::{FrameError, FramePublisher},
+    protocol::Client,
+};
+
+/// Publisher of frames to the clients of a socket
+pub struct UdsBridge {
+    listener: UnixListener,
+    path: PathBuf,
+    // boxed, the readers of the clients keep its address
+    frames: Box<FramePublisher>,
+    clients: Vec<Connection<UnixStream>>,
+}
+
+impl UdsBridge {
+    /// Listen at `path` for clients, replacing a stale socket file
+    pub fn bind(path: impl AsRef<Path>, max_frame: usize) -> io::Result<Self> {
+        let path = path.as_ref();
+        match fs::remove_file(path) {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+            _ => {}
+        }
+        let listener = UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            listener,
+            path: path.to_owned(),
+            frames: Box::new(FramePublisher::new(max_frame)),
+            clients: Vec::new(),
+        })
+    }
+    /// Bound the bytes retained for clients which didn't take them yet
+    pub fn set_capacity_limit(&mut self, bytes: usize) {
+        self.frames.set_capacity_limit(bytes);
+    }
+    /// Publish one frame to every connected client
+    ///
+    /// It is sent on the next [`poll`](Self::poll).
+    pub fn publish(&mut self, frame: &[u8]) -> Result<(), FrameError> {
+        self.frames.publish(frame)
+    }
+    /// Accept new clients and write the frames published so far
+    ///
+    /// Clients which disconnected or lagged are dropped. Fails only if the
+    /// listening socket does.
+    pub fn poll(&mut self) -> io::Result<()> {
+        loop {
+            match self.listener.accept() {
+                // a client which can't be made non-blocking is skipped
+                Ok((stream, _)) => {
+                    if stream.set_nonblocking(true).is_ok() {
+                        self.clients
+                            .push(Connection::subscribe(stream, &mut self.frames));
+                    }
+                }
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => break,
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+                Err(error) => return Err(error),
+            }
+        }
+        let now = Instant::now();
+        let frames = &mut self.frames;
+        self.clients.retain_mut(|client| client.poll(frames, now));
+        Ok(())
+    }
+    /// Number of connected clients, as of the last poll
+    pub fn clients(&self) -> usize {
+        self.clients.len()
+    }
+    /// Signal clients the end of the stream once they received every frame
+    pub fn close(&mut self) {
+        self.frames.close();
+    }
+}
+
+impl Drop for UdsBridge {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Reads the frames of a bridge, blocking until each arrived
+pub struct UdsClient {
+    stream: UnixStream,
+    client: Client,
+}
+
+impl UdsClient {
+    /// Connect to the bridge at `path`, accepting frames up to `max_frame` bytes
+    pub fn connect(path: impl AsRef<Path>, max_frame: usize) -> io::Result<Self> {
+        Ok(Self {
+            stream: UnixStream::connect(path)?,
+            client: Client::without_handshake(max_frame),
+        })
+    }
+    /// The next frame, or `None` once the bridge ended the stream
+    ///
+    /// Fails with [`InvalidData`](io::ErrorKind::InvalidData) on a frame above
+    /// the maximum.
+    pub fn recv(&mut self) -> io::Result<Option<Vec<u8>>> {
+        bridge::recv(&mut self.stream, &mut self.client)
+    }
+    pub fn stream(&self) -> &UnixStream {
+        &self.stream
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{UdsBridge, UdsClient};
+    use std::path::PathBuf;
+
+    fn path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("mc-uds-{}-{name}", std::process::id()))
+    }
+
+    #[test]
+    fn streams_frames() {
+        let path = path("frames");
+        let mut bridge = UdsBridge::bind(&path, 64).unwrap();
+        bridge.publish(b"before").unwrap();
+        let mut a = UdsClient::connect(&path, 64).unwrap();
+        let mut b = UdsClient::connect(&path, 64).unwrap();
+        bridge.poll().unwrap();
+        assert_eq!(bridge.clients(), 2);
+        bridge.publish(b"one").unwrap();
+        bridge.publish(b"").unwrap();
+        bridge.poll().unwrap();
+        assert_eq!(a.recv().unwrap().unwrap(), b"before");
+        assert_eq!(a.recv().unwrap().unwrap(), b"one");
+        assert_eq!(a.recv().unwrap().unwrap(), b"");
+        drop(a);
+        bridge.publish(b"two").unwrap();
+        bridge.close();
+        bridge.poll().unwrap();
+        bridge.poll().unwrap();
+        assert_eq!(bridge.clients(), 0);
+        assert_eq!(b.recv().unwrap().unwrap(), b"before");
+        assert_eq!(b.recv().unwrap().unwrap(), b"one");
+        assert_eq!(b.recv().unwrap().unwrap(), b"");
+        assert_eq!(b.recv().unwrap().unwrap(), b"two");
+        assert_eq!(b.recv().unwrap(), None);
+        drop(bridge);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn drops_lagging_clients() {
+        let path = path("lag");
+        let mut bridge = UdsBridge::bind(&path, 64).unwrap();
+        bridge.set_capacity_limit(16);
+        let mut slow = UdsClient::connect(&path, 64).unwrap();
+        bridge.poll().unwrap();
+        for _ in 0..4 {
+            bridge.publish(b"0123456789").unwrap();
+        }
+        bridge.poll().unwrap();
+        assert_eq!(bridge.clients(), 0);
+        assert_eq!(slow.recv().unwrap(), None);
+    }
+}
@@ -0,0 +1,137 @@
+//! JavaScript bindings for the browser
+//!
+//! Items are any `JsValue`, `publishBytes` copies a byte slice into a new
+//! `Uint8Array`. Every subscriber receives each item, and
+//! [`ready`](JsSubscriber::ready) returns a promise which resolves once
+//! there is something to read, so a consumer loops over `await sub.ready()`
+//! and `sub.read()`.
+//!
+//! The standard library has no clock on `wasm32-unknown-unknown`, so the
+//! publisher here records no timestamps and its subscribers have no idle
+//! timeout.
+
+use alloc::rc::Rc;
+use core::cell::RefCell;
+
+use js_sys::{Function, Promise, Uint8Array};
+use wasm_bindgen::prelude::*;
+
+use crate::{Publisher, ReadError, StreamReader};
+
+/// Broadcasts every item to each of its subscribers
+#[wasm_bindgen(js_name = Publisher)]
+pub struct JsPublisher {
+    // boxed, the subscribers keep its address
+    inner: Box<Publisher<JsValue>>,
+}
+
+#[wasm_bindgen(js_class = Publisher)]
+impl JsPublisher {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        Self {
+            inner: Box::new(Publisher::new()),
+        }
+    }
+    /// Add a subscriber, it receives the items published from now on
+    pub fn subscribe(&mut self) -> JsSubscriber {
+        let mut reader = Box::new(StreamReader::new());
+        let waiting: Rc<RefCell<Option<Function>>> = Rc::default();
+        let wake = waiting.clone();
+        reader.set_notification(Box::new(move || {
+            if let Some(resolve) = wake.borrow_mut().take() {
+                let _ = resolve.call0(&JsValue::UNDEFINED);
+            }
+        }));
+        self.inner.add_stream_reader(&mut reader);
+        JsSubscriber { reader, waiting }
+    }
+    /// Publish a value, returns its sequence number
+    pub fn publish(&mut self, item: JsValue) -> f64 {
+        self.inner.publish(item) as f64
+    }
+    /// Publish a copy of the bytes as a `Uint8Array`
+    #[wasm_bindgen(js_name = publishBytes)]
+    pub fn publish_bytes(&mut self, data: &[u8]) -> f64 {
+        self.publish(Uint8Array::from(data).into())
+    }
+    /// Signal subscribers that no further items will be published
+    pub fn close(&mut self) {
+        self.inner.close();
+    }
+}
+
+impl Default for JsPublisher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Receives the items of a publisher
+#[wasm_bindgen(js_name = Subscriber)]
+pub struct JsSubscriber {
+    reader: Box<StreamReader<JsValue>>,
+    /// resolves the promise of the pending `ready`
+    waiting: Rc<RefCell<Option<Function>>>,
+}
+
+#[wasm_bindgen(js_class = Subscriber)]
+impl JsSubscriber {
+    /// The next item, `undefined` while nothing is queued
+    ///
+    /// Throws once the stream is closed, and once after items were lost.
+    pub fn read(&mut self) -> Result<JsValue, JsError> {
+        match self.reader.try_read() {
+            Ok(item) => Ok(item.map_or(JsValue::UNDEFINED, |item| item.clone())),
+            Err(error) => Err(JsError::new(&error.to_string())),
+        }
+    }
+    /// A promise resolving once an item is queued or the stream closed
+    pub fn ready(&mut self) -> Promise {
+        if self.reader.stats().current_lag > 0 || self.reader.is_closed() {
+            return Promise::resolve(&JsValue::UNDEFINED);
+        }
+        let waiting = self.waiting.clone();
+        Promise::new(&mut |resolve, _| {
+            waiting.borrow_mut().replace(resolve);
+        })
+    }
+    #[wasm_bindgen(js_name = isClosed)]
+    pub fn is_closed(&self) -> bool {
+        self.reader.is_closed()
+    }
+}
+
+impl JsSubscriber {
+    /// Like [`read`](Self::read), for Rust code holding a subscriber
+    pub fn try_read(&mut self) -> Result<Option<JsValue>, ReadError> {
+        Ok(self.reader.try_read()?.map(|item| item.clone()))
+    }
+}
+
+#[cfg(all(test, target_arch = "wasm32"))]
+mod test {
+    use super::JsPublisher;
+    use crate::ReadError;
+    use wasm_bindgen::JsValue;
+    use wasm_bindgen_futures::JsFuture;
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    #[wasm_bindgen_test]
+    async fn wakes_on_publish() {
+        let mut p = JsPublisher::new();
+        let mut a = p.subscribe();
+        let mut b = p.subscribe();
+        let ready = JsFuture::from(a.ready());
+        p.publish(JsValue::from(1));
+        ready.await.unwrap();
+        assert_eq!(a.try_read().unwrap(), Some(JsValue::from(1)));
+        p.publish_bytes(b"ab");
+        let bytes = a.read().unwrap();
+        assert_eq!(js_sys::Uint8Array::new(&bytes).to_vec(), b"ab");
+        assert_eq!(b.try_read().unwrap(), Some(JsValue::from(1)));
+        p.close();
+        JsFuture::from(a.ready()).await.unwrap();
+        assert_eq!(a.try_read(), Err(ReadError::Closed));
+    }
+}
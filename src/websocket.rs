@@ -0,0 +1,247 @@
+//! Fan-out to browsers over WebSocket
+//!
+//! A [`WsBridge`] accepts WebSocket connections and re-broadcasts every
+//! text or binary message it publishes to each of them. A connection gets a
+//! [`StreamReader`] of its own, so the publisher's retention applies per
+//! browser tab: messages a slow tab didn't take yet stay queued only within
+//! the [capacity limit](WsBridge::set_capacity_limit), beyond it the tab
+//! misses the oldest ones. With [`Delivery::Latest`] a tab which can't keep
+//! up only gets the newest message whenever its socket has room, which
+//! suits state updates better than a backlog.
+//!
+//! Messages are shared between the connections, not copied. Nothing here
+//! needs a runtime, call [`poll`](WsBridge::poll) after publishing and
+//! periodically.
+
+use std::{
+    io,
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+};
+
+use tungstenite::{
+    Bytes, Error, HandshakeError, Message, WebSocket,
+    handshake::{MidHandshake, server::NoCallback, server::ServerHandshake},
+};
+
+use crate::{Counter, Publisher, ReadError, StreamReader};
+
+/// What a connection receives when it falls behind
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Delivery {
+    /// every retained message, in order
+    #[default]
+    Every,
+    /// only the newest message, skipping the backlog
+    Latest,
+}
+
+/// An open connection and its reader
+struct Connection {
+    socket: WebSocket<TcpStream>,
+    // boxed, the publisher keeps its address
+    reader: Box<StreamReader<Message>>,
+}
+
+impl Connection {
+    /// Write the queued messages as far as the socket takes them, returns
+    /// false once the connection is done with
+    fn flush(&mut self, delivery: Delivery) -> bool {
+        // answers pings and notices a close by the browser
+        loop {
+            match self.socket.read() {
+                Ok(_) => {}
+                Err(Error::Io(error)) if error.kind() == io::ErrorKind::WouldBlock => break,
+                Err(_) => return false,
+            }
+        }
+        loop {
+            match self.socket.flush() {
+                Ok(()) => {}
+                Err(Error::Io(error)) if error.kind() == io::ErrorKind::WouldBlock => return true,
+                Err(_) => return false,
+            }
+            let message = match delivery {
+                Delivery::Every => match self.reader.try_read() {
+                    Ok(message) => message.map(|message| message.clone()),
+                    Err(ReadError::Lagged(_)) => continue,
+                    Err(_) => None,
+                },
+                Delivery::Latest => self.reader.latest().map(|message| message.clone()),
+            };
+            let Some(message) = message else {
+                if self.reader.is_closed() {
+                    let _ = self.socket.close(None);
+                    let _ = self.socket.flush();
+                    return false;
+                }
+                return true;
+            };
+            match self.socket.write(message) {
+                Ok(()) => {}
+                Err(Error::Io(error)) if error.kind() == io::ErrorKind::WouldBlock => return true,
+                Err(_) => return false,
+            }
+        }
+    }
+}
+
+/// Publisher of messages to the WebSocket clients of a TCP listener
+pub struct WsBridge {
+    listener: TcpListener,
+    // boxed, the readers of the connections keep its address
+    messages: Box<Publisher<Message>>,
+    delivery: Delivery,
+    handshakes: Vec<MidHandshake<ServerHandshake<TcpStream, NoCallback>>>,
+    connections: Vec<Connection>,
+}
+
+impl WsBridge {
+    /// Listen at `addr` for WebSocket clients
+    pub fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            listener,
+            messages: Box::new(Publisher::new()),
+            delivery: Delivery::Every,
+            handshakes: Vec::new(),
+            connections: Vec::new(),
+        })
+    }
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+    /// Bound the number of messages retained for slow connections
+    pub fn set_capacity_limit(&mut self, messages: usize) {
+        self.messages.set_capacity_limit(messages);
+    }
+    /// Choose what connections falling behind receive from now on
+    pub fn set_delivery(&mut self, delivery: Delivery) {
+        self.delivery = delivery;
+    }
+    /// Publish a text message, e.g. JSON, returns its sequence number
+    pub fn publish_text(&mut self, text: impl Into<String>) -> Counter {
+        self.messages.publish(Message::text(text.into()))
+    }
+    /// Publish a binary message, returns its sequence number
+    pub fn publish_binary(&mut self, data: impl Into<Bytes>) -> Counter {
+        self.messages.publish(Message::binary(data))
+    }
+    /// Accept new connections and write the messages published so far
+    ///
+    /// Connections which closed, or failed their handshake, are dropped.
+    /// Fails only if the listening socket does.
+    pub fn poll(&mut self) -> io::Result<()> {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    if stream.set_nonblocking(true).is_ok() {
+                        let _ = stream.set_nodelay(true);
+                        self.upgrade(tungstenite::accept(stream));
+                    }
+                }
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => break,
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+                Err(error) => return Err(error),
+            }
+        }
+        for handshake in core::mem::take(&mut self.handshakes) {
+            self.upgrade(handshake.handshake());
+        }
+        let delivery = self.delivery;
+        self.connections
+            .retain_mut(|connection| connection.flush(delivery));
+        Ok(())
+    }
+    /// Subscribe a connection once its handshake completed
+    fn upgrade(
+        &mut self,
+        result: Result<
+            WebSocket<TcpStream>,
+            HandshakeError<ServerHandshake<TcpStream, NoCallback>>,
+        >,
+    ) {
+        match result {
+            Ok(socket) => {
+                let mut reader = Box::new(StreamReader::new());
+                self.messages.add_stream_reader(&mut reader);
+                self.connections.push(Connection { socket, reader });
+            }
+            Err(HandshakeError::Interrupted(handshake)) => self.handshakes.push(handshake),
+            Err(HandshakeError::Failure(_)) => {}
+        }
+    }
+    /// Number of open connections, as of the last poll
+    pub fn connections(&self) -> usize {
+        self.connections.len()
+    }
+    /// Close every connection once it received the retained messages
+    pub fn close(&mut self) {
+        self.messages.close();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Delivery, WsBridge};
+    use std::{net::TcpStream, thread, time::Duration};
+    use tungstenite::Message;
+
+    /// Connect a client in a thread, it collects messages until closed
+    fn client(bridge: &mut WsBridge) -> thread::JoinHandle<Vec<Message>> {
+        let addr = bridge.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let stream = TcpStream::connect(addr).unwrap();
+            let (mut socket, _) = tungstenite::client(format!("ws://{addr}/"), stream).unwrap();
+            let mut messages = Vec::new();
+            while let Ok(message) = socket.read() {
+                if message.is_close() {
+                    break;
+                }
+                messages.push(message);
+            }
+            messages
+        });
+        let connections = bridge.connections();
+        while bridge.connections() == connections {
+            bridge.poll().unwrap();
+            thread::sleep(Duration::from_millis(1));
+        }
+        client
+    }
+
+    /// Poll until `client` finished
+    fn join(bridge: &mut WsBridge, client: thread::JoinHandle<Vec<Message>>) -> Vec<Message> {
+        while !client.is_finished() {
+            bridge.poll().unwrap();
+            thread::sleep(Duration::from_millis(1));
+        }
+        client.join().unwrap()
+    }
+
+    #[test]
+    fn fan_out() {
+        let mut bridge = WsBridge::bind("127.0.0.1:0").unwrap();
+        let a = client(&mut bridge);
+        let b = client(&mut bridge);
+        bridge.publish_text("{\"n\":1}");
+        bridge.publish_binary(&b"\x02"[..]);
+        bridge.close();
+        let expected = [Message::text("{\"n\":1}"), Message::binary(&b"\x02"[..])];
+        assert_eq!(join(&mut bridge, a), expected);
+        assert_eq!(join(&mut bridge, b), expected);
+        assert_eq!(bridge.connections(), 0);
+    }
+
+    #[test]
+    fn conflates_to_latest() {
+        let mut bridge = WsBridge::bind("127.0.0.1:0").unwrap();
+        bridge.set_delivery(Delivery::Latest);
+        let client = client(&mut bridge);
+        for n in 0..10 {
+            bridge.publish_text(n.to_string());
+        }
+        bridge.close();
+        assert_eq!(join(&mut bridge, client), [Message::text("9")]);
+    }
+}